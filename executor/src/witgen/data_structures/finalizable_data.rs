@@ -132,10 +132,23 @@ impl<'a, T: FieldElement> CompactDataRef<'a, T> {
         v
     }
 
+    /// Returns the value of the cell or `None` if it is not known.
+    pub fn try_get(&self, row: i32, col: u32) -> Option<T> {
+        let (v, known) = self.data.get(self.inner_row(row), col as u64);
+        known.then_some(v)
+    }
+
     pub fn set(&mut self, row: i32, col: u32, value: T) {
         self.data.set(self.inner_row(row), col as u64, value);
     }
 
+    /// Returns true if the row, relative to "row zero", is neither before the
+    /// start nor after the end of the data.
+    pub fn contains_row(&self, row: i32) -> bool {
+        let row = row + self.row_offset as i32;
+        row >= 0 && (row as usize) < self.data.len()
+    }
+
     fn inner_row(&self, row: i32) -> usize {
         (row + self.row_offset as i32) as usize
     }
@@ -1,18 +1,24 @@
 use std::{
     collections::BTreeMap,
     fmt::{self, Display, Formatter},
-    ops::{Add, Mul, Neg, Sub},
+    ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign},
 };
 
 use itertools::Itertools;
 use num_traits::Zero;
-use powdr_number::FieldElement;
+use powdr_number::{log2_exact, FieldElement};
+use serde::{Deserialize, Serialize};
 
 use crate::witgen::EvalError;
 
-use super::{super::range_constraints::RangeConstraint, symbolic_expression::SymbolicExpression};
+use super::{
+    super::range_constraints::RangeConstraint,
+    symbolic_expression::{Formatted, NumberFormat, SymbolicExpression},
+};
 
 /// The effect of solving a symbolic equation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "V: Ord + Deserialize<'de>"))]
 pub enum Effect<T: FieldElement, V> {
     /// Variable can be assigned a value.
     Assignment(V, SymbolicExpression<T, V>),
@@ -20,11 +26,128 @@ pub enum Effect<T: FieldElement, V> {
     RangeConstraint(V, RangeConstraint<T>),
     /// A run-time assertion. If this fails, we have conflicting constraints.
     Assertion(Assertion<T, V>),
-    /// a call to a different machine.
-    MachineCall(u64, Vec<MachineCallArgument<T, V>>),
+    /// A run-time check that the value of the variable is allowed by the range
+    /// constraint, see `WitgenInferenceConfig::with_input_range_checks`.
+    RangeCheck(V, RangeConstraint<T>),
+    /// a call to a different machine, with the arguments and the cells the call
+    /// writes to, in the order of the unknown arguments, see `Effect::machine_call`.
+    MachineCall(u64, Vec<MachineCallArgument<T, V>>, Vec<V>),
+    /// Writes the values into a shared state, e.g. a memory machine, via the
+    /// interaction with the given id. In contrast to a machine call, nothing is read.
+    StateWrite(u64, Vec<SymbolicExpression<T, V>>),
+    /// The constraints are not satisfiable if this code is reached,
+    /// see `ConflictPolicy::Speculative`. Contains the reason.
+    Infeasible(String),
+}
+
+impl<T: FieldElement, V: Display> Display for Effect<T, V> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.display_with(NumberFormat::default()).fmt(f)
+    }
+}
+
+impl<T: FieldElement, V: Display> Display for Formatted<'_, Effect<T, V>> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let Formatted(effect, format) = *self;
+        match effect {
+            Effect::Assignment(v, expr) => write!(f, "{v} = {};", expr.display_with(format)),
+            Effect::RangeConstraint(v, rc) => write!(f, "{v} in {rc};"),
+            Effect::RangeCheck(v, rc) => write!(f, "assert {v} in {rc};"),
+            Effect::Assertion(Assertion {
+                lhs,
+                rhs,
+                expected_equal,
+            }) => write!(
+                f,
+                "assert {} {} {};",
+                lhs.display_with(format),
+                if *expected_equal { "==" } else { "!=" },
+                rhs.display_with(format)
+            ),
+            Effect::MachineCall(id, args, _) => write!(
+                f,
+                "lookup({id}, [{}]);",
+                args.iter()
+                    .map(|arg| match arg {
+                        MachineCallArgument::Known(k) =>
+                            format!("Known({})", k.display_with(format)),
+                        MachineCallArgument::Unknown(u) => {
+                            format!("Unknown({})", u.display_with(format))
+                        }
+                    })
+                    .join(", ")
+            ),
+            Effect::StateWrite(id, args) => write!(
+                f,
+                "state_write({id}, [{}]);",
+                args.iter().map(|arg| arg.display_with(format)).format(", ")
+            ),
+            Effect::Infeasible(reason) => write!(f, "infeasible({reason:?});"),
+        }
+    }
+}
+
+impl<T: FieldElement, V> Effect<T, V> {
+    /// Returns a value that displays the effect with the given number format.
+    pub fn display_with(&self, format: NumberFormat) -> Formatted<'_, Self> {
+        Formatted(self, format)
+    }
+
+    /// Returns a copy of this effect where each variable is replaced by `f(variable)`.
+    pub fn map_variables<W: Ord>(&self, f: &impl Fn(&V) -> W) -> Effect<T, W> {
+        match self {
+            Effect::Assignment(v, expr) => Effect::Assignment(f(v), expr.map_symbols(f)),
+            Effect::RangeConstraint(v, rc) => Effect::RangeConstraint(f(v), rc.clone()),
+            Effect::RangeCheck(v, rc) => Effect::RangeCheck(f(v), rc.clone()),
+            Effect::Assertion(Assertion {
+                lhs,
+                rhs,
+                expected_equal,
+            }) => Effect::Assertion(Assertion {
+                lhs: lhs.map_symbols(f),
+                rhs: rhs.map_symbols(f),
+                expected_equal: *expected_equal,
+            }),
+            Effect::MachineCall(id, args, outputs) => Effect::MachineCall(
+                *id,
+                args.iter()
+                    .map(|arg| match arg {
+                        MachineCallArgument::Known(k) => {
+                            MachineCallArgument::Known(k.map_symbols(f))
+                        }
+                        MachineCallArgument::Unknown(u) => {
+                            MachineCallArgument::Unknown(u.map_variables(f))
+                        }
+                    })
+                    .collect(),
+                outputs.iter().map(f).collect(),
+            ),
+            Effect::StateWrite(id, args) => {
+                Effect::StateWrite(*id, args.iter().map(|a| a.map_symbols(f)).collect())
+            }
+            Effect::Infeasible(reason) => Effect::Infeasible(reason.clone()),
+        }
+    }
+}
+
+impl<T: FieldElement, V: Ord + Clone + Display> Effect<T, V> {
+    /// Creates a machine call that writes to the unknown variables of the `Unknown`
+    /// arguments. The inference only creates `Unknown` arguments with a single unknown
+    /// variable, others (which can only be parsed) do not contribute an output cell.
+    pub fn machine_call(id: u64, arguments: Vec<MachineCallArgument<T, V>>) -> Self {
+        let outputs = arguments
+            .iter()
+            .filter_map(|arg| match arg {
+                MachineCallArgument::Known(_) => None,
+                MachineCallArgument::Unknown(u) => u.single_unknown_variable().cloned(),
+            })
+            .collect();
+        Effect::MachineCall(id, arguments, outputs)
+    }
 }
 
 /// A run-time assertion. If this fails, we have conflicting constraints.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Assertion<T: FieldElement, V> {
     pub lhs: SymbolicExpression<T, V>,
     pub rhs: SymbolicExpression<T, V>,
@@ -59,6 +182,8 @@ impl<T: FieldElement, V> Assertion<T, V> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "V: Ord + Deserialize<'de>"))]
 pub enum MachineCallArgument<T: FieldElement, V> {
     Known(SymbolicExpression<T, V>),
     Unknown(AffineSymbolicExpression<T, V>),
@@ -90,7 +215,8 @@ impl<T: FieldElement, V> ProcessResult<T, V> {
 /// (which can still include variables or symbols, which are only known at run-time),
 /// and the `x_i` are variables that are unknown at this point.
 /// It also stores range constraints for all unknown variables.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "V: Ord + Deserialize<'de>"))]
 pub struct AffineSymbolicExpression<T: FieldElement, V> {
     coefficients: BTreeMap<V, SymbolicExpression<T, V>>,
     offset: SymbolicExpression<T, V>,
@@ -100,31 +226,63 @@ pub struct AffineSymbolicExpression<T: FieldElement, V> {
 /// Display for affine symbolic expressions, for informational purposes only.
 impl<T: FieldElement, V: Display> Display for AffineSymbolicExpression<T, V> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        if self.coefficients.is_empty() {
-            write!(f, "{}", self.offset)
+        self.display_with(NumberFormat::default()).fmt(f)
+    }
+}
+
+impl<T: FieldElement, V: Display> Display for Formatted<'_, AffineSymbolicExpression<T, V>> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Formatted(expr, format) = *self;
+        if expr.coefficients.is_empty() {
+            write!(f, "{}", expr.offset.display_with(format))
         } else {
             write!(
                 f,
                 "{}",
-                self.coefficients
+                expr.coefficients
                     .iter()
                     .map(|(var, coeff)| if coeff.is_known_one() {
                         var.to_string()
                     } else if coeff.is_known_minus_one() {
                         format!("-{var}")
                     } else {
-                        format!("{coeff} * {var}")
+                        format!("{} * {var}", coeff.display_with(format))
                     })
                     .join(" + ")
             )?;
-            if !self.offset.is_known_zero() {
-                write!(f, " + {}", self.offset)?;
+            if !expr.offset.is_known_zero() {
+                write!(f, " + {}", expr.offset.display_with(format))?;
             }
             Ok(())
         }
     }
 }
 
+impl<T: FieldElement, V> AffineSymbolicExpression<T, V> {
+    /// Returns a value that displays the expression with the given number format.
+    pub fn display_with(&self, format: NumberFormat) -> Formatted<'_, Self> {
+        Formatted(self, format)
+    }
+
+    /// Returns a copy of this expression where each variable (known or unknown)
+    /// is replaced by `f(variable)`.
+    pub fn map_variables<W: Ord>(&self, f: &impl Fn(&V) -> W) -> AffineSymbolicExpression<T, W> {
+        AffineSymbolicExpression {
+            coefficients: self
+                .coefficients
+                .iter()
+                .map(|(var, coeff)| (f(var), coeff.map_symbols(f)))
+                .collect(),
+            offset: self.offset.map_symbols(f),
+            range_constraints: self
+                .range_constraints
+                .iter()
+                .map(|(var, rc)| (f(var), rc.clone()))
+                .collect(),
+        }
+    }
+}
+
 impl<T: FieldElement, V> From<SymbolicExpression<T, V>> for AffineSymbolicExpression<T, V> {
     fn from(k: SymbolicExpression<T, V>) -> Self {
         AffineSymbolicExpression {
@@ -171,15 +329,43 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
         }
     }
 
+    /// If this expression is exactly an unknown variable, i.e. it has
+    /// coefficient one and no offset, returns the variable.
+    pub fn try_to_single_variable(&self) -> Option<&V> {
+        let var = self.single_unknown_variable()?;
+        (self.coefficients[var].is_known_one() && self.offset.is_known_zero()).then_some(var)
+    }
+
+    /// If this expression is `a * X + b` for a single unknown variable `X` and
+    /// known numbers `a` and `b`, returns `X` and the value of `X` where the
+    /// expression is zero.
+    pub fn try_to_root(&self) -> Option<(&V, T)> {
+        let var = self.single_unknown_variable()?;
+        let coeff = self.coefficients[var].try_to_number()?;
+        let offset = self.offset.try_to_number()?;
+        Some((var, -offset / coeff))
+    }
+
+    /// If the range constraints of the unknown variables only allow a single
+    /// value for this expression, returns that value.
+    pub fn try_to_pinned_value(&self) -> Option<T> {
+        self.range_constraint()?.try_to_single_value()
+    }
+
     /// Tries to multiply this expression with another one.
     /// Returns `None` if the result would be quadratic, i.e.
-    /// if both expressions contain unknown variables.
+    /// if both expressions contain unknown variables, unless one of them
+    /// is pinned to a single value by range constraints.
     pub fn try_mul(&self, other: &Self) -> Option<Self> {
         if let Some(multiplier) = other.try_to_known() {
             Some(self.clone() * multiplier)
+        } else if let Some(multiplier) = self.try_to_known() {
+            Some(other.clone() * multiplier)
+        } else if let Some(value) = other.try_to_pinned_value() {
+            Some(self.clone() * &SymbolicExpression::from(value))
         } else {
-            self.try_to_known()
-                .map(|multiplier| other.clone() * multiplier)
+            self.try_to_pinned_value()
+                .map(|value| other.clone() * &SymbolicExpression::from(value))
         }
     }
 
@@ -190,6 +376,32 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
     /// unknown variables, returns an empty, incomplete result.
     /// If the equation is known to be unsolvable, returns an error.
     pub fn solve(&self) -> Result<ProcessResult<T, V>, EvalError<T>> {
+        self.solve_with_limb_limit(None)
+    }
+
+    /// Solves the equation `coeff * X + offset = 0` for a coefficient that is only
+    /// known at runtime and might be zero, e.g. a selector or a scale factor in a
+    /// known cell, by dividing by the coefficient and asserting at runtime that it
+    /// is not zero. Since the equation does not determine `X` if the coefficient
+    /// is zero, this should only be used if `X` cannot be determined otherwise.
+    /// Returns `None` if the equation is not of this form.
+    pub fn solve_by_runtime_division(&self) -> Option<ProcessResult<T, V>> {
+        let (var, coeff) = self.coefficients.iter().exactly_one().ok()?;
+        if coeff.try_to_number().is_some() || coeff.is_known_nonzero() {
+            return None;
+        }
+        Some(ProcessResult::complete(vec![
+            Assertion::assert_is_nonzero(coeff.clone()),
+            Effect::Assignment(var.clone(), self.offset.field_div(&-coeff)),
+        ]))
+    }
+
+    /// Like `solve`, but only solves bit decompositions into at most
+    /// `max_limbs` variables, if given.
+    pub fn solve_with_limb_limit(
+        &self,
+        max_limbs: Option<usize>,
+    ) -> Result<ProcessResult<T, V>, EvalError<T>> {
         Ok(match self.coefficients.len() {
             0 => {
                 if self.offset.is_known_nonzero() {
@@ -224,12 +436,12 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
                 }
             }
             _ => {
-                let r = self.solve_bit_decomposition();
+                let r = self.solve_bit_decomposition(max_limbs);
                 if r.complete {
                     r
                 } else {
                     let negated = -self;
-                    let r = negated.solve_bit_decomposition();
+                    let r = negated.solve_bit_decomposition(max_limbs);
                     if r.complete {
                         r
                     } else {
@@ -248,15 +460,22 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
         })
     }
 
-    /// Tries to solve a bit-decomposition equation.
-    fn solve_bit_decomposition(&self) -> ProcessResult<T, V> {
-        // All the coefficients need to be known numbers and the
+    /// Tries to solve a bit-decomposition equation with at most `max_limbs` variables.
+    fn solve_bit_decomposition(&self, max_limbs: Option<usize>) -> ProcessResult<T, V> {
+        if max_limbs.is_some_and(|max_limbs| self.coefficients.len() > max_limbs) {
+            return ProcessResult::empty();
+        }
+        // All the coefficients need to be known powers of two and the
         // variables need to be range-constrained.
+        // Other coefficients are not supported since the masked value
+        // would not be divisible by the coefficient in general.
         let constrained_coefficients = self
             .coefficients
             .iter()
             .map(|(var, coeff)| {
-                let c = coeff.try_to_number()?;
+                let c = coeff
+                    .try_to_number()
+                    .filter(|c| log2_exact(c.to_arbitrary_integer()).is_some())?;
                 let rc = self.range_constraints.get(var)?;
                 Some((var.clone(), c, rc))
             })
@@ -289,16 +508,55 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
 
         // We need to assert that the masks cover "-offset",
         // otherwise the equation is not solvable.
-        // We assert -offset & !masks == 0 <=> -offset == -offset | masks.
-        // We use the latter since we cannot properly bit-negate inside the field.
-        effects.push(Assertion::assert_eq(
-            -&self.offset,
-            -&self.offset | T::from(covered_bits).into(),
-        ));
+        // This is not needed if the range constraint of "-offset" already implies it.
+        let negated_offset = -&self.offset;
+        let implied = negated_offset
+            .range_constraint()
+            .is_some_and(|rc| *rc.mask() | covered_bits == covered_bits);
+        if !implied {
+            // We assert -offset & !masks == 0 <=> -offset == -offset & masks.
+            // We use the latter since we cannot properly bit-negate inside the field.
+            effects.push(Assertion::assert_eq(
+                negated_offset.clone(),
+                negated_offset.clone() & T::from(covered_bits).into(),
+            ));
+            // If "-offset" is a single variable, the assertion is a range constraint on it.
+            if let SymbolicExpression::Symbol(var, _) = &negated_offset {
+                effects.push(Effect::RangeConstraint(
+                    var.clone(),
+                    RangeConstraint::from_mask(covered_bits),
+                ));
+            }
+        }
 
         ProcessResult::complete(effects)
     }
 
+    /// Returns the range constraint of the value of the expression, derived from
+    /// the range constraints of the unknown variables and of the offset.
+    /// Returns `None` if a coefficient is not a known number or an unknown
+    /// variable is not range-constrained.
+    pub fn range_constraint(&self) -> Option<RangeConstraint<T>> {
+        self.range_constraint_of_sum(self.coefficients.iter())
+    }
+
+    /// Returns the range constraint of the sum of the given summands and the offset.
+    fn range_constraint_of_sum<'b>(
+        &'b self,
+        summands: impl Iterator<Item = (&'b V, &'b SymbolicExpression<T, V>)>,
+    ) -> Option<RangeConstraint<T>> {
+        summands
+            .map(|(var, coeff)| {
+                let coeff = coeff.try_to_number()?;
+                let rc = self.range_constraints.get(var)?;
+                Some(rc.multiple(coeff))
+            })
+            .chain(std::iter::once(self.offset.range_constraint()))
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .reduce(|c1, c2| c1.combine_sum(&c2))
+    }
+
     fn transfer_constraints(&self) -> Option<Effect<T, V>> {
         // We are looking for X = a * Y + b * Z + ... or -X = a * Y + b * Z + ...
         // where X is least constrained.
@@ -316,18 +574,11 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
             })?;
 
         // This only works if the coefficients are all known.
-        let summands = self
-            .coefficients
-            .iter()
-            .filter(|(var, _)| *var != solve_for)
-            .map(|(var, coeff)| {
-                let coeff = coeff.try_to_number()?;
-                let rc = self.range_constraints.get(var)?;
-                Some(rc.multiple(coeff))
-            })
-            .chain(std::iter::once(self.offset.range_constraint()))
-            .collect::<Option<Vec<_>>>()?;
-        let constraint = summands.into_iter().reduce(|c1, c2| c1.combine_sum(&c2))?;
+        let constraint = self.range_constraint_of_sum(
+            self.coefficients
+                .iter()
+                .filter(|(var, _)| *var != solve_for),
+        )?;
         let constraint = if solve_for_coefficient.is_known_one() {
             -constraint
         } else {
@@ -337,31 +588,55 @@ impl<T: FieldElement, V: Ord + Clone + Display> AffineSymbolicExpression<T, V> {
     }
 }
 
-impl<T: FieldElement, V: Clone + Ord> Add for &AffineSymbolicExpression<T, V> {
-    type Output = AffineSymbolicExpression<T, V>;
+impl<T: FieldElement, V: Ord> AffineSymbolicExpression<T, V> {
+    /// Removes the variables whose coefficients are known to be zero, including
+    /// their range constraints, so that an expression where all variables
+    /// cancel out is known.
+    fn remove_zero_coefficients(&mut self) {
+        self.coefficients.retain(|_, f| !f.is_known_zero());
+        let coefficients = &self.coefficients;
+        self.range_constraints
+            .retain(|var, _| coefficients.contains_key(var));
+    }
+}
 
-    fn add(self, rhs: Self) -> Self::Output {
-        let mut coefficients = self.coefficients.clone();
-        let mut range_constraints = self.range_constraints.clone();
+impl<T: FieldElement, V: Clone + Ord> AddAssign<&AffineSymbolicExpression<T, V>>
+    for AffineSymbolicExpression<T, V>
+{
+    fn add_assign(&mut self, rhs: &AffineSymbolicExpression<T, V>) {
+        // Variables that appear on both sides are merged into a single coefficient.
         for (var, coeff) in &rhs.coefficients {
-            coefficients
+            self.coefficients
                 .entry(var.clone())
                 .and_modify(|f| *f = &*f + coeff)
                 .or_insert_with(|| coeff.clone());
             if let Some(range_right) = rhs.range_constraints.get(var) {
-                range_constraints
+                self.range_constraints
                     .entry(var.clone())
                     .and_modify(|rc| *rc = rc.conjunction(range_right))
                     .or_insert_with(|| range_right.clone());
             }
         }
-        coefficients.retain(|_, f| !f.is_known_zero());
-        let offset = &self.offset + &rhs.offset;
-        AffineSymbolicExpression {
-            coefficients,
-            offset,
-            range_constraints,
-        }
+        self.remove_zero_coefficients();
+        self.offset = &self.offset + &rhs.offset;
+    }
+}
+
+impl<T: FieldElement, V: Clone + Ord> SubAssign<&AffineSymbolicExpression<T, V>>
+    for AffineSymbolicExpression<T, V>
+{
+    fn sub_assign(&mut self, rhs: &AffineSymbolicExpression<T, V>) {
+        *self += &-rhs;
+    }
+}
+
+impl<T: FieldElement, V: Clone + Ord> Add for &AffineSymbolicExpression<T, V> {
+    type Output = AffineSymbolicExpression<T, V>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        let mut result = self.clone();
+        result += rhs;
+        result
     }
 }
 
@@ -423,6 +698,7 @@ impl<T: FieldElement, V: Clone + Ord> Mul<&SymbolicExpression<T, V>>
         for coeff in self.coefficients.values_mut() {
             *coeff = &*coeff * rhs;
         }
+        self.remove_zero_coefficients();
         self.offset = &self.offset * rhs;
         self
     }
@@ -431,10 +707,19 @@ impl<T: FieldElement, V: Clone + Ord> Mul<&SymbolicExpression<T, V>>
 #[cfg(test)]
 mod test {
     use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
 
-    use powdr_number::GoldilocksField;
+    use powdr_number::{BabyBearField, GoldilocksField};
 
-    use super::*;
+    use super::{
+        super::{
+            interpreter::evaluate,
+            test_util::{
+                affine_test_case, check_solve_against_brute_force, AffineTestCase, VariableDomain,
+            },
+        },
+        *,
+    };
 
     type Ase = AffineSymbolicExpression<GoldilocksField, &'static str>;
 
@@ -464,6 +749,74 @@ mod test {
         assert!(from_number(10).solve().is_err());
     }
 
+    #[test]
+    fn multiply_by_zero() {
+        let x = Ase::from_unknown_variable("X", None);
+        let constr = mul(&x, &from_number(0)) + from_number(3);
+        // The unknown disappears and the constraint is conflicting.
+        assert!(constr.single_unknown_variable().is_none());
+        assert!(constr.solve().is_err());
+    }
+
+    #[test]
+    fn multiply_by_pinned_variable() {
+        let x = Ase::from_unknown_variable("X", None);
+        let c = Ase::from_unknown_variable("C", Some(RangeConstraint::from_value(5.into())));
+        let y = Ase::from_known_symbol("Y", None);
+        // Both factors are unknown, but C can only be 5.
+        let constr = mul(&x, &c) - y.clone();
+        assert_eq!(constr.to_string(), "5 * X + -Y");
+        let result = constr.solve().unwrap();
+        assert!(result.complete);
+        let [Effect::Assignment(var, expr)] = &result.effects[..] else {
+            panic!("Expected assignment");
+        };
+        assert_eq!(var.to_string(), "X");
+        assert_eq!(expr.to_string(), "(-Y / -5)");
+        let c =
+            Ase::from_unknown_variable("C", Some(RangeConstraint::from_range(4.into(), 5.into())));
+        assert!(x.try_mul(&c).is_none());
+    }
+
+    #[test]
+    fn repeated_variable() {
+        let x = Ase::from_unknown_variable("X", None);
+        // 2 * X + 3 * X - 10 = 0
+        let constr = mul(&from_number(2), &x) + mul(&from_number(3), &x) - from_number(10);
+        assert_eq!(constr.to_string(), "5 * X + -10");
+        let result = constr.solve().unwrap();
+        assert!(result.complete);
+        let [Effect::Assignment(var, expr)] = &result.effects[..] else {
+            panic!("Expected a single assignment");
+        };
+        assert_eq!(var.to_string(), "X");
+        assert_eq!(expr.to_string(), "2");
+    }
+
+    #[test]
+    fn cancelled_variable() {
+        let x = Ase::from_unknown_variable("X", Some(RangeConstraint::from_mask(0xffu32)));
+        let y = Ase::from_unknown_variable("Y", None);
+        // X - X + Y - 5 = 0
+        let constr = &x - &x + y - from_number(5);
+        assert_eq!(constr.to_string(), "Y + -5");
+        assert_eq!(constr.single_unknown_variable(), Some(&"Y"));
+        assert!(!constr.range_constraints.contains_key("X"));
+        let result = constr.solve().unwrap();
+        let [Effect::Assignment(var, expr)] = &result.effects[..] else {
+            panic!("Expected a single assignment");
+        };
+        assert_eq!(var.to_string(), "Y");
+        assert_eq!(expr.to_string(), "5");
+
+        // X - X is the constant zero.
+        let constr = &x - &x;
+        assert!(constr.try_to_known().unwrap().is_known_zero());
+        assert!(constr.range_constraints.is_empty());
+        let result = constr.solve().unwrap();
+        assert!(result.complete && result.effects.is_empty());
+    }
+
     #[test]
     fn solvable_without_vars() {
         let constr = &from_number(0);
@@ -502,6 +855,18 @@ mod test {
         // If we do not range-constrain z, we cannot solve since we don't know if it might be zero.
         let result = constr.solve().unwrap();
         assert!(!result.complete && result.effects.is_empty());
+        // As a last resort, we can divide by z and assert at runtime that it is not zero.
+        let result = constr.solve_by_runtime_division().unwrap();
+        assert!(result.complete);
+        let [Effect::Assertion(assertion), Effect::Assignment(var, expr)] = &result.effects[..]
+        else {
+            panic!("Expected assertion and assignment");
+        };
+        assert_eq!(assertion.lhs.to_string(), "z");
+        assert_eq!(assertion.rhs.to_string(), "0");
+        assert!(!assertion.expected_equal);
+        assert_eq!(var.to_string(), "X");
+        assert_eq!(expr.to_string(), "(((7 * y) + -10) / -z)");
         let z =
             Ase::from_known_symbol("z", Some(RangeConstraint::from_range(10.into(), 20.into())));
         let constr = mul(&z, &x) + mul(&seven, &y) - ten;
@@ -566,11 +931,131 @@ mod test {
             "a = ((-(10 + Z) & 65280) // 256);
 b = ((-(10 + Z) & 16711680) // 65536);
 c = ((-(10 + Z) & 4278190080) // 16777216);
-assert -(10 + Z) == (-(10 + Z) | 4294967040);
+assert -(10 + Z) == (-(10 + Z) & 4294967040);
+"
+        );
+    }
+
+    #[test]
+    fn solve_bit_decomposition_implied_assertion() {
+        let rc = Some(RangeConstraint::from_mask(0xffu32));
+        let a = Ase::from_unknown_variable("a", rc.clone());
+        let b = Ase::from_unknown_variable("b", rc.clone());
+        let c = Ase::from_unknown_variable("c", rc.clone());
+        let d = Ase::from_unknown_variable("d", rc.clone());
+        let z = Ase::from_known_symbol("Z", Some(RangeConstraint::from_mask(0xffffffffu32)));
+        // a + b * 0x100 + c * 0x10000 + d * 0x1000000 - Z = 0
+        let constr = a
+            + mul(&b, &from_number(0x100))
+            + mul(&c, &from_number(0x10000))
+            + mul(&d, &from_number(0x1000000))
+            - z;
+        let result = constr.solve().unwrap();
+        assert!(result.complete);
+        // Z is known to fit in 32 bits, so it does not need to be asserted.
+        let effects = result
+            .effects
+            .into_iter()
+            .map(|effect| match effect {
+                Effect::Assignment(v, expr) => format!("{v} = {expr};\n"),
+                _ => panic!(),
+            })
+            .format("")
+            .to_string();
+        assert_eq!(
+            effects,
+            "a = (Z & 255);
+b = ((Z & 65280) // 256);
+c = ((Z & 16711680) // 65536);
+d = ((Z & 4278190080) // 16777216);
 "
         );
     }
 
+    #[test]
+    fn solve_bit_decomposition_small_field() {
+        type Ase = AffineSymbolicExpression<BabyBearField, &'static str>;
+        let rc = Some(RangeConstraint::from_mask(0xffu32));
+        let bytes = ["a", "b", "c", "d"].map(|v| Ase::from_unknown_variable(v, rc.clone()));
+        let z = Ase::from_known_symbol("Z", None);
+        let decomposition = |byte_count: usize| {
+            bytes[..byte_count]
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte.clone() * &BabyBearField::from(1u64 << (8 * i)).into())
+                .fold(-z.clone(), |acc, term| acc + term)
+        };
+        // Three bytes fit into the field, so this can be solved.
+        let result = decomposition(3).solve().unwrap();
+        assert!(result.complete);
+        // Three assignments, the assertion on Z and the range constraint it implies.
+        assert_eq!(result.effects.len(), 5);
+        // A four-byte word exceeds the modulus and would not be unique.
+        let result = decomposition(4).solve().unwrap();
+        assert!(!result.complete);
+        assert!(result
+            .effects
+            .iter()
+            .all(|e| !matches!(e, Effect::Assignment(..))));
+    }
+
+    #[test]
+    fn solve_bit_decomposition_assertion() {
+        let rc = Some(RangeConstraint::from_mask(0xffu32));
+        let a = Ase::from_unknown_variable("a", rc.clone());
+        let b = Ase::from_unknown_variable("b", rc.clone());
+        let z = Ase::from_known_symbol("Z", None);
+        // a * 0x100 + b * 0x10000 - Z = 0
+        let constr = mul(&a, &from_number(0x100)) + mul(&b, &from_number(0x10000)) - z;
+        let result = constr.solve().unwrap();
+        assert!(result.complete);
+        let run = |z: u64| {
+            let value_of = |_: &&str| Some(GoldilocksField::from(z));
+            let mut assigned = vec![];
+            let mut assertions_hold = true;
+            for effect in &result.effects {
+                match effect {
+                    Effect::Assignment(v, expr) => {
+                        assigned.push((*v, evaluate(expr, &value_of).unwrap()))
+                    }
+                    Effect::Assertion(Assertion {
+                        lhs,
+                        rhs,
+                        expected_equal,
+                    }) => {
+                        assertions_hold &= (evaluate(lhs, &value_of).unwrap()
+                            == evaluate(rhs, &value_of).unwrap())
+                            == *expected_equal
+                    }
+                    _ => {}
+                }
+            }
+            (assigned, assertions_hold)
+        };
+        // The assertion has to accept every value that is covered by the masks...
+        assert_eq!(run(0x20100), (vec![("a", 1.into()), ("b", 2.into())], true));
+        // ...and reject the values with bits outside of the masks.
+        assert!(!run(0x20101).1);
+        assert!(!run(0x1000000).1);
+    }
+
+    #[test]
+    fn solve_bit_decomposition_non_power_of_two_coefficient() {
+        // 3 * a + 0x10000 * b - 1 = 0 has no solution for bytes a and b, but the masks
+        // do not overlap and "1 // 3" would assign zero to a.
+        let byte = VariableDomain::Mask { bits: 8, shift: 0 };
+        let case = AffineTestCase {
+            summands: vec![(3, byte.clone()), (0x10000, byte)],
+            offset: -1,
+        };
+        let result = case.to_expression::<GoldilocksField>().solve().unwrap();
+        assert!(!result
+            .effects
+            .iter()
+            .any(|e| matches!(e, Effect::Assignment(..))));
+        check_solve_against_brute_force::<GoldilocksField>(&case).unwrap();
+    }
+
     #[test]
     fn solve_constraint_transfer() {
         let rc = Some(RangeConstraint::from_mask(0xffu32));
@@ -605,4 +1090,18 @@ Z: [10, 4294967050] & 0xffffffff;
 "
         );
     }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1000))]
+
+        #[test]
+        fn solve_matches_brute_force_goldilocks(case in affine_test_case()) {
+            check_solve_against_brute_force::<GoldilocksField>(&case)?;
+        }
+
+        #[test]
+        fn solve_matches_brute_force_baby_bear(case in affine_test_case()) {
+            check_solve_against_brute_force::<BabyBearField>(&case)?;
+        }
+    }
 }
@@ -0,0 +1,261 @@
+use std::{fs, io, path::Path};
+
+use itertools::Itertools;
+use powdr_ast::analyzed::{PolynomialType, SymbolKind};
+use powdr_number::FieldElement;
+use sha2::{Digest, Sha256};
+
+use crate::witgen::FixedData;
+
+use super::witgen_inference::WitgenInferenceConfig;
+
+/// Returns a key for caching code derived for the fixed data with the given config,
+/// e.g. on disk. It covers everything the derived code depends on: all definitions
+/// (including helper functions used by fixed columns), public declarations,
+/// intermediate columns and identities, the values of fixed columns that are
+/// supplied externally instead of being defined in the PIL, the stage, the challenge
+/// values, the field and the config. In contrast to `pil_hash`, the key is stable
+/// across runs and executables.
+pub fn cache_key<T: FieldElement>(
+    fixed_data: &FixedData<'_, T>,
+    config: &WitgenInferenceConfig,
+) -> [u8; 32] {
+    let analyzed = fixed_data.analyzed;
+    let mut hasher = Sha256::new();
+    // Every entry is terminated by a newline, so that the boundaries between
+    // entries are part of the key.
+    let mut add = |entry: String| {
+        hasher.update(entry.as_bytes());
+        hasher.update(b"\n");
+    };
+    add(format!("modulus {}", T::modulus()));
+    add(format!("stage {}", fixed_data.stage()));
+    for (id, value) in &fixed_data.challenges {
+        add(format!("challenge {id} {value}"));
+    }
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    for (name, (symbol, definition)) in analyzed.definitions.iter().sorted_by_key(|(n, _)| *n) {
+        let kind = match symbol.kind {
+            SymbolKind::Poly(PolynomialType::Committed) => "witness",
+            SymbolKind::Poly(PolynomialType::Constant) => "fixed",
+            SymbolKind::Poly(PolynomialType::Intermediate) => "intermediate",
+            SymbolKind::Other() => "other",
+        };
+        add(format!(
+            "definition {name} {kind} stage={} length={} degree={} = {}",
+            optional(symbol.stage.map(|s| s.to_string())),
+            optional(symbol.length.map(|l| l.to_string())),
+            optional(symbol.degree.map(|d| format!("{}..{}", d.min, d.max))),
+            optional(definition.as_ref().map(|d| d.to_string()))
+        ));
+    }
+    // Fixed columns without a definition are supplied externally, so their values
+    // are the only way to tell them apart.
+    let is_defined = |name: &str| {
+        let array_name = name
+            .split_once('[')
+            .map_or(name, |(array_name, _)| array_name);
+        analyzed
+            .definitions
+            .get(array_name)
+            .is_some_and(|(_, definition)| definition.is_some())
+    };
+    for (poly_id, column) in fixed_data.fixed_cols.iter() {
+        if is_defined(&column.name) {
+            continue;
+        }
+        for size in column.values.available_sizes() {
+            add(format!(
+                "fixed values {} {} {size}: {}",
+                column.name,
+                poly_id.id,
+                column.values(size).iter().join(",")
+            ));
+        }
+    }
+    for (name, public) in analyzed
+        .public_declarations
+        .iter()
+        .sorted_by_key(|(n, _)| *n)
+    {
+        add(format!(
+            "public {name} {}{} {}",
+            public.polynomial.name,
+            public
+                .array_index
+                .map(|i| format!("[{i}]"))
+                .unwrap_or_default(),
+            public.index
+        ));
+    }
+    for (name, (_, expressions)) in analyzed
+        .intermediate_columns
+        .iter()
+        .sorted_by_key(|(n, _)| *n)
+    {
+        add(format!(
+            "intermediate {name} = [{}]",
+            expressions.iter().format(", ")
+        ));
+    }
+    for identity in &analyzed.identities {
+        add(format!("identity {identity}"));
+    }
+    add(format!("config {}", config.cache_description()));
+    hasher.finalize().into()
+}
+
+fn format_key(key: &[u8; 32]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).join("")
+}
+
+/// Writes `contents` to the file at `path`, preceded by the cache key,
+/// so that `load_with_key` can reject it if the key does not match.
+pub fn save_with_key(path: impl AsRef<Path>, key: &[u8; 32], contents: &str) -> io::Result<()> {
+    fs::write(path, format!("key {}\n{contents}", format_key(key)))
+}
+
+/// Reads a file written by `save_with_key` and returns its contents.
+/// Fails if the file was written with a different cache key.
+pub fn load_with_key(path: impl AsRef<Path>, key: &[u8; 32]) -> io::Result<String> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (header, contents) = text.split_once('\n').unwrap_or((&text, ""));
+    let stored_key = header.strip_prefix("key ").ok_or_else(|| {
+        invalid(format!(
+            "{} does not start with a cache key.",
+            path.display()
+        ))
+    })?;
+    if stored_key != format_key(key) {
+        return Err(invalid(format!(
+            "{} was created for a different PIL or inference config \
+             (cache key {stored_key}, expected {}).",
+            path.display(),
+            format_key(key)
+        )));
+    }
+    Ok(contents.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use powdr_ast::analyzed::Analyzed;
+    use powdr_number::GoldilocksField;
+
+    use crate::constant_evaluator;
+
+    use super::*;
+
+    fn key_with(
+        input: &str,
+        config: &WitgenInferenceConfig,
+        challenges: BTreeMap<u64, GoldilocksField>,
+        stage: u8,
+    ) -> [u8; 32] {
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], challenges, stage);
+        cache_key(&fixed_data, config)
+    }
+
+    fn key_of(input: &str, config: &WitgenInferenceConfig) -> [u8; 32] {
+        key_with(input, config, Default::default(), 0)
+    }
+
+    #[test]
+    fn changes_with_pil() {
+        let config = WitgenInferenceConfig::default();
+        let input = "namespace N(8); col fixed F = [1, 2]*; let X; X = F + 1;";
+        let key = key_of(input, &config);
+        assert_eq!(key, key_of(input, &config));
+        assert_ne!(
+            key,
+            key_of(
+                "namespace N(8); col fixed F = [1, 2]*; let X; X = F + 2;",
+                &config
+            )
+        );
+        assert_ne!(
+            key,
+            key_of(
+                "namespace N(8); col fixed F = [1, 3]*; let X; X = F + 1;",
+                &config
+            )
+        );
+        assert_ne!(key, key_of(input, &config.clone().with_round_limit(5)));
+    }
+
+    #[test]
+    fn changes_with_helper_function() {
+        let config = WitgenInferenceConfig::default();
+        let key = key_of(
+            "namespace N(8); let f = |i| i + 1; col fixed F(i) { f(i) }; let X; X = F;",
+            &config,
+        );
+        assert_ne!(
+            key,
+            key_of(
+                "namespace N(8); let f = |i| i + 2; col fixed F(i) { f(i) }; let X; X = F;",
+                &config
+            )
+        );
+    }
+
+    #[test]
+    fn changes_with_stage_and_challenges() {
+        let config = WitgenInferenceConfig::default();
+        let input = "namespace N(8); let X; X = 1;";
+        let key = key_of(input, &config);
+        assert_ne!(key, key_with(input, &config, Default::default(), 1));
+        let challenges = [(7, GoldilocksField::from(3u32))].into_iter().collect();
+        let with_challenge = key_with(input, &config, challenges, 0);
+        assert_ne!(key, with_challenge);
+        let challenges = [(7, GoldilocksField::from(4u32))].into_iter().collect();
+        assert_ne!(with_challenge, key_with(input, &config, challenges, 0));
+    }
+
+    #[test]
+    fn changes_with_external_fixed_values() {
+        let config = WitgenInferenceConfig::default();
+        let input = "namespace N(4); col fixed F; let X; X = F;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let key_for_values = |values: Vec<u32>| {
+            let fixed_col_vals = vec![(
+                "N::F".to_string(),
+                values
+                    .into_iter()
+                    .map(GoldilocksField::from)
+                    .collect::<Vec<_>>()
+                    .into(),
+            )];
+            let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+            cache_key(&fixed_data, &config)
+        };
+        assert_ne!(
+            key_for_values(vec![1, 2, 3, 4]),
+            key_for_values(vec![1, 2, 3, 5])
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let config = WitgenInferenceConfig::default();
+        let key = key_of("namespace N(8); let X; X = 1;", &config);
+        let other_key = key_of("namespace N(8); let X; X = 2;", &config);
+        let path = std::env::temp_dir().join(format!("cache_key_{}.txt", std::process::id()));
+        save_with_key(&path, &key, "X[0] = 1;\n").unwrap();
+        assert_eq!(load_with_key(&path, &key).unwrap(), "X[0] = 1;\n");
+        let error = load_with_key(&path, &other_key).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error
+            .to_string()
+            .contains("was created for a different PIL or inference config"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,11 +4,14 @@ use std::{
 };
 
 use powdr_ast::analyzed::AlgebraicReference;
+use serde::{Deserialize, Serialize};
 
 /// The identifier of a witness cell in the trace table.
 /// The `row_offset` is relative to a certain "zero row" defined
-/// by the component that uses this data structure.
-#[derive(Debug, Clone, Eq)]
+/// by the component that uses this data structure. It can be negative,
+/// e.g. if an identity with a next reference is processed on row -1,
+/// and is only resolved to an absolute row when the code is applied.
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct Cell {
     /// Name of the column, used only for display purposes.
     pub column_name: String,
@@ -25,13 +28,18 @@ impl Hash for Cell {
 
 impl PartialEq for Cell {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id && self.row_offset == other.row_offset
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 
 impl Ord for Cell {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        (self.id, self.row_offset).cmp(&(other.id, other.row_offset))
+        let ordering = (self.id, self.row_offset).cmp(&(other.id, other.row_offset));
+        debug_assert!(
+            ordering.is_ne() || self.column_name == other.column_name,
+            "Cells {self} and {other} have the same id and row offset."
+        );
+        ordering
     }
 }
 
@@ -42,12 +50,47 @@ impl PartialOrd for Cell {
 }
 
 impl Cell {
+    /// Creates the cell of the column with the given id on the given row,
+    /// i.e. the row has to be resolved already, see `from_reference`.
+    /// All cells should be created through this function.
+    pub fn new(column_name: impl Into<String>, id: u64, row_offset: i32) -> Self {
+        Self {
+            column_name: column_name.into(),
+            id,
+            row_offset,
+        }
+    }
+
+    /// Returns the cell the reference refers to if it is evaluated on the given row,
+    /// i.e. `x'` on row `i` is the same cell as `x` on row `i + 1`.
     pub fn from_reference(r: &AlgebraicReference, row_offset: i32) -> Self {
         assert!(r.is_witness());
-        Self {
-            column_name: r.name.clone(),
-            id: r.poly_id.id,
-            row_offset: r.next as i32 + row_offset,
+        Self::new(&r.name, r.poly_id.id, r.next as i32 + row_offset)
+    }
+}
+
+/// How cells are rendered in generated code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CellNaming {
+    /// `Namespace::Name[row]`, as used by `Display`.
+    #[default]
+    Indexed,
+    /// `Namespace::Name@row`
+    At,
+    /// `Name[row]`, i.e. without the namespace.
+    Short,
+}
+
+impl Cell {
+    /// Returns the name of the cell according to the naming scheme.
+    pub fn name(&self, naming: CellNaming) -> String {
+        match naming {
+            CellNaming::Indexed => self.to_string(),
+            CellNaming::At => format!("{}@{}", self.column_name, self.row_offset),
+            CellNaming::Short => {
+                let name = self.column_name.rsplit("::").next().unwrap();
+                format!("{name}[{}]", self.row_offset)
+            }
         }
     }
 }
@@ -57,3 +100,42 @@ impl Display for Cell {
         write!(f, "{}[{}]", self.column_name, self.row_offset)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashSet};
+
+    use powdr_ast::analyzed::{PolyID, PolynomialType};
+
+    use super::*;
+
+    fn reference(next: bool) -> AlgebraicReference {
+        AlgebraicReference {
+            name: "N::X".to_string(),
+            poly_id: PolyID {
+                id: 2,
+                ptype: PolynomialType::Committed,
+            },
+            next,
+        }
+    }
+
+    #[test]
+    fn next_reference_is_normalized() {
+        let next = Cell::from_reference(&reference(true), 3);
+        let plain = Cell::from_reference(&reference(false), 4);
+        assert_eq!(next, plain);
+        assert_eq!(next, Cell::new("N::X", 2, 4));
+        assert_eq!(next.to_string(), "N::X[4]");
+        assert!(HashSet::from([next.clone()]).contains(&plain));
+        assert!(BTreeSet::from([next]).contains(&plain));
+        assert_ne!(plain, Cell::from_reference(&reference(true), 4));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "have the same id and row offset")]
+    fn same_id_different_name() {
+        let _ = Cell::new("N::X", 2, 4) == Cell::new("N::Y", 2, 4);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+};
+
+use itertools::Itertools;
+use powdr_number::FieldElement;
+
+use super::{
+    affine_symbolic_expression::{Effect, MachineCallArgument},
+    cell::Cell,
+};
+
+/// Identifies an effect across two versions of generated code, so that
+/// effects that determine the same thing can be compared.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EffectKey<V> {
+    /// An assignment to the cell.
+    Assignment(V),
+    /// A range constraint on the cell.
+    RangeConstraint(V),
+    /// A machine call with the given identity id and unknown variables.
+    MachineCall(u64, Vec<V>),
+    /// A state write with the given identity id.
+    StateWrite(u64),
+    /// Assertions, range checks and infeasible code, which are identified by their text,
+    /// i.e. they can only be added or removed, but never changed.
+    Other(String),
+}
+
+impl<T: FieldElement, V: Clone + Display + Ord> From<&Effect<T, V>> for EffectKey<V> {
+    fn from(effect: &Effect<T, V>) -> Self {
+        match effect {
+            Effect::Assignment(v, _) => EffectKey::Assignment(v.clone()),
+            Effect::RangeConstraint(v, _) => EffectKey::RangeConstraint(v.clone()),
+            Effect::MachineCall(id, _, outputs) => EffectKey::MachineCall(*id, outputs.clone()),
+            Effect::StateWrite(id, _) => EffectKey::StateWrite(*id),
+            Effect::Assertion(_) | Effect::RangeCheck(..) | Effect::Infeasible(_) => {
+                EffectKey::Other(effect.to_string())
+            }
+        }
+    }
+}
+
+/// The difference between two versions of generated code, see `diff_code`.
+pub struct CodeDiff<T: FieldElement, V = Cell> {
+    /// The effects only present in the new code, in the order of the new code.
+    pub added: Vec<Effect<T, V>>,
+    /// The effects only present in the old code, in the order of the old code.
+    pub removed: Vec<Effect<T, V>>,
+    /// The pairs of old and new effects with the same key but a different
+    /// result, in the order of the new code.
+    pub changed: Vec<(Effect<T, V>, Effect<T, V>)>,
+}
+
+impl<T: FieldElement, V> CodeDiff<T, V> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl<T: FieldElement, V: Display> Display for CodeDiff<T, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .removed
+            .iter()
+            .map(|e| format!("- {e}"))
+            .chain(self.added.iter().map(|e| format!("+ {e}")))
+            .chain(
+                self.changed
+                    .iter()
+                    .map(|(old, new)| format!("~ {old}\n  {new}")),
+            );
+        write!(f, "{}", lines.format("\n"))
+    }
+}
+
+/// Compares the code `old` to the code `new`, matching the effects by their
+/// target cell or machine call signature (see `EffectKey`).
+/// Effects with the same key are matched in the order they appear in the code.
+pub fn diff_code<T: FieldElement, V: Clone + Display + Ord + Hash>(
+    old: &[Effect<T, V>],
+    new: &[Effect<T, V>],
+) -> CodeDiff<T, V> {
+    // The indices of the effects of `old` per key, in reverse order so that they can be popped.
+    let mut old_by_key: HashMap<EffectKey<V>, Vec<usize>> = HashMap::new();
+    for (i, effect) in old.iter().enumerate().rev() {
+        old_by_key.entry(effect.into()).or_default().push(i);
+    }
+    let mut matched = vec![false; old.len()];
+    let mut added = vec![];
+    let mut changed = vec![];
+    for effect in new {
+        match old_by_key
+            .get_mut(&EffectKey::from(effect))
+            .and_then(|indices| indices.pop())
+        {
+            Some(i) => {
+                matched[i] = true;
+                if old[i].to_string() != effect.to_string() {
+                    changed.push((old[i].clone(), effect.clone()));
+                }
+            }
+            None => added.push(effect.clone()),
+        }
+    }
+    let removed = old
+        .iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(effect, _)| effect.clone())
+        .collect();
+    CodeDiff {
+        added,
+        removed,
+        changed,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use powdr_number::GoldilocksField;
+    use pretty_assertions::assert_eq;
+
+    use crate::witgen::jit::test_util::solve_on_rows_and_then;
+
+    use super::*;
+
+    fn code_of(input: &str) -> Vec<Effect<GoldilocksField, Cell>> {
+        solve_on_rows_and_then(input, &[0, 1], vec![("X", 0), ("Y", 0)], None, |witgen| {
+            witgen.code()
+        })
+    }
+
+    #[test]
+    fn fib_with_extra_identity() {
+        let fib = code_of("let X; let Y; X' = Y; Y' = X + Y;");
+        assert!(diff_code(&fib, &fib).is_empty());
+
+        let with_sum = code_of("let X; let Y; let S; X' = Y; Y' = X + Y; S = X + Y;");
+        let diff = diff_code(&fib, &with_sum);
+        assert_eq!(
+            diff.to_string(),
+            "+ S[0] = (X[0] + Y[0]);\n+ S[1] = (X[1] + Y[1]);"
+        );
+        assert_eq!(
+            diff_code(&with_sum, &fib).to_string(),
+            "- S[0] = (X[0] + Y[0]);\n- S[1] = (X[1] + Y[1]);"
+        );
+    }
+
+    #[test]
+    fn changed_assignment() {
+        let fib = code_of("let X; let Y; X' = Y; Y' = X + Y;");
+        let variant = code_of("let X; let Y; X' = Y; Y' = X + 2 * Y;");
+        let diff = diff_code(&fib, &variant);
+        assert_eq!(
+            diff.to_string(),
+            "~ Y[1] = (X[0] + Y[0]);\n  Y[1] = (X[0] + (2 * Y[0]));\n\
+             ~ Y[2] = (X[1] + Y[1]);\n  Y[2] = (X[1] + (2 * Y[1]));"
+        );
+    }
+}
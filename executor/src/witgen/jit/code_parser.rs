@@ -0,0 +1,483 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+    str::FromStr,
+};
+
+use powdr_number::{FieldElement, LargeInt};
+
+use super::{
+    super::range_constraints::RangeConstraint,
+    affine_symbolic_expression::{
+        AffineSymbolicExpression, Assertion, Effect, MachineCallArgument,
+    },
+    cell::Cell,
+    symbolic_expression::{BinaryOperator, SymbolicExpression, UnaryOperator},
+};
+
+/// An error returned by `parse_code`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The line the error occurred on, starting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parses code in the format produced by the `Display` implementation of `Effect`
+/// (one effect per line, as in `format_code`) back into effects.
+///
+/// Since the code does not contain the column ids, the cells of a column get
+/// ids in the order in which the columns first appear in the code.
+/// Range constraints of sub-expressions are not part of the code and are not restored.
+/// In the unknown arguments of machine calls, all terms that consist of a cell and
+/// an optional coefficient are parsed as unknown variables.
+pub fn parse_code<T: FieldElement>(s: &str) -> Result<Vec<Effect<T, Cell>>, ParseError> {
+    let mut column_ids = HashMap::new();
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            EffectParser {
+                input: line.trim(),
+                pos: 0,
+                column_ids: &mut column_ids,
+            }
+            .effect()
+            .map_err(|message| ParseError {
+                line: i + 1,
+                message,
+            })
+        })
+        .collect()
+}
+
+/// Reads code written by `WitgenInference::process_with_writer`, i.e. a sequence
+/// of CBOR-serialized effects, each prefixed by its length in bytes as a little-endian `u32`.
+/// In contrast to `parse_code`, the cells (including their ids) and range constraints
+/// are restored exactly.
+/// The line numbers in errors refer to the index of the record, starting from 1.
+pub fn parse_streamed_code<T: FieldElement>(
+    mut data: &[u8],
+) -> Result<Vec<Effect<T, Cell>>, ParseError> {
+    let mut effects = vec![];
+    while !data.is_empty() {
+        let error = |message: &str| ParseError {
+            line: effects.len() + 1,
+            message: message.to_string(),
+        };
+        let (len, rest) = data
+            .split_first_chunk::<4>()
+            .ok_or_else(|| error("Truncated length prefix"))?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err(error("Truncated record"));
+        }
+        let (record, rest) = rest.split_at(len);
+        let effect =
+            serde_cbor::from_slice(record).map_err(|e| error(&format!("Invalid record: {e}")))?;
+        effects.push(effect);
+        data = rest;
+    }
+    Ok(effects)
+}
+
+struct EffectParser<'a, 'b> {
+    input: &'a str,
+    pos: usize,
+    /// The ids assigned to the column names seen so far.
+    column_ids: &'b mut HashMap<String, u64>,
+}
+
+impl EffectParser<'_, '_> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn error<R>(&self, expected: &str) -> Result<R, String> {
+        Err(format!(
+            "Expected {expected} at column {}: {}",
+            self.pos + 1,
+            self.input
+        ))
+    }
+
+    fn try_eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat(&mut self, s: &str) -> Result<(), String> {
+        if self.try_eat(s) {
+            Ok(())
+        } else {
+            self.error(&format!("\"{s}\""))
+        }
+    }
+
+    /// Consumes and returns the longest prefix whose characters satisfy `f`.
+    fn eat_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let len = self.rest().find(|c| !f(c)).unwrap_or(self.rest().len());
+        self.pos += len;
+        &self.input[start..self.pos]
+    }
+
+    fn effect<T: FieldElement>(&mut self) -> Result<Effect<T, Cell>, String> {
+        let effect = if self.try_eat("assert ") {
+            // A range check is a cell followed by " in ", everything else is an assertion.
+            let start = self.pos;
+            if let Some(cell) = self.cell().ok().filter(|_| self.try_eat(" in ")) {
+                let rc = self.range_constraint()?;
+                self.eat(";")?;
+                if !self.rest().is_empty() {
+                    return self.error("end of line");
+                }
+                return Ok(Effect::RangeCheck(cell, rc));
+            }
+            self.pos = start;
+            let lhs = self.expression()?;
+            let expected_equal = if self.try_eat(" == ") {
+                true
+            } else {
+                self.eat(" != ")?;
+                false
+            };
+            let rhs = self.expression()?;
+            Effect::Assertion(Assertion {
+                lhs,
+                rhs,
+                expected_equal,
+            })
+        } else if self.try_eat("lookup(") {
+            let id = self.integer()?;
+            self.eat(", [")?;
+            let args = self.list(|p| {
+                if p.try_eat("Known(") {
+                    let expr = p.expression()?;
+                    p.eat(")")?;
+                    Ok(MachineCallArgument::Known(expr))
+                } else {
+                    p.eat("Unknown(")?;
+                    let expr = p.affine_expression()?;
+                    p.eat(")")?;
+                    Ok(MachineCallArgument::Unknown(expr))
+                }
+            })?;
+            self.eat(")")?;
+            Effect::machine_call(id, args)
+        } else if self.try_eat("state_write(") {
+            let id = self.integer()?;
+            self.eat(", [")?;
+            let args = self.list(|p| p.expression())?;
+            self.eat(")")?;
+            Effect::StateWrite(id, args)
+        } else if self.try_eat("infeasible(") {
+            let reason = self.string()?;
+            self.eat(")")?;
+            Effect::Infeasible(reason)
+        } else {
+            let cell = self.cell()?;
+            if self.try_eat(" in ") {
+                Effect::RangeConstraint(cell, self.range_constraint()?)
+            } else {
+                self.eat(" = ")?;
+                Effect::Assignment(cell, self.expression()?)
+            }
+        };
+        self.eat(";")?;
+        if !self.rest().is_empty() {
+            return self.error("end of line");
+        }
+        Ok(effect)
+    }
+
+    /// Parses a comma-separated list of elements terminated by `]`.
+    fn list<R>(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Result<R, String>,
+    ) -> Result<Vec<R>, String> {
+        let mut elements = vec![];
+        if self.try_eat("]") {
+            return Ok(elements);
+        }
+        loop {
+            elements.push(element(self)?);
+            if self.try_eat("]") {
+                return Ok(elements);
+            }
+            self.eat(", ")?;
+        }
+    }
+
+    fn integer<I: FromStr>(&mut self) -> Result<I, String> {
+        let start = self.pos;
+        self.try_eat("-");
+        self.eat_while(|c| c.is_ascii_digit());
+        self.input[start..self.pos].parse().or_else(|_| {
+            self.pos = start;
+            self.error("an integer")
+        })
+    }
+
+    fn number<T: FieldElement>(&mut self) -> Result<T, String> {
+        let negative = self.try_eat("-");
+        let start = self.pos;
+        let digits = self.eat_while(|c| c.is_ascii_digit());
+        let value = T::from_str(digits).or_else(|_| {
+            self.pos = start;
+            self.error("a number")
+        })?;
+        Ok(if negative { -value } else { value })
+    }
+
+    /// Parses a Rust string literal in the format produced by `{:?}`.
+    fn string(&mut self) -> Result<String, String> {
+        self.eat("\"")?;
+        let mut result = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(result);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => result.push('\n'),
+                    Some('r') => result.push('\r'),
+                    Some('t') => result.push('\t'),
+                    Some('0') => result.push('\0'),
+                    Some(c @ ('\\' | '"' | '\'')) => result.push(c),
+                    _ => {
+                        self.pos += i;
+                        return self.error("a supported escape sequence");
+                    }
+                },
+                c => result.push(c),
+            }
+        }
+        self.pos = self.input.len();
+        self.error("\"")
+    }
+
+    /// Parses a cell of the form `name[row]`, where the name can contain
+    /// array indices, i.e. the row is the last index.
+    fn cell(&mut self) -> Result<Cell, String> {
+        let start = self.pos;
+        let name_chars = |c: char| c.is_alphanumeric() || "_:.$".contains(c);
+        if self.eat_while(name_chars).is_empty() {
+            return self.error("a cell");
+        }
+        let mut row_start;
+        loop {
+            row_start = self.pos;
+            self.eat("[")?;
+            self.integer::<i32>()?;
+            self.eat("]")?;
+            if !self.rest().starts_with('[') {
+                break;
+            }
+        }
+        let column_name = self.input[start..row_start].to_string();
+        let row_offset = self.input[row_start + 1..self.pos - 1].parse().unwrap();
+        let next_id = self.column_ids.len() as u64;
+        let id = *self
+            .column_ids
+            .entry(column_name.clone())
+            .or_insert(next_id);
+        Ok(Cell::new(column_name, id, row_offset))
+    }
+
+    fn range_constraint<T: FieldElement>(&mut self) -> Result<RangeConstraint<T>, String> {
+        self.eat("[")?;
+        let min = self.number()?;
+        self.eat(", ")?;
+        let max = self.number()?;
+        self.eat("] & 0x")?;
+        let mask = self.eat_while(|c| c.is_ascii_hexdigit());
+        if mask.is_empty() {
+            return self.error("a hexadecimal mask");
+        }
+        let mask = T::Integer::from_hex(mask);
+        Ok(RangeConstraint::from_range(min, max).conjunction(&RangeConstraint::from_mask(mask)))
+    }
+
+    fn expression<T: FieldElement>(&mut self) -> Result<SymbolicExpression<T, Cell>, String> {
+        if self.try_eat("(") {
+            let left = self.expression()?;
+            let op = [
+                (" + ", BinaryOperator::Add),
+                (" - ", BinaryOperator::Sub),
+                (" * ", BinaryOperator::Mul),
+                (" // ", BinaryOperator::IntegerDiv),
+                (" / ", BinaryOperator::Div),
+                (" & ", BinaryOperator::BitAnd),
+                (" | ", BinaryOperator::BitOr),
+            ]
+            .into_iter()
+            .find_map(|(s, op)| self.try_eat(s).then_some(op));
+            let Some(op) = op else {
+                return self.error("a binary operator");
+            };
+            let right = self.expression()?;
+            self.eat(")")?;
+            Ok(SymbolicExpression::BinaryOperation(
+                Rc::new(left),
+                op,
+                Rc::new(right),
+                None,
+            ))
+        } else if self.rest().starts_with(|c: char| c.is_ascii_digit())
+            || self.rest().starts_with("-")
+                && self.rest()[1..].starts_with(|c: char| c.is_ascii_digit())
+        {
+            Ok(SymbolicExpression::Concrete(self.number()?))
+        } else if self.try_eat("select(") {
+            let key = self.expression()?;
+            self.eat(", [")?;
+            let table = self.list(|p| {
+                let key = p.number()?;
+                p.eat(" => ")?;
+                Ok((key, p.number()?))
+            })?;
+            self.eat(")")?;
+            Ok(SymbolicExpression::Select(Rc::new(key), table.into(), None))
+        } else if self.try_eat("-") {
+            Ok(SymbolicExpression::UnaryOperation(
+                UnaryOperator::Neg,
+                Rc::new(self.expression()?),
+                None,
+            ))
+        } else {
+            Ok(SymbolicExpression::from_symbol(self.cell()?, None))
+        }
+    }
+
+    /// Parses an affine expression in the unknown variables, i.e. a sum of terms
+    /// of the form `cell`, `-cell` or `coefficient * cell`, optionally followed
+    /// by an offset.
+    fn affine_expression<T: FieldElement>(
+        &mut self,
+    ) -> Result<AffineSymbolicExpression<T, Cell>, String> {
+        let unknown = |cell| AffineSymbolicExpression::from_unknown_variable(cell, None);
+        let mut result = AffineSymbolicExpression::from(T::zero());
+        loop {
+            let expr = self.expression()?;
+            let term = if self.try_eat(" * ") {
+                unknown(self.cell()?) * &expr
+            } else {
+                match &expr {
+                    SymbolicExpression::Symbol(cell, _) => unknown(cell.clone()),
+                    SymbolicExpression::UnaryOperation(UnaryOperator::Neg, inner, _) => {
+                        match inner.as_ref() {
+                            SymbolicExpression::Symbol(cell, _) => -unknown(cell.clone()),
+                            _ => expr.into(),
+                        }
+                    }
+                    _ => expr.into(),
+                }
+            };
+            result = result + term;
+            if !self.try_eat(" + ") {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use powdr_number::GoldilocksField;
+    use pretty_assertions::assert_eq;
+
+    use crate::witgen::jit::test_util::{format_code, solve_on_rows};
+
+    use super::*;
+
+    fn round_trip(code: &str) -> String {
+        format_code(&parse_code::<GoldilocksField>(code).unwrap())
+    }
+
+    #[test]
+    fn simple_polynomial_solving() {
+        let input = "let X; let Y; let Z; X = 1; Y = X + 1; Z * Y = X + 10;";
+        let code = solve_on_rows(input, &[0], vec![], None);
+        assert_eq!(round_trip(&code), code);
+    }
+
+    #[test]
+    fn all_effects() {
+        let code = "\
+Main::X[0] = ((Main::A[-1] + -Main::B[0]) // 256);
+x[1][2] = (-(y[0][0] * 3) / (params[0] & 255));
+assert (Main::X[0] | 1) != -7;
+assert Main::X[0] == Main::Y[1];
+assert Main::Y[1] in [0, 255] & 0xff;
+lookup(3, [Known(Main::X[0]), Unknown(Main::Y[1]), Unknown(-Main::Y[2] + 4 * Main::Z[0] + -1)]);
+state_write(4, [42, Main::X[0]]);
+Main::OP_A[3] = select(Main::OPCODE[3], [0 => 1, 7 => -2]);
+Main::OP_B[3] = select(Main::OPCODE[3], []);
+lookup(5, []);
+infeasible(\"Not \\\"satisfiable\\\":\\n-1 != 0\");";
+        assert_eq!(round_trip(code), code);
+    }
+
+    #[test]
+    fn cell_ids() {
+        let code = parse_code::<GoldilocksField>("X[0] = Y[1];\nY[2] = X[1];").unwrap();
+        let [Effect::Assignment(x0, y1), Effect::Assignment(y2, x1)] = &code[..] else {
+            panic!()
+        };
+        let [SymbolicExpression::Symbol(y1, _), SymbolicExpression::Symbol(x1, _)] = [y1, x1]
+        else {
+            panic!()
+        };
+        assert_eq!((x0.id, x1.id, y1.id, y2.id), (0, 0, 1, 1));
+        assert_eq!((x0.row_offset, y1.row_offset), (0, 1));
+    }
+
+    #[test]
+    fn range_constraint() {
+        let code = parse_code::<GoldilocksField>("X[0] in [0, 255] & 0xff;").unwrap();
+        let [Effect::RangeConstraint(_, rc)] = &code[..] else {
+            panic!()
+        };
+        assert_eq!(rc, &RangeConstraint::from_mask(0xffu64));
+    }
+
+    fn parse_error(code: &str) -> ParseError {
+        match parse_code::<GoldilocksField>(code) {
+            Ok(_) => panic!("Expected a parse error."),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            parse_error("X[0] = 1;\n\nX[0] = (1 ^ 2);"),
+            ParseError {
+                line: 3,
+                message: "Expected a binary operator at column 10: X[0] = (1 ^ 2);".to_string()
+            }
+        );
+        assert_eq!(
+            parse_error("X[0] = 1").to_string(),
+            "Line 1: Expected \";\" at column 9: X[0] = 1"
+        );
+        assert_eq!(
+            parse_error("X = 1;").message,
+            "Expected \"[\" at column 2: X = 1;"
+        );
+    }
+}
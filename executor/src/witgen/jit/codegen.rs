@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use powdr_number::{FieldElement, LargeInt};
+
+use crate::witgen::range_constraints::RangeConstraint;
+
+use super::{
+    affine_symbolic_expression::{Assertion, Effect, MachineCallArgument},
+    cell::Cell,
+    symbolic_expression::{BinaryOperator, SymbolicExpression, UnaryOperator},
+    witgen_inference::BlockPlan,
+};
+
+/// Renders the code of a block into source code of a target language,
+/// so that the same plan can be compiled for different backends.
+/// There is one method per variant of `Effect`.
+pub trait CodeGenerator<T: FieldElement> {
+    fn assignment(&self, cell: &Cell, value: &SymbolicExpression<T, Cell>) -> String;
+
+    /// Range constraints are only used during inference, so nothing
+    /// is generated for them by default.
+    fn range_constraint(&self, _cell: &Cell, _rc: &RangeConstraint<T>) -> String {
+        String::new()
+    }
+
+    fn assertion(&self, assertion: &Assertion<T, Cell>) -> String;
+
+    fn range_check(&self, cell: &Cell, rc: &RangeConstraint<T>) -> String;
+
+    fn machine_call(
+        &self,
+        identity_id: u64,
+        arguments: &[MachineCallArgument<T, Cell>],
+        outputs: &[Cell],
+    ) -> String;
+
+    fn state_write(&self, identity_id: u64, arguments: &[SymbolicExpression<T, Cell>]) -> String;
+
+    fn infeasible(&self, reason: &str) -> String;
+
+    /// Renders a single effect using the method for its variant.
+    fn effect(&self, effect: &Effect<T, Cell>) -> String {
+        match effect {
+            Effect::Assignment(cell, value) => self.assignment(cell, value),
+            Effect::RangeConstraint(cell, rc) => self.range_constraint(cell, rc),
+            Effect::Assertion(assertion) => self.assertion(assertion),
+            Effect::RangeCheck(cell, rc) => self.range_check(cell, rc),
+            Effect::MachineCall(id, arguments, outputs) => {
+                self.machine_call(*id, arguments, outputs)
+            }
+            Effect::StateWrite(id, arguments) => self.state_write(*id, arguments),
+            Effect::Infeasible(reason) => self.infeasible(reason),
+        }
+    }
+
+    /// Renders the code of the whole block, including reading the inputs.
+    fn block(&self, plan: &BlockPlan<T>) -> String;
+}
+
+/// Generates a Rust closure that runs the code of a block on a witness buffer.
+/// The buffer stores the values row by row, using the order of the columns given
+/// at construction, and the closure receives the row the block starts at.
+/// Machine calls are performed through a callback that returns false if the
+/// call cannot be answered. Expects `powdr_number::FieldElement` and
+/// `LookupCell` to be in scope.
+pub struct RustCodeGenerator {
+    /// The name of the field type, e.g. `GoldilocksField`.
+    field: String,
+    /// The index of each column in a row of the witness buffer, by column id.
+    column_indices: HashMap<u64, usize>,
+}
+
+impl RustCodeGenerator {
+    /// Creates a generator for the field `T` and a witness buffer with the given
+    /// columns per row. Panics if `T` is not one of the known fields.
+    pub fn new<T: FieldElement>(column_ids: &[u64]) -> Self {
+        let field = T::known_field().expect("Code can only be generated for known fields.");
+        Self {
+            field: format!("{field}Field"),
+            column_indices: column_ids
+                .iter()
+                .enumerate()
+                .map(|(index, id)| (*id, index))
+                .collect(),
+        }
+    }
+
+    fn variable(&self, cell: &Cell) -> String {
+        let name = cell
+            .column_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        let row = if cell.row_offset < 0 {
+            format!("m{}", -cell.row_offset)
+        } else {
+            cell.row_offset.to_string()
+        };
+        format!("c_{name}_{}_{row}", cell.id)
+    }
+
+    /// The expression that accesses the cell in the witness buffer.
+    fn buffer_entry(&self, cell: &Cell) -> String {
+        let row = match cell.row_offset {
+            0 => "row".to_string(),
+            r if r < 0 => format!("(row - {})", -r),
+            r => format!("(row + {r})"),
+        };
+        format!(
+            "data[{row} * {} + {}]",
+            self.column_indices.len(),
+            self.column_indices[&cell.id]
+        )
+    }
+
+    fn expression<T: FieldElement>(&self, expr: &SymbolicExpression<T, Cell>) -> String {
+        match expr {
+            SymbolicExpression::Concrete(n) => {
+                if n.is_in_lower_half() {
+                    self.constant(*n)
+                } else {
+                    format!("-{}", self.constant(-*n))
+                }
+            }
+            SymbolicExpression::Symbol(cell, _) => self.variable(cell),
+            SymbolicExpression::BinaryOperation(left, op, right, _) => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                match op {
+                    BinaryOperator::IntegerDiv => format!(
+                        "{}::from({left}.to_arbitrary_integer() / {right}.to_arbitrary_integer())",
+                        self.field
+                    ),
+                    BinaryOperator::BitAnd => format!(
+                        "{}::from({left}.to_integer() & {right}.to_integer())",
+                        self.field
+                    ),
+                    BinaryOperator::BitOr => format!(
+                        "{}::from({left}.to_integer() | {right}.to_integer())",
+                        self.field
+                    ),
+                    BinaryOperator::Add
+                    | BinaryOperator::Sub
+                    | BinaryOperator::Mul
+                    | BinaryOperator::Div => format!("({left} {op} {right})"),
+                }
+            }
+            SymbolicExpression::UnaryOperation(UnaryOperator::Neg, inner, _) => {
+                format!("-{}", self.expression(inner))
+            }
+            SymbolicExpression::Select(key, table, _) => {
+                let cases = table
+                    .iter()
+                    .map(|(k, v)| {
+                        format!(
+                            "if key == {} {{ {} }} else ",
+                            self.expression(&SymbolicExpression::<T, Cell>::Concrete(*k)),
+                            self.expression(&SymbolicExpression::<T, Cell>::Concrete(*v))
+                        )
+                    })
+                    .format("");
+                let missing = "return Err(format!(\"Key {key} is not in the table.\"));";
+                format!(
+                    "{{ let key = {}; {cases}{{ {missing} }} }}",
+                    self.expression(key)
+                )
+            }
+        }
+    }
+
+    fn constant<T: FieldElement>(&self, n: T) -> String {
+        match n.to_integer().try_into_u64() {
+            Some(n) => format!("{}::from({n}_u64)", self.field),
+            None => format!("{}::from_str(\"{n}\").unwrap()", self.field),
+        }
+    }
+
+    /// Returns early from the closure with the given error message.
+    fn fail(message: &str) -> String {
+        format!("return Err({message:?}.to_string());")
+    }
+}
+
+impl<T: FieldElement> CodeGenerator<T> for RustCodeGenerator {
+    fn assignment(&self, cell: &Cell, value: &SymbolicExpression<T, Cell>) -> String {
+        let var = self.variable(cell);
+        format!(
+            "let {var} = {};\n{} = {var};",
+            self.expression(value),
+            self.buffer_entry(cell)
+        )
+    }
+
+    fn assertion(&self, assertion: &Assertion<T, Cell>) -> String {
+        let Assertion {
+            lhs,
+            rhs,
+            expected_equal,
+        } = assertion;
+        format!(
+            "if {} {} {} {{\n    {}\n}}",
+            self.expression(lhs),
+            if *expected_equal { "!=" } else { "==" },
+            self.expression(rhs),
+            Self::fail(&format!(
+                "Assertion failed: {}",
+                Effect::Assertion(assertion.clone())
+            ))
+        )
+    }
+
+    fn range_check(&self, cell: &Cell, rc: &RangeConstraint<T>) -> String {
+        let var = self.variable(cell);
+        let (min, max) = rc.range();
+        let mut conditions = vec![if min <= max {
+            format!(
+                "{} <= {var} && {var} <= {}",
+                self.constant(min),
+                self.constant(max)
+            )
+        } else {
+            format!(
+                "({var} <= {} || {} <= {var})",
+                self.constant(min),
+                self.constant(max)
+            )
+        }];
+        // Masks that are not smaller than the modulus are the unconstrained
+        // masks in practice, so we only check the others.
+        if *rc.mask() < T::modulus() {
+            conditions.push(format!(
+                "{var}.to_integer() & {}.to_integer() == {var}.to_integer()",
+                self.constant(T::from(*rc.mask()))
+            ));
+        }
+        format!(
+            "if !({}) {{\n    {}\n}}",
+            conditions.join(" && "),
+            Self::fail(&format!(
+                "Range check failed: {}",
+                Effect::RangeCheck(cell.clone(), rc.clone())
+            ))
+        )
+    }
+
+    fn machine_call(
+        &self,
+        identity_id: u64,
+        arguments: &[MachineCallArgument<T, Cell>],
+        outputs: &[Cell],
+    ) -> String {
+        let cells = arguments
+            .iter()
+            .map(|arg| match arg {
+                MachineCallArgument::Known(k) => {
+                    format!("LookupCell::Input(&{})", self.expression(k))
+                }
+                MachineCallArgument::Unknown(u) => format!(
+                    "LookupCell::Output(&mut {})",
+                    self.variable(u.try_to_single_variable().unwrap())
+                ),
+            })
+            .join(", ");
+        outputs
+            .iter()
+            .map(|cell| {
+                format!(
+                    "let mut {} = {};",
+                    self.variable(cell),
+                    self.constant(T::zero())
+                )
+            })
+            .chain(std::iter::once(format!(
+                "if !call_machine({identity_id}, &mut [{cells}]) {{\n    {}\n}}",
+                Self::fail(&format!("Machine call {identity_id} failed."))
+            )))
+            .chain(
+                outputs
+                    .iter()
+                    .map(|cell| format!("{} = {};", self.buffer_entry(cell), self.variable(cell))),
+            )
+            .join("\n")
+    }
+
+    fn state_write(&self, identity_id: u64, arguments: &[SymbolicExpression<T, Cell>]) -> String {
+        let cells = arguments
+            .iter()
+            .map(|arg| format!("LookupCell::Input(&{})", self.expression(arg)))
+            .join(", ");
+        format!(
+            "if !call_machine({identity_id}, &mut [{cells}]) {{\n    {}\n}}",
+            Self::fail(&format!("State write {identity_id} failed."))
+        )
+    }
+
+    fn infeasible(&self, reason: &str) -> String {
+        Self::fail(&format!("Infeasible: {reason}"))
+    }
+
+    fn block(&self, plan: &BlockPlan<T>) -> String {
+        let body = plan
+            .inputs
+            .iter()
+            .map(|cell| format!("let {} = {};", self.variable(cell), self.buffer_entry(cell)))
+            .chain(plan.code.iter().map(|effect| self.effect(effect)))
+            .chain(std::iter::once("Ok(())".to_string()))
+            .filter(|code| !code.is_empty())
+            .flat_map(|code| code.lines().map(|line| format!("    {line}")).collect_vec())
+            .join("\n");
+        format!(
+            "|data: &mut [{field}], row: usize, \
+             call_machine: &mut dyn FnMut(u64, &mut [LookupCell<'_, {field}>]) -> bool| \
+             -> Result<(), String> {{\n{body}\n}}",
+            field = self.field
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use powdr_number::GoldilocksField;
+
+    use crate::witgen::jit::test_util::solve_on_rows_and_then;
+
+    use super::*;
+
+    #[test]
+    fn fib() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let code =
+            solve_on_rows_and_then(input, &[0, 1], vec![("X", 0), ("Y", 0)], None, |witgen| {
+                let plan = BlockPlan {
+                    block_size: 3,
+                    inputs: witgen.inputs(),
+                    code: witgen.code(),
+                };
+                let generator = RustCodeGenerator::new::<GoldilocksField>(&[0, 1]);
+                CodeGenerator::<GoldilocksField>::block(&generator, &plan)
+            });
+        assert_eq!(
+            code,
+            "|data: &mut [GoldilocksField], row: usize, \
+             call_machine: &mut dyn FnMut(u64, &mut [LookupCell<'_, GoldilocksField>]) -> bool| \
+             -> Result<(), String> {
+    let c_X_0_0 = data[row * 2 + 0];
+    let c_Y_1_0 = data[row * 2 + 1];
+    let c_X_0_1 = c_Y_1_0;
+    data[(row + 1) * 2 + 0] = c_X_0_1;
+    let c_Y_1_1 = (c_X_0_0 + c_Y_1_0);
+    data[(row + 1) * 2 + 1] = c_Y_1_1;
+    let c_X_0_2 = c_Y_1_1;
+    data[(row + 2) * 2 + 0] = c_X_0_2;
+    let c_Y_1_2 = (c_X_0_1 + c_Y_1_1);
+    data[(row + 2) * 2 + 1] = c_Y_1_2;
+    Ok(())
+}"
+        );
+    }
+
+    #[test]
+    fn select() {
+        let op = Cell::new("op", 0, 0);
+        let table = vec![(1.into(), 2.into()), (3.into(), (-4).into())];
+        let expr =
+            SymbolicExpression::select(SymbolicExpression::from_symbol(op, None), table.clone())
+                .unwrap();
+        let generator = RustCodeGenerator::new::<GoldilocksField>(&[0]);
+        assert_eq!(
+            generator.expression::<GoldilocksField>(&expr),
+            "{ let key = c_op_0_0; \
+             if key == GoldilocksField::from(1_u64) { GoldilocksField::from(2_u64) } \
+             else if key == GoldilocksField::from(3_u64) { -GoldilocksField::from(4_u64) } \
+             else { return Err(format!(\"Key {key} is not in the table.\")); } }"
+        );
+        // Constant keys are selected directly.
+        assert_eq!(
+            SymbolicExpression::<GoldilocksField, Cell>::select(
+                GoldilocksField::from(3).into(),
+                table.clone()
+            )
+            .and_then(|e| e.try_to_number()),
+            Some((-4).into())
+        );
+        assert!(SymbolicExpression::<GoldilocksField, Cell>::select(
+            GoldilocksField::from(2).into(),
+            table
+        )
+        .is_none());
+    }
+}
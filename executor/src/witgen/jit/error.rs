@@ -0,0 +1,266 @@
+use std::fmt::Display;
+
+use itertools::Itertools;
+use powdr_ast::analyzed::IdentityKind;
+use powdr_number::FieldElement;
+use thiserror::Error;
+
+use crate::witgen::EvalError;
+
+use super::{
+    super::range_constraints::RangeConstraint,
+    cell::Cell,
+    interpreter::{AssertionFailure, InterpreterError},
+    witgen_inference::{ConflictInfo, RangeConflict},
+};
+
+/// The errors of the witgen JIT, i.e. of deriving code with `WitgenInference`
+/// and of running it with `EffectInterpreter`.
+/// The errors of the individual components (`InterpreterError`, `ProcessStatus`)
+/// can be converted into this type.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum WitgenJitError<T: FieldElement, V = Cell> {
+    /// An identity is not satisfiable on a row.
+    #[error(
+        "Identity {} is not satisfiable on row {}: {}",
+        .0.identity_id,
+        .0.row_offset,
+        .0.message
+    )]
+    Conflict(ConflictInfo),
+    /// A value was derived for a cell that is outside of the range constraint of the cell,
+    /// i.e. the range constraint of the cell became empty.
+    #[error("Value {value} of {cell} violates range constraint {range_constraint}")]
+    EmptyRangeConstraint {
+        cell: V,
+        value: T,
+        range_constraint: RangeConstraint<T>,
+    },
+    /// The identity can never be processed by the inference.
+    #[error("Identity {identity_id} is not supported: {reason}")]
+    UnsupportedIdentity { identity_id: u64, reason: String },
+    /// Identities of this kind are never processed by the inference.
+    #[error("Identity {identity_id} is not supported: {kind:?} identities are not supported.")]
+    UnsupportedIdentityKind {
+        identity_id: u64,
+        kind: IdentityKind,
+    },
+    /// The fixed evaluator has no value for a fixed column referenced by the identity,
+    /// see `UnresolvedFixed::Error`.
+    #[error(
+        "Identity {identity_id} references {column} on row {row}, \
+         which the fixed evaluator cannot provide."
+    )]
+    UnresolvedFixed {
+        identity_id: u64,
+        column: String,
+        row: i32,
+    },
+    /// The block does not fit into the columns.
+    #[error("The block does not fit into the columns.")]
+    RowOutOfBounds,
+    /// The round limit was reached while still making progress.
+    #[error("The round limit was reached while still making progress.")]
+    BudgetExceeded,
+    /// No more progress could be made, contains the unknown variables
+    /// referenced by the incomplete identities.
+    #[error("Could not solve the cells {}", .0.iter().format(", "))]
+    Stuck(Vec<V>),
+    /// No more progress could be made and all cells are known, but the contained
+    /// identity-row pairs are not complete, i.e. the code would not account for them.
+    #[error(
+        "Could not complete {}",
+        .0.iter().map(|(id, row)| format!("identity {id} on row {row}")).format(", ")
+    )]
+    Incomplete(Vec<(u64, i32)>),
+    /// An assertion in the derived code failed.
+    #[error("{0}")]
+    ValidationFailed(AssertionFailure<T, V>),
+    /// A variable is used by the derived code, but it was neither provided nor computed.
+    #[error("No value for {0}")]
+    MissingValue(V),
+    /// The derived code assigns a variable that already has a value.
+    #[error("{0} is written more than once.")]
+    DoubleWrite(V),
+    /// The key of a select expression in the derived code is not in its table.
+    #[error("Key {0} is not in the table.")]
+    MissingSelectKey(T),
+    /// Code marked as infeasible was reached.
+    #[error("Infeasible code reached: {0}")]
+    Infeasible(String),
+    /// The machine call with the given identity id could not be answered.
+    #[error("Machine call {identity_id} could not be answered.")]
+    MachineCallFailed { identity_id: u64 },
+    /// Evaluating a machine call or an equation at run-time failed.
+    #[error("{0}")]
+    Eval(EvalError<T>),
+    /// A knowledge snapshot cannot be used.
+    #[error("{0}")]
+    Serialization(String),
+}
+
+impl<T: FieldElement, V> From<ConflictInfo> for WitgenJitError<T, V> {
+    fn from(conflict: ConflictInfo) -> Self {
+        WitgenJitError::Conflict(conflict)
+    }
+}
+
+impl<T: FieldElement, V> From<RangeConflict<T, V>> for WitgenJitError<T, V> {
+    fn from(conflict: RangeConflict<T, V>) -> Self {
+        WitgenJitError::EmptyRangeConstraint {
+            cell: conflict.cell,
+            value: conflict.value,
+            range_constraint: conflict.range_constraint,
+        }
+    }
+}
+
+impl<T: FieldElement, V> From<InterpreterError<T, V>> for WitgenJitError<T, V> {
+    fn from(error: InterpreterError<T, V>) -> Self {
+        match error {
+            InterpreterError::AssertionFailed(failure) => WitgenJitError::ValidationFailed(failure),
+            InterpreterError::MissingValue(v) => WitgenJitError::MissingValue(v),
+            InterpreterError::MachineCallFailed(identity_id) => {
+                WitgenJitError::MachineCallFailed { identity_id }
+            }
+            InterpreterError::Infeasible(reason) => WitgenJitError::Infeasible(reason),
+            InterpreterError::RangeCheckFailed(conflict) => conflict.into(),
+            InterpreterError::DoubleWrite(v) => WitgenJitError::DoubleWrite(v),
+            InterpreterError::MissingSelectKey(key) => WitgenJitError::MissingSelectKey(key),
+            InterpreterError::Eval(e) => WitgenJitError::Eval(e),
+        }
+    }
+}
+
+impl<T: FieldElement, V: Display> From<WitgenJitError<T, V>> for EvalError<T> {
+    fn from(error: WitgenJitError<T, V>) -> Self {
+        match error {
+            WitgenJitError::EmptyRangeConstraint { .. } => EvalError::ConflictingRangeConstraints,
+            WitgenJitError::Eval(e) => e,
+            e => EvalError::Generic(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use powdr_number::GoldilocksField;
+    use pretty_assertions::assert_eq;
+
+    use crate::witgen::{
+        jit::{
+            affine_symbolic_expression::{Assertion, Effect},
+            interpreter::EffectInterpreter,
+            symbolic_expression::SymbolicExpression,
+            test_util::{solve_block_on, solve_on_rows_and_then},
+        },
+        machines::LookupCell,
+    };
+
+    use super::*;
+
+    type Error = WitgenJitError<GoldilocksField>;
+
+    fn solve_block_error(input: &str) -> Error {
+        solve_block_on(input, 1, Default::default()).unwrap_err()
+    }
+
+    #[test]
+    fn conflict() {
+        let error = solve_block_error("namespace N(8); let X; X = 1; X = 2;");
+        assert_eq!(
+            error.to_string(),
+            "Identity 1 is not satisfiable on row 0: Linear constraint is not satisfiable: -1 != 0"
+        );
+        assert!(matches!(error, WitgenJitError::Conflict(_)));
+    }
+
+    #[test]
+    fn unsupported_identity() {
+        let error = solve_block_error("namespace N(8); let X; let Y; [ X ] connect [ Y ];");
+        assert_eq!(
+            error,
+            WitgenJitError::UnsupportedIdentityKind {
+                identity_id: 0,
+                kind: IdentityKind::Connect
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Identity 0 is not supported: Connect identities are not supported."
+        );
+    }
+
+    #[test]
+    fn stuck() {
+        let error = solve_block_error("namespace N(8); let X; let Y; X = Y;");
+        assert_eq!(
+            error.to_string(),
+            "Could not solve the cells N::X[0], N::Y[0]"
+        );
+    }
+
+    #[test]
+    fn empty_range_constraint() {
+        let input = "
+        namespace N(256);
+            let BYTE: col = |i| i & 0xff;
+            let X;
+            let Y;
+            [ X ] in [ BYTE ];
+            Y = 300;
+            X = Y;
+        ";
+        let error: Error = solve_on_rows_and_then(input, &[0], vec![], None, |witgen| {
+            witgen.conflicts()[0].clone().into()
+        });
+        assert_eq!(
+            error.to_string(),
+            "Value 300 of N::X[0] violates range constraint [0, 255] & 0xff"
+        );
+        assert_eq!(
+            EvalError::from(error),
+            EvalError::ConflictingRangeConstraints
+        );
+    }
+
+    #[test]
+    fn validation_failed() {
+        let cell = |name: &str, id| Cell {
+            column_name: name.to_string(),
+            id,
+            row_offset: 0,
+        };
+        let code = vec![Effect::Assertion(Assertion {
+            lhs: SymbolicExpression::from_symbol(cell("X", 0), None),
+            rhs: SymbolicExpression::from_symbol(cell("Y", 1), None),
+            expected_equal: true,
+        })];
+        let error: Error = EffectInterpreter::new(&code)
+            .run(
+                vec![(cell("X", 0), 1.into()), (cell("Y", 1), 2.into())],
+                &mut |_, _: &mut [LookupCell<'_, GoldilocksField>]| -> Result<bool, _> {
+                    unreachable!()
+                },
+            )
+            .unwrap_err()
+            .into();
+        assert_eq!(
+            error.to_string(),
+            "Assertion 0 failed: 1 == 2 with X[0] = 1, Y[0] = 2"
+        );
+    }
+
+    #[test]
+    fn into_eval_error() {
+        let error = solve_block_error("namespace N(8); let X; X = 1; X = 2;");
+        assert_eq!(
+            EvalError::from(error),
+            EvalError::Generic(
+                "Identity 1 is not satisfiable on row 0: \
+                 Linear constraint is not satisfiable: -1 != 0"
+                    .to_string()
+            )
+        );
+    }
+}
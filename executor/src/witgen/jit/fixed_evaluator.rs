@@ -0,0 +1,318 @@
+use std::collections::HashSet;
+
+use powdr_ast::{
+    analyzed::{AlgebraicExpression as Expression, AlgebraicReference, Identity, PolyID},
+    parsed::visitor::AllChildren,
+};
+use powdr_number::{DegreeType, FieldElement};
+
+use crate::witgen::FixedData;
+
+use super::witgen_inference::FixedEvaluator;
+
+/// How rows outside of `0..degree` are evaluated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    /// The row is taken modulo the degree, i.e. the row after the last row is the first row.
+    #[default]
+    Wrap,
+    /// The value is unknown, so identities referencing it cannot be solved.
+    /// Requires `UnresolvedFixed::Skip` in the config of the inference.
+    Error,
+}
+
+/// Evaluates fixed columns using the values in [`FixedData`] at a certain degree.
+#[derive(Clone)]
+pub struct FixedEvaluatorForFixedData<'a, T: FieldElement> {
+    fixed_data: &'a FixedData<'a, T>,
+    degree: DegreeType,
+    boundary: Boundary,
+}
+
+impl<'a, T: FieldElement> FixedEvaluatorForFixedData<'a, T> {
+    /// Creates an evaluator that uses the values of the fixed columns for
+    /// the given degree, i.e. the size of the machine instance witgen is
+    /// performed for. Rows wrap around at the boundary.
+    /// Evaluating a column that does not have values for this degree panics.
+    pub fn new(fixed_data: &'a FixedData<'a, T>, degree: DegreeType) -> Self {
+        Self {
+            fixed_data,
+            degree,
+            boundary: Boundary::default(),
+        }
+    }
+
+    pub fn with_boundary(self, boundary: Boundary) -> Self {
+        Self { boundary, ..self }
+    }
+}
+
+impl<T: FieldElement> FixedEvaluator<T> for FixedEvaluatorForFixedData<'_, T> {
+    fn evaluate(&self, var: &AlgebraicReference, row_offset: i32) -> Option<T> {
+        assert!(var.is_fixed());
+        let values = self.fixed_data.fixed_cols[&var.poly_id].values(self.degree);
+        let row = row_offset as i64 + var.next as i64;
+        let row = match self.boundary {
+            Boundary::Wrap => row.rem_euclid(values.len() as i64),
+            Boundary::Error if (0..values.len() as i64).contains(&row) => row,
+            Boundary::Error => return None,
+        };
+        Some(values[row as usize])
+    }
+
+    fn degree(&self) -> Option<DegreeType> {
+        Some(self.degree)
+    }
+}
+
+/// Evaluates the fixed columns that are periodic with the block size of a block machine
+/// using [`FixedEvaluatorForFixedData`] and refuses to evaluate all other fixed columns,
+/// since their values differ between the blocks, e.g. a column that is only set on the
+/// last row. Depending on `UnresolvedFixed` in the config of the inference, identities
+/// referencing such columns are either skipped or the columns are treated symbolically.
+#[derive(Clone)]
+pub struct CompositeFixedEvaluator<'a, T: FieldElement> {
+    concrete: FixedEvaluatorForFixedData<'a, T>,
+    /// The fixed columns whose values are not periodic with the block size.
+    non_periodic: HashSet<PolyID>,
+}
+
+impl<'a, T: FieldElement> CompositeFixedEvaluator<'a, T> {
+    /// Creates an evaluator for a machine instance of the given degree and block size.
+    /// The periodicity of the fixed columns is determined on their values for the largest size.
+    pub fn new(fixed_data: &'a FixedData<'a, T>, degree: DegreeType, block_size: usize) -> Self {
+        let non_periodic = fixed_data
+            .fixed_cols
+            .iter()
+            .filter(|(_, col)| {
+                let values = col.values_max_size();
+                values
+                    .iter()
+                    .enumerate()
+                    .any(|(i, v)| *v != values[i % block_size])
+            })
+            .map(|(poly_id, _)| poly_id)
+            .collect();
+        Self {
+            concrete: FixedEvaluatorForFixedData::new(fixed_data, degree),
+            non_periodic,
+        }
+    }
+
+    pub fn is_periodic(&self, poly_id: &PolyID) -> bool {
+        !self.non_periodic.contains(poly_id)
+    }
+
+    /// Returns true if code derived for the given identities cannot be used for all blocks,
+    /// i.e. the last block needs a program derived with the concrete fixed values of
+    /// the last block, because one of the identities references a non-periodic column.
+    pub fn requires_last_block_program(&self, identities: &[Identity<T>]) -> bool {
+        identities.iter().flat_map(|id| id.all_children()).any(|e| {
+            matches!(e, Expression::Reference(r) if r.is_fixed() && !self.is_periodic(&r.poly_id))
+        })
+    }
+}
+
+impl<T: FieldElement> FixedEvaluator<T> for CompositeFixedEvaluator<'_, T> {
+    fn evaluate(&self, var: &AlgebraicReference, row_offset: i32) -> Option<T> {
+        if self.is_periodic(&var.poly_id) {
+            self.concrete.evaluate(var, row_offset)
+        } else {
+            None
+        }
+    }
+
+    fn degree(&self) -> Option<DegreeType> {
+        self.concrete.degree()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use powdr_number::GoldilocksField;
+    use static_assertions::assert_impl_all;
+
+    use itertools::Itertools;
+    use powdr_ast::analyzed::Analyzed;
+
+    use crate::witgen::jit::{
+        cell::Cell,
+        test_util::{expression_fixture, FixedDataBuilder},
+        witgen_inference::{UnresolvedFixed, WitgenInference, WitgenInferenceConfig},
+    };
+
+    use super::*;
+
+    // Inference for different machines can run on different threads,
+    // sharing the fixed data.
+    assert_impl_all!(FixedData<'static, GoldilocksField>: Sync);
+    assert_impl_all!(FixedEvaluatorForFixedData<'static, GoldilocksField>: Clone, Send, Sync);
+
+    fn reference(fixed_data: &FixedData<GoldilocksField>, next: bool) -> AlgebraicReference {
+        AlgebraicReference {
+            name: "F".to_string(),
+            poly_id: fixed_data.try_column_by_name("F").unwrap(),
+            next,
+        }
+    }
+
+    #[test]
+    fn last_row() {
+        let fixture = expression_fixture();
+        let fixed_data = fixture.fixed_data();
+        let evaluator = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+        assert_eq!(
+            evaluator.evaluate(&reference(&fixed_data, false), 3),
+            Some(7.into())
+        );
+        assert_eq!(
+            evaluator
+                .with_boundary(Boundary::Error)
+                .evaluate(&reference(&fixed_data, false), 3),
+            Some(7.into())
+        );
+    }
+
+    #[test]
+    fn next_on_last_row_wraps() {
+        let fixture = expression_fixture();
+        let fixed_data = fixture.fixed_data();
+        let evaluator = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+        assert_eq!(
+            evaluator.evaluate(&reference(&fixed_data, true), 3),
+            Some(1.into())
+        );
+        assert_eq!(
+            evaluator.evaluate(&reference(&fixed_data, false), -1),
+            Some(7.into())
+        );
+    }
+
+    #[test]
+    fn next_on_last_row_errors() {
+        let fixture = expression_fixture();
+        let fixed_data = fixture.fixed_data();
+        let evaluator =
+            FixedEvaluatorForFixedData::new(&fixed_data, 4).with_boundary(Boundary::Error);
+        assert_eq!(evaluator.evaluate(&reference(&fixed_data, true), 3), None);
+        assert_eq!(evaluator.evaluate(&reference(&fixed_data, false), -1), None);
+    }
+
+    #[test]
+    fn next_matches_cell_row() {
+        // A next reference on a row refers to the same row for fixed and witness columns.
+        let fixture = expression_fixture();
+        let fixed_data = fixture.fixed_data();
+        let evaluator = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+        let x = AlgebraicReference {
+            name: "X".to_string(),
+            poly_id: fixed_data.try_column_by_name("X").unwrap(),
+            next: true,
+        };
+        let values = [1, 0, 0, 7].map(GoldilocksField::from);
+        for row in 0..3 {
+            let cell_row = Cell::from_reference(&x, row).row_offset;
+            assert_eq!(cell_row, row + 1);
+            assert_eq!(
+                evaluator.evaluate(&reference(&fixed_data, true), row),
+                Some(values[cell_row as usize])
+            );
+            assert_eq!(
+                evaluator.evaluate(&reference(&fixed_data, false), row + 1),
+                Some(values[cell_row as usize])
+            );
+        }
+    }
+
+    #[test]
+    fn multiple_sizes() {
+        let fixture = FixedDataBuilder::new(4)
+            .fixed_with_sizes(
+                "F",
+                vec![
+                    [1, 2, 3, 4].map(GoldilocksField::from).to_vec(),
+                    [5, 6, 7, 8, 9, 10, 11, 12]
+                        .map(GoldilocksField::from)
+                        .to_vec(),
+                ],
+            )
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let small = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+        let large = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        assert_eq!(
+            small.evaluate(&reference(&fixed_data, true), 3),
+            Some(1.into())
+        );
+        assert_eq!(
+            large.evaluate(&reference(&fixed_data, true), 3),
+            Some(9.into())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Fixed column F does not have a value for size 8")]
+    fn missing_size() {
+        let fixture = expression_fixture();
+        let fixed_data = fixture.fixed_data();
+        FixedEvaluatorForFixedData::new(&fixed_data, 8).evaluate(&reference(&fixed_data, false), 0);
+    }
+
+    /// Solves a block of size two with `X` known using the composite evaluator and returns
+    /// the code and whether the last block needs a specialized program.
+    fn solve_with_composite(identity: &str) -> (Result<String, String>, bool) {
+        let input =
+            format!("let X; let Y; col fixed LATCH = [0, 1]*; col fixed LAST = [0]*; {identity}");
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(&input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .fixed(
+                "LATCH",
+                [0, 1, 0, 1, 0, 1, 0, 1].map(GoldilocksField::from).to_vec(),
+            )
+            .fixed(
+                "LAST",
+                [0, 0, 0, 0, 0, 0, 0, 1].map(GoldilocksField::from).to_vec(),
+            )
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let evaluator = CompositeFixedEvaluator::new(&fixed_data, 8, 2);
+        assert!(evaluator.is_periodic(&fixed_data.try_column_by_name("LATCH").unwrap()));
+        assert!(!evaluator.is_periodic(&fixed_data.try_column_by_name("LAST").unwrap()));
+        let x_id = fixed_data.try_column_by_name("X").unwrap().id;
+        let known_cells = [0, 1].map(|row_offset| Cell {
+            column_name: "X".to_string(),
+            id: x_id,
+            row_offset,
+        });
+        let requires_last_block_program =
+            evaluator.requires_last_block_program(&analyzed.identities);
+        let config = WitgenInferenceConfig::default().with_unresolved_fixed(UnresolvedFixed::Skip);
+        let code = WitgenInference::new(&fixed_data, evaluator, known_cells)
+            .with_config(config)
+            .solve_block(&analyzed.identities, 2)
+            .map(|plan| plan.code.iter().map(|e| e.to_string()).join("\n"))
+            .map_err(|e| format!("{e:?}"));
+        (code, requires_last_block_program)
+    }
+
+    #[test]
+    fn composite_periodic() {
+        // A single program can be used for all blocks.
+        assert_eq!(
+            solve_with_composite("Y = X + LATCH;"),
+            (Ok("Y[0] = X[0];\nY[1] = (X[1] + 1);".to_string()), false)
+        );
+    }
+
+    #[test]
+    fn composite_last_row() {
+        // The value of LAST is only known for a specific block,
+        // so the last block needs its own program.
+        let (code, requires_last_block_program) = solve_with_composite("Y = X + LATCH + LAST;");
+        assert!(code.unwrap_err().starts_with("Stuck("));
+        assert!(requires_last_block_program);
+    }
+}
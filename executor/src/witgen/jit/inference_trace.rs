@@ -0,0 +1,213 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::Path,
+    str::FromStr,
+};
+
+/// A single call to `WitgenInference::process_identity` and its outcome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    /// The round in which the identity was processed, see `WitgenInference::start_round`.
+    pub round: usize,
+    pub identity_id: u64,
+    pub row_offset: i32,
+    pub complete: bool,
+    /// The code generated by the call, one formatted effect per entry.
+    pub code: Vec<String>,
+}
+
+/// The sequence of identity-row pairs processed during an inference run,
+/// recorded so that the run can be replayed independently of the driver.
+///
+/// The text format has one line per step, `<round> <identity id> <row> complete|incomplete`,
+/// followed by the generated code, indented by four spaces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InferenceTrace {
+    pub steps: Vec<TraceStep>,
+}
+
+/// Summary of the steps of a single round, see `InferenceTrace::rounds`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoundTrace {
+    pub round: usize,
+    /// The identity-row pairs that were completed in this round.
+    pub completed: Vec<(u64, i32)>,
+    /// The identity-row pairs that generated code in this round.
+    pub emitted_code: Vec<(u64, i32)>,
+}
+
+impl RoundTrace {
+    /// Returns true if an identity was completed or code was generated in this round.
+    pub fn made_progress(&self) -> bool {
+        !self.completed.is_empty() || !self.emitted_code.is_empty()
+    }
+}
+
+impl InferenceTrace {
+    /// Returns, for each round with at least one step, the identities that
+    /// made progress in that round, to diagnose slow convergence.
+    pub fn rounds(&self) -> Vec<RoundTrace> {
+        let mut rounds: Vec<RoundTrace> = vec![];
+        for step in &self.steps {
+            if rounds.last().map(|r| r.round) != Some(step.round) {
+                rounds.push(RoundTrace {
+                    round: step.round,
+                    ..Default::default()
+                });
+            }
+            let round = rounds.last_mut().unwrap();
+            let pair = (step.identity_id, step.row_offset);
+            if step.complete {
+                round.completed.push(pair);
+            }
+            if !step.code.is_empty() {
+                round.emitted_code.push(pair);
+            }
+        }
+        rounds
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn read_from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Display for InferenceTrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            let outcome = if step.complete {
+                "complete"
+            } else {
+                "incomplete"
+            };
+            writeln!(
+                f,
+                "{} {} {} {outcome}",
+                step.round, step.identity_id, step.row_offset
+            )?;
+            for line in &step.code {
+                writeln!(f, "    {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InferenceTrace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps: Vec<TraceStep> = vec![];
+        for (nr, line) in s.lines().enumerate() {
+            if let Some(code) = line.strip_prefix("    ") {
+                let step = steps
+                    .last_mut()
+                    .ok_or_else(|| format!("Line {}: Code before the first step.", nr + 1))?;
+                step.code.push(code.to_string());
+                continue;
+            }
+            let parse_error = || format!("Line {}: Invalid step: {line}", nr + 1);
+            let [round, identity_id, row_offset, outcome] = line
+                .split(' ')
+                .collect::<Vec<_>>()
+                .try_into()
+                .map_err(|_| parse_error())?;
+            steps.push(TraceStep {
+                round: round.parse().map_err(|_| parse_error())?,
+                identity_id: identity_id.parse().map_err(|_| parse_error())?,
+                row_offset: row_offset.parse().map_err(|_| parse_error())?,
+                complete: match outcome {
+                    "complete" => true,
+                    "incomplete" => false,
+                    _ => return Err(parse_error()),
+                },
+                code: vec![],
+            });
+        }
+        Ok(InferenceTrace { steps })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let text = "1 0 -1 incomplete
+1 1 0 complete
+    X[1] = Y[0];
+    Y[1] = (X[0] + Y[0]);
+2 0 -1 complete
+";
+        let trace: InferenceTrace = text.parse().unwrap();
+        assert_eq!(trace.steps.len(), 3);
+        assert_eq!(
+            trace.steps[1],
+            TraceStep {
+                round: 1,
+                identity_id: 1,
+                row_offset: 0,
+                complete: true,
+                code: vec![
+                    "X[1] = Y[0];".to_string(),
+                    "Y[1] = (X[0] + Y[0]);".to_string()
+                ],
+            }
+        );
+        assert_eq!(trace.to_string(), text);
+    }
+
+    #[test]
+    fn rounds() {
+        let text = "1 0 0 incomplete
+1 1 0 complete
+    X[1] = Y[0];
+2 0 0 incomplete
+    Y[1] = 7;
+3 0 0 complete
+";
+        let trace: InferenceTrace = text.parse().unwrap();
+        assert_eq!(
+            trace.rounds(),
+            vec![
+                RoundTrace {
+                    round: 1,
+                    completed: vec![(1, 0)],
+                    emitted_code: vec![(1, 0)],
+                },
+                RoundTrace {
+                    round: 2,
+                    completed: vec![],
+                    emitted_code: vec![(0, 0)],
+                },
+                RoundTrace {
+                    round: 3,
+                    completed: vec![(0, 0)],
+                    emitted_code: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            "    X[1] = Y[0];".parse::<InferenceTrace>(),
+            Err("Line 1: Code before the first step.".to_string())
+        );
+        assert_eq!(
+            "1 0 0 done".parse::<InferenceTrace>(),
+            Err("Line 1: Invalid step: 1 0 0 done".to_string())
+        );
+    }
+}
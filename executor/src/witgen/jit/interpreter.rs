@@ -0,0 +1,933 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+};
+
+use itertools::Itertools;
+use powdr_number::FieldElement;
+
+use crate::witgen::{
+    data_structures::finalizable_data::CompactDataRef, machines::LookupCell, EvalError,
+    MutableState, QueryCallback,
+};
+
+use super::{
+    affine_symbolic_expression::{
+        AffineSymbolicExpression, Assertion, Effect, MachineCallArgument,
+    },
+    cell::Cell,
+    error::WitgenJitError,
+    symbolic_expression::{BinaryOperator, SymbolicExpression, UnaryOperator},
+    witgen_inference::{BlockPlan, RangeConflict},
+};
+
+/// Something that can answer the machine calls of an effect program.
+pub trait MachineCallHandler<T: FieldElement> {
+    /// Performs the machine call for the identity with the given id.
+    /// Returns false if the call could not be answered.
+    fn call(
+        &mut self,
+        identity_id: u64,
+        values: &mut [LookupCell<'_, T>],
+    ) -> Result<bool, EvalError<T>>;
+}
+
+impl<T: FieldElement, F> MachineCallHandler<T> for F
+where
+    F: FnMut(u64, &mut [LookupCell<'_, T>]) -> Result<bool, EvalError<T>>,
+{
+    fn call(
+        &mut self,
+        identity_id: u64,
+        values: &mut [LookupCell<'_, T>],
+    ) -> Result<bool, EvalError<T>> {
+        self(identity_id, values)
+    }
+}
+
+/// Performs the machine calls on the machines of the executor.
+impl<T: FieldElement, Q: QueryCallback<T>> MachineCallHandler<T> for &MutableState<'_, T, Q> {
+    fn call(
+        &mut self,
+        identity_id: u64,
+        values: &mut [LookupCell<'_, T>],
+    ) -> Result<bool, EvalError<T>> {
+        self.call_direct(identity_id, values)
+    }
+}
+
+/// Answers machine calls with the same identity id and known arguments as an
+/// earlier call from a cache instead of calling `inner` again.
+/// Only calls to the given identities are cached, which must not include
+/// identities whose results depend on the state of the callee,
+/// see `WitgenInference::cacheable_calls`.
+pub struct CachingMachineCallHandler<T, H> {
+    inner: H,
+    cacheable: HashSet<u64>,
+    /// The values of the outputs per identity id and arguments,
+    /// where the arguments contain `None` for the outputs.
+    cache: HashMap<(u64, Vec<Option<T>>), Vec<T>>,
+}
+
+impl<T: FieldElement, H: MachineCallHandler<T>> CachingMachineCallHandler<T, H> {
+    pub fn new(inner: H, cacheable: HashSet<u64>) -> Self {
+        Self {
+            inner,
+            cacheable,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<T: FieldElement, H: MachineCallHandler<T>> MachineCallHandler<T>
+    for CachingMachineCallHandler<T, H>
+{
+    fn call(
+        &mut self,
+        identity_id: u64,
+        values: &mut [LookupCell<'_, T>],
+    ) -> Result<bool, EvalError<T>> {
+        if !self.cacheable.contains(&identity_id) {
+            return self.inner.call(identity_id, values);
+        }
+        let arguments = values
+            .iter()
+            .map(|v| match v {
+                LookupCell::Input(v) => Some(**v),
+                LookupCell::Output(_) => None,
+            })
+            .collect_vec();
+        let key = (identity_id, arguments);
+        if let Some(outputs) = self.cache.get(&key) {
+            let output_cells = values.iter_mut().filter_map(|v| match v {
+                LookupCell::Input(_) => None,
+                LookupCell::Output(v) => Some(v),
+            });
+            for (cell, value) in output_cells.zip(outputs) {
+                **cell = *value;
+            }
+            return Ok(true);
+        }
+        if !self.inner.call(identity_id, values)? {
+            return Ok(false);
+        }
+        let outputs = values
+            .iter()
+            .filter_map(|v| match v {
+                LookupCell::Input(_) => None,
+                LookupCell::Output(v) => Some(**v),
+            })
+            .collect();
+        self.cache.insert(key, outputs);
+        Ok(true)
+    }
+}
+
+/// Runs the code of a block on the witness data, where `data` starts at the
+/// first row of the block. The inputs of the plan are read from `data` and
+/// all cells assigned by the code are written to `data`.
+/// Cells with negative row offsets refer to the rows before the block. Fails
+/// with `RowOutOfBounds` if a cell is before the start or after the end of
+/// `data` and with `MissingValue` if an input is not known.
+pub fn apply_effects<T: FieldElement>(
+    plan: &BlockPlan<T>,
+    data: &mut CompactDataRef<'_, T>,
+    machine_calls: &mut impl MachineCallHandler<T>,
+) -> Result<(), WitgenJitError<T>> {
+    let in_bounds = |cell: &Cell| data.contains_row(cell.row_offset);
+    if !plan.inputs.iter().all(in_bounds) {
+        return Err(WitgenJitError::RowOutOfBounds);
+    }
+    let inputs = plan
+        .inputs
+        .iter()
+        .map(|cell| {
+            data.try_get(cell.row_offset, cell.id as u32)
+                .map(|value| (cell.clone(), value))
+                .ok_or_else(|| WitgenJitError::MissingValue(cell.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let result = EffectInterpreter::new(&plan.code).run(inputs, machine_calls)?;
+    if !result.values.keys().all(in_bounds) {
+        return Err(WitgenJitError::RowOutOfBounds);
+    }
+    for (cell, value) in result.values {
+        data.set(cell.row_offset, cell.id as u32, value);
+    }
+    Ok(())
+}
+
+/// Executes effect programs on concrete values.
+pub struct EffectInterpreter<'a, T: FieldElement, V> {
+    code: &'a [Effect<T, V>],
+    record_assertion_failures: bool,
+}
+
+/// A failed run-time assertion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssertionFailure<T, V> {
+    /// The index of the assertion in the program.
+    pub index: usize,
+    pub lhs: T,
+    pub rhs: T,
+    pub expected_equal: bool,
+    /// The values of the variables referenced by the assertion.
+    pub values: Vec<(V, T)>,
+}
+
+impl<T: Display, V: Display> Display for AssertionFailure<T, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Assertion {} failed: {} {} {} with {}",
+            self.index,
+            self.lhs,
+            if self.expected_equal { "==" } else { "!=" },
+            self.rhs,
+            self.values
+                .iter()
+                .map(|(v, value)| format!("{v} = {value}"))
+                .format(", ")
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum InterpreterError<T: FieldElement, V> {
+    /// An assertion failed (only returned if failures are not recorded).
+    AssertionFailed(AssertionFailure<T, V>),
+    /// A variable is used, but it was neither provided nor computed.
+    MissingValue(V),
+    /// The machine call with the given identity id could not be answered.
+    MachineCallFailed(u64),
+    /// Code marked as infeasible was reached.
+    Infeasible(String),
+    /// The value of a variable is not allowed by the range constraint of a range check.
+    RangeCheckFailed(RangeConflict<T, V>),
+    /// A variable is assigned although it already has a value.
+    DoubleWrite(V),
+    /// The key of a select expression is not in its table.
+    MissingSelectKey(T),
+    Eval(EvalError<T>),
+}
+
+#[derive(Debug)]
+pub struct InterpreterResult<T, V> {
+    pub values: HashMap<V, T>,
+    /// Failed assertions, if they are recorded instead of aborting.
+    pub assertion_failures: Vec<AssertionFailure<T, V>>,
+    /// Variables that are involved in or derived from a failed assertion.
+    /// They might not have a value.
+    pub poisoned: HashSet<V>,
+}
+
+impl<'a, T: FieldElement, V: Clone + Hash + Eq + Ord + Display> EffectInterpreter<'a, T, V> {
+    pub fn new(code: &'a [Effect<T, V>]) -> Self {
+        Self {
+            code,
+            record_assertion_failures: false,
+        }
+    }
+
+    /// Instead of aborting on the first failed assertion, records all failures
+    /// and marks the variables involved and everything derived from them as poisoned.
+    pub fn recording_assertion_failures(self) -> Self {
+        Self {
+            record_assertion_failures: true,
+            ..self
+        }
+    }
+
+    pub fn run(
+        &self,
+        inputs: impl IntoIterator<Item = (V, T)>,
+        machine_calls: &mut impl MachineCallHandler<T>,
+    ) -> Result<InterpreterResult<T, V>, InterpreterError<T, V>> {
+        let mut state = State {
+            result: InterpreterResult {
+                values: inputs.into_iter().collect(),
+                assertion_failures: vec![],
+                poisoned: HashSet::new(),
+            },
+            derivations: vec![],
+        };
+        for (index, effect) in self.code.iter().enumerate() {
+            match effect {
+                Effect::Assignment(var, expr) => {
+                    let dependencies = expr.referenced_symbols().cloned().collect();
+                    state.assign(var.clone(), dependencies, |s| s.evaluate(expr))?;
+                }
+                Effect::RangeConstraint(..) => {}
+                Effect::RangeCheck(var, rc) => {
+                    if !state.result.poisoned.contains(var) {
+                        let value = *state
+                            .result
+                            .values
+                            .get(var)
+                            .ok_or_else(|| InterpreterError::MissingValue(var.clone()))?;
+                        if !rc.allows_value(value) {
+                            return Err(InterpreterError::RangeCheckFailed(RangeConflict {
+                                cell: var.clone(),
+                                value,
+                                range_constraint: rc.clone(),
+                            }));
+                        }
+                    }
+                }
+                Effect::Assertion(assertion) => {
+                    if let Some(failure) = state.check_assertion(index, assertion)? {
+                        if !self.record_assertion_failures {
+                            return Err(InterpreterError::AssertionFailed(failure));
+                        }
+                        state.poison(failure.values.iter().map(|(v, _)| v.clone()));
+                        state.result.assertion_failures.push(failure);
+                    }
+                }
+                Effect::MachineCall(id, arguments, outputs) => {
+                    state.machine_call(*id, arguments, outputs, machine_calls)?
+                }
+                Effect::StateWrite(id, arguments) => {
+                    state.state_write(*id, arguments, machine_calls)?
+                }
+                Effect::Infeasible(reason) => {
+                    return Err(InterpreterError::Infeasible(reason.clone()))
+                }
+            }
+        }
+        Ok(state.result)
+    }
+}
+
+struct State<T, V> {
+    result: InterpreterResult<T, V>,
+    /// The assigned variables in order, together with the variables they were computed from.
+    derivations: Vec<(V, Vec<V>)>,
+}
+
+impl<T: FieldElement, V: Clone + Hash + Eq + Ord + Display> State<T, V> {
+    fn evaluate(&self, expr: &SymbolicExpression<T, V>) -> Result<T, InterpreterError<T, V>> {
+        evaluate(expr, &|v| self.result.values.get(v).copied())
+    }
+
+    /// Assigns the value returned by `compute` to `var`. If one of the dependencies
+    /// is poisoned, `var` is also poisoned and does not need to have a value.
+    fn assign(
+        &mut self,
+        var: V,
+        dependencies: Vec<V>,
+        compute: impl FnOnce(&Self) -> Result<T, InterpreterError<T, V>>,
+    ) -> Result<(), InterpreterError<T, V>> {
+        if self.result.values.contains_key(&var) {
+            return Err(InterpreterError::DoubleWrite(var));
+        }
+        if dependencies
+            .iter()
+            .any(|d| self.result.poisoned.contains(d))
+        {
+            if let Ok(value) = compute(self) {
+                self.result.values.insert(var.clone(), value);
+            }
+            self.result.poisoned.insert(var.clone());
+        } else {
+            let value = compute(self)?;
+            self.result.values.insert(var.clone(), value);
+        }
+        self.derivations.push((var, dependencies));
+        Ok(())
+    }
+
+    /// Poisons the given variables and all variables computed from them so far.
+    fn poison(&mut self, vars: impl IntoIterator<Item = V>) {
+        self.result.poisoned.extend(vars);
+        for (var, dependencies) in &self.derivations {
+            if dependencies
+                .iter()
+                .any(|d| self.result.poisoned.contains(d))
+            {
+                self.result.poisoned.insert(var.clone());
+            }
+        }
+    }
+
+    fn check_assertion(
+        &self,
+        index: usize,
+        Assertion {
+            lhs,
+            rhs,
+            expected_equal,
+        }: &Assertion<T, V>,
+    ) -> Result<Option<AssertionFailure<T, V>>, InterpreterError<T, V>> {
+        let symbols = lhs
+            .referenced_symbols()
+            .chain(rhs.referenced_symbols())
+            .cloned()
+            .sorted()
+            .dedup()
+            .collect_vec();
+        if symbols.iter().any(|s| self.result.poisoned.contains(s)) {
+            // The values are already known to be invalid.
+            return Ok(None);
+        }
+        let lhs = self.evaluate(lhs)?;
+        let rhs = self.evaluate(rhs)?;
+        if (lhs == rhs) == *expected_equal {
+            return Ok(None);
+        }
+        Ok(Some(AssertionFailure {
+            index,
+            lhs,
+            rhs,
+            expected_equal: *expected_equal,
+            values: symbols
+                .into_iter()
+                .map(|s| {
+                    let value = self.result.values[&s];
+                    (s, value)
+                })
+                .collect(),
+        }))
+    }
+
+    fn machine_call(
+        &mut self,
+        id: u64,
+        arguments: &[MachineCallArgument<T, V>],
+        outputs: &[V],
+        machine_calls: &mut impl MachineCallHandler<T>,
+    ) -> Result<(), InterpreterError<T, V>> {
+        let inputs = arguments
+            .iter()
+            .flat_map(|arg| match arg {
+                MachineCallArgument::Known(expr) => expr.referenced_symbols().cloned().collect(),
+                MachineCallArgument::Unknown(_) => vec![],
+            })
+            .collect_vec();
+        if inputs.iter().any(|i| self.result.poisoned.contains(i)) {
+            // Do not perform calls on poisoned inputs, they would most likely fail.
+            for var in outputs {
+                self.assign(var.clone(), inputs.clone(), |_| {
+                    Err(InterpreterError::MissingValue(inputs[0].clone()))
+                })?;
+            }
+            return Ok(());
+        }
+        let mut values = arguments
+            .iter()
+            .map(|arg| match arg {
+                MachineCallArgument::Known(expr) => self.evaluate(expr),
+                MachineCallArgument::Unknown(_) => Ok(T::zero()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut cells = arguments
+            .iter()
+            .zip(values.iter_mut())
+            .map(|(arg, value)| match arg {
+                MachineCallArgument::Known(_) => LookupCell::Input(value),
+                MachineCallArgument::Unknown(_) => LookupCell::Output(value),
+            })
+            .collect_vec();
+        if !machine_calls
+            .call(id, &mut cells)
+            .map_err(InterpreterError::Eval)?
+        {
+            return Err(InterpreterError::MachineCallFailed(id));
+        }
+        for (arg, value) in arguments.iter().zip(values) {
+            if let MachineCallArgument::Unknown(expr) = arg {
+                self.assign_affine(id, expr, value, &inputs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the values of `arguments` to the machine call handler as inputs.
+    fn state_write(
+        &mut self,
+        id: u64,
+        arguments: &[SymbolicExpression<T, V>],
+        machine_calls: &mut impl MachineCallHandler<T>,
+    ) -> Result<(), InterpreterError<T, V>> {
+        if arguments
+            .iter()
+            .flat_map(|arg| arg.referenced_symbols())
+            .any(|v| self.result.poisoned.contains(v))
+        {
+            // Do not write values derived from a failed assertion.
+            return Ok(());
+        }
+        let values = arguments
+            .iter()
+            .map(|arg| self.evaluate(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut cells = values.iter().map(LookupCell::Input).collect_vec();
+        if !machine_calls
+            .call(id, &mut cells)
+            .map_err(InterpreterError::Eval)?
+        {
+            return Err(InterpreterError::MachineCallFailed(id));
+        }
+        Ok(())
+    }
+
+    /// Solves `expr = value` for the single unknown variable in `expr`.
+    fn assign_affine(
+        &mut self,
+        id: u64,
+        expr: &AffineSymbolicExpression<T, V>,
+        value: T,
+        dependencies: &[V],
+    ) -> Result<(), InterpreterError<T, V>> {
+        let equation = expr - &AffineSymbolicExpression::from(value);
+        for effect in equation.solve().map_err(InterpreterError::Eval)?.effects {
+            match effect {
+                Effect::Assignment(var, expr) => {
+                    self.assign(var, dependencies.to_vec(), |s| s.evaluate(&expr))?;
+                }
+                Effect::Assertion(assertion) => {
+                    if self.check_assertion(0, &assertion)?.is_some() {
+                        return Err(InterpreterError::MachineCallFailed(id));
+                    }
+                }
+                Effect::RangeConstraint(..)
+                | Effect::RangeCheck(..)
+                | Effect::MachineCall(..)
+                | Effect::StateWrite(..)
+                | Effect::Infeasible(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Evaluates a symbolic expression, using `value_of` to determine the values of symbols.
+/// Fails with `MissingValue` for the first symbol without a value and with
+/// `MissingSelectKey` if a select expression has no entry for its key.
+pub fn evaluate<T: FieldElement, V: Clone>(
+    expr: &SymbolicExpression<T, V>,
+    value_of: &impl Fn(&V) -> Option<T>,
+) -> Result<T, InterpreterError<T, V>> {
+    Ok(match expr {
+        SymbolicExpression::Concrete(v) => *v,
+        SymbolicExpression::Symbol(s, _) => {
+            value_of(s).ok_or_else(|| InterpreterError::MissingValue(s.clone()))?
+        }
+        SymbolicExpression::BinaryOperation(left, op, right, _) => {
+            let left = evaluate(left, value_of)?;
+            let right = evaluate(right, value_of)?;
+            match op {
+                BinaryOperator::Add => left + right,
+                BinaryOperator::Sub => left - right,
+                BinaryOperator::Mul => left * right,
+                BinaryOperator::Div => left / right,
+                BinaryOperator::IntegerDiv => {
+                    T::from(left.to_arbitrary_integer() / right.to_arbitrary_integer())
+                }
+                BinaryOperator::BitAnd => T::from(left.to_integer() & right.to_integer()),
+                BinaryOperator::BitOr => T::from(left.to_integer() | right.to_integer()),
+            }
+        }
+        SymbolicExpression::UnaryOperation(UnaryOperator::Neg, inner, _) => {
+            -evaluate(inner, value_of)?
+        }
+        SymbolicExpression::Select(key, table, _) => {
+            let key = evaluate(key, value_of)?;
+            table
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| *v)
+                .ok_or(InterpreterError::MissingSelectKey(key))?
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use pretty_assertions::assert_eq;
+
+    use powdr_number::GoldilocksField;
+
+    use powdr_ast::analyzed::{PolyID, PolynomialType};
+
+    use crate::witgen::{
+        data_structures::finalizable_data::CompactData,
+        jit::test_util::{
+            find_cell, format_code, solve_on_rows_and_then, solve_xor, MockCallHandler, XOR_INPUT,
+        },
+    };
+
+    use super::*;
+
+    type T = GoldilocksField;
+
+    fn xor_lookup(_id: u64, values: &mut [LookupCell<'_, T>]) -> Result<bool, EvalError<T>> {
+        let [LookupCell::Input(a), LookupCell::Output(b), LookupCell::Input(c)] = values else {
+            panic!("Unexpected lookup.");
+        };
+        **b = T::from(a.to_integer() ^ c.to_integer());
+        Ok(true)
+    }
+
+    #[test]
+    fn xor_range_violation() {
+        let code = solve_xor(|witgen| witgen.code());
+        let a = find_cell(&code, "Xor::A[7]");
+        let c = find_cell(&code, "Xor::C[7]");
+        let a_value = T::from((1u64 << 32) + 5);
+        let result = EffectInterpreter::new(&code)
+            .recording_assertion_failures()
+            .run(
+                [(a.clone(), a_value), (c, T::from(0x12345678))],
+                &mut xor_lookup,
+            )
+            .unwrap();
+
+        assert_eq!(
+            result.assertion_failures,
+            vec![AssertionFailure {
+                index: 2,
+                lhs: a_value,
+                rhs: T::from(5),
+                expected_equal: true,
+                values: vec![(a, a_value)],
+            }]
+        );
+        let poisoned = result
+            .poisoned
+            .iter()
+            .map(|c| c.to_string())
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            poisoned,
+            [
+                "Xor::A[4]",
+                "Xor::A[5]",
+                "Xor::A[6]",
+                "Xor::A[7]",
+                "Xor::A_byte[3]",
+                "Xor::A_byte[4]",
+                "Xor::A_byte[5]",
+                "Xor::A_byte[6]",
+                "Xor::B[4]",
+                "Xor::B[5]",
+                "Xor::B[6]",
+                "Xor::B[7]",
+                "Xor::B_byte[3]",
+                "Xor::B_byte[4]",
+                "Xor::B_byte[5]",
+                "Xor::B_byte[6]",
+            ]
+        );
+        // The C cells are independent of A and are still computed.
+        let c_values = result
+            .values
+            .iter()
+            .filter(|(cell, _)| cell.column_name.starts_with("Xor::C"))
+            .map(|(cell, value)| (cell.to_string(), value.to_degree()))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            c_values,
+            [
+                ("Xor::C[4]", 0x78),
+                ("Xor::C[5]", 0x5678),
+                ("Xor::C[6]", 0x345678),
+                ("Xor::C[7]", 0x12345678),
+                ("Xor::C_byte[3]", 0x78),
+                ("Xor::C_byte[4]", 0x56),
+                ("Xor::C_byte[5]", 0x34),
+                ("Xor::C_byte[6]", 0x12),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+        );
+    }
+
+    #[test]
+    fn xor_range_violation_aborts() {
+        let code = solve_xor(|witgen| witgen.code());
+        let a = find_cell(&code, "Xor::A[7]");
+        let c = find_cell(&code, "Xor::C[7]");
+        let result = EffectInterpreter::new(&code).run(
+            [(a, T::from((1u64 << 32) + 5)), (c, T::from(0x12345678))],
+            &mut xor_lookup,
+        );
+        assert!(matches!(
+            result,
+            Err(InterpreterError::AssertionFailed(AssertionFailure {
+                index: 2,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn xor_apply_effects() {
+        let plan = solve_xor(|witgen| BlockPlan {
+            block_size: 4,
+            inputs: witgen.inputs(),
+            code: witgen.code(),
+        });
+        let column_ids = (0..6)
+            .map(|id| PolyID {
+                id,
+                ptype: PolynomialType::Committed,
+            })
+            .collect_vec();
+        let mut data = CompactData::new(&column_ids);
+        data.append_new_rows(12);
+        // The block of the plan starts at row 3, see `solve_xor`.
+        let mut block = CompactDataRef::new(&mut data, 4);
+        // Xor::A and Xor::C on row 7.
+        block.set(7, 3, T::from(0xabcdef01u64));
+        block.set(7, 5, T::from(0x12345678));
+        apply_effects(&plan, &mut block, &mut xor_lookup).unwrap();
+
+        // Xor::B on rows 4 to 7.
+        assert_eq!(
+            (4..8)
+                .map(|row| block.get(row, 4).to_degree())
+                .collect_vec(),
+            [0x79, 0xb979, 0xf9b979, 0xb9f9b979]
+        );
+        // Xor::B_byte on rows 3 to 6.
+        assert_eq!(
+            (3..7)
+                .map(|row| block.get(row, 1).to_degree())
+                .collect_vec(),
+            [0x79, 0xb9, 0xf9, 0xb9]
+        );
+    }
+
+    #[test]
+    fn apply_effects_look_behind() {
+        // Processing the identity on row -1 results in code that reads the row before the block.
+        let input = "namespace N(4); let x; x' = x + 1;";
+        let plan = solve_on_rows_and_then(input, &[-1, 0], vec![("N::x", -1)], None, |witgen| {
+            BlockPlan {
+                block_size: 2,
+                inputs: witgen.inputs(),
+                code: witgen.code(),
+            }
+        });
+        assert_eq!(
+            format_code(&plan.code),
+            "N::x[0] = (N::x[-1] + 1);\nN::x[1] = (N::x[0] + 1);"
+        );
+        let column_ids = [PolyID {
+            id: 0,
+            ptype: PolynomialType::Committed,
+        }];
+        let mut data = CompactData::new(&column_ids);
+        data.append_new_rows(4);
+        let mut machine_calls = MockCallHandler::from_fn(|_, _| None).strict();
+
+        let mut block = CompactDataRef::new(&mut data, 1);
+        block.set(-1, 0, T::from(7));
+        apply_effects(&plan, &mut block, &mut machine_calls).unwrap();
+        assert_eq!(
+            (0..2).map(|row| block.get(row, 0)).collect_vec(),
+            [T::from(8), T::from(9)]
+        );
+
+        // There is no row before the first block.
+        let mut block = CompactDataRef::new(&mut data, 0);
+        assert!(matches!(
+            apply_effects(&plan, &mut block, &mut machine_calls),
+            Err(WitgenJitError::RowOutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn apply_effects_look_ahead() {
+        // Processing the identity on the last row of the block writes the first row of the next block.
+        let input = "namespace N(4); let x; x' = x + 1;";
+        let plan = solve_on_rows_and_then(input, &[0, 1], vec![("N::x", 0)], None, |witgen| {
+            BlockPlan {
+                block_size: 2,
+                inputs: witgen.inputs(),
+                code: witgen.code(),
+            }
+        });
+        assert_eq!(
+            format_code(&plan.code),
+            "N::x[1] = (N::x[0] + 1);\nN::x[2] = (N::x[1] + 1);"
+        );
+        let column_ids = [PolyID {
+            id: 0,
+            ptype: PolynomialType::Committed,
+        }];
+        let mut data = CompactData::new(&column_ids);
+        data.append_new_rows(2);
+        let mut machine_calls = MockCallHandler::from_fn(|_, _| None).strict();
+
+        // The input is not known yet.
+        let mut block = CompactDataRef::new(&mut data, 0);
+        assert!(matches!(
+            apply_effects(&plan, &mut block, &mut machine_calls),
+            Err(WitgenJitError::MissingValue(_))
+        ));
+
+        // There is no row after the block.
+        block.set(0, 0, T::from(7));
+        assert!(matches!(
+            apply_effects(&plan, &mut block, &mut machine_calls),
+            Err(WitgenJitError::RowOutOfBounds)
+        ));
+
+        data.append_new_rows(1);
+        let mut block = CompactDataRef::new(&mut data, 0);
+        apply_effects(&plan, &mut block, &mut machine_calls).unwrap();
+        assert_eq!(
+            (0..3).map(|row| block.get(row, 0)).collect_vec(),
+            [T::from(7), T::from(8), T::from(9)]
+        );
+    }
+
+    #[test]
+    fn xor_cached_machine_calls() {
+        let (cacheable, code) = solve_xor(|witgen| (witgen.cacheable_calls(), witgen.code()));
+        let a = find_cell(&code, "Xor::A[7]");
+        let c = find_cell(&code, "Xor::C[7]");
+        let b = find_cell(&code, "Xor::B[6]");
+        let xor = MockCallHandler::from_fn(|_, inputs: &[T]| {
+            Some(vec![T::from(
+                inputs[0].to_integer() ^ inputs[1].to_integer(),
+            )])
+        });
+        let mut handler = CachingMachineCallHandler::new(xor, cacheable);
+        // All bytes are the same, so all lookups have the same arguments.
+        let result = EffectInterpreter::new(&code)
+            .run(
+                [(a, T::from(0x01010101)), (c, T::from(0x03030303))],
+                &mut handler,
+            )
+            .unwrap();
+        assert_eq!(result.values[&b], T::from(0x020202));
+        assert_eq!(handler.into_inner().calls().len(), 1);
+
+        // With a multiplicity column, the callee has to see every call to count it.
+        let input = XOR_INPUT.replace(
+            "[ A_byte, B_byte, C_byte ] in [ P_A, P_B, P_C ];",
+            "let m;\n\
+             Constr::PhantomLookup((Option::None, Option::None), \
+             [(A_byte, P_A), (B_byte, P_B), (C_byte, P_C)], m);",
+        );
+        let (cacheable, code) = solve_on_rows_and_then(
+            &input,
+            &[3, 4, 5, 6, 7],
+            vec![("Xor::A", 7), ("Xor::C", 7)],
+            Some(16),
+            |witgen| (witgen.cacheable_calls(), witgen.code()),
+        );
+        assert!(cacheable.is_empty());
+        let a = find_cell(&code, "Xor::A[7]");
+        let c = find_cell(&code, "Xor::C[7]");
+        let xor = MockCallHandler::from_fn(|_, inputs: &[T]| {
+            Some(vec![T::from(
+                inputs[0].to_integer() ^ inputs[1].to_integer(),
+            )])
+        });
+        let mut handler = CachingMachineCallHandler::new(xor, cacheable);
+        EffectInterpreter::new(&code)
+            .run(
+                [(a, T::from(0x01010101)), (c, T::from(0x03030303))],
+                &mut handler,
+            )
+            .unwrap();
+        let xor = handler.into_inner();
+        assert_eq!(xor.calls().len(), 4);
+        assert!(xor.calls().iter().all(|call| call.identity_id == 0));
+    }
+
+    #[test]
+    fn repeated_bus_receive_not_cached() {
+        // The receives on both rows have the same arguments, but the sender
+        // (e.g. a memory) can provide a different payload every time.
+        let input = "
+        namespace N(8);
+            col fixed LATCH = [1]*;
+            let X;
+            let Z;
+            X' = X;
+            Constr::PhantomBusInteraction(-1 * LATCH, [7, X, Z]);
+        ";
+        let (cacheable, code) =
+            solve_on_rows_and_then(input, &[0, 1], vec![("N::X", 0)], None, |witgen| {
+                (witgen.cacheable_calls(), witgen.code())
+            });
+        assert!(cacheable.is_empty());
+        let x = find_cell(&code, "N::X[0]");
+        let mut counter = 0;
+        let receive = MockCallHandler::from_fn(|_, _: &[T]| {
+            counter += 1;
+            Some(vec![T::from(counter)])
+        });
+        let mut handler = CachingMachineCallHandler::new(receive, cacheable);
+        let result = EffectInterpreter::new(&code)
+            .run([(x, T::from(5))], &mut handler)
+            .unwrap();
+        assert_eq!(handler.into_inner().calls().len(), 2);
+        let z = result
+            .values
+            .iter()
+            .filter(|(cell, _)| cell.column_name == "N::Z")
+            .sorted_by_key(|(cell, _)| cell.row_offset)
+            .map(|(_, value)| *value)
+            .collect_vec();
+        assert_eq!(z, [T::from(1), T::from(2)]);
+    }
+
+    #[test]
+    fn state_write() {
+        let cell = |name: &str, id| Cell {
+            column_name: name.to_string(),
+            id,
+            row_offset: 0,
+        };
+        let addr = SymbolicExpression::from_symbol(cell("ADDR", 0), None);
+        let value = SymbolicExpression::from_symbol(cell("VALUE", 1), None);
+        let code = vec![Effect::StateWrite(
+            2,
+            vec![T::from(42).into(), addr + T::from(4).into(), value],
+        )];
+        let mut memory = MockCallHandler::from_fn(|_, _| Some(vec![])).strict();
+        EffectInterpreter::new(&code)
+            .run(
+                [
+                    (cell("ADDR", 0), T::from(8)),
+                    (cell("VALUE", 1), T::from(7)),
+                ],
+                &mut memory,
+            )
+            .unwrap();
+        let calls = memory.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].identity_id, 2);
+        assert_eq!(calls[0].inputs, vec![T::from(42), T::from(12), T::from(7)]);
+    }
+
+    #[test]
+    fn double_write() {
+        let x = Cell {
+            column_name: "X".to_string(),
+            id: 0,
+            row_offset: 0,
+        };
+        let code = vec![
+            Effect::Assignment(x.clone(), T::from(1).into()),
+            Effect::Assignment(x.clone(), T::from(2).into()),
+        ];
+        let mut machine_calls = MockCallHandler::from_fn(|_, _| None).strict();
+        let result = EffectInterpreter::new(&code).run([], &mut machine_calls);
+        assert!(matches!(result, Err(InterpreterError::DoubleWrite(v)) if v == x));
+    }
+}
@@ -1,4 +1,10 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use bit_vec::BitVec;
+use itertools::Itertools;
 use powdr_number::FieldElement;
 
 use crate::witgen::{
@@ -8,11 +14,39 @@ use crate::witgen::{
     EvalError, FixedData, MutableState, QueryCallback,
 };
 
+use super::{
+    cell::Cell,
+    error::WitgenJitError,
+    fixed_evaluator::CompositeFixedEvaluator,
+    interpreter::{apply_effects, MachineCallHandler},
+    witgen_inference::{verify_outputs, BlockPlan, OutputProblem, WitgenInference},
+};
+
+/// Returns true if witgen code should be generated for block machines,
+/// which is enabled by setting the environment variable `POWDR_JIT_WITGEN` to `1`.
+pub fn jit_witgen_enabled() -> bool {
+    std::env::var("POWDR_JIT_WITGEN").is_ok_and(|v| v == "1")
+}
+
+/// The number of lookups answered by generated code in this process.
+static JIT_ANSWERED_LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of lookups that were answered by generated code so far
+/// (across all machines in this process).
+pub fn jit_answered_lookups() -> usize {
+    JIT_ANSWERED_LOOKUPS.load(Ordering::Relaxed)
+}
+
 pub struct JitProcessor<'a, T: FieldElement> {
-    _fixed_data: &'a FixedData<'a, T>,
+    fixed_data: &'a FixedData<'a, T>,
     parts: MachineParts<'a, T>,
-    _block_size: usize,
+    block_size: usize,
     latch_row: usize,
+    /// If false, no code is generated and all lookups are left to the machine.
+    enabled: bool,
+    /// The code generated for a connection and the known elements of its right-hand side,
+    /// or `None` if no code could be generated.
+    witgen_functions: HashMap<(u64, BitVec), Option<BlockPlan<T>>>,
 }
 
 impl<'a, T: FieldElement> JitProcessor<'a, T> {
@@ -23,41 +57,280 @@ impl<'a, T: FieldElement> JitProcessor<'a, T> {
         latch_row: usize,
     ) -> Self {
         JitProcessor {
-            _fixed_data: fixed_data,
+            fixed_data,
             parts,
-            _block_size: block_size,
+            block_size,
             latch_row,
+            enabled: false,
+            witgen_functions: Default::default(),
         }
     }
 
-    pub fn can_answer_lookup(&self, _identity_id: u64, _known_inputs: &BitVec) -> bool {
-        // TODO call the JIT compiler here.
-        false
+    /// Enables code generation, see `jit_witgen_enabled`.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Returns true if code can be generated that answers lookups via the given
+    /// connection where the elements of the right-hand side in `known_inputs` are known.
+    /// The code is generated on the first call and cached.
+    pub fn can_answer_lookup(&mut self, identity_id: u64, known_inputs: &BitVec) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let key = (identity_id, known_inputs.clone());
+        if !self.witgen_functions.contains_key(&key) {
+            let plan = self
+                .generate_code(identity_id, known_inputs)
+                .map_err(|e| {
+                    log::debug!("Could not generate code for connection {identity_id}: {e}");
+                })
+                .ok();
+            self.witgen_functions.insert(key.clone(), plan);
+        }
+        self.witgen_functions[&key].is_some()
+    }
+
+    /// The cells of the right-hand side of the connection on the latch row,
+    /// or `None` if it is not a list of witness columns.
+    fn connection_cells(&self, identity_id: u64) -> Option<Vec<Cell>> {
+        self.parts.connections[&identity_id]
+            .right
+            .expressions
+            .iter()
+            .map(|e| {
+                let r = try_to_simple_poly(e)?;
+                r.is_witness()
+                    .then(|| Cell::from_reference(r, self.latch_row as i32))
+            })
+            .collect()
+    }
+
+    fn generate_code(
+        &self,
+        identity_id: u64,
+        known_inputs: &BitVec,
+    ) -> Result<BlockPlan<T>, WitgenJitError<T>> {
+        let cells = self.connection_cells(identity_id).ok_or_else(|| {
+            WitgenJitError::UnsupportedIdentity {
+                identity_id,
+                reason: "The right-hand side is not a list of witness columns.".to_string(),
+            }
+        })?;
+        let known_cells = cells
+            .iter()
+            .zip(known_inputs.iter())
+            .filter(|(_, known)| *known)
+            .map(|(cell, _)| cell.clone());
+        let identities = self
+            .parts
+            .identities
+            .iter()
+            .map(|id| (*id).clone())
+            .collect_vec();
+        let degree = self.parts.common_degree_range().max;
+        let fixed_evaluator =
+            CompositeFixedEvaluator::new(self.fixed_data, degree, self.block_size);
+        if fixed_evaluator.requires_last_block_program(&identities) {
+            // TODO derive a separate program for the last block.
+            return Err(WitgenJitError::UnsupportedIdentity {
+                identity_id,
+                reason: "A separate program for the last block is needed, \
+                         which is not supported yet."
+                    .to_string(),
+            });
+        }
+        let mut inference = WitgenInference::new(self.fixed_data, fixed_evaluator, known_cells);
+        let plan = inference.solve_block(&identities, self.block_size)?;
+        // The code has to determine all outputs of the connection.
+        let undetermined = cells
+            .iter()
+            .filter(|cell| inference.provenance(cell).is_none())
+            .cloned()
+            .collect_vec();
+        if !undetermined.is_empty() {
+            return Err(WitgenJitError::Stuck(undetermined));
+        }
+        let outputs = cells
+            .iter()
+            .zip(known_inputs.iter())
+            .filter(|(_, known)| !known)
+            .map(|(cell, _)| cell.clone())
+            .collect_vec();
+        if let Err(problems) = verify_outputs(&plan.code, &outputs) {
+            for problem in &problems {
+                log::trace!("Code for connection {identity_id}: {problem}");
+            }
+            if let Some(problem) = problems.into_iter().find(|p| p.is_error()) {
+                return Err(match problem {
+                    OutputProblem::Missing(cell) => WitgenJitError::Stuck(vec![cell]),
+                    OutputProblem::AssignedTwice(cell) => WitgenJitError::DoubleWrite(cell),
+                    OutputProblem::Unused(_) => unreachable!(),
+                });
+            }
+        }
+        Ok(plan)
     }
 
     pub fn process_lookup_direct<'c, 'd, Q: QueryCallback<T>>(
         &self,
-        _mutable_state: &MutableState<'a, T, Q>,
+        mutable_state: &MutableState<'a, T, Q>,
         connection_id: u64,
         values: Vec<LookupCell<'c, T>>,
-        mut data: CompactDataRef<'d, T>,
+        data: CompactDataRef<'d, T>,
     ) -> Result<bool, EvalError<T>> {
+        let result = self.run_code(connection_id, values, data, &mut &*mutable_state)?;
+        JIT_ANSWERED_LOOKUPS.fetch_add(1, Ordering::Relaxed);
+        Ok(result)
+    }
+
+    /// Runs the code generated for the connection on `data`, which starts at the first
+    /// row of the new block, and stores the outputs of the connection in `values`.
+    fn run_code(
+        &self,
+        connection_id: u64,
+        mut values: Vec<LookupCell<'_, T>>,
+        mut data: CompactDataRef<'_, T>,
+        machine_calls: &mut impl MachineCallHandler<T>,
+    ) -> Result<bool, EvalError<T>> {
+        let known_inputs = values
+            .iter()
+            .map(|v| matches!(v, LookupCell::Input(_)))
+            .collect();
+        let Some(Some(plan)) = self.witgen_functions.get(&(connection_id, known_inputs)) else {
+            panic!("No code was generated for connection {connection_id}.");
+        };
+        let cells = self.connection_cells(connection_id).unwrap();
+
         // Transfer inputs.
-        let right = self.parts.connections[&connection_id].right;
-        for (e, v) in right.expressions.iter().zip(&values) {
-            match v {
-                LookupCell::Input(&v) => {
-                    let col = try_to_simple_poly(e).unwrap();
-                    data.set(self.latch_row as i32, col.poly_id.id as u32, v);
-                }
-                LookupCell::Output(_) => {}
+        for (cell, v) in cells.iter().zip(&values) {
+            if let LookupCell::Input(&v) = v {
+                data.set(cell.row_offset, cell.id as u32, v);
+            }
+        }
+
+        apply_effects(plan, &mut data, machine_calls)?;
+
+        // Transfer outputs.
+        for (cell, v) in cells.iter().zip(&mut values) {
+            if let LookupCell::Output(v) = v {
+                **v = data.get(cell.row_offset, cell.id as u32);
             }
         }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
 
-        // Just some code here to avoid "unused" warnings.
-        // This code will not be called as long as `can_answer_lookup` returns false.
-        data.get(self.latch_row as i32, 0);
+    use powdr_ast::analyzed::Analyzed;
+    use powdr_number::GoldilocksField;
+
+    use crate::{
+        constant_evaluator,
+        witgen::{
+            data_structures::finalizable_data::CompactData, jit::test_util::MockCallHandler,
+            machines::Connection,
+        },
+    };
+
+    use super::*;
+
+    type T = GoldilocksField;
+
+    const ADD_INPUT: &str = "
+namespace Add(8);
+    col fixed LATCH = [1]*;
+    let A;
+    let B;
+    let C;
+    let D;
+    D = A + B;
+    C = D * LATCH;
+namespace Main(8);
+    let X;
+    let Y;
+    let Z;
+    [ X, Y, Z ] is Add::LATCH $ [ Add::A, Add::B, Add::C ];
+";
+
+    fn with_processor<R>(f: impl FnOnce(JitProcessor<'_, T>) -> R) -> R {
+        let analyzed: Analyzed<T> = powdr_pil_analyzer::analyze_string(ADD_INPUT).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let connection = Connection::try_from(&analyzed.identities[2]).unwrap();
+        let connections = BTreeMap::from([(connection.id, connection)]);
+        let witnesses = ["Add::A", "Add::B", "Add::C", "Add::D"]
+            .iter()
+            .map(|name| fixed_data.try_column_by_name(name).unwrap())
+            .collect();
+        let parts = MachineParts::new(
+            &fixed_data,
+            connections,
+            analyzed.identities[..2].iter().collect(),
+            witnesses,
+            vec![],
+        );
+        let mut processor = JitProcessor::new(&fixed_data, parts, 1, 0);
+        processor.enable();
+        f(processor)
+    }
+
+    #[test]
+    fn disabled() {
+        with_processor(|mut processor| {
+            processor.enabled = false;
+            assert!(!processor.can_answer_lookup(2, &BitVec::from_iter([true, true, false])));
+        });
+    }
+
+    #[test]
+    fn can_answer_lookup() {
+        with_processor(|mut processor| {
+            assert!(processor.can_answer_lookup(2, &BitVec::from_iter([true, true, false])));
+            assert!(!processor.can_answer_lookup(2, &BitVec::from_iter([false, false, true])));
+            assert_eq!(processor.witgen_functions.len(), 2);
+        });
+    }
+
+    #[test]
+    fn generate_code_error() {
+        with_processor(|processor| {
+            let Err(error) = processor.generate_code(2, &BitVec::from_iter([false, false, true]))
+            else {
+                panic!("Expected code generation to fail.");
+            };
+            assert_eq!(
+                error.to_string(),
+                "Could not solve the cells Add::A[0], Add::B[0]"
+            );
+        });
+    }
 
-        unimplemented!();
+    #[test]
+    fn run_code() {
+        with_processor(|mut processor| {
+            assert!(processor.can_answer_lookup(2, &BitVec::from_iter([true, true, false])));
+            let (a, b, mut c) = (T::from(3), T::from(4), T::from(0));
+            let values = vec![
+                LookupCell::Input(&a),
+                LookupCell::Input(&b),
+                LookupCell::Output(&mut c),
+            ];
+            let column_ids = processor.parts.witnesses.iter().copied().collect_vec();
+            let mut data = CompactData::new(&column_ids);
+            data.append_new_rows(4);
+            let mut machine_calls = MockCallHandler::from_fn(|_, _| None).strict();
+            let result = processor.run_code(
+                2,
+                values,
+                CompactDataRef::new(&mut data, 1),
+                &mut machine_calls,
+            );
+            assert_eq!(result, Ok(true));
+            assert_eq!(c, T::from(7));
+        });
     }
 }
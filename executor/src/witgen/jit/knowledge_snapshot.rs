@@ -0,0 +1,197 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::{self, Display, Formatter},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+    str::FromStr,
+};
+
+use powdr_ast::analyzed::Analyzed;
+use powdr_number::{FieldElement, LargeInt};
+
+use super::{super::range_constraints::RangeConstraint, cell::Cell};
+
+/// The knowledge derived by `WitgenInference::solve_block` up to a certain round,
+/// see `WitgenInference::serialize_state` and `WitgenInference::restore_state`.
+/// The code derived so far is not part of the snapshot.
+///
+/// The text format has one entry per line:
+/// - `pil <hash>`
+/// - `round <round>`
+/// - `known <column id> <row> <column name>`
+/// - `constant <column id> <row> <value> <column name>`
+/// - `range <column id> <row> <mask (hex)> <min> <max> <column name>`
+/// - `complete <identity id> <row>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnowledgeSnapshot<T: FieldElement> {
+    /// The hash of the analyzed PIL the snapshot was taken for, see [`pil_hash`].
+    pub pil_hash: u64,
+    pub round: usize,
+    pub known_cells: Vec<Cell>,
+    /// Cells that are known only because of their range constraint.
+    pub constant_cells: Vec<(Cell, T)>,
+    /// The range constraints derived during inference, not including the global ones.
+    pub range_constraints: Vec<(Cell, RangeConstraint<T>)>,
+    /// The completed identity-row pairs.
+    pub complete: Vec<(u64, i32)>,
+}
+
+/// Returns a hash of the analyzed PIL, used to reject snapshots that were
+/// taken for a different PIL. The hash is only stable across runs of the
+/// same executable.
+pub fn pil_hash<T: FieldElement>(analyzed: &Analyzed<T>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    analyzed.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<T: FieldElement> KnowledgeSnapshot<T> {
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn read_from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<T: FieldElement> Display for KnowledgeSnapshot<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pil {}", self.pil_hash)?;
+        writeln!(f, "round {}", self.round)?;
+        for cell in &self.known_cells {
+            writeln!(
+                f,
+                "known {} {} {}",
+                cell.id, cell.row_offset, cell.column_name
+            )?;
+        }
+        for (cell, value) in &self.constant_cells {
+            writeln!(
+                f,
+                "constant {} {} {value} {}",
+                cell.id, cell.row_offset, cell.column_name
+            )?;
+        }
+        for (cell, rc) in &self.range_constraints {
+            let (min, max) = rc.range();
+            writeln!(
+                f,
+                "range {} {} {:x} {min} {max} {}",
+                cell.id,
+                cell.row_offset,
+                rc.mask(),
+                cell.column_name
+            )?;
+        }
+        for (identity_id, row_offset) in &self.complete {
+            writeln!(f, "complete {identity_id} {row_offset}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: FieldElement> FromStr for KnowledgeSnapshot<T> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pil_hash = None;
+        let mut round = None;
+        let mut snapshot = KnowledgeSnapshot {
+            pil_hash: 0,
+            round: 0,
+            known_cells: vec![],
+            constant_cells: vec![],
+            range_constraints: vec![],
+            complete: vec![],
+        };
+        for (nr, line) in s.lines().enumerate() {
+            let parse_error = || format!("Line {}: Invalid entry: {line}", nr + 1);
+            let parts = line.split(' ').collect::<Vec<_>>();
+            let cell = |name: &[&str]| -> Result<Cell, String> {
+                Ok(Cell::new(
+                    name.join(" "),
+                    parts[1].parse().map_err(|_| parse_error())?,
+                    parts[2].parse().map_err(|_| parse_error())?,
+                ))
+            };
+            let value = |s: &str| T::from_str(s).map_err(|_| parse_error());
+            match parts.as_slice() {
+                ["pil", hash] => pil_hash = Some(hash.parse().map_err(|_| parse_error())?),
+                ["round", r] => round = Some(r.parse().map_err(|_| parse_error())?),
+                ["known", _, _, name @ ..] if !name.is_empty() => {
+                    snapshot.known_cells.push(cell(name)?);
+                }
+                ["constant", _, _, v, name @ ..] if !name.is_empty() => {
+                    snapshot.constant_cells.push((cell(name)?, value(v)?));
+                }
+                ["range", _, _, mask, min, max, name @ ..] if !name.is_empty() => {
+                    if mask.is_empty() || !mask.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(parse_error());
+                    }
+                    let rc = RangeConstraint::from_range(value(min)?, value(max)?)
+                        .conjunction(&RangeConstraint::from_mask(T::Integer::from_hex(mask)));
+                    snapshot.range_constraints.push((cell(name)?, rc));
+                }
+                ["complete", identity_id, row_offset] => snapshot.complete.push((
+                    identity_id.parse().map_err(|_| parse_error())?,
+                    row_offset.parse().map_err(|_| parse_error())?,
+                )),
+                _ => return Err(parse_error()),
+            }
+        }
+        snapshot.pil_hash = pil_hash.ok_or("Missing PIL hash.")?;
+        snapshot.round = round.ok_or("Missing round.")?;
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use powdr_number::GoldilocksField;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let text = "pil 1234
+round 3
+known 0 1 Xor::A
+constant 1 1 7 Xor::B
+range 2 0 ff 0 255 Xor::C
+complete 4 -1
+";
+        let snapshot: KnowledgeSnapshot<GoldilocksField> = text.parse().unwrap();
+        assert_eq!(snapshot.round, 3);
+        assert_eq!(
+            snapshot.range_constraints,
+            vec![(
+                Cell {
+                    column_name: "Xor::C".to_string(),
+                    id: 2,
+                    row_offset: 0
+                },
+                RangeConstraint::from_mask(0xffu64)
+            )]
+        );
+        assert_eq!(snapshot.complete, vec![(4, -1)]);
+        assert_eq!(snapshot.to_string(), text);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            "round 1".parse::<KnowledgeSnapshot<GoldilocksField>>(),
+            Err("Missing PIL hash.".to_string())
+        );
+        assert_eq!(
+            "pil 1\nround 1\nrange 2 0 xy 0 255 C".parse::<KnowledgeSnapshot<GoldilocksField>>(),
+            Err("Line 3: Invalid entry: range 2 0 xy 0 255 C".to_string())
+        );
+    }
+}
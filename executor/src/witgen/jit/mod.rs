@@ -1,5 +1,16 @@
 pub(crate) mod affine_symbolic_expression;
+pub(crate) mod cache_key;
 mod cell;
+pub(crate) mod code_diff;
+pub(crate) mod code_parser;
+pub(crate) mod codegen;
+pub(crate) mod error;
+pub(crate) mod fixed_evaluator;
+pub(crate) mod inference_trace;
+pub(crate) mod interpreter;
 pub(crate) mod jit_processor;
+pub(crate) mod knowledge_snapshot;
 mod symbolic_expression;
+#[cfg(test)]
+mod test_util;
 pub(crate) mod witgen_inference;
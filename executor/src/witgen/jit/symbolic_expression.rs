@@ -4,14 +4,16 @@ use std::{
     rc::Rc,
 };
 
+use itertools::Itertools;
 use powdr_number::FieldElement;
+use serde::{Deserialize, Serialize};
 
 use crate::witgen::range_constraints::RangeConstraint;
 
 /// A value that is known at run-time, defined through a complex expression
 /// involving known cells or variables and compile-time constants.
 /// Each of the sub-expressions can have its own range constraint.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SymbolicExpression<T: FieldElement, S> {
     /// A concrete constant value known at compile time.
     Concrete(T),
@@ -25,9 +27,13 @@ pub enum SymbolicExpression<T: FieldElement, S> {
         Option<RangeConstraint<T>>,
     ),
     UnaryOperation(UnaryOperator, Rc<Self>, Option<RangeConstraint<T>>),
+    /// The value paired with the value of the expression in the table of
+    /// `(key, value)` pairs, i.e. a `match` on the expression. Evaluation fails
+    /// if the key is not in the table.
+    Select(Rc<Self>, Rc<[(T, T)]>, Option<RangeConstraint<T>>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BinaryOperator {
     Add,
     Sub,
@@ -40,7 +46,7 @@ pub enum BinaryOperator {
     BitOr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UnaryOperator {
     Neg,
 }
@@ -50,6 +56,25 @@ impl<T: FieldElement, S> SymbolicExpression<T, S> {
         SymbolicExpression::Symbol(symbol, rc)
     }
 
+    /// Returns an expression that selects the value paired with the value of `key`
+    /// in `table`, see `SymbolicExpression::Select`.
+    /// Returns `None` if `key` is a number that is not in the table.
+    pub fn select(key: Self, table: Vec<(T, T)>) -> Option<Self> {
+        if let Some(key) = key.try_to_number() {
+            return table
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| SymbolicExpression::Concrete(*v));
+        }
+        let max = table.iter().map(|(_, v)| *v).max().unwrap_or_default();
+        let rc = RangeConstraint::from_range(T::zero(), max);
+        Some(SymbolicExpression::Select(
+            Rc::new(key),
+            table.into(),
+            Some(rc),
+        ))
+    }
+
     pub fn is_known_zero(&self) -> bool {
         self.try_to_number().map_or(false, |n| n.is_zero())
     }
@@ -78,7 +103,8 @@ impl<T: FieldElement, S> SymbolicExpression<T, S> {
             SymbolicExpression::Concrete(v) => Some(RangeConstraint::from_value(*v)),
             SymbolicExpression::Symbol(.., rc)
             | SymbolicExpression::BinaryOperation(.., rc)
-            | SymbolicExpression::UnaryOperation(.., rc) => rc.clone(),
+            | SymbolicExpression::UnaryOperation(.., rc)
+            | SymbolicExpression::Select(.., rc) => rc.clone(),
         }
     }
 
@@ -87,27 +113,115 @@ impl<T: FieldElement, S> SymbolicExpression<T, S> {
             SymbolicExpression::Concrete(n) => Some(*n),
             SymbolicExpression::Symbol(..)
             | SymbolicExpression::BinaryOperation(..)
-            | SymbolicExpression::UnaryOperation(..) => None,
+            | SymbolicExpression::UnaryOperation(..)
+            | SymbolicExpression::Select(..) => None,
+        }
+    }
+
+    /// Returns all symbols referenced in this expression, potentially with duplicates.
+    pub fn referenced_symbols(&self) -> Box<dyn Iterator<Item = &S> + '_> {
+        match self {
+            SymbolicExpression::Concrete(_) => Box::new(std::iter::empty()),
+            SymbolicExpression::Symbol(s, _) => Box::new(std::iter::once(s)),
+            SymbolicExpression::BinaryOperation(left, _, right, _) => {
+                Box::new(left.referenced_symbols().chain(right.referenced_symbols()))
+            }
+            SymbolicExpression::UnaryOperation(_, expr, _)
+            | SymbolicExpression::Select(expr, _, _) => expr.referenced_symbols(),
+        }
+    }
+
+    /// Returns a copy of this expression where each symbol is replaced by `f(symbol)`.
+    pub fn map_symbols<S2>(&self, f: &impl Fn(&S) -> S2) -> SymbolicExpression<T, S2> {
+        match self {
+            SymbolicExpression::Concrete(n) => SymbolicExpression::Concrete(*n),
+            SymbolicExpression::Symbol(s, rc) => SymbolicExpression::Symbol(f(s), rc.clone()),
+            SymbolicExpression::BinaryOperation(left, op, right, rc) => {
+                SymbolicExpression::BinaryOperation(
+                    Rc::new(left.map_symbols(f)),
+                    op.clone(),
+                    Rc::new(right.map_symbols(f)),
+                    rc.clone(),
+                )
+            }
+            SymbolicExpression::UnaryOperation(op, expr, rc) => SymbolicExpression::UnaryOperation(
+                op.clone(),
+                Rc::new(expr.map_symbols(f)),
+                rc.clone(),
+            ),
+            SymbolicExpression::Select(key, table, rc) => {
+                SymbolicExpression::Select(Rc::new(key.map_symbols(f)), table.clone(), rc.clone())
+            }
         }
     }
 }
 
+/// How compile-time constants are rendered when displaying expressions and code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// Values in the upper half of the field are rendered as negative numbers,
+    /// i.e. the representation is centered around zero.
+    #[default]
+    Balanced,
+    /// Values are rendered as their canonical representative in `[0, p)`.
+    Unsigned,
+}
+
+/// Displays an expression or effect with the given number format,
+/// see e.g. `SymbolicExpression::display_with`.
+pub struct Formatted<'a, E>(pub &'a E, pub NumberFormat);
+
+impl<T: FieldElement, S> SymbolicExpression<T, S> {
+    /// Returns a value that displays the expression with the given number format.
+    pub fn display_with(&self, format: NumberFormat) -> Formatted<'_, Self> {
+        Formatted(self, format)
+    }
+}
+
 /// Display for affine symbolic expressions, for informational purposes only.
 impl<T: FieldElement, V: Display> Display for SymbolicExpression<T, V> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
+        self.display_with(NumberFormat::default()).fmt(f)
+    }
+}
+
+impl<T: FieldElement, V: Display> Display for Formatted<'_, SymbolicExpression<T, V>> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Formatted(expr, format) = *self;
+        match expr {
             SymbolicExpression::Concrete(n) => {
-                if n.is_in_lower_half() {
+                if format == NumberFormat::Unsigned || n.is_in_lower_half() {
                     write!(f, "{n}")
                 } else {
                     write!(f, "-{}", -*n)
                 }
             }
             SymbolicExpression::Symbol(name, _) => write!(f, "{name}"),
-            SymbolicExpression::BinaryOperation(lhs, op, rhs, _) => {
-                write!(f, "({lhs} {op} {rhs})")
+            SymbolicExpression::BinaryOperation(lhs, op, rhs, _) => write!(
+                f,
+                "({} {op} {})",
+                lhs.display_with(format),
+                rhs.display_with(format)
+            ),
+            SymbolicExpression::UnaryOperation(op, expr, _) => {
+                write!(f, "{op}{}", expr.display_with(format))
+            }
+            SymbolicExpression::Select(key, table, _) => {
+                let number = |n: &T| SymbolicExpression::<T, V>::Concrete(*n);
+                write!(
+                    f,
+                    "select({}, [{}])",
+                    key.display_with(format),
+                    table
+                        .iter()
+                        .map(|(k, v)| format!(
+                            "{} => {}",
+                            number(k).display_with(format),
+                            number(v).display_with(format)
+                        ))
+                        .join(", ")
+                )
             }
-            SymbolicExpression::UnaryOperation(op, expr, _) => write!(f, "{op}{expr}"),
         }
     }
 }
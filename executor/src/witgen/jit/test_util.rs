@@ -0,0 +1,1010 @@
+//! Test support for the JIT witgen code: helpers to run inference on PIL
+//! sources, a mock machine call handler, snapshot files for generated code and
+//! a brute-force oracle for the affine solver, together with proptest
+//! strategies to generate small affine constraints.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use itertools::Itertools;
+use powdr_ast::analyzed::{
+    AlgebraicBinaryOperator, AlgebraicExpression as Expression, AlgebraicReference,
+    AlgebraicUnaryOperator, Analyzed, PolyID, PolynomialType, StatementIdentifier, Symbol,
+    SymbolKind,
+};
+use powdr_number::{DegreeType, FieldElement, GoldilocksField};
+use powdr_parser_util::SourceRef;
+use proptest::prelude::*;
+
+use crate::{
+    constant_evaluator::{self, VariablySizedColumn},
+    witgen::{
+        data_structures::column_map::{FixedColumnMap, WitnessColumnMap},
+        global_constraints::{self, GlobalConstraints},
+        machines::LookupCell,
+        range_constraints::RangeConstraint,
+        EvalError, FixedData,
+    },
+};
+
+use super::{
+    affine_symbolic_expression::{AffineSymbolicExpression, Assertion, Effect},
+    cell::{Cell, CellNaming},
+    error::WitgenJitError,
+    fixed_evaluator::FixedEvaluatorForFixedData,
+    interpreter::{evaluate, MachineCallHandler},
+    symbolic_expression::{NumberFormat, SymbolicExpression},
+    witgen_inference::{FixedEvaluator, ProcessStatus, WitgenInference, WitgenInferenceConfig},
+};
+
+pub fn format_code<T: FieldElement>(effects: &[Effect<T, Cell>]) -> String {
+    format_code_with_naming(effects, CellNaming::default())
+}
+
+/// Formats the code like [`format_code`], but renders cells using the given naming scheme.
+pub fn format_code_with_naming<T: FieldElement>(
+    effects: &[Effect<T, Cell>],
+    naming: CellNaming,
+) -> String {
+    effects
+        .iter()
+        .map(|effect| match effect {
+            Effect::RangeConstraint(..) => {
+                panic!("Range constraints should not be part of the code.")
+            }
+            _ => effect.map_variables(&|cell| cell.name(naming)).to_string(),
+        })
+        .join("\n")
+}
+
+/// Formats the code like [`format_code`], but renders numbers in the given format.
+pub fn format_code_with_number_format<T: FieldElement>(
+    effects: &[Effect<T, Cell>],
+    format: NumberFormat,
+) -> String {
+    effects
+        .iter()
+        .map(|effect| effect.display_with(format).to_string())
+        .join("\n")
+}
+
+/// Returns the size of the machine instance to generate witnesses for in tests,
+/// i.e. the largest declared degree, or zero if there is none.
+pub fn instance_degree<T: FieldElement>(fixed_data: &FixedData<T>) -> DegreeType {
+    fixed_data
+        .all_poly_symbols()
+        .filter_map(|symbol| symbol.degree)
+        .map(|degree| degree.max)
+        .max()
+        .unwrap_or_default()
+}
+
+/// The `WitgenInference` returned by [`witgen_for`].
+pub type TestWitgen =
+    WitgenInference<'static, GoldilocksField, FixedEvaluatorForFixedData<'static, GoldilocksField>>;
+
+/// Analyzes `input` and returns a `WitgenInference` on an instance with `degree` rows,
+/// where `known_cells` are known, together with the fixed data, which also provides
+/// the analyzed PIL. In contrast to [`solve_on_rows`], no global constraints are derived.
+/// The analyzed PIL and the fixed data are leaked, so that they outlive the test.
+pub fn witgen_for(
+    input: &str,
+    degree: DegreeType,
+    known_cells: Vec<(&str, i32)>,
+) -> (TestWitgen, &'static FixedData<'static, GoldilocksField>) {
+    let analyzed: &'static Analyzed<GoldilocksField> =
+        Box::leak(Box::new(powdr_pil_analyzer::analyze_string(input).unwrap()));
+    let fixed_col_vals = constant_evaluator::generate(analyzed).leak();
+    let fixed_data: &'static FixedData<'static, GoldilocksField> = Box::leak(Box::new(
+        FixedData::new(analyzed, fixed_col_vals, &[], Default::default(), 0),
+    ));
+    let known_cells = known_cells
+        .into_iter()
+        .map(|(name, row_offset)| column_cell(fixed_data, name, row_offset))
+        .collect_vec();
+    let ref_eval = FixedEvaluatorForFixedData::new(fixed_data, degree);
+    (
+        WitgenInference::new(fixed_data, ref_eval, known_cells),
+        fixed_data,
+    )
+}
+
+/// Returns the cell of the column with the given name on the given row.
+pub fn column_cell<T: FieldElement>(
+    fixed_data: &FixedData<T>,
+    name: &str,
+    row_offset: i32,
+) -> Cell {
+    let poly_id = fixed_data.try_column_by_name(name).unwrap();
+    Cell::new(fixed_data.column_name(&poly_id), poly_id.id, row_offset)
+}
+
+pub fn solve_on_rows(
+    input: &str,
+    rows: &[i32],
+    known_cells: Vec<(&str, i32)>,
+    expected_complete: Option<usize>,
+) -> String {
+    solve_on_rows_and_then(input, rows, known_cells, expected_complete, |witgen| {
+        format_code(&witgen.code())
+    })
+}
+
+/// Runs inference like [`solve_on_rows`] and passes the final state to `f`.
+pub fn solve_on_rows_and_then<R>(
+    input: &str,
+    rows: &[i32],
+    known_cells: Vec<(&str, i32)>,
+    expected_complete: Option<usize>,
+    f: impl FnOnce(
+        WitgenInference<'_, GoldilocksField, FixedEvaluatorForFixedData<'_, GoldilocksField>>,
+    ) -> R,
+) -> R {
+    solve_in_field_on_rows_and_then(input, rows, known_cells, expected_complete, f)
+}
+
+/// Like [`solve_on_rows_and_then`], but over an arbitrary field.
+pub fn solve_in_field_on_rows_and_then<T: FieldElement, R>(
+    input: &str,
+    rows: &[i32],
+    known_cells: Vec<(&str, i32)>,
+    expected_complete: Option<usize>,
+    f: impl FnOnce(WitgenInference<'_, T, FixedEvaluatorForFixedData<'_, T>>) -> R,
+) -> R {
+    let analyzed: Analyzed<T> = powdr_pil_analyzer::analyze_string(input).unwrap();
+    let fixed_col_vals = constant_evaluator::generate(&analyzed);
+    let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+    let (fixed_data, retained_identities) =
+        global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
+    let known_cells = known_cells
+        .iter()
+        .map(|(name, row_offset)| column_cell(&fixed_data, name, *row_offset));
+
+    let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, instance_degree(&fixed_data));
+    let mut witgen = WitgenInference::new(&fixed_data, ref_eval, known_cells);
+    let retained_identities = retained_identities.into_iter().cloned().collect_vec();
+    let identities = witgen.supported_identities(&retained_identities);
+    let mut complete = HashSet::new();
+    let mut counter = 0;
+    let expected_complete = expected_complete.unwrap_or(identities.len() * rows.len());
+    while complete.len() != expected_complete {
+        counter += 1;
+        witgen.start_round();
+        let mut progress = false;
+        for row in rows {
+            for id in &identities {
+                if complete.contains(&(id.id(), *row)) {
+                    continue;
+                }
+                match witgen.process_identity(id, *row) {
+                    ProcessStatus::Complete | ProcessStatus::Error(_) => {
+                        complete.insert((id.id(), *row));
+                        progress = true;
+                    }
+                    ProcessStatus::Progress => progress = true,
+                    _ => {}
+                }
+            }
+        }
+        if !progress {
+            let pairs = rows
+                .iter()
+                .flat_map(|row| identities.iter().map(move |id| (*id, *row)))
+                .collect_vec();
+            complete.extend(witgen.divide_by_runtime_coefficient(&pairs));
+        }
+        assert!(counter < 10000, "Solving took more than 10000 rounds.");
+    }
+    f(witgen)
+}
+
+/// Runs `solve_block` on the identities of `input` without any known cells
+/// and returns the formatted code.
+pub fn solve_block_on(
+    input: &str,
+    block_size: usize,
+    config: WitgenInferenceConfig,
+) -> Result<String, WitgenJitError<GoldilocksField>> {
+    let analyzed: Analyzed<GoldilocksField> = powdr_pil_analyzer::analyze_string(input).unwrap();
+    let fixed_col_vals = constant_evaluator::generate(&analyzed);
+    let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+    let (fixed_data, retained_identities) =
+        global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
+    let retained_identities = retained_identities.into_iter().cloned().collect_vec();
+    let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, instance_degree(&fixed_data));
+    let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]).with_config(config);
+    let plan = witgen.solve_block(&retained_identities, block_size)?;
+    Ok(format_code(&plan.code))
+}
+
+pub const XOR_INPUT: &str = "
+namespace Xor(256 * 256);
+let latch: col = |i| { if (i % 4) == 3 { 1 } else { 0 } };
+let FACTOR: col = |i| { 1 << (((i + 1) % 4) * 8) };
+
+let a: int -> int = |i| i % 256;
+let b: int -> int = |i| (i / 256) % 256;
+let P_A: col = a;
+let P_B: col = b;
+let P_C: col = |i| a(i) ^ b(i);
+
+let A_byte;
+let B_byte;
+let C_byte;
+
+[ A_byte, B_byte, C_byte ] in [ P_A, P_B, P_C ];
+
+let A;
+let B;
+let C;
+
+A' = A * (1 - latch) + A_byte * FACTOR;
+B' = B * (1 - latch) + B_byte * FACTOR;
+C' = C * (1 - latch) + C_byte * FACTOR;
+";
+
+pub fn solve_xor<R>(
+    f: impl FnOnce(
+        WitgenInference<'_, GoldilocksField, FixedEvaluatorForFixedData<'_, GoldilocksField>>,
+    ) -> R,
+) -> R {
+    solve_on_rows_and_then(
+        XOR_INPUT,
+        // Use the second block to avoid wrap-around.
+        &[3, 4, 5, 6, 7],
+        vec![
+            ("Xor::A", 7),
+            ("Xor::C", 7), // We solve it in reverse, just for fun.
+        ],
+        Some(16),
+        f,
+    )
+}
+
+/// Builds [`FixedData`] for tests directly from column names and values,
+/// without parsing PIL. Column ids are assigned in the order of declaration,
+/// separately for witness and fixed columns, just like the PIL analyzer does.
+pub struct FixedDataBuilder<T: FieldElement> {
+    degree: DegreeType,
+    witness_columns: Vec<String>,
+    fixed_columns: Vec<(String, Vec<Vec<T>>)>,
+    range_constraints: Vec<(String, RangeConstraint<T>)>,
+}
+
+impl<T: FieldElement> FixedDataBuilder<T> {
+    pub fn new(degree: DegreeType) -> Self {
+        Self {
+            degree,
+            witness_columns: vec![],
+            fixed_columns: vec![],
+            range_constraints: vec![],
+        }
+    }
+
+    pub fn witness(mut self, name: &str) -> Self {
+        self.witness_columns.push(name.to_string());
+        self
+    }
+
+    pub fn fixed(mut self, name: &str, values: Vec<T>) -> Self {
+        assert_eq!(values.len() as DegreeType, self.degree);
+        self.fixed_columns.push((name.to_string(), vec![values]));
+        self
+    }
+
+    /// Adds a fixed column with values for several sizes, as used by
+    /// machines that are instantiated at different degrees.
+    pub fn fixed_with_sizes(mut self, name: &str, values: Vec<Vec<T>>) -> Self {
+        self.fixed_columns.push((name.to_string(), values));
+        self
+    }
+
+    /// Adds a global range constraint on a witness or fixed column.
+    pub fn range_constraint(mut self, name: &str, rc: RangeConstraint<T>) -> Self {
+        self.range_constraints.push((name.to_string(), rc));
+        self
+    }
+
+    pub fn build(self) -> FixedDataFixture<T> {
+        let mut analyzed = Analyzed::default();
+        let columns = self
+            .witness_columns
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (id, name, PolynomialType::Committed))
+            .chain(
+                self.fixed_columns
+                    .iter()
+                    .enumerate()
+                    .map(|(id, (name, _))| (id, name, PolynomialType::Constant)),
+            );
+        for (id, name, ptype) in columns {
+            let symbol = Symbol {
+                id: id as u64,
+                source: SourceRef::unknown(),
+                absolute_name: name.clone(),
+                stage: None,
+                kind: SymbolKind::Poly(ptype),
+                length: None,
+                degree: Some(self.degree.into()),
+            };
+            analyzed.definitions.insert(name.clone(), (symbol, None));
+            analyzed
+                .source_order
+                .push(StatementIdentifier::Definition(name.clone()));
+        }
+        FixedDataFixture {
+            analyzed,
+            fixed_values: self
+                .fixed_columns
+                .into_iter()
+                .map(|(name, values)| (name, values.into()))
+                .collect(),
+            range_constraints: self.range_constraints,
+        }
+    }
+}
+
+/// Owns the data a [`FixedData`] created by a [`FixedDataBuilder`] refers to.
+pub struct FixedDataFixture<T: FieldElement> {
+    analyzed: Analyzed<T>,
+    fixed_values: Vec<(String, VariablySizedColumn<T>)>,
+    range_constraints: Vec<(String, RangeConstraint<T>)>,
+}
+
+impl<T: FieldElement> FixedDataFixture<T> {
+    pub fn fixed_data(&self) -> FixedData<'_, T> {
+        let fixed_data = FixedData::new(
+            &self.analyzed,
+            &self.fixed_values,
+            &[],
+            Default::default(),
+            0,
+        );
+        let mut constraints = GlobalConstraints {
+            witness_constraints: WitnessColumnMap::new(None, fixed_data.witness_cols.len()),
+            fixed_constraints: FixedColumnMap::new(None, fixed_data.fixed_cols.len()),
+            phantom_range_constraints: Default::default(),
+        };
+        for (name, rc) in &self.range_constraints {
+            let poly_id = fixed_data.try_column_by_name(name).unwrap();
+            let entry = match poly_id.ptype {
+                PolynomialType::Committed => &mut constraints.witness_constraints[&poly_id],
+                PolynomialType::Constant => &mut constraints.fixed_constraints[&poly_id],
+                PolynomialType::Intermediate => unreachable!(),
+            };
+            *entry = Some(rc.clone());
+        }
+        fixed_data.with_global_range_constraints(constraints)
+    }
+}
+
+/// Fixed data with the witness columns `X` and `Y` and the fixed column `F`,
+/// the columns referenced by [`algebraic_expression`] (apart from the
+/// undefined intermediate column `I`).
+pub fn expression_fixture() -> FixedDataFixture<GoldilocksField> {
+    FixedDataBuilder::new(4)
+        .witness("X")
+        .witness("Y")
+        .fixed("F", [1, 0, 0, 7].map(GoldilocksField::from).to_vec())
+        .build()
+}
+
+/// Generates algebraic expressions over the columns of [`expression_fixture`],
+/// including unusual ones like deeply nested expressions, next references
+/// and powers with huge exponents.
+pub fn algebraic_expression() -> impl Strategy<Value = Expression<GoldilocksField>> {
+    let reference = (0usize..4, any::<bool>()).prop_map(|(i, next)| {
+        let (name, id, ptype) = [
+            ("X", 0, PolynomialType::Committed),
+            ("Y", 1, PolynomialType::Committed),
+            ("F", 0, PolynomialType::Constant),
+            ("I", 0, PolynomialType::Intermediate),
+        ][i];
+        Expression::Reference(AlgebraicReference {
+            name: name.to_string(),
+            poly_id: PolyID { id, ptype },
+            next,
+        })
+    });
+    let number = prop_oneof![
+        (-4i64..=4).prop_map(GoldilocksField::from),
+        any::<u64>().prop_map(GoldilocksField::from),
+    ];
+    let leaf = prop_oneof![3 => reference, 1 => number.prop_map(Expression::Number)];
+    leaf.prop_recursive(8, 64, 2, |inner| {
+        let operator = prop::sample::select(vec![
+            AlgebraicBinaryOperator::Add,
+            AlgebraicBinaryOperator::Sub,
+            AlgebraicBinaryOperator::Mul,
+            AlgebraicBinaryOperator::Pow,
+        ]);
+        prop_oneof![
+            4 => (inner.clone(), operator, inner.clone())
+                .prop_map(|(left, op, right)| Expression::new_binary(left, op, right)),
+            1 => (inner.clone(), any::<u64>()).prop_map(|(base, exponent)| {
+                Expression::new_binary(
+                    base,
+                    AlgebraicBinaryOperator::Pow,
+                    Expression::Number(exponent.into()),
+                )
+            }),
+            1 => inner.prop_map(|e| Expression::new_unary(AlgebraicUnaryOperator::Minus, e)),
+        ]
+    })
+}
+
+/// Finds the cell with the given name among the cells referenced in the code.
+pub fn find_cell(code: &[Effect<GoldilocksField, Cell>], name: &str) -> Cell {
+    code.iter()
+        .filter_map(|effect| match effect {
+            Effect::Assignment(_, expr) => Some(expr),
+            _ => None,
+        })
+        .flat_map(|expr| expr.referenced_symbols())
+        .find(|cell| cell.to_string() == name)
+        .unwrap()
+        .clone()
+}
+
+/// A machine call answered by a [`MockCallHandler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedCall<T> {
+    pub identity_id: u64,
+    /// The values of the known arguments.
+    pub inputs: Vec<T>,
+    /// The values of the unknown arguments, or `None` if the call was not answered.
+    pub outputs: Option<Vec<T>>,
+}
+
+type MockAnswer<'a, T> = Box<dyn FnMut(u64, &[T]) -> Option<Vec<T>> + 'a>;
+
+/// A [`MachineCallHandler`] that answers machine calls from a closure or a table
+/// instead of an actual machine. The answers receive the values of the known
+/// arguments and return the values of the unknown arguments.
+///
+/// All calls are recorded and can be inspected via [`MockCallHandler::calls`].
+/// In strict mode, a call that cannot be answered fails the test, otherwise
+/// it is reported to the interpreter as a failed call.
+pub struct MockCallHandler<'a, T> {
+    answer: MockAnswer<'a, T>,
+    strict: bool,
+    calls: Vec<RecordedCall<T>>,
+}
+
+impl<'a, T: FieldElement> MockCallHandler<'a, T> {
+    pub fn from_fn(answer: impl FnMut(u64, &[T]) -> Option<Vec<T>> + 'a) -> Self {
+        Self {
+            answer: Box::new(answer),
+            strict: false,
+            calls: vec![],
+        }
+    }
+
+    /// Answers calls by looking up the identity id and the known arguments in the table.
+    pub fn from_table(table: impl IntoIterator<Item = ((u64, Vec<T>), Vec<T>)>) -> Self {
+        let table: HashMap<_, _> = table.into_iter().collect();
+        Self::from_fn(move |id, inputs| table.get(&(id, inputs.to_vec())).cloned())
+    }
+
+    /// Panics on calls that cannot be answered.
+    pub fn strict(self) -> Self {
+        Self {
+            strict: true,
+            ..self
+        }
+    }
+
+    /// Returns the calls performed so far, in order.
+    pub fn calls(&self) -> &[RecordedCall<T>] {
+        &self.calls
+    }
+}
+
+impl<T: FieldElement> MachineCallHandler<T> for MockCallHandler<'_, T> {
+    fn call(
+        &mut self,
+        identity_id: u64,
+        values: &mut [LookupCell<'_, T>],
+    ) -> Result<bool, EvalError<T>> {
+        let inputs = values
+            .iter()
+            .filter_map(|v| match v {
+                LookupCell::Input(v) => Some(**v),
+                LookupCell::Output(_) => None,
+            })
+            .collect_vec();
+        let outputs = (self.answer)(identity_id, &inputs);
+        if let Some(outputs) = &outputs {
+            let mut outputs = outputs.iter();
+            for value in values.iter_mut() {
+                if let LookupCell::Output(v) = value {
+                    **v = *outputs.next().expect("Too few output values.");
+                }
+            }
+            assert!(outputs.next().is_none(), "Too many output values.");
+        } else if self.strict {
+            panic!("Unexpected machine call to identity {identity_id} with inputs {inputs:?}.");
+        }
+        let answered = outputs.is_some();
+        self.calls.push(RecordedCall {
+            identity_id,
+            inputs,
+            outputs,
+        });
+        Ok(answered)
+    }
+}
+
+/// Compares `actual` with the snapshot file `test_data/witgen_jit/{name}.expected`
+/// and prints a diff on mismatch.
+/// If the environment variable `UPDATE_EXPECT` is set to `1`, the snapshot
+/// file is (re-)generated instead.
+pub fn assert_snapshot(name: &str, actual: &str) {
+    let path = PathBuf::from(format!("../test_data/witgen_jit/{name}.expected"));
+    let update = std::env::var("UPDATE_EXPECT").is_ok_and(|v| v == "1");
+    compare_with_snapshot(&path, actual, update);
+}
+
+fn compare_with_snapshot(path: &Path, actual: &str, update: bool) {
+    if update {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{actual}\n")).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(path).unwrap_or_else(|_| {
+        panic!(
+            "Snapshot {} not found. Run with UPDATE_EXPECT=1 to create it.",
+            path.display()
+        )
+    });
+    pretty_assertions::assert_eq!(
+        expected.strip_suffix('\n').unwrap_or(&expected),
+        actual,
+        "Snapshot {} does not match. Run with UPDATE_EXPECT=1 to update it.",
+        path.display()
+    );
+}
+
+fn evaluate_constant<T: FieldElement>(expr: &SymbolicExpression<T, &'static str>) -> T {
+    evaluate(expr, &|_| None).unwrap()
+}
+
+/// The names of the unknown variables in generated constraints.
+const VARIABLES: [&str; 4] = ["a", "b", "c", "d"];
+
+/// The set of values an unknown variable of a generated constraint can take.
+#[derive(Debug, Clone)]
+pub enum VariableDomain {
+    /// `bits` consecutive bits, starting at bit `shift`.
+    Mask { bits: u32, shift: u32 },
+    /// The (potentially wrapping) interval `[min, min + width]`.
+    Range { min: i64, width: u64 },
+}
+
+impl VariableDomain {
+    pub fn range_constraint<T: FieldElement>(&self) -> RangeConstraint<T> {
+        match self {
+            VariableDomain::Mask { bits, shift } => {
+                RangeConstraint::from_mask(((1u64 << bits) - 1) << shift)
+            }
+            VariableDomain::Range { min, width } => {
+                RangeConstraint::from_range(T::from(*min), T::from(*min) + T::from(*width))
+            }
+        }
+    }
+
+    /// Returns all values in the domain.
+    pub fn values<T: FieldElement>(&self) -> Vec<T> {
+        self.integer_values().into_iter().map(T::from).collect()
+    }
+
+    fn integer_values(&self) -> Vec<i64> {
+        match self {
+            VariableDomain::Mask { bits, shift } => {
+                (0..(1i64 << bits)).map(|v| v << shift).collect()
+            }
+            VariableDomain::Range { min, width } => (*min..=*min + *width as i64).collect(),
+        }
+    }
+}
+
+/// A constraint `sum_i coefficient_i * var_i + offset = 0` where all `var_i`
+/// are unknown and range-constrained.
+#[derive(Debug, Clone)]
+pub struct AffineTestCase {
+    /// The coefficient and domain of each variable.
+    pub summands: Vec<(i64, VariableDomain)>,
+    pub offset: i64,
+}
+
+impl AffineTestCase {
+    pub fn to_expression<T: FieldElement>(&self) -> AffineSymbolicExpression<T, &'static str> {
+        self.summands
+            .iter()
+            .zip_eq(&VARIABLES[..self.summands.len()])
+            .map(|((coeff, domain), var)| {
+                AffineSymbolicExpression::from_unknown_variable(
+                    *var,
+                    Some(domain.range_constraint()),
+                ) * &T::from(*coeff).into()
+            })
+            .fold(T::from(self.offset).into(), |acc, summand| acc + summand)
+    }
+
+    /// Returns all assignments to the variables that are inside the domains
+    /// and satisfy the constraint.
+    pub fn solutions<T: FieldElement>(&self) -> Vec<BTreeMap<&'static str, T>> {
+        self.summands
+            .iter()
+            .map(|(_, domain)| {
+                let rc = domain.range_constraint();
+                let values = domain.values::<T>();
+                assert!(values.iter().all(|v| rc.allows_value(*v)));
+                values
+            })
+            .multi_cartesian_product()
+            .filter(|values| {
+                let sum = self
+                    .summands
+                    .iter()
+                    .zip_eq(values)
+                    .map(|((coeff, _), v)| T::from(*coeff) * *v)
+                    .fold(T::from(self.offset), |acc, s| acc + s);
+                sum.is_zero()
+            })
+            .map(|values| VARIABLES.iter().copied().zip(values).collect())
+            .collect()
+    }
+}
+
+fn variable_domain() -> impl Strategy<Value = VariableDomain> {
+    prop_oneof![
+        (0u32..=3, 0u32..=16).prop_map(|(bits, shift)| VariableDomain::Mask { bits, shift }),
+        (-8i64..=64, 0u64..=7).prop_map(|(min, width)| VariableDomain::Range { min, width }),
+    ]
+}
+
+fn coefficient() -> impl Strategy<Value = i64> {
+    let magnitude = prop_oneof![(0u32..=20).prop_map(|e| 1i64 << e), 1i64..=5];
+    (magnitude, any::<bool>()).prop_map(|(m, negative)| if negative { -m } else { m })
+}
+
+/// Generates constraints over two to four variables. Most of them are derived
+/// from a value inside the domains so that they have at least one solution.
+pub fn affine_test_case() -> impl Strategy<Value = AffineTestCase> {
+    let summand = (
+        coefficient(),
+        variable_domain(),
+        any::<prop::sample::Index>(),
+    );
+    let offset_delta = prop_oneof![3 => Just(0i64), 1 => -3i64..=3];
+    (prop::collection::vec(summand, 2..=4), offset_delta).prop_map(|(summands, delta)| {
+        let offset = delta
+            - summands
+                .iter()
+                .map(|(coeff, domain, index)| coeff * index.get(&domain.integer_values()))
+                .sum::<i64>();
+        AffineTestCase {
+            summands: summands
+                .into_iter()
+                .map(|(coeff, domain, _)| (coeff, domain))
+                .collect(),
+            offset,
+        }
+    })
+}
+
+/// Calls `solve` on the constraint and compares the result with the
+/// solutions found by enumerating the domains of the variables.
+pub fn check_solve_against_brute_force<T: FieldElement>(
+    case: &AffineTestCase,
+) -> Result<(), TestCaseError> {
+    let solutions = case.solutions::<T>();
+    let result = match case.to_expression::<T>().solve() {
+        Ok(result) => result,
+        Err(_) => {
+            prop_assert!(
+                solutions.is_empty(),
+                "Solver reported a conflict, but there are solutions: {solutions:?}"
+            );
+            return Ok(());
+        }
+    };
+    let mut assigned = BTreeMap::new();
+    let mut assertions_hold = true;
+    for effect in &result.effects {
+        match effect {
+            Effect::Assignment(var, value) => {
+                let value = evaluate_constant(value);
+                for solution in &solutions {
+                    prop_assert_eq!(solution[var], value, "Wrong value for {}", var);
+                }
+                assigned.insert(*var, value);
+            }
+            Effect::RangeConstraint(var, rc) => {
+                for solution in &solutions {
+                    prop_assert!(
+                        rc.allows_value(solution[var]),
+                        "Range constraint {rc} on {var} excludes solution {solution:?}"
+                    );
+                }
+            }
+            Effect::Assertion(Assertion {
+                lhs,
+                rhs,
+                expected_equal,
+            }) => {
+                let holds = (evaluate_constant(lhs) == evaluate_constant(rhs)) == *expected_equal;
+                prop_assert!(
+                    holds || solutions.is_empty(),
+                    "Assertion fails even though there are solutions: {solutions:?}"
+                );
+                assertions_hold &= holds;
+            }
+            Effect::MachineCall(..) | Effect::StateWrite(..) => {
+                prop_assert!(false, "Unexpected machine call.")
+            }
+            Effect::RangeCheck(..) => prop_assert!(false, "Unexpected range check."),
+            Effect::Infeasible(_) => prop_assert!(false, "Unexpected infeasible code."),
+        }
+    }
+    if result.complete && assertions_hold {
+        // The assigned values have to satisfy the constraint.
+        prop_assert_eq!(assigned.len(), case.summands.len());
+        let sum = case
+            .summands
+            .iter()
+            .zip(VARIABLES)
+            .map(|((coeff, _), var)| T::from(*coeff) * assigned[var])
+            .fold(T::from(case.offset), |acc, s| acc + s);
+        prop_assert!(
+            sum.is_zero(),
+            "Assignment {assigned:?} violates the constraint."
+        );
+    }
+    Ok(())
+}
+
+/// A tracing subscriber that records the names of all created spans and the
+/// fields of all events, formatted as `name=value` and separated by spaces.
+#[derive(Default)]
+pub struct SpanCollector {
+    pub spans: Arc<Mutex<Vec<String>>>,
+    pub events: Arc<Mutex<Vec<String>>>,
+}
+
+/// Collects the fields of a tracing event.
+#[derive(Default)]
+struct EventFields(Vec<String>);
+
+impl tracing::field::Visit for EventFields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0.push(format!("{}={value:?}", field.name()));
+    }
+}
+
+impl tracing::Subscriber for SpanCollector {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(span.metadata().name().to_string());
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        self.events.lock().unwrap().push(fields.0.join(" "));
+    }
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+/// A global allocator that counts the allocations of each thread,
+/// see [`count_allocations`].
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Ignore allocations during thread teardown, when the counter is gone.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns its result together with the number of heap
+/// allocations performed by the current thread in the meantime.
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.with(|count| count.get());
+    let result = f();
+    (result, ALLOCATIONS.with(|count| count.get()) - before)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::witgen::jit::interpreter::{EffectInterpreter, InterpreterError};
+
+    use super::*;
+
+    type T = GoldilocksField;
+
+    fn temp_snapshot(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "powdr_witgen_jit_{}_{name}/snapshot.expected",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn snapshot_update_and_compare() {
+        let path = temp_snapshot("update_and_compare");
+        compare_with_snapshot(&path, "X[0] = 1;\nY[0] = 2;", true);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "X[0] = 1;\nY[0] = 2;\n");
+        compare_with_snapshot(&path, "X[0] = 1;\nY[0] = 2;", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not match")]
+    fn snapshot_mismatch() {
+        let path = temp_snapshot("mismatch");
+        compare_with_snapshot(&path, "X[0] = 1;", true);
+        compare_with_snapshot(&path, "X[0] = 2;", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "not found")]
+    fn snapshot_missing() {
+        compare_with_snapshot(&temp_snapshot("missing"), "X[0] = 1;", false);
+    }
+
+    fn run_xor(
+        handler: &mut MockCallHandler<'_, T>,
+    ) -> Result<HashMap<Cell, T>, InterpreterError<T, Cell>> {
+        let code = solve_xor(|witgen| witgen.code());
+        let inputs = [
+            (find_cell(&code, "Xor::A[7]"), T::from(0xaabbccddu64)),
+            (find_cell(&code, "Xor::C[7]"), T::from(0x12345678u64)),
+        ];
+        let result = EffectInterpreter::new(&code).run(inputs, handler)?;
+        Ok(result.values)
+    }
+
+    fn xor_table() -> impl Iterator<Item = ((u64, Vec<T>), Vec<T>)> {
+        [(0xaa, 0x12), (0xbb, 0x34), (0xcc, 0x56), (0xdd, 0x78)]
+            .into_iter()
+            .map(|(a, c): (u64, u64)| ((0, vec![a.into(), c.into()]), vec![(a ^ c).into()]))
+    }
+
+    #[test]
+    fn mock_xor_lookups() {
+        let mut handler = MockCallHandler::from_table(xor_table()).strict();
+        let values = run_xor(&mut handler).unwrap();
+        let b = values
+            .iter()
+            .find(|(cell, _)| cell.to_string() == "Xor::B[7]")
+            .unwrap()
+            .1;
+        assert_eq!(*b, T::from(0xaabbccddu64 ^ 0x12345678));
+        // The lookups are performed from the most significant byte downwards.
+        let expected_calls = xor_table()
+            .map(|((identity_id, inputs), outputs)| RecordedCall {
+                identity_id,
+                inputs,
+                outputs: Some(outputs),
+            })
+            .collect_vec();
+        assert_eq!(handler.calls(), expected_calls);
+    }
+
+    #[test]
+    fn mock_unanswered_call() {
+        let mut handler = MockCallHandler::from_table(xor_table().skip(1));
+        assert!(matches!(
+            run_xor(&mut handler),
+            Err(InterpreterError::MachineCallFailed(0))
+        ));
+        assert_eq!(handler.calls().len(), 1);
+        assert_eq!(handler.calls()[0].outputs, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected machine call to identity 0")]
+    fn mock_strict_unexpected_call() {
+        let mut handler = MockCallHandler::from_fn(|_, _| None).strict();
+        let _ = run_xor(&mut handler);
+    }
+
+    #[test]
+    fn fixed_data_builder_parity() {
+        let input = "
+        namespace T(4);
+            col fixed F = [1] + [0]*;
+            let a;
+            let b;
+        ";
+        let analyzed: Analyzed<T> = powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let from_pil = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+
+        let fixture = FixedDataBuilder::new(4)
+            .witness("T::a")
+            .witness("T::b")
+            .fixed("T::F", vec![1.into(), 0.into(), 0.into(), 0.into()])
+            .build();
+        let from_builder = fixture.fixed_data();
+
+        for name in ["T::a", "T::b", "T::F"] {
+            assert_eq!(
+                from_builder.try_column_by_name(name),
+                from_pil.try_column_by_name(name)
+            );
+        }
+        let witness_names = |fixed_data: &FixedData<'_, T>| {
+            fixed_data
+                .witness_cols
+                .values()
+                .map(|w| w.poly.name.clone())
+                .collect_vec()
+        };
+        assert_eq!(witness_names(&from_builder), witness_names(&from_pil));
+        let f = from_pil.try_column_by_name("T::F").unwrap();
+        assert_eq!(
+            from_builder.fixed_cols[&f].values_max_size(),
+            from_pil.fixed_cols[&f].values_max_size()
+        );
+    }
+
+    #[test]
+    fn fixed_data_builder_range_constraints() {
+        let fixture = FixedDataBuilder::<T>::new(4)
+            .witness("a")
+            .witness("b")
+            .fixed("F", vec![0.into(); 4])
+            .range_constraint("b", RangeConstraint::from_mask(0xffu64))
+            .range_constraint("F", RangeConstraint::from_mask(1u64))
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let constraints = fixed_data.global_range_constraints();
+        let b = fixed_data.try_column_by_name("b").unwrap();
+        let f = fixed_data.try_column_by_name("F").unwrap();
+        assert_eq!(
+            constraints
+                .witness_constraints
+                .values()
+                .cloned()
+                .collect_vec(),
+            vec![None, Some(RangeConstraint::from_mask(0xffu64))]
+        );
+        assert_eq!(
+            constraints.witness_constraints[&b],
+            Some(RangeConstraint::from_mask(0xffu64))
+        );
+        assert_eq!(
+            constraints.fixed_constraints[&f],
+            Some(RangeConstraint::from_mask(1u64))
+        );
+    }
+}
@@ -1,501 +1,6277 @@
 #![allow(unused)]
-use std::collections::{HashMap, HashSet};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+    ops::Range,
+    rc::Rc,
+};
 
 use itertools::Itertools;
 use powdr_ast::analyzed::{
     AlgebraicBinaryOperation, AlgebraicBinaryOperator, AlgebraicExpression as Expression,
-    AlgebraicReference, AlgebraicUnaryOperation, AlgebraicUnaryOperator, Identity, LookupIdentity,
-    PermutationIdentity, PhantomLookupIdentity, PhantomPermutationIdentity, PolyID,
-    PolynomialIdentity, PolynomialType, SelectedExpressions,
+    AlgebraicReference, AlgebraicUnaryOperation, AlgebraicUnaryOperator, Identity, IdentityKind,
+    LookupIdentity, PermutationIdentity, PhantomBusInteractionIdentity, PhantomLookupIdentity,
+    PhantomPermutationIdentity, PolyID, PolynomialIdentity, PolynomialType, SelectedExpressions,
+    SymbolKind,
 };
-use powdr_number::FieldElement;
+use powdr_ast::parsed::visitor::AllChildren;
+use powdr_number::{DegreeType, FieldElement, LargeInt};
+use serde::Serialize;
+use tracing::{debug_span, Span};
 
 use crate::witgen::{
     global_constraints::RangeConstraintSet, jit::affine_symbolic_expression::MachineCallArgument,
+    EvalError,
 };
 
 use super::{
     super::{range_constraints::RangeConstraint, FixedData},
-    affine_symbolic_expression::{AffineSymbolicExpression, Effect, ProcessResult},
+    affine_symbolic_expression::{AffineSymbolicExpression, Assertion, Effect, ProcessResult},
     cell::Cell,
+    error::WitgenJitError,
+    inference_trace::{InferenceTrace, TraceStep},
+    knowledge_snapshot::{pil_hash, KnowledgeSnapshot},
+    symbolic_expression::SymbolicExpression,
 };
 
 /// This component can generate code that solves identities.
 /// It needs a driver that tells it which identities to process on which rows.
-pub struct WitgenInference<'a, T: FieldElement, FixedEval: FixedEvaluator<T>> {
+/// The variables of the generated code are of type `V`, which by default are
+/// trace cells. The resolver given at construction determines the variable
+/// a column reference on a certain row refers to.
+pub struct WitgenInference<'a, T: FieldElement, FixedEval: FixedEvaluator<T>, V = Cell> {
     fixed_data: &'a FixedData<'a, T>,
     fixed_evaluator: FixedEval,
-    derived_range_constraints: HashMap<Cell, RangeConstraint<T>>,
-    known_cells: HashSet<Cell>,
-    code: Vec<Effect<T, Cell>>,
+    resolver: Resolver<'a, V>,
+    /// Global range constraints of the columns the variables refer to,
+    /// registered when the variables are first referenced.
+    global_range_constraints: HashMap<V, RangeConstraint<T>>,
+    derived_range_constraints: InternedRangeConstraints<T, V>,
+    /// Values derived for cells that violate the cells' range constraints.
+    conflicts: Vec<RangeConflict<T, V>>,
+    /// All identity-row pairs found to be not satisfiable, including
+    /// those leading to the conflicts in `conflicts`.
+    identity_conflicts: Vec<ConflictInfo>,
+    known_cells: HashSet<V>,
+    /// The cells that are fixed to a constant by range constraints alone.
+    constant_cells: HashMap<V, T>,
+    /// The identity-row pairs completed by `solve_block`, with the round they were completed in.
+    complete: HashMap<(u64, i32), usize>,
+    /// The fixed cells the code depends on, see `fixed_reads`.
+    fixed_reads: HashSet<(PolyID, usize)>,
+    /// The values returned by the fixed evaluator, per column and absolute row
+    /// (i.e. including the `next` shift), so that every fixed cell is only
+    /// evaluated once, no matter how many identities reference it.
+    fixed_values: RefCell<HashMap<(PolyID, i32), Option<T>>>,
+    /// How often `evaluate` failed because of each kind of unsupported expression.
+    unsupported_counts: RefCell<HashMap<UnsupportedKind, usize>>,
+    /// The first fixed cell (column name and row) the fixed evaluator could not provide
+    /// during the current call to `process_identity`, with `UnresolvedFixed::Error`.
+    unresolved_fixed: RefCell<Option<(String, i32)>>,
+    /// Whether the known values of a lookup into a fixed table determine the row,
+    /// per lookup id and known LHS expressions, see `table_determines_unknowns`.
+    determined_lookups: RefCell<HashMap<(u64, Vec<bool>), bool>>,
+    /// The range of each fixed column whose values form a contiguous range of
+    /// integers, or `None`, see `contiguous_range`.
+    contiguous_ranges: RefCell<HashMap<PolyID, Option<RangeConstraint<T>>>>,
+    code: Vec<Effect<T, V>>,
+    /// The current round of the driver, see `start_round`.
+    round: usize,
+    config: WitgenInferenceConfig,
+    /// The span of the current round, used as parent of the spans of the identities
+    /// processed in the round.
+    round_span: Span,
+    /// For each element of `code`, the round in which it was derived.
+    code_rounds: Vec<usize>,
+    /// The number of effects removed from `code` by `take_code`.
+    drained_code_len: usize,
+    /// The number of machine calls and state writes per identity id in all code
+    /// generated, including code removed by `take_code`, see `machine_call_summary`.
+    machine_call_counts: HashMap<u64, usize>,
+    /// The identity ids of the machine calls in all code generated, including code
+    /// removed by `take_code`, see `cacheable_calls`.
+    called_machines: HashSet<u64>,
+    progress: ProgressReport,
+    /// Called for every effect that is ingested, see `with_effect_logger`.
+    effect_logger: Option<EffectLogger<'a, T, V>>,
+    /// The cells that were known initially.
+    inputs: HashSet<V>,
+    /// Witness columns that are provided by the caller on all rows, see `mark_column_known`.
+    known_columns: HashSet<PolyID>,
+    /// The processed identity-row pairs, if recording is enabled, see `with_trace_recording`.
+    trace: Option<InferenceTrace>,
+    /// The direction polynomial identities are solved in, per identity id,
+    /// see `with_solve_direction`.
+    solve_directions: HashMap<u64, SolveDirection>,
+    /// The semantics of the machines answering lookups, per identity id,
+    /// see `register_machine`.
+    machine_semantics: HashMap<u64, MachineSemantics>,
+    /// The variables holding the values of the publics, by name of the public,
+    /// see `register_public`.
+    publics: HashMap<String, V>,
+    /// If this is a fork, the length of the code of the parent at the time of the fork,
+    /// see `fork`.
+    forked_at: Option<usize>,
+    /// The state of a paused call to `run_budgeted`.
+    paused_run: Option<PausedRun>,
+    /// If true, polynomial identities are also solved by dividing by coefficients
+    /// that might be zero, see `divide_by_runtime_coefficient`.
+    runtime_division: bool,
 }
 
-impl<'a, T: FieldElement, FixedEval: FixedEvaluator<T>> WitgenInference<'a, T, FixedEval> {
-    pub fn new(
-        fixed_data: &'a FixedData<'a, T>,
-        fixed_evaluator: FixedEval,
-        known_cells: impl IntoIterator<Item = Cell>,
-    ) -> Self {
+/// Determines the variable a column reference refers to, given the row offset
+/// the identity is processed on.
+pub type Resolver<'a, V> = Rc<dyn Fn(&AlgebraicReference, i32) -> V + 'a>;
+
+/// A callback that receives every ingested effect together with the
+/// identity id and row offset that produced it.
+pub type EffectLogger<'a, T, V = Cell> = Box<dyn FnMut(u64, i32, &Effect<T, V>) + 'a>;
+
+/// A value derived for a cell that is not allowed by the range constraint
+/// of the cell, i.e. the constraints are not satisfiable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeConflict<T: FieldElement, V = Cell> {
+    pub cell: V,
+    pub value: T,
+    pub range_constraint: RangeConstraint<T>,
+}
+
+/// Range constraints per variable. Many variables usually have the same
+/// range constraint (e.g. all bytes), so every distinct range constraint
+/// is only stored once and shared between the variables.
+#[derive(Clone)]
+struct InternedRangeConstraints<T: FieldElement, V> {
+    constraints: HashMap<V, Rc<RangeConstraint<T>>>,
+    interned: BTreeSet<Rc<RangeConstraint<T>>>,
+}
+
+impl<T: FieldElement, V> Default for InternedRangeConstraints<T, V> {
+    fn default() -> Self {
         Self {
-            fixed_data,
-            fixed_evaluator,
-            derived_range_constraints: Default::default(),
-            known_cells: known_cells.into_iter().collect(),
-            code: Default::default(),
+            constraints: Default::default(),
+            interned: Default::default(),
         }
     }
+}
 
-    pub fn code(self) -> Vec<Effect<T, Cell>> {
-        self.code
+impl<T: FieldElement, V: Hash + Eq> InternedRangeConstraints<T, V> {
+    fn get(&self, var: &V) -> Option<&RangeConstraint<T>> {
+        self.constraints.get(var).map(|rc| rc.as_ref())
     }
 
-    /// Process an identity on a certain row.
-    /// Returns true if this identity/row pair was fully processed and
-    /// should not be considered again.
-    pub fn process_identity(&mut self, id: &Identity<T>, row_offset: i32) -> bool {
-        let result = match id {
-            Identity::Polynomial(PolynomialIdentity { expression, .. }) => {
-                self.process_polynomial_identity(expression, row_offset)
-            }
-            Identity::Lookup(LookupIdentity {
-                id, left, right, ..
-            })
-            | Identity::Permutation(PermutationIdentity {
-                id, left, right, ..
-            })
-            | Identity::PhantomPermutation(PhantomPermutationIdentity {
-                id, left, right, ..
-            })
-            | Identity::PhantomLookup(PhantomLookupIdentity {
-                id, left, right, ..
-            }) => self.process_lookup(*id, left, right, row_offset),
-            Identity::PhantomBusInteraction(_) => {
-                // TODO(bus_interaction) Once we have a concept of "can_be_answered", bus interactions
-                // should be as easy as lookups.
-                ProcessResult::empty()
+    fn insert(&mut self, var: V, rc: RangeConstraint<T>) {
+        let rc = match self.interned.get(&rc) {
+            Some(interned) => interned.clone(),
+            None => {
+                let rc = Rc::new(rc);
+                self.interned.insert(rc.clone());
+                rc
             }
-            Identity::Connect(_) => ProcessResult::empty(),
         };
-        self.ingest_effects(result.effects);
-        result.complete
+        self.constraints.insert(var, rc);
     }
 
-    fn process_polynomial_identity(
-        &self,
-        expression: &'a Expression<T>,
-        offset: i32,
-    ) -> ProcessResult<T, Cell> {
-        if let Some(r) = self.evaluate(expression, offset) {
-            // TODO propagate or report error properly.
-            // If solve returns an error, it means that the constraint is conflicting.
-            // In the future, we might run this in a runtime-conditional, so an error
-            // could just mean that this case cannot happen in practice.
-            r.solve().unwrap()
+    fn len(&self) -> usize {
+        self.constraints.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&V, &RangeConstraint<T>)> {
+        self.constraints.iter().map(|(var, rc)| (var, rc.as_ref()))
+    }
+}
+
+/// Limits the work done by a single call to `WitgenInference::run_budgeted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    /// Never pauses.
+    Unlimited,
+    /// Pauses after processing the given number of identity-row pairs.
+    Attempts(usize),
+    /// Pauses as soon as at least the given number of effects were emitted.
+    Effects(usize),
+}
+
+/// The result of `WitgenInference::run_budgeted`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SolveProgress<T: FieldElement, V = Cell> {
+    /// No more progress can be made.
+    Done,
+    /// The budget was used up, the run can be resumed by calling
+    /// `run_budgeted` again with the same identities and rows.
+    Paused,
+    Failed(WitgenJitError<T, V>),
+}
+
+/// The state of a paused call to `WitgenInference::run_budgeted`.
+#[derive(Debug, Clone, Default)]
+struct PausedRun {
+    /// Identity-row pairs that cannot be completed and are not processed again.
+    dropped: HashSet<(u64, i32)>,
+    /// The number of rounds started.
+    rounds: usize,
+    /// The index of the next identity-row pair to process in the current round.
+    position: usize,
+    /// The state at the start of the current round, see `solve_state`.
+    state_before: (usize, usize, usize),
+}
+
+/// An identity that was found to be not satisfiable on a certain row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictInfo {
+    pub identity_id: u64,
+    pub row_offset: i32,
+    pub message: String,
+}
+
+/// The reason why a cell is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provenance {
+    /// The cell was known initially, i.e. it is provided by the caller of the generated code.
+    Input,
+    /// The cell is determined by the generated code or is a constant.
+    Derived,
+}
+
+/// The direction in which an identity referencing two consecutive rows is solved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolveDirection {
+    /// Only cells on the next row are determined.
+    Forward,
+    /// Only cells on the current row are determined.
+    Reverse,
+}
+
+/// What is known about the machine that answers the lookup with a certain id,
+/// see `WitgenInference::register_machine`. It determines if the lookup can
+/// be resolved by a machine call in the generated code or has to be deferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineSemantics {
+    /// A pure function of the inputs, e.g. given by a fixed table, so every
+    /// query with all but one argument known can be answered.
+    PureFixedTable,
+    /// The answer depends on the state of the machine, e.g. a memory,
+    /// so the lookup is deferred, i.e. it is not resolved by the generated code
+    /// and reported as unsupported.
+    Stateful,
+}
+
+/// The direction of a bus interaction, determined by the sign of its multiplicity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusDirection {
+    /// The payload is produced by this machine and sent to the bus.
+    Send,
+    /// The payload is consumed from the bus, i.e. it is provided by the sender.
+    Receive,
+}
+
+impl BusDirection {
+    /// Returns `Receive` if the multiplicity is syntactically negative, like
+    /// `-1 * latch` as created by `std::protocols::bus::bus_receive`, and `Send` otherwise.
+    pub fn of<T: FieldElement>(multiplicity: &Expression<T>) -> Self {
+        if is_negative(multiplicity) {
+            BusDirection::Receive
         } else {
-            ProcessResult::empty()
+            BusDirection::Send
         }
     }
+}
 
-    fn process_lookup(
-        &self,
-        lookup_id: u64,
-        left: &SelectedExpressions<T>,
-        right: &SelectedExpressions<T>,
-        offset: i32,
-    ) -> ProcessResult<T, Cell> {
-        // TODO: In the future, call the 'mutable state' to check if the
-        // lookup can always be answered.
+/// The outcome of processing an identity on a row, see `WitgenInference::process_identity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessStatus {
+    /// The identity-row pair was fully processed and does not need to be considered again.
+    Complete,
+    /// New facts were derived, but the identity-row pair is not complete.
+    Progress,
+    /// Nothing was derived, but more knowledge might allow progress.
+    NoProgress,
+    /// The identity can never be completed by the inference, for the given reason.
+    Unsupported(String),
+    /// The identity is not satisfiable on this row.
+    Error(ConflictInfo),
+    /// The fixed evaluator has no value for the fixed column on the given row
+    /// (including the `next` shift), see `UnresolvedFixed::Error`.
+    UnresolvedFixed { column: String, row: i32 },
+}
 
-        // If the RHS is fully fixed columns...
-        if right.expressions.iter().all(|e| match e {
-            Expression::Reference(r) => r.is_fixed(),
-            Expression::Number(_) => true,
-            _ => false,
-        }) {
-            // and the selector is known to be 1...
-            if self
-                .evaluate(&left.selector, offset)
-                .and_then(|s| s.try_to_known().map(|k| k.is_known_one()))
-                == Some(true)
-            {
-                if let Some(lhs) = left
-                    .expressions
-                    .iter()
-                    .map(|e| self.evaluate(e, offset))
-                    .collect::<Option<Vec<_>>>()
-                {
-                    // and all except one expression is known on the LHS.
-                    let unknown = lhs
-                        .iter()
-                        .filter(|e| e.try_to_known().is_none())
-                        .collect_vec();
-                    if unknown.len() == 1 && unknown[0].single_unknown_variable().is_some() {
-                        let effects = vec![Effect::MachineCall(
-                            lookup_id,
-                            lhs.into_iter()
-                                .map(|e| {
-                                    if let Some(val) = e.try_to_known() {
-                                        MachineCallArgument::Known(val.clone())
-                                    } else {
-                                        MachineCallArgument::Unknown(e)
-                                    }
-                                })
-                                .collect(),
-                        )];
-                        return ProcessResult::complete(effects);
-                    }
-                }
+/// Whether the inference can handle an identity, determined once before solving,
+/// see `WitgenInference::classify`. The classification is conservative, i.e. an
+/// identity is only `Unsupported` if `process_identity` can never complete it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentitySupport {
+    /// The identity can be completed once enough cells are known.
+    Supported,
+    /// The identity can only be completed in special cases, e.g. a lookup into
+    /// a non-fixed table whose selector is known to be zero.
+    ConditionallySupported,
+    /// The identity can never be completed, for the given reason.
+    Unsupported(String),
+}
+
+impl ProcessStatus {
+    pub fn is_complete(&self) -> bool {
+        matches!(self, ProcessStatus::Complete)
+    }
+
+    /// Returns the error for the statuses `Unsupported`, `Error` and `UnresolvedFixed`
+    /// of the given identity.
+    pub fn into_error<T: FieldElement, V>(self, identity_id: u64) -> Option<WitgenJitError<T, V>> {
+        match self {
+            ProcessStatus::Unsupported(reason) => Some(WitgenJitError::UnsupportedIdentity {
+                identity_id,
+                reason,
+            }),
+            ProcessStatus::Error(conflict) => Some(WitgenJitError::Conflict(conflict)),
+            ProcessStatus::UnresolvedFixed { column, row } => {
+                Some(WitgenJitError::UnresolvedFixed {
+                    identity_id,
+                    column,
+                    row,
+                })
             }
+            ProcessStatus::Complete | ProcessStatus::Progress | ProcessStatus::NoProgress => None,
         }
-        ProcessResult::empty()
     }
+}
 
-    fn ingest_effects(&mut self, effects: Vec<Effect<T, Cell>>) {
-        for e in effects {
-            match &e {
-                Effect::Assignment(cell, assignment) => {
-                    self.known_cells.insert(cell.clone());
-                    if let Some(rc) = assignment.range_constraint() {
-                        // If the cell was determined to be a constant, we add this
-                        // as a range constraint, so we can use it in future evaluations.
-                        self.add_range_constraint(cell.clone(), rc);
-                    }
-                    self.code.push(e);
-                }
-                Effect::RangeConstraint(cell, rc) => {
-                    self.add_range_constraint(cell.clone(), rc.clone());
-                }
-                Effect::MachineCall(_, arguments) => {
-                    for arg in arguments {
-                        if let MachineCallArgument::Unknown(expr) = arg {
-                            let cell = expr.single_unknown_variable().unwrap();
-                            self.known_cells.insert(cell.clone());
-                        }
-                    }
-                    self.code.push(e);
-                }
-                Effect::Assertion(_) => self.code.push(e),
-            }
+/// What to do if a polynomial identity is found to be not satisfiable,
+/// see `WitgenInferenceConfig::with_conflict_policy`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The identity stays incomplete and the conflict is reported, i.e.
+    /// `solve_block` returns `WitgenJitError::Conflict`.
+    #[default]
+    Error,
+    /// The conflict means that the code is never reached when the constraints
+    /// are satisfiable, e.g. because it is a speculative branch. The identity
+    /// is completed with an `Effect::Infeasible`.
+    Speculative,
+}
+
+/// What to do if the fixed evaluator cannot provide the value of a fixed column
+/// reference, see `WitgenInferenceConfig::with_unresolved_fixed`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnresolvedFixed {
+    /// The identity is not processed on this row.
+    Skip,
+    /// The reference is turned into a variable using the resolver and its value
+    /// is only known at runtime. The resolver has to support fixed column references.
+    Symbolic,
+    /// The identity is not processed and `process_identity` returns
+    /// `ProcessStatus::UnresolvedFixed`, which makes `solve_block` fail,
+    /// since the fixed evaluator is most likely misconfigured.
+    #[default]
+    Error,
+}
+
+/// How to evaluate `0**0` in polynomial identities,
+/// see `WitgenInferenceConfig::with_pow_zero_zero`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PowZeroZero {
+    /// `0**0` evaluates to one, as for any other base.
+    #[default]
+    One,
+    /// `0**0` cannot be evaluated, i.e. the identity is not processed on this row.
+    Error,
+}
+
+/// What to do with identities with next-references that are processed on the
+/// last row of the trace, see `WitgenInferenceConfig::with_last_row_policy`.
+/// The row offsets are taken as absolute rows of a trace with the degree of
+/// the fixed evaluator or, if unknown, the maximal degree of the witness columns.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LastRowPolicy {
+    /// The degree is ignored, i.e. next-references on the last row refer to
+    /// the row after the last row.
+    #[default]
+    Unbounded,
+    /// The identity is completed on the last row without processing it.
+    /// Note that the constraint checker evaluates next-references on the last
+    /// row against the first row, so this is only correct if the identity is
+    /// satisfied there for other reasons, e.g. a selector that is zero on the last row.
+    Skip,
+    /// Next-references on the last row refer to the first row, as in the
+    /// constraint checker.
+    Wrap,
+}
+
+/// Options of [`WitgenInference`]. The default matches the behaviour without
+/// any options set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WitgenInferenceConfig {
+    redundant_checks: bool,
+    equality_checks: bool,
+    input_range_checks: bool,
+    round_limit: usize,
+    max_decomposition_limbs: Option<usize>,
+    /// Ordered, so that the cache description is deterministic, see `cache_description`.
+    column_priorities: BTreeMap<PolyID, u32>,
+    unresolved_fixed: UnresolvedFixed,
+    conflict_policy: ConflictPolicy,
+    pow_zero_zero: PowZeroZero,
+    last_row_policy: LastRowPolicy,
+    max_inlined_table_rows: Option<usize>,
+    stage: Option<u8>,
+}
+
+impl Default for WitgenInferenceConfig {
+    fn default() -> Self {
+        Self {
+            redundant_checks: false,
+            equality_checks: true,
+            input_range_checks: false,
+            round_limit: 1000,
+            max_decomposition_limbs: None,
+            column_priorities: Default::default(),
+            unresolved_fixed: Default::default(),
+            conflict_policy: Default::default(),
+            pow_zero_zero: Default::default(),
+            last_row_policy: Default::default(),
+            max_inlined_table_rows: None,
+            stage: None,
         }
     }
+}
 
-    fn add_range_constraint(&mut self, cell: Cell, rc: RangeConstraint<T>) {
-        let rc = self
-            .range_constraint(cell.clone())
-            .map_or(rc.clone(), |existing_rc| existing_rc.conjunction(&rc));
-        if !self.known_cells.contains(&cell) {
-            if let Some(v) = rc.try_to_single_value() {
-                // Special case: Cell is fixed to a constant by range constraints only.
-                self.known_cells.insert(cell.clone());
-                self.code.push(Effect::Assignment(cell.clone(), v.into()));
-            }
+impl WitgenInferenceConfig {
+    /// Emits an assertion for every polynomial identity where all cells are
+    /// already known, instead of dropping it. If the identity determines one of
+    /// the input cells, the assertion compares the input to the derived value.
+    pub fn with_redundant_checks(self) -> Self {
+        Self {
+            redundant_checks: true,
+            ..self
         }
-        self.derived_range_constraints.insert(cell.clone(), rc);
     }
 
-    fn evaluate(
-        &self,
-        expr: &Expression<T>,
-        offset: i32,
-    ) -> Option<AffineSymbolicExpression<T, Cell>> {
-        Some(match expr {
-            Expression::Reference(r) => {
-                if r.is_fixed() {
-                    self.fixed_evaluator.evaluate(r, offset)?.into()
-                } else {
-                    let cell = Cell::from_reference(r, offset);
-                    // If a cell is known and has a compile-time constant value,
-                    // that value is stored in the range constraints.
-                    let rc = self.range_constraint(cell.clone());
-                    if let Some(val) = rc.as_ref().and_then(|rc| rc.try_to_single_value()) {
-                        val.into()
-                    } else if self.known_cells.contains(&cell) {
-                        AffineSymbolicExpression::from_known_symbol(cell, rc)
-                    } else {
-                        AffineSymbolicExpression::from_unknown_variable(cell, rc)
-                    }
-                }
-            }
-            Expression::PublicReference(_) | Expression::Challenge(_) => {
-                // TODO we need to introduce a variable type for those.
-                return None;
-            }
-            Expression::Number(n) => (*n).into(),
-            Expression::BinaryOperation(op) => self.evaluate_binary_operation(op, offset)?,
-            Expression::UnaryOperation(op) => self.evaluate_unary_operation(op, offset)?,
-        })
+    /// Determines if an equality assertion is emitted for a polynomial identity
+    /// of the form `a = b` where both sides are known at run time, but not at
+    /// compile time, e.g. two input cells. This way, the generated code validates
+    /// its inputs. Enabled by default, superseded by `with_redundant_checks`.
+    pub fn with_equality_checks(self, equality_checks: bool) -> Self {
+        Self {
+            equality_checks,
+            ..self
+        }
     }
 
-    fn evaluate_binary_operation(
-        &self,
-        op: &AlgebraicBinaryOperation<T>,
-        offset: i32,
-    ) -> Option<AffineSymbolicExpression<T, Cell>> {
-        let left = self.evaluate(&op.left, offset)?;
-        let right = self.evaluate(&op.right, offset)?;
-        match op.op {
-            AlgebraicBinaryOperator::Add => Some(&left + &right),
-            AlgebraicBinaryOperator::Sub => Some(&left - &right),
-            AlgebraicBinaryOperator::Mul => left.try_mul(&right),
-            AlgebraicBinaryOperator::Pow => {
-                let result = left
-                    .try_to_known()?
-                    .try_to_number()?
-                    .pow(right.try_to_known()?.try_to_number()?.to_integer());
-                Some(AffineSymbolicExpression::from(result))
-            }
+    /// Emits a range check for every range constraint derived for an input cell,
+    /// e.g. from the decomposition of an input into bytes. Without this, the
+    /// generated code assumes that the inputs satisfy these constraints.
+    /// Constraints on the other cells are not checked, since they hold by construction.
+    pub fn with_input_range_checks(self) -> Self {
+        Self {
+            input_range_checks: true,
+            ..self
         }
     }
 
-    fn evaluate_unary_operation(
-        &self,
-        op: &AlgebraicUnaryOperation<T>,
-        offset: i32,
-    ) -> Option<AffineSymbolicExpression<T, Cell>> {
-        let expr = self.evaluate(&op.expr, offset)?;
-        match op.op {
-            AlgebraicUnaryOperator::Minus => Some(-&expr),
+    /// Sets the maximum number of rounds `solve_block` runs before it
+    /// returns `WitgenJitError::BudgetExceeded`.
+    pub fn with_round_limit(self, round_limit: usize) -> Self {
+        Self {
+            round_limit,
+            ..self
         }
     }
 
-    /// Returns the current best-known range constraint on the given cell
-    /// combining global range constraints and newly derived local range constraints.
-    fn range_constraint(&self, cell: Cell) -> Option<RangeConstraint<T>> {
-        self.fixed_data
-            .global_range_constraints
-            .range_constraint(&AlgebraicReference {
-                name: Default::default(),
-                poly_id: PolyID {
-                    id: cell.id,
-                    ptype: PolynomialType::Committed,
-                },
-                next: false,
-            })
-            .iter()
-            .chain(self.derived_range_constraints.get(&cell))
-            .cloned()
-            .reduce(|gc, rc| gc.conjunction(&rc))
+    /// Only solves bit decompositions into at most `max_limbs` variables.
+    pub fn with_max_decomposition_limbs(self, max_limbs: usize) -> Self {
+        Self {
+            max_decomposition_limbs: Some(max_limbs),
+            ..self
+        }
     }
-}
 
-pub trait FixedEvaluator<T: FieldElement> {
-    fn evaluate(&self, _var: &AlgebraicReference, _row_offset: i32) -> Option<T> {
-        None
+    /// Sets the priorities of columns for `WitgenInference::order_by_priority`.
+    /// Columns without a priority have priority zero.
+    pub fn with_column_priorities(self, column_priorities: HashMap<PolyID, u32>) -> Self {
+        Self {
+            column_priorities: column_priorities.into_iter().collect(),
+            ..self
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
+    /// Sets what to do if a fixed column reference cannot be evaluated.
+    /// The default is `UnresolvedFixed::Error`.
+    pub fn with_unresolved_fixed(self, unresolved_fixed: UnresolvedFixed) -> Self {
+        Self {
+            unresolved_fixed,
+            ..self
+        }
+    }
 
-    use pretty_assertions::assert_eq;
+    /// Sets what to do if a polynomial identity is not satisfiable.
+    /// The default is `ConflictPolicy::Error`.
+    pub fn with_conflict_policy(self, conflict_policy: ConflictPolicy) -> Self {
+        Self {
+            conflict_policy,
+            ..self
+        }
+    }
 
-    use powdr_ast::analyzed::Analyzed;
-    use powdr_number::GoldilocksField;
+    /// Sets how to evaluate `0**0`. The default is `PowZeroZero::One`.
+    pub fn with_pow_zero_zero(self, pow_zero_zero: PowZeroZero) -> Self {
+        Self {
+            pow_zero_zero,
+            ..self
+        }
+    }
 
-    use crate::{
-        constant_evaluator,
-        witgen::{global_constraints, jit::affine_symbolic_expression::Assertion, FixedData},
-    };
+    /// Sets what to do with identities with next-references on the last row.
+    /// The default is `LastRowPolicy::Unbounded`.
+    pub fn with_last_row_policy(self, last_row_policy: LastRowPolicy) -> Self {
+        Self {
+            last_row_policy,
+            ..self
+        }
+    }
 
-    use super::*;
+    /// Inlines lookups into fixed tables with at most `max_rows` rows, where one
+    /// value is known and one is computed from it (e.g. an instruction decoder):
+    /// instead of a machine call, the computed value is selected from the table
+    /// in the generated code. The lookup still results in a state write of all
+    /// values, so that the fixed lookup machine checks the tuple and records
+    /// the multiplicity.
+    pub fn with_inlined_fixed_tables(self, max_rows: usize) -> Self {
+        Self {
+            max_inlined_table_rows: Some(max_rows),
+            ..self
+        }
+    }
 
-    fn format_code(effects: &[Effect<GoldilocksField, Cell>]) -> String {
-        effects
+    /// Sets the proof stage the code is generated for. Witness columns of later
+    /// stages cannot be solved and identities referencing them or challenges that
+    /// are not yet available are unsupported. Challenges of earlier stages
+    /// evaluate to their values in the `FixedData`.
+    /// The default is the stage of the `FixedData`.
+    pub fn with_stage(self, stage: u8) -> Self {
+        Self {
+            stage: Some(stage),
+            ..self
+        }
+    }
+
+    /// Returns a description of all options that does not depend on the `Debug`
+    /// representation, so that it only changes if an option changes, see `cache_key`.
+    pub fn cache_description(&self) -> String {
+        let Self {
+            redundant_checks,
+            equality_checks,
+            input_range_checks,
+            round_limit,
+            max_decomposition_limbs,
+            column_priorities,
+            unresolved_fixed,
+            conflict_policy,
+            pow_zero_zero,
+            last_row_policy,
+            max_inlined_table_rows,
+            stage,
+        } = self;
+        let optional = |value: Option<usize>| value.map_or("none".to_string(), |v| v.to_string());
+        let column_priorities = column_priorities
             .iter()
-            .map(|effect| match effect {
-                Effect::Assignment(v, expr) => format!("{v} = {expr};"),
-                Effect::Assertion(Assertion {
-                    lhs,
-                    rhs,
-                    expected_equal,
-                }) => {
-                    format!(
-                        "assert {lhs} {} {rhs};",
-                        if *expected_equal { "==" } else { "!=" }
-                    )
-                }
-                Effect::MachineCall(id, args) => {
-                    format!(
-                        "lookup({id}, [{}]);",
-                        args.iter()
-                            .map(|arg| match arg {
-                                MachineCallArgument::Known(k) => format!("Known({k})"),
-                                MachineCallArgument::Unknown(u) => format!("Unknown({u})"),
-                            })
-                            .join(", ")
-                    )
-                }
-                Effect::RangeConstraint(..) => {
-                    panic!("Range constraints should not be part of the code.")
-                }
+            .map(|(poly_id, priority)| {
+                let ptype = match poly_id.ptype {
+                    PolynomialType::Committed => "witness",
+                    PolynomialType::Constant => "fixed",
+                    PolynomialType::Intermediate => "intermediate",
+                };
+                format!("{ptype}:{}={priority}", poly_id.id)
             })
-            .join("\n")
+            .join(",");
+        let unresolved_fixed = match unresolved_fixed {
+            UnresolvedFixed::Skip => "skip",
+            UnresolvedFixed::Symbolic => "symbolic",
+            UnresolvedFixed::Error => "error",
+        };
+        let conflict_policy = match conflict_policy {
+            ConflictPolicy::Error => "error",
+            ConflictPolicy::Speculative => "speculative",
+        };
+        let pow_zero_zero = match pow_zero_zero {
+            PowZeroZero::One => "one",
+            PowZeroZero::Error => "error",
+        };
+        let last_row_policy = match last_row_policy {
+            LastRowPolicy::Unbounded => "unbounded",
+            LastRowPolicy::Skip => "skip",
+            LastRowPolicy::Wrap => "wrap",
+        };
+        [
+            format!("redundant_checks={redundant_checks}"),
+            format!("equality_checks={equality_checks}"),
+            format!("input_range_checks={input_range_checks}"),
+            format!("round_limit={round_limit}"),
+            format!(
+                "max_decomposition_limbs={}",
+                optional(*max_decomposition_limbs)
+            ),
+            format!("column_priorities={column_priorities}"),
+            format!("unresolved_fixed={unresolved_fixed}"),
+            format!("conflict_policy={conflict_policy}"),
+            format!("pow_zero_zero={pow_zero_zero}"),
+            format!("last_row_policy={last_row_policy}"),
+            format!(
+                "max_inlined_table_rows={}",
+                optional(*max_inlined_table_rows)
+            ),
+            format!("stage={}", optional(stage.map(usize::from))),
+        ]
+        .join(" ")
     }
+}
 
-    struct FixedEvaluatorForFixedData<'a>(&'a FixedData<'a, GoldilocksField>);
-    impl<'a> FixedEvaluator<GoldilocksField> for FixedEvaluatorForFixedData<'a> {
-        fn evaluate(&self, var: &AlgebraicReference, row_offset: i32) -> Option<GoldilocksField> {
-            assert!(var.is_fixed());
-            let values = self.0.fixed_cols[&var.poly_id].values_max_size();
-            let row = (row_offset as usize + var.next as usize) % values.len();
-            Some(values[row])
+/// Code that solves a single block of a block machine.
+/// The row offsets of all cells are relative to the first row of the block,
+/// so the same code can be used for every block after shifting the rows by
+/// the start of the block (see `block_start`).
+pub struct BlockPlan<T: FieldElement, V = Cell> {
+    pub block_size: usize,
+    /// The cells that have to be provided by the caller, in ascending order.
+    /// All other cells used by the code are assigned by the code itself.
+    pub inputs: Vec<V>,
+    pub code: Vec<Effect<T, V>>,
+}
+
+impl<T: FieldElement, V> BlockPlan<T, V> {
+    /// Returns the first row of the block with the given index.
+    pub fn block_start(&self, block_index: usize) -> usize {
+        block_index * self.block_size
+    }
+}
+
+/// A kind of expression that `evaluate` cannot handle, see `WitgenInference::unsupported_report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum UnsupportedKind {
+    /// A fixed column the fixed evaluator has no value for, with `UnresolvedFixed::Skip`.
+    UnresolvedFixedColumn,
+    /// A reference to an intermediate column, which are not inlined yet.
+    IntermediateColumn,
+    /// A witness column of a later stage than the code is generated for.
+    LaterStageColumn,
+    /// A public that is not registered.
+    PublicReference,
+    /// A challenge that is not available in the current stage.
+    Challenge,
+    /// An exponentiation where the base or the exponent is not a number.
+    NonConstantPower,
+}
+
+/// Summary of the progress made in each round of the driver.
+/// The entry at index `i` corresponds to round `i + 1`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProgressReport {
+    /// The number of cells that became known in each round.
+    pub cells_solved_per_round: Vec<usize>,
+    /// The number of identity-row pairs that were completed in each round.
+    pub identities_completed_per_round: Vec<usize>,
+    /// The identities that were excluded from solving up front since they can
+    /// never be completed, with the reason, see `WitgenInference::supported_identities`.
+    pub unsupported_identities: BTreeMap<u64, String>,
+}
+
+/// The state of an identity-row pair after a run of the driver, see `IdentityReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityStatus {
+    /// The pair was completed in the given round.
+    Completed { round: usize },
+    /// The pair is not complete and waits for the given cells to become known.
+    NoProgress { waiting_on: Vec<Cell> },
+    /// The identity can never be completed, for the given reason.
+    Unsupported(String),
+}
+
+impl Display for IdentityStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityStatus::Completed { round } => write!(f, "completed in round {round}"),
+            IdentityStatus::NoProgress { waiting_on } => write!(
+                f,
+                "waiting on {}",
+                waiting_on
+                    .iter()
+                    .map(|c| &c.column_name)
+                    .unique()
+                    .join(", ")
+            ),
+            IdentityStatus::Unsupported(reason) => write!(f, "unsupported: {reason}"),
         }
     }
+}
 
-    fn solve_on_rows(
-        input: &str,
-        rows: &[i32],
-        known_cells: Vec<(&str, i32)>,
-        expected_complete: Option<usize>,
-    ) -> String {
-        let analyzed: Analyzed<GoldilocksField> =
-            powdr_pil_analyzer::analyze_string(input).unwrap();
-        let fixed_col_vals = constant_evaluator::generate(&analyzed);
-        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
-        let (fixed_data, retained_identities) =
-            global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
-        let known_cells = known_cells.iter().map(|(name, row_offset)| {
-            let id = fixed_data.try_column_by_name(name).unwrap().id;
-            Cell {
-                column_name: name.to_string(),
-                id,
-                row_offset: *row_offset,
-            }
-        });
+/// The status of every identity-row pair after a run of the driver,
+/// see `WitgenInference::identity_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdentityReport {
+    pub statuses: BTreeMap<(u64, i32), IdentityStatus>,
+}
 
-        let ref_eval = FixedEvaluatorForFixedData(&fixed_data);
-        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, known_cells);
-        let mut complete = HashSet::new();
-        let mut counter = 0;
-        let expected_complete = expected_complete.unwrap_or(retained_identities.len() * rows.len());
-        while complete.len() != expected_complete {
-            counter += 1;
-            for row in rows {
-                for id in retained_identities.iter() {
-                    if !complete.contains(&(id.id(), *row)) && witgen.process_identity(id, *row) {
-                        complete.insert((id.id(), *row));
+/// Groups the statuses by identity and merges consecutive rows with the same
+/// description (which only mentions the columns of the cells), e.g.
+/// `rows 1..=254: waiting on A_byte`.
+impl Display for IdentityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for identity_id in self.statuses.keys().map(|(id, _)| *id).unique() {
+            writeln!(f, "Identity {identity_id}:")?;
+            for (first, last, description) in self
+                .statuses
+                .range((identity_id, i32::MIN)..=(identity_id, i32::MAX))
+                .map(|((_, row), status)| (*row, *row, status.to_string()))
+                .coalesce(|(first, last, a), (row, _, b)| {
+                    if row == last + 1 && a == b {
+                        Ok((first, row, a))
+                    } else {
+                        Err(((first, last, a), (row, row, b)))
                     }
+                })
+            {
+                if first == last {
+                    writeln!(f, "    row {first}: {description}")?;
+                } else {
+                    writeln!(f, "    rows {first}..={last}: {description}")?;
                 }
             }
-            assert!(counter < 10000, "Solving took more than 10000 rounds.");
         }
-        format_code(&witgen.code())
+        Ok(())
+    }
+}
+
+impl<'a, T: FieldElement, FixedEval: FixedEvaluator<T>> WitgenInference<'a, T, FixedEval> {
+    pub fn new(
+        fixed_data: &'a FixedData<'a, T>,
+        fixed_evaluator: FixedEval,
+        known_cells: impl IntoIterator<Item = Cell>,
+    ) -> Self {
+        Self::new_with_resolver(
+            fixed_data,
+            fixed_evaluator,
+            known_cells,
+            Cell::from_reference,
+        )
+    }
+
+    /// Returns a table of the current state of the cells in the given rows, for debugging.
+    /// Each entry shows the value of the cell if it is a constant, `K` if it is
+    /// known, its range constraint (`bit`, `byte` or `[min, max]`) or `?`.
+    /// If `columns` is `None`, all witness columns are shown.
+    pub fn dump_state(&self, rows: Range<i32>, columns: Option<&[PolyID]>) -> String {
+        let columns = match columns {
+            Some(columns) => columns.to_vec(),
+            None => self.fixed_data.witness_cols.keys().collect(),
+        };
+        let header = std::iter::once("row".to_string())
+            .chain(
+                columns
+                    .iter()
+                    .map(|id| self.fixed_data.column_name(id).to_string()),
+            )
+            .collect_vec();
+        let table = rows
+            .map(|row_offset| {
+                std::iter::once(row_offset.to_string())
+                    .chain(columns.iter().map(|id| {
+                        self.cell_state(Cell::new(
+                            self.fixed_data.column_name(id),
+                            id.id,
+                            row_offset,
+                        ))
+                    }))
+                    .collect_vec()
+            })
+            .collect_vec();
+        let widths = (0..header.len())
+            .map(|i| {
+                std::iter::once(&header)
+                    .chain(&table)
+                    .map(|line| line[i].len())
+                    .max()
+                    .unwrap()
+            })
+            .collect_vec();
+        std::iter::once(&header)
+            .chain(&table)
+            .map(|line| {
+                line.iter()
+                    .zip(&widths)
+                    .map(|(entry, width)| format!("{entry:>width$}"))
+                    .join(" | ")
+            })
+            .join("\n")
+    }
+
+    fn cell_state(&self, cell: Cell) -> String {
+        // The global range constraint is only registered once the cell is referenced.
+        let global_rc =
+            self.fixed_data
+                .global_range_constraints
+                .range_constraint(&AlgebraicReference {
+                    name: Default::default(),
+                    poly_id: PolyID {
+                        id: cell.id,
+                        ptype: PolynomialType::Committed,
+                    },
+                    next: false,
+                });
+        let rc = global_rc
+            .into_iter()
+            .chain(self.range_constraint(cell.clone()))
+            .reduce(|gc, rc| gc.conjunction(&rc));
+        if let Some(v) = rc.as_ref().and_then(|rc| rc.try_to_single_value()) {
+            v.to_string()
+        } else if self.known_cells.contains(&cell) {
+            "K".to_string()
+        } else if let Some(rc) = rc {
+            match rc.range() {
+                (min, max) if min == 0.into() && max == 1.into() => "bit".to_string(),
+                (min, max) if min == 0.into() && max == 0xff.into() => "byte".to_string(),
+                (min, max) => format!("[{min}, {max}]"),
+            }
+        } else {
+            "?".to_string()
+        }
+    }
+
+    /// Returns a snapshot of the knowledge derived so far, so that `solve_block`
+    /// can be resumed later using `restore_state`, without redoing the earlier rounds.
+    /// The code derived so far is not part of the snapshot.
+    pub fn serialize_state(&self) -> KnowledgeSnapshot<T> {
+        KnowledgeSnapshot {
+            pil_hash: pil_hash(self.fixed_data.analyzed),
+            round: self.round,
+            known_cells: self.known_cells.iter().cloned().sorted().collect(),
+            constant_cells: self
+                .constant_cells
+                .iter()
+                .map(|(cell, value)| (cell.clone(), *value))
+                .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                .collect(),
+            range_constraints: self
+                .derived_range_constraints
+                .iter()
+                .map(|(cell, rc)| (cell.clone(), rc.clone()))
+                .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                .collect(),
+            complete: self.complete.keys().copied().sorted().collect(),
+        }
+    }
+
+    /// Adds the knowledge of a snapshot created by `serialize_state` and continues
+    /// counting rounds from the round of the snapshot. Progress made before the
+    /// snapshot is reported as zero.
+    /// Fails if the snapshot was taken for a different PIL.
+    pub fn restore_state(
+        &mut self,
+        snapshot: KnowledgeSnapshot<T>,
+    ) -> Result<(), WitgenJitError<T>> {
+        if snapshot.pil_hash != pil_hash(self.fixed_data.analyzed) {
+            return Err(WitgenJitError::Serialization(
+                "The snapshot was taken for a different PIL.".to_string(),
+            ));
+        }
+        self.known_cells.extend(snapshot.known_cells);
+        self.constant_cells.extend(snapshot.constant_cells);
+        for (cell, rc) in snapshot.range_constraints {
+            self.derived_range_constraints.insert(cell, rc);
+        }
+        self.complete.extend(
+            snapshot
+                .complete
+                .into_iter()
+                .map(|key| (key, snapshot.round)),
+        );
+        self.round = snapshot.round;
+        self.progress
+            .cells_solved_per_round
+            .resize(snapshot.round, 0);
+        self.progress
+            .identities_completed_per_round
+            .resize(snapshot.round, 0);
+        Ok(())
+    }
+
+    /// Returns the status of the given identities on the given rows after a
+    /// run of the driver, e.g. `solve_block` or `run_budgeted`.
+    pub fn identity_report(&self, ids: &[Identity<T>], rows: &[i32]) -> IdentityReport {
+        let statuses = rows
+            .iter()
+            .flat_map(|row| ids.iter().map(move |id| (id, row)))
+            .map(|(id, row)| {
+                let key = (id.id(), *row);
+                let status = if let Some(round) = self.complete.get(&key) {
+                    IdentityStatus::Completed { round: *round }
+                } else if let Some(reason) = self.unsupported_reason(id) {
+                    IdentityStatus::Unsupported(reason)
+                } else {
+                    let waiting_on = id
+                        .all_children()
+                        .filter_map(|e| match e {
+                            Expression::Reference(r) if r.is_witness() => {
+                                Some(self.resolve(r, *row))
+                            }
+                            _ => None,
+                        })
+                        .filter(|cell| !self.known_cells.contains(cell))
+                        .unique()
+                        .sorted()
+                        .collect();
+                    IdentityStatus::NoProgress { waiting_on }
+                };
+                (key, status)
+            })
+            .collect();
+        IdentityReport { statuses }
+    }
+}
+
+impl<'a, T: FieldElement, FixedEval: FixedEvaluator<T>, V: Hash + Eq + Clone + Ord + Display>
+    WitgenInference<'a, T, FixedEval, V>
+{
+    /// Creates a new instance where column references are turned into variables
+    /// using `resolver`, which receives the reference and the row offset
+    /// the identity is processed on.
+    pub fn new_with_resolver(
+        fixed_data: &'a FixedData<'a, T>,
+        fixed_evaluator: FixedEval,
+        known_variables: impl IntoIterator<Item = V>,
+        resolver: impl Fn(&AlgebraicReference, i32) -> V + 'a,
+    ) -> Self {
+        Self::new_with_shared_resolver(
+            fixed_data,
+            fixed_evaluator,
+            known_variables,
+            Rc::new(resolver),
+        )
+    }
+
+    fn new_with_shared_resolver(
+        fixed_data: &'a FixedData<'a, T>,
+        fixed_evaluator: FixedEval,
+        known_variables: impl IntoIterator<Item = V>,
+        resolver: Resolver<'a, V>,
+    ) -> Self {
+        let inputs: HashSet<_> = known_variables.into_iter().collect();
+        Self {
+            fixed_data,
+            fixed_evaluator,
+            resolver,
+            global_range_constraints: Default::default(),
+            derived_range_constraints: Default::default(),
+            conflicts: Default::default(),
+            identity_conflicts: Default::default(),
+            known_cells: inputs.clone(),
+            constant_cells: Default::default(),
+            complete: Default::default(),
+            fixed_reads: Default::default(),
+            fixed_values: Default::default(),
+            unsupported_counts: Default::default(),
+            unresolved_fixed: Default::default(),
+            determined_lookups: Default::default(),
+            contiguous_ranges: Default::default(),
+            code: Default::default(),
+            round: 0,
+            config: Default::default(),
+            round_span: Span::none(),
+            code_rounds: Default::default(),
+            drained_code_len: 0,
+            machine_call_counts: Default::default(),
+            called_machines: Default::default(),
+            progress: Default::default(),
+            effect_logger: None,
+            inputs,
+            known_columns: Default::default(),
+            trace: None,
+            solve_directions: Default::default(),
+            machine_semantics: Default::default(),
+            publics: Default::default(),
+            forked_at: None,
+            paused_run: None,
+            runtime_division: false,
+        }
+    }
+
+    pub fn with_config(self, config: WitgenInferenceConfig) -> Self {
+        Self { config, ..self }
+    }
+
+    /// Records every call to `process_identity` together with its outcome,
+    /// so that the run can be reproduced with `replay`.
+    pub fn with_trace_recording(self) -> Self {
+        Self {
+            trace: Some(Default::default()),
+            ..self
+        }
+    }
+
+    /// Pins the direction the polynomial identity with the given id is solved in:
+    /// If its only unknown cell is not on the row given by `direction`, the
+    /// identity is not solved for it, even if that would be possible.
+    pub fn with_solve_direction(mut self, identity_id: u64, direction: SolveDirection) -> Self {
+        self.solve_directions.insert(identity_id, direction);
+        self
+    }
+
+    /// Registers the semantics of the machine that answers the lookup with the given id.
+    /// Without registration, a lookup is only resolved if its right-hand side
+    /// consists of fixed columns.
+    pub fn register_machine(&mut self, id: u64, semantics: MachineSemantics) {
+        self.machine_semantics.insert(id, semantics);
+    }
+
+    /// Returns the semantics registered for the lookup with the given id, if any.
+    pub fn machine_semantics(&self, id: u64) -> Option<MachineSemantics> {
+        self.machine_semantics.get(&id).copied()
+    }
+
+    /// Marks the witness column as provided by the caller on all rows, e.g. a program
+    /// committed as a witness column or the output of a previous stage.
+    /// The cells of the column are known on every row without listing them
+    /// individually, the code reads them and they are reported as inputs once
+    /// they are referenced by a processed identity.
+    pub fn mark_column_known(&mut self, poly_id: PolyID) {
+        self.known_columns.insert(poly_id);
+    }
+
+    /// Registers the variable that holds the value of the public with the given name,
+    /// usually the cell the public is anchored at. The variable is an input, i.e. it
+    /// is known from the start and its value is provided at run-time from the public
+    /// values. References to the public evaluate to this variable.
+    pub fn register_public(&mut self, name: impl Into<String>, variable: V) {
+        self.inputs.insert(variable.clone());
+        self.add_known_cell(variable.clone());
+        self.publics.insert(name.into(), variable);
+    }
+
+    /// Installs a callback that is called for every ingested effect,
+    /// in addition to the debug-level log message.
+    pub fn with_effect_logger(mut self, logger: impl FnMut(u64, i32, &Effect<T, V>) + 'a) -> Self {
+        self.effect_logger = Some(Box::new(logger));
+        self
+    }
+
+    pub fn code(self) -> Vec<Effect<T, V>> {
+        self.code
+    }
+
+    /// Returns the code generated so far, excluding code removed by `take_code`.
+    pub fn code_so_far(&self) -> &[Effect<T, V>] {
+        &self.code
+    }
+
+    /// Removes and returns the code generated since the last call to `take_code`,
+    /// so that the code can be consumed while the inference continues.
+    /// The rounds of the removed effects are removed from `code_rounds` as well,
+    /// while `machine_call_summary` and `cacheable_calls` still include the removed code.
+    pub fn take_code(&mut self) -> Vec<Effect<T, V>> {
+        self.code_rounds.clear();
+        self.drained_code_len += self.code.len();
+        std::mem::take(&mut self.code)
+    }
+
+    /// The length of all code generated, including code removed by `take_code`.
+    fn total_code_len(&self) -> usize {
+        self.drained_code_len + self.code.len()
+    }
+
+    /// The proof stage the code is generated for, see `WitgenInferenceConfig::with_stage`.
+    pub fn stage(&self) -> u8 {
+        self.config.stage.unwrap_or_else(|| self.fixed_data.stage())
+    }
+
+    /// The stage of the witness column, i.e. the stage in which it is committed.
+    fn column_stage(&self, r: &AlgebraicReference) -> u32 {
+        self.fixed_data.witness_cols[&r.poly_id].stage
+    }
+
+    /// Returns the length of the longest chain of effects in the generated code
+    /// where each effect reads a variable written by the previous one, i.e. the
+    /// number of steps that have to be performed sequentially at runtime.
+    /// Range constraints are not counted, since they do not result in any work.
+    pub fn critical_path_length(&self) -> usize {
+        // The length of the longest chain that ends in the effect writing the variable.
+        let mut chain_lengths: HashMap<&V, usize> = HashMap::new();
+        let mut longest = 0;
+        for effect in &self.code {
+            if let Effect::RangeConstraint(..) = effect {
+                continue;
+            }
+            let (reads, writes) = reads_and_writes(effect);
+            let length = 1 + reads
+                .into_iter()
+                .filter_map(|var| chain_lengths.get(var))
+                .max()
+                .unwrap_or(&0);
+            for var in writes {
+                chain_lengths.insert(var, length);
+            }
+            longest = longest.max(length);
+        }
+        longest
+    }
+
+    /// Checks that no variable in the generated code is computed from itself,
+    /// directly or through other variables. Otherwise, returns the variables of
+    /// such a cycle, where each variable is computed from the next one and the
+    /// last one from the first one.
+    /// Code generated by the inference is always acyclic, so a cycle indicates a bug.
+    pub fn validate_acyclic(&self) -> Result<(), Vec<V>> {
+        // The variables each variable is computed from.
+        let mut dependencies: BTreeMap<&V, Vec<&V>> = BTreeMap::new();
+        let mut written = vec![];
+        for effect in &self.code {
+            let (reads, writes) = reads_and_writes(effect);
+            for var in writes {
+                written.push(var);
+                dependencies.entry(var).or_default().extend(&reads);
+            }
+        }
+        let mut finished = HashSet::new();
+        let mut path: Vec<&V> = vec![];
+        for var in written {
+            find_cycle(var, &dependencies, &mut finished, &mut path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of machine calls and state writes in the generated code
+    /// (including code removed by `take_code`) per identity id, i.e. the interactions
+    /// that still need to be resolved at runtime.
+    pub fn machine_call_summary(&self) -> HashMap<u64, usize> {
+        self.machine_call_counts.clone()
+    }
+
+    /// Starts a new round. Rounds are numbered starting from 1 and are used
+    /// to track progress. The driver should call this before each pass over
+    /// the identities. Progress made before the first round is not reported.
+    pub fn start_round(&mut self) {
+        self.round += 1;
+        self.round_span = debug_span!("round", round = self.round);
+        self.progress.cells_solved_per_round.push(0);
+        self.progress.identities_completed_per_round.push(0);
+    }
+
+    /// Returns, for each element of the generated code, the round in which it was derived.
+    pub fn code_rounds(&self) -> &[usize] {
+        &self.code_rounds
+    }
+
+    pub fn progress_report(&self) -> &ProgressReport {
+        &self.progress
+    }
+
+    /// Returns how often the evaluation of an expression failed because of each
+    /// kind of unsupported expression, to see what blocked progress.
+    pub fn unsupported_report(&self) -> HashMap<UnsupportedKind, usize> {
+        self.unsupported_counts.borrow().clone()
+    }
+
+    /// Returns the recorded trace, if recording is enabled.
+    pub fn trace(&self) -> Option<&InferenceTrace> {
+        self.trace.as_ref()
+    }
+
+    /// Processes the identity-row pairs in the order given by the trace instead
+    /// of using a driver, and checks that each call has the same outcome and
+    /// generates the same code as in the recorded run.
+    pub fn replay(&mut self, ids: &[Identity<T>], trace: &InferenceTrace) -> Result<(), String> {
+        for step in &trace.steps {
+            while self.round < step.round {
+                self.start_round();
+            }
+            let id = ids
+                .iter()
+                .find(|id| id.id() == step.identity_id)
+                .ok_or_else(|| format!("Identity {} not found.", step.identity_id))?;
+            let code_start = self.code.len();
+            let complete = self.process_identity(id, step.row_offset).is_complete();
+            let code = self.code[code_start..]
+                .iter()
+                .map(|e| e.to_string())
+                .collect_vec();
+            if complete != step.complete || code != step.code {
+                return Err(format!(
+                    "Identity {} on row {} in round {} diverged from the trace:\n\
+                     expected complete: {}, code:\n{}\n\
+                     actual complete: {complete}, code:\n{}",
+                    step.identity_id,
+                    step.row_offset,
+                    step.round,
+                    step.complete,
+                    step.code.join("\n"),
+                    code.join("\n"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the cells that were fixed to a constant by the conjunction of
+    /// range constraints, not by an assignment. The generated code also contains
+    /// assignments for them, but they can be inlined as compile-time constants.
+    pub fn constant_cells(&self) -> HashMap<V, T> {
+        self.constant_cells.clone()
+    }
+
+    /// Returns the ids of the machine calls in the generated code (including code
+    /// removed by `take_code`) whose results only depend on the known arguments,
+    /// so that they can be cached at runtime, see `CachingMachineCallHandler`.
+    /// These are the lookups into fixed tables and into machines registered as
+    /// `MachineSemantics::PureFixedTable`. Lookups with a multiplicity column,
+    /// permutations and bus receives are not cacheable, since the callee has to
+    /// see every call to count it or to update its state. State writes are never cacheable.
+    pub fn cacheable_calls(&self) -> HashSet<u64> {
+        self.called_machines
+            .iter()
+            .copied()
+            .filter(|id| self.is_cacheable(*id))
+            .collect()
+    }
+
+    /// Returns true if calls to the machine answering the identity with the given id
+    /// can be cached, see `cacheable_calls`.
+    fn is_cacheable(&self, identity_id: u64) -> bool {
+        let Some(Identity::Lookup(LookupIdentity { right, .. })) = self
+            .fixed_data
+            .analyzed
+            .identities
+            .iter()
+            .find(|id| id.id() == identity_id)
+        else {
+            return false;
+        };
+        match self.machine_semantics(identity_id) {
+            Some(MachineSemantics::PureFixedTable) => true,
+            Some(MachineSemantics::Stateful) => false,
+            None => is_fixed_table(right),
+        }
+    }
+
+    /// Returns the cells that were known initially, in ascending order.
+    pub fn inputs(&self) -> Vec<V> {
+        self.inputs.iter().cloned().sorted().collect()
+    }
+
+    /// Returns whether the cell was provided as input or derived,
+    /// or `None` if the cell is not known.
+    pub fn provenance(&self, cell: &V) -> Option<Provenance> {
+        if self.inputs.contains(cell) {
+            Some(Provenance::Input)
+        } else if self.known_cells.contains(cell) {
+            Some(Provenance::Derived)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the fixed column values (by column and absolute row) read by
+    /// the identity-row pairs that derived code or were completed, i.e. all
+    /// fixed values the generated code depends on.
+    pub fn fixed_reads(&self) -> HashSet<(PolyID, usize)> {
+        self.fixed_reads.clone()
+    }
+
+    /// Returns the conflicts between derived values and range constraints
+    /// found so far.
+    pub fn conflicts(&self) -> &[RangeConflict<T, V>] {
+        &self.conflicts
+    }
+
+    /// Returns a copy of the current state, apart from the code, which is empty.
+    /// This way, the fork can be used to speculatively derive new facts, which
+    /// are either discarded by dropping the fork or applied to `self` by `merge_fork`.
+    /// The effect logger is not copied.
+    pub fn fork(&self) -> Self
+    where
+        FixedEval: Clone,
+    {
+        Self {
+            fixed_data: self.fixed_data,
+            fixed_evaluator: self.fixed_evaluator.clone(),
+            resolver: self.resolver.clone(),
+            global_range_constraints: self.global_range_constraints.clone(),
+            derived_range_constraints: self.derived_range_constraints.clone(),
+            conflicts: self.conflicts.clone(),
+            identity_conflicts: self.identity_conflicts.clone(),
+            known_cells: self.known_cells.clone(),
+            constant_cells: self.constant_cells.clone(),
+            complete: self.complete.clone(),
+            fixed_reads: self.fixed_reads.clone(),
+            fixed_values: self.fixed_values.clone(),
+            unsupported_counts: self.unsupported_counts.clone(),
+            unresolved_fixed: Default::default(),
+            determined_lookups: self.determined_lookups.clone(),
+            contiguous_ranges: self.contiguous_ranges.clone(),
+            code: vec![],
+            config: self.config.clone(),
+            round: self.round,
+            round_span: self.round_span.clone(),
+            code_rounds: vec![],
+            drained_code_len: 0,
+            machine_call_counts: Default::default(),
+            called_machines: Default::default(),
+            progress: self.progress.clone(),
+            effect_logger: None,
+            inputs: self.inputs.clone(),
+            known_columns: self.known_columns.clone(),
+            trace: self.trace.as_ref().map(|_| Default::default()),
+            solve_directions: self.solve_directions.clone(),
+            machine_semantics: self.machine_semantics.clone(),
+            publics: self.publics.clone(),
+            forked_at: Some(self.total_code_len()),
+            paused_run: self.paused_run.clone(),
+            runtime_division: false,
+        }
+    }
+
+    /// Takes over the state of a fork created by `fork` and appends the code
+    /// derived by the fork. `self` must not have derived any code since the fork.
+    /// Code removed from the fork by `take_code` counts as removed from `self`.
+    /// The fixed data, evaluator, resolver, config and effect logger of `self` are kept.
+    pub fn merge_fork(&mut self, fork: Self) {
+        // Destructured completely, so that new fields cannot be forgotten here.
+        let Self {
+            fixed_data: _,
+            fixed_evaluator: _,
+            resolver: _,
+            global_range_constraints,
+            derived_range_constraints,
+            conflicts,
+            identity_conflicts,
+            known_cells,
+            constant_cells,
+            complete,
+            fixed_reads,
+            fixed_values,
+            unsupported_counts,
+            unresolved_fixed: _,
+            determined_lookups,
+            contiguous_ranges,
+            code,
+            round,
+            config: _,
+            round_span,
+            code_rounds,
+            drained_code_len,
+            machine_call_counts,
+            called_machines,
+            progress,
+            effect_logger: _,
+            inputs,
+            known_columns,
+            trace,
+            solve_directions,
+            machine_semantics,
+            publics,
+            forked_at,
+            paused_run,
+            runtime_division: _,
+        } = fork;
+        assert_eq!(
+            forked_at,
+            Some(self.total_code_len()),
+            "The fork was not created from this state."
+        );
+        self.global_range_constraints = global_range_constraints;
+        self.derived_range_constraints = derived_range_constraints;
+        self.conflicts = conflicts;
+        self.identity_conflicts = identity_conflicts;
+        self.known_cells = known_cells;
+        self.constant_cells = constant_cells;
+        self.complete = complete;
+        self.fixed_reads = fixed_reads;
+        self.fixed_values = fixed_values;
+        self.unsupported_counts = unsupported_counts;
+        self.determined_lookups = determined_lookups;
+        self.contiguous_ranges = contiguous_ranges;
+        self.code.extend(code);
+        self.round = round;
+        self.round_span = round_span;
+        self.code_rounds.extend(code_rounds);
+        self.drained_code_len += drained_code_len;
+        for (id, count) in machine_call_counts {
+            *self.machine_call_counts.entry(id).or_default() += count;
+        }
+        self.called_machines.extend(called_machines);
+        self.progress = progress;
+        self.inputs = inputs;
+        self.known_columns = known_columns;
+        self.solve_directions = solve_directions;
+        self.machine_semantics = machine_semantics;
+        self.publics = publics;
+        self.paused_run = paused_run;
+        if let (Some(self_trace), Some(trace)) = (&mut self.trace, trace) {
+            self_trace.steps.extend(trace.steps);
+        }
+    }
+
+    /// Processes the given identities on the rows `0..block_size` until no more
+    /// progress can be made and returns the code derived in the process.
+    /// Fails if not all identities could be completed on all rows, see
+    /// `WitgenJitError` for the possible reasons.
+    ///
+    /// The set of known cells and the values computed for them do not depend on
+    /// the order of `identities`, but the exact code (order of statements and
+    /// how values are expressed) does. Callers that need reproducible code
+    /// should pass the identities in a canonical order, e.g. sorted by id.
+    pub fn solve_block(
+        &mut self,
+        ids: &[Identity<T>],
+        block_size: usize,
+    ) -> Result<BlockPlan<T, V>, WitgenJitError<T, V>> {
+        if let Some(id) = ids.iter().find(|id| !is_supported(id)) {
+            return Err(WitgenJitError::UnsupportedIdentityKind {
+                identity_id: id.id(),
+                kind: id.kind(),
+            });
+        }
+        if let Some(degree) = self.degree() {
+            if block_size as DegreeType > degree {
+                log::debug!("Block size {block_size} exceeds the degree {degree}.");
+                return Err(WitgenJitError::RowOutOfBounds);
+            }
+        }
+        let code_start = self.code.len();
+        let rows = (0..block_size as i32).collect_vec();
+        self.solve_rows(ids, &rows)?;
+        if let Some(conflict) = self.identity_conflicts.first() {
+            return Err(WitgenJitError::Conflict(conflict.clone()));
+        }
+        let resolve = |r, row| self.resolve(r, row);
+        let incomplete = rows
+            .iter()
+            .flat_map(|row| ids.iter().map(move |id| (id, *row)))
+            .filter(|(id, row)| !self.complete.contains_key(&(id.id(), *row)))
+            .collect_vec();
+        let unknown_variables = incomplete
+            .iter()
+            .flat_map(|(id, row)| {
+                let row = *row;
+                id.all_children().filter_map(move |e| match e {
+                    Expression::Reference(r) if r.is_witness() => Some(resolve(r, row)),
+                    _ => None,
+                })
+            })
+            .filter(|var| !self.known_cells.contains(var))
+            .unique()
+            .sorted()
+            .collect_vec();
+        if !unknown_variables.is_empty() {
+            return Err(WitgenJitError::Stuck(unknown_variables));
+        }
+        if !incomplete.is_empty() {
+            // All cells are known, but the code does not account for these identities,
+            // e.g. a lookup whose selector is only known at runtime.
+            return Err(WitgenJitError::Incomplete(
+                incomplete
+                    .into_iter()
+                    .map(|(id, row)| (id.id(), row))
+                    .collect(),
+            ));
+        }
+        Ok(BlockPlan {
+            block_size,
+            inputs: self.inputs(),
+            code: self.code[code_start..].to_vec(),
+        })
+    }
+
+    /// Returns the degree of the fixed evaluator or, if unknown, the maximal degree
+    /// of the witness columns.
+    fn degree(&self) -> Option<DegreeType> {
+        self.fixed_evaluator.degree().or_else(|| self.max_degree())
+    }
+
+    /// Returns true if the identity has a next-reference and `row_offset`
+    /// is the last row of the trace (or beyond).
+    fn is_on_last_row(&self, id: &Identity<T>, row_offset: i32) -> bool {
+        self.degree()
+            .is_some_and(|degree| row_offset as i64 + 1 >= degree as i64)
+            && id
+                .all_children()
+                .any(|e| matches!(e, Expression::Reference(r) if r.next))
+    }
+
+    /// Returns the variable the reference refers to if the identity is processed
+    /// on the given row, taking `LastRowPolicy::Wrap` into account.
+    fn resolve(&self, r: &AlgebraicReference, row_offset: i32) -> V {
+        match (self.config.last_row_policy, self.degree()) {
+            (LastRowPolicy::Wrap, Some(degree))
+                if r.next && row_offset as i64 + 1 >= degree as i64 =>
+            {
+                (self.resolver)(r, row_offset - degree as i32)
+            }
+            _ => (self.resolver)(r, row_offset),
+        }
+    }
+
+    /// Returns the maximal degree of the witness columns, if all of them have a declared degree.
+    fn max_degree(&self) -> Option<DegreeType> {
+        self.fixed_data
+            .all_poly_symbols()
+            .filter(|symbol| symbol.kind == SymbolKind::Poly(PolynomialType::Committed))
+            .map(|symbol| symbol.degree.map(|degree| degree.max))
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .max()
+    }
+
+    /// Processes the given identities on the given rows until no more progress
+    /// can be made, recording the identity-row pairs that were completed.
+    /// Fails if progress is still made after `round_limit` rounds.
+    fn solve_rows(
+        &mut self,
+        ids: &[Identity<T>],
+        rows: &[i32],
+    ) -> Result<(), WitgenJitError<T, V>> {
+        self.paused_run = None;
+        match self.run_budgeted(ids, rows, Budget::Unlimited) {
+            SolveProgress::Done => Ok(()),
+            SolveProgress::Paused => unreachable!(),
+            SolveProgress::Failed(e) => Err(e),
+        }
+    }
+
+    /// Like `solve_block` without the final checks, but pauses once `budget` is used up.
+    /// A paused run is resumed by calling this function again with the same
+    /// identities and rows, which leads to the same code as a single unlimited run.
+    pub fn run_budgeted(
+        &mut self,
+        ids: &[Identity<T>],
+        rows: &[i32],
+        budget: Budget,
+    ) -> SolveProgress<T, V> {
+        let ids = self.supported_identities(ids);
+        let pairs = rows
+            .iter()
+            .flat_map(|row| ids.iter().map(move |id| (*id, *row)))
+            .collect_vec();
+        let mut run = self.paused_run.take().unwrap_or_default();
+        let code_start = self.total_code_len();
+        let mut attempts = 0;
+        loop {
+            if run.position == 0 {
+                if run.rounds > self.config.round_limit {
+                    return SolveProgress::Failed(WitgenJitError::BudgetExceeded);
+                }
+                self.start_round();
+                run.rounds += 1;
+                run.state_before = self.solve_state();
+            }
+            while let Some((id, row)) = pairs.get(run.position) {
+                let exhausted = match budget {
+                    Budget::Unlimited => false,
+                    Budget::Attempts(max) => attempts >= max,
+                    Budget::Effects(max) => self.total_code_len() - code_start >= max,
+                };
+                if exhausted {
+                    self.paused_run = Some(run);
+                    return SolveProgress::Paused;
+                }
+                run.position += 1;
+                let key = (id.id(), *row);
+                if self.complete.contains_key(&key) || run.dropped.contains(&key) {
+                    continue;
+                }
+                attempts += 1;
+                match self.process_identity(id, *row) {
+                    ProcessStatus::Complete => {
+                        self.complete.insert(key, self.round);
+                    }
+                    ProcessStatus::Unsupported(_) | ProcessStatus::Error(_) => {
+                        run.dropped.insert(key);
+                    }
+                    status @ ProcessStatus::UnresolvedFixed { .. } => {
+                        return SolveProgress::Failed(status.into_error(id.id()).unwrap());
+                    }
+                    ProcessStatus::Progress | ProcessStatus::NoProgress => {}
+                }
+            }
+            run.position = 0;
+            if run.state_before == self.solve_state() {
+                let remaining = pairs
+                    .iter()
+                    .filter(|(id, row)| !run.dropped.contains(&(id.id(), *row)))
+                    .map(|(id, row)| (*id, *row))
+                    .collect_vec();
+                if self.divide_by_runtime_coefficient(&remaining).is_none() {
+                    return SolveProgress::Done;
+                }
+            }
+        }
+    }
+
+    /// Last resort if no more progress can be made otherwise: Solves the first
+    /// incomplete polynomial identity of the form `coeff * X + offset = 0` whose
+    /// coefficient is only known at runtime by dividing by the coefficient, together
+    /// with a runtime assertion that it is not zero.
+    /// This is only done once no other identity can determine `X`, since the
+    /// identity does not determine `X` on rows where the coefficient is zero,
+    /// as for example in `sel * (X - A) = 0`.
+    /// Returns the completed identity-row pair, if any.
+    pub fn divide_by_runtime_coefficient(
+        &mut self,
+        pairs: &[(&Identity<T>, i32)],
+    ) -> Option<(u64, i32)> {
+        self.runtime_division = true;
+        let completed = pairs.iter().find_map(|(id, row)| {
+            let key = (id.id(), *row);
+            if !matches!(id, Identity::Polynomial(_)) || self.complete.contains_key(&key) {
+                return None;
+            }
+            (self.process_identity(id, *row) == ProcessStatus::Complete).then(|| {
+                self.complete.insert(key, self.round);
+                key
+            })
+        });
+        self.runtime_division = false;
+        completed
+    }
+
+    /// The state `run_budgeted` compares to determine whether progress was made in a round.
+    fn solve_state(&self) -> (usize, usize, usize) {
+        (
+            self.total_code_len(),
+            self.complete.len(),
+            self.derived_range_constraints.len(),
+        )
+    }
+
+    /// Determines a minimal set of cells that have to be known so that all `outputs`
+    /// can be solved by processing the identities on the given rows.
+    /// The analysis starts from all cells referenced by the identities and greedily
+    /// removes cells that are not needed, starting with the cells on the last rows,
+    /// so that inputs on earlier rows are preferred.
+    /// The current state of `self` is not taken into account.
+    /// If the outputs cannot be solved even if all other cells are known, returns all of them.
+    pub fn minimal_inputs_for(&self, outputs: &[V], ids: &[Identity<T>], rows: &[i32]) -> HashSet<V>
+    where
+        FixedEval: Clone,
+    {
+        let candidates = rows
+            .iter()
+            .flat_map(|row| {
+                ids.iter().flat_map(move |id| {
+                    id.all_children().filter_map(move |e| match e {
+                        Expression::Reference(r) if r.is_witness() => {
+                            Some((row + r.next as i32, self.resolve(r, *row)))
+                        }
+                        _ => None,
+                    })
+                })
+            })
+            .filter(|(_, var)| !outputs.contains(var))
+            .sorted_by(|(row1, var1), (row2, var2)| row2.cmp(row1).then(var1.cmp(var2)))
+            .map(|(_, var)| var)
+            .unique()
+            .collect_vec();
+        let solves_outputs = |inputs: &HashSet<V>| {
+            let mut inference = WitgenInference::new_with_shared_resolver(
+                self.fixed_data,
+                self.fixed_evaluator.clone(),
+                inputs.iter().cloned(),
+                self.resolver.clone(),
+            );
+            inference.solve_directions = self.solve_directions.clone();
+            inference.machine_semantics = self.machine_semantics.clone();
+            inference.known_columns = self.known_columns.clone();
+            for (name, variable) in &self.publics {
+                inference.register_public(name.clone(), variable.clone());
+            }
+            inference.config = self.config.clone();
+            inference.solve_rows(ids, rows).is_ok()
+                && outputs
+                    .iter()
+                    .all(|cell| inference.known_cells.contains(cell))
+        };
+        let mut inputs: HashSet<V> = candidates.iter().cloned().collect();
+        for cell in &candidates {
+            inputs.remove(cell);
+            if !solves_outputs(&inputs) {
+                inputs.insert(cell.clone());
+            }
+        }
+        inputs
+    }
+
+    /// Orders the given identity-row pairs so that the pairs that could determine
+    /// a cell of a column with higher priority come first, see
+    /// `WitgenInferenceConfig::with_column_priorities`. The order of pairs with
+    /// the same priority is kept.
+    pub fn order_by_priority<'b>(
+        &self,
+        ids: &[(&'b Identity<T>, i32)],
+    ) -> Vec<(&'b Identity<T>, i32)> {
+        ids.iter()
+            .copied()
+            .sorted_by_key(|(id, row)| {
+                std::cmp::Reverse(self.max_unknown_column_priority(id, *row))
+            })
+            .collect()
+    }
+
+    /// Orders the given identity-row pairs that could determine `cell` by the
+    /// estimated cost of the code they emit, see `code_cost`, cheapest first.
+    /// Each pair is processed on a fork of the current state, pairs that do not
+    /// determine the cell are left out. The order of pairs with the same cost is kept.
+    pub fn order_by_cost<'b>(
+        &self,
+        cell: &V,
+        ids: &[(&'b Identity<T>, i32)],
+    ) -> Vec<(&'b Identity<T>, i32)>
+    where
+        FixedEval: Clone,
+    {
+        ids.iter()
+            .filter_map(|(id, row)| {
+                let mut fork = self.fork();
+                fork.process_identity(id, *row);
+                fork.known_cells
+                    .contains(cell)
+                    .then(|| (code_cost(fork.code_so_far()), (*id, *row)))
+            })
+            .sorted_by_key(|(cost, _)| *cost)
+            .map(|(_, pair)| pair)
+            .collect()
+    }
+
+    /// Returns the highest priority of the columns of the unknown cells
+    /// referenced by the identity on the given row.
+    fn max_unknown_column_priority(&self, id: &Identity<T>, row_offset: i32) -> u32 {
+        id.all_children()
+            .filter_map(|e| match e {
+                Expression::Reference(r)
+                    if r.is_witness()
+                        && !self.known_cells.contains(&self.resolve(r, row_offset)) =>
+                {
+                    Some(
+                        self.config
+                            .column_priorities
+                            .get(&r.poly_id)
+                            .copied()
+                            .unwrap_or_default(),
+                    )
+                }
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Processes the identity on those of the given rows where the fixed expression `gate`
+    /// is not known to be zero and returns the status for each processed row.
+    /// This is only correct if the identity is trivially satisfied where the gate is zero,
+    /// e.g. if it is multiplied by the gate like `FIRST * (x - 1) = 0`.
+    pub fn process_identity_where(
+        &mut self,
+        id: &Identity<T>,
+        rows: &[i32],
+        gate: &Expression<T>,
+    ) -> Vec<(i32, ProcessStatus)> {
+        let mut statuses = vec![];
+        for row in rows {
+            if !self
+                .evaluate(gate, *row)
+                .as_ref()
+                .is_some_and(is_known_zero)
+            {
+                statuses.push((*row, self.process_identity(id, *row)));
+            }
+        }
+        statuses
+    }
+
+    /// Processes the identity on the given rows and, after each row, moves the code
+    /// generated so far (see `take_code`) to `w`, so that the code does not have to be
+    /// kept in memory. Each effect is serialized using CBOR and
+    /// prefixed by its length in bytes as a little-endian `u32`.
+    /// The result can be read back using `parse_streamed_code`.
+    pub fn process_with_writer(
+        &mut self,
+        id: &Identity<T>,
+        rows: &[i32],
+        w: &mut impl std::io::Write,
+    ) -> std::io::Result<Vec<(i32, ProcessStatus)>>
+    where
+        V: Serialize,
+    {
+        let mut statuses = vec![];
+        for row in rows {
+            statuses.push((*row, self.process_identity(id, *row)));
+            for effect in self.take_code() {
+                let record = serde_cbor::to_vec(&effect).map_err(std::io::Error::other)?;
+                w.write_all(&(record.len() as u32).to_le_bytes())?;
+                w.write_all(&record)?;
+            }
+        }
+        Ok(statuses)
+    }
+
+    /// Like `process_identity`, but only returns whether the identity-row pair is complete.
+    #[deprecated(note = "use `process_identity`, which returns a `ProcessStatus`")]
+    pub fn process_identity_complete(&mut self, id: &Identity<T>, row_offset: i32) -> bool {
+        self.process_identity(id, row_offset).is_complete()
+    }
+
+    /// Process an identity on a certain row and returns whether the
+    /// identity-row pair is complete, progress was made, or why it cannot be completed.
+    pub fn process_identity(&mut self, id: &Identity<T>, row_offset: i32) -> ProcessStatus {
+        let _span = debug_span!(
+            parent: &self.round_span,
+            "process_identity",
+            identity_id = id.id(),
+            row = row_offset
+        )
+        .entered();
+        self.register_global_range_constraints(id, row_offset);
+        self.register_known_columns(id, row_offset);
+        let code_start = self.code.len();
+        let conflicts_start = self.conflicts.len();
+        let identity_conflicts_start = self.identity_conflicts.len();
+        self.unresolved_fixed.take();
+        let known_cells_before = self.known_cells.len();
+        let range_constraints_before = self.derived_range_constraints.len();
+        let ignored = constrains_only_fixed_columns(id)
+            || (self.config.last_row_policy == LastRowPolicy::Skip
+                && self.is_on_last_row(id, row_offset))
+            || self.implied_by_global_constraints(id, row_offset);
+        let result = if ignored {
+            // Either a consistency check on the fixed columns or a range constraint
+            // that is already known, which do not tell us anything about the witness,
+            // or skipped on the last row.
+            ProcessResult::complete(vec![])
+        } else {
+            match id {
+                Identity::Polynomial(PolynomialIdentity { expression, .. }) => self
+                    .process_polynomial_identity(id.id(), expression, row_offset)
+                    .unwrap_or_else(|e| {
+                        // TODO propagate or report error properly.
+                        // If solve returns an error, it means that the constraint is conflicting.
+                        // In the future, we might run this in a runtime-conditional, so an error
+                        // could just mean that this case cannot happen in practice.
+                        // For now, we leave the identity incomplete and record the conflict
+                        // so that it is reported by the driver instead of aborting.
+                        log::debug!("Conflicting constraint on row {row_offset}: {e}");
+                        self.identity_conflicts.push(ConflictInfo {
+                            identity_id: id.id(),
+                            row_offset,
+                            message: e.to_string(),
+                        });
+                        ProcessResult::empty()
+                    }),
+                Identity::Lookup(LookupIdentity {
+                    id, left, right, ..
+                })
+                | Identity::PhantomLookup(PhantomLookupIdentity {
+                    id, left, right, ..
+                }) => self
+                    .process_range_check_lookup(*id, left, right, row_offset)
+                    .or_else(|| self.process_contiguous_range_lookup(*id, left, right, row_offset))
+                    .unwrap_or_else(|| self.process_lookup(*id, left, right, row_offset)),
+                Identity::Permutation(PermutationIdentity {
+                    id, left, right, ..
+                })
+                | Identity::PhantomPermutation(PhantomPermutationIdentity {
+                    id,
+                    left,
+                    right,
+                    ..
+                }) => self.process_lookup(*id, left, right, row_offset),
+                Identity::PhantomBusInteraction(bus_interaction) => {
+                    self.process_bus_interaction(bus_interaction, row_offset)
+                }
+                Identity::Connect(_) => ProcessResult::empty(),
+            }
+        };
+        self.ingest_effects(id.id(), row_offset, result.effects);
+        if (result.complete && !ignored) || self.code.len() > code_start {
+            self.record_fixed_reads(id, row_offset);
+        }
+        for conflict in &self.conflicts[conflicts_start..] {
+            self.identity_conflicts.push(ConflictInfo {
+                identity_id: id.id(),
+                row_offset,
+                message: format!(
+                    "Value {} of {} violates range constraint {}",
+                    conflict.value, conflict.cell, conflict.range_constraint
+                ),
+            });
+        }
+        if result.complete && self.round > 0 {
+            self.progress.identities_completed_per_round[self.round - 1] += 1;
+        }
+        let status = if let Some(conflict) = self.identity_conflicts.get(identity_conflicts_start) {
+            ProcessStatus::Error(conflict.clone())
+        } else if result.complete {
+            ProcessStatus::Complete
+        } else if let Some((column, row)) = self.unresolved_fixed.take() {
+            ProcessStatus::UnresolvedFixed { column, row }
+        } else if let Some(reason) = self.unsupported_reason(id) {
+            ProcessStatus::Unsupported(reason)
+        } else if self.code.len() > code_start
+            || self.known_cells.len() > known_cells_before
+            || self.derived_range_constraints.len() > range_constraints_before
+        {
+            ProcessStatus::Progress
+        } else {
+            ProcessStatus::NoProgress
+        };
+        if let Some(trace) = &mut self.trace {
+            trace.steps.push(TraceStep {
+                round: self.round,
+                identity_id: id.id(),
+                row_offset,
+                complete: status.is_complete(),
+                code: self.code[code_start..]
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect(),
+            });
+        }
+        status
+    }
+
+    /// Stores the global range constraints of the columns referenced by the identity
+    /// for the variables the references resolve to on the given row.
+    fn record_fixed_reads(&mut self, id: &Identity<T>, row_offset: i32) {
+        let degree = self.fixed_evaluator.degree();
+        for e in id.all_children() {
+            let Expression::Reference(r) = e else {
+                continue;
+            };
+            if !r.is_fixed() {
+                continue;
+            }
+            let row = row_offset as i64 + r.next as i64;
+            let row = match degree {
+                Some(degree) => row.rem_euclid(degree as i64),
+                None => row,
+            };
+            if let Ok(row) = usize::try_from(row) {
+                self.fixed_reads.insert((r.poly_id, row));
+            }
+        }
+    }
+
+    /// Adds the cells of the columns marked by `mark_column_known` that are referenced
+    /// by the identity on the given row to the inputs.
+    fn register_known_columns(&mut self, id: &Identity<T>, row_offset: i32) {
+        if self.known_columns.is_empty() {
+            return;
+        }
+        for e in id.all_children() {
+            if let Expression::Reference(r) = e {
+                if r.is_witness() && self.known_columns.contains(&r.poly_id) {
+                    let cell = self.resolve(r, row_offset);
+                    self.inputs.insert(cell.clone());
+                    // Not counted as progress, the cell was known from the start.
+                    self.known_cells.insert(cell);
+                }
+            }
+        }
+    }
+
+    fn register_global_range_constraints(&mut self, id: &Identity<T>, row_offset: i32) {
+        for e in id.all_children() {
+            let Expression::Reference(r) = e else {
+                continue;
+            };
+            if !r.is_witness() {
+                continue;
+            }
+            let var = self.resolve(r, row_offset);
+            if self.global_range_constraints.contains_key(&var) {
+                continue;
+            }
+            let rc =
+                self.fixed_data
+                    .global_range_constraints
+                    .range_constraint(&AlgebraicReference {
+                        next: false,
+                        ..r.clone()
+                    });
+            if let Some(rc) = rc {
+                self.global_range_constraints.insert(var, rc);
+            }
+        }
+    }
+
+    /// Returns true if the identity holds for all values allowed by the global
+    /// range constraints of the cells it references on the given row, i.e. it
+    /// only expresses a range constraint that is already known.
+    /// Detects identities of the form `(X - a) * (X - b) * ... = 0` and
+    /// lookups of the form `[ X ] in [ F ]` with a fixed column `F`.
+    fn implied_by_global_constraints(&self, id: &Identity<T>, row_offset: i32) -> bool {
+        match id {
+            Identity::Polynomial(PolynomialIdentity { expression, .. }) => {
+                let Some((var, roots)) = self.root_set(expression, row_offset) else {
+                    return false;
+                };
+                self.global_range_constraints
+                    .get(&var)
+                    .and_then(|rc| allowed_values(rc, roots.len()))
+                    .is_some_and(|values| values.iter().all(|v| roots.contains(v)))
+            }
+            Identity::Lookup(LookupIdentity { left, right, .. })
+            | Identity::PhantomLookup(PhantomLookupIdentity { left, right, .. }) => {
+                let selectors_are_one = [&left.selector, &right.selector].iter().all(|s| {
+                    self.evaluate(s, row_offset)
+                        .and_then(|s| s.try_to_known().map(|k| k.is_known_one()))
+                        == Some(true)
+                });
+                let ([Expression::Reference(l)], [Expression::Reference(r)]) =
+                    (&left.expressions[..], &right.expressions[..])
+                else {
+                    return false;
+                };
+                if !selectors_are_one || !l.is_witness() || !r.is_fixed() {
+                    return false;
+                }
+                let Some(rc) = self
+                    .global_range_constraints
+                    .get(&self.resolve(l, row_offset))
+                else {
+                    return false;
+                };
+                let fixed_values = self.fixed_data.fixed_cols[&r.poly_id].values_max_size();
+                allowed_values(rc, fixed_values.len()).is_some_and(|values| {
+                    let fixed_values: HashSet<_> = fixed_values.iter().collect();
+                    values.iter().all(|v| fixed_values.contains(v))
+                })
+            }
+            _ => false,
+        }
+    }
+
+    fn process_polynomial_identity(
+        &self,
+        identity_id: u64,
+        expression: &'a Expression<T>,
+        offset: i32,
+    ) -> Result<ProcessResult<T, V>, EvalError<T>> {
+        let Some(r) = self.evaluate(expression, offset) else {
+            return Ok(self.process_root_set(expression, offset));
+        };
+        if let (Some(direction), Some(var)) = (
+            self.solve_directions.get(&identity_id),
+            r.single_unknown_variable(),
+        ) {
+            let next = *direction == SolveDirection::Forward;
+            let in_direction = expression.all_children().any(|e| match e {
+                Expression::Reference(r) if r.is_witness() && r.next == next => {
+                    self.resolve(r, offset) == *var
+                }
+                _ => false,
+            });
+            if !in_direction {
+                return Ok(ProcessResult::empty());
+            }
+        }
+        let mut result = match r.solve_with_limb_limit(self.config.max_decomposition_limbs) {
+            Ok(result) if self.runtime_division && !result.complete => {
+                r.solve_by_runtime_division().unwrap_or(result)
+            }
+            Ok(result) => result,
+            Err(e) if self.config.conflict_policy == ConflictPolicy::Speculative => {
+                log::debug!("Infeasible constraint on row {offset}: {e}");
+                return Ok(ProcessResult {
+                    effects: vec![Effect::Infeasible(e.to_string())],
+                    complete: true,
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        if let (true, Some(known)) = (result.complete, r.try_to_known()) {
+            result
+                .effects
+                .extend(self.implied_constant(expression, offset, known));
+            if self.config.redundant_checks {
+                result
+                    .effects
+                    .extend(self.redundant_check(expression, offset, known));
+            } else if let Some(check) = self
+                .config
+                .equality_checks
+                .then(|| self.equality_check(expression, offset, known))
+                .flatten()
+            {
+                result.effects.push(check);
+            } else if !known.is_known_zero() && self.root_set(expression, offset).is_some() {
+                // The range constraint derived from the roots can be wider than the roots,
+                // so the identity still needs to be checked.
+                result
+                    .effects
+                    .push(Assertion::assert_is_zero(known.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Processes an identity of the form `(X - c_1) * ... * (X - c_k) = 0`
+    /// for an unknown cell `X` by deriving a range constraint on `X` that covers
+    /// all the roots `c_i`. The identity stays incomplete, it is turned into an
+    /// assertion once `X` is known.
+    fn process_root_set(&self, expression: &Expression<T>, offset: i32) -> ProcessResult<T, V> {
+        let Some((var, roots)) = self.root_set(expression, offset) else {
+            return ProcessResult::empty();
+        };
+        let min = *roots.iter().min_by_key(|root| root.to_integer()).unwrap();
+        let max = *roots.iter().max_by_key(|root| root.to_integer()).unwrap();
+        ProcessResult {
+            effects: vec![Effect::RangeConstraint(
+                var,
+                RangeConstraint::from_range(min, max),
+            )],
+            complete: false,
+        }
+    }
+
+    /// If the expression is a product of at least two factors that are affine
+    /// in the only cell referenced by the expression, returns the cell and the
+    /// roots of the factors.
+    fn root_set(&self, expression: &Expression<T>, offset: i32) -> Option<(V, Vec<T>)> {
+        let var = expression
+            .all_children()
+            .filter_map(|e| match e {
+                Expression::Reference(r) if r.is_witness() => Some(self.resolve(r, offset)),
+                _ => None,
+            })
+            .unique()
+            .exactly_one()
+            .ok()?;
+        // Identities of the form `a = b` are stored as `a - b`.
+        let product = match expression {
+            Expression::BinaryOperation(AlgebraicBinaryOperation {
+                left,
+                op: AlgebraicBinaryOperator::Sub,
+                right,
+            }) if matches!(right.as_ref(), Expression::Number(n) if n.is_zero()) => left,
+            _ => expression,
+        };
+        let mut factors = vec![];
+        let mut to_visit = vec![product];
+        while let Some(e) = to_visit.pop() {
+            match e {
+                Expression::BinaryOperation(AlgebraicBinaryOperation {
+                    left,
+                    op: AlgebraicBinaryOperator::Mul,
+                    right,
+                }) => {
+                    to_visit.push(right);
+                    to_visit.push(left);
+                }
+                _ => factors.push(e),
+            }
+        }
+        if factors.len() < 2 {
+            return None;
+        }
+        let roots = factors
+            .into_iter()
+            .map(|factor| {
+                let affine = self.evaluate_with_unknown(factor, offset, Some(&var))?;
+                let (root_var, root) = affine.try_to_root()?;
+                (*root_var == var).then_some(root)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((var, roots))
+    }
+
+    /// Returns an assertion that checks a polynomial identity where all cells
+    /// are already known. If an input cell is involved, the identity is solved
+    /// for it and the assertion compares the input to the derived value.
+    fn redundant_check(
+        &self,
+        expression: &Expression<T>,
+        offset: i32,
+        known: &SymbolicExpression<T, V>,
+    ) -> Option<Effect<T, V>> {
+        if known.is_known_zero() {
+            return None;
+        }
+        let rederived_input = known
+            .referenced_symbols()
+            .filter(|cell| self.inputs.contains(cell))
+            .find_map(|cell| {
+                let solved = self
+                    .evaluate_with_unknown(expression, offset, Some(cell))?
+                    .solve()
+                    .ok()?;
+                match &solved.effects[..] {
+                    [Effect::Assignment(var, value)] if var == cell => Some(Assertion::assert_eq(
+                        SymbolicExpression::from_symbol(cell.clone(), None),
+                        value.clone(),
+                    )),
+                    _ => None,
+                }
+            });
+        Some(rederived_input.unwrap_or_else(|| Assertion::assert_is_zero(known.clone())))
+    }
+
+    /// If the identity only references a single known cell and determines it to be
+    /// a constant, returns the range constraint that fixes the cell to this constant.
+    /// Without this, whether the cell is a compile-time constant would depend on
+    /// whether this identity or another one is used to determine it.
+    fn implied_constant(
+        &self,
+        expression: &Expression<T>,
+        offset: i32,
+        known: &SymbolicExpression<T, V>,
+    ) -> Option<Effect<T, V>> {
+        let [cell] = &known.referenced_symbols().unique().collect_vec()[..] else {
+            return None;
+        };
+        let solved = self
+            .evaluate_with_unknown(expression, offset, Some(cell))?
+            .solve()
+            .ok()?;
+        match &solved.effects[..] {
+            [Effect::Assignment(var, value)] if var == *cell => Some(Effect::RangeConstraint(
+                var.clone(),
+                RangeConstraint::from_value(value.try_to_number()?),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns an assertion that the two sides of an identity `a = b` are equal,
+    /// if both sides are known, but the identity does not hold trivially.
+    fn equality_check(
+        &self,
+        expression: &Expression<T>,
+        offset: i32,
+        known: &SymbolicExpression<T, V>,
+    ) -> Option<Effect<T, V>> {
+        let Expression::BinaryOperation(AlgebraicBinaryOperation {
+            left,
+            op: AlgebraicBinaryOperator::Sub,
+            right,
+        }) = expression
+        else {
+            return None;
+        };
+        if known.is_known_zero() || known.try_to_number().is_some() {
+            return None;
+        }
+        let left = self.evaluate(left, offset)?;
+        let right = self.evaluate(right, offset)?;
+        Some(Assertion::assert_eq(
+            left.try_to_known()?.clone(),
+            right.try_to_known()?.clone(),
+        ))
+    }
+
+    /// Classifies whether `process_identity` can complete the identity.
+    pub fn classify(&self, id: &Identity<T>) -> IdentitySupport {
+        // Connect identities are never processed, but they are ignored
+        // on the last row if they reference the next row.
+        let skipped_on_last_row = self.config.last_row_policy == LastRowPolicy::Skip
+            && id
+                .all_children()
+                .any(|e| matches!(e, Expression::Reference(r) if r.next));
+        match id {
+            Identity::Connect(_) if !skipped_on_last_row && !constrains_only_fixed_columns(id) => {
+                IdentitySupport::Unsupported(format!(
+                    "{:?} identities are not supported.",
+                    id.kind()
+                ))
+            }
+            _ if self.unsupported_reason(id).is_some() => IdentitySupport::ConditionallySupported,
+            _ => IdentitySupport::Supported,
+        }
+    }
+
+    /// Returns the identities that are not `Unsupported` according to `classify`
+    /// and records the others in the progress report, so that drivers do not
+    /// process them again in every round.
+    pub fn supported_identities<'b>(&mut self, ids: &'b [Identity<T>]) -> Vec<&'b Identity<T>> {
+        ids.iter()
+            .filter(|id| match self.classify(id) {
+                IdentitySupport::Unsupported(reason) => {
+                    log::debug!("Excluding identity {} from solving: {reason}", id.id());
+                    self.progress.unsupported_identities.insert(id.id(), reason);
+                    false
+                }
+                IdentitySupport::Supported | IdentitySupport::ConditionallySupported => true,
+            })
+            .collect()
+    }
+
+    /// Returns the reason why `process_identity` can never complete the identity, if any,
+    /// taking the machine semantics registered for lookups into account.
+    fn unsupported_reason(&self, id: &Identity<T>) -> Option<String> {
+        if let Some(reason) = self.later_stage_reason(id) {
+            return Some(reason);
+        }
+        match self.machine_semantics(id.id()) {
+            Some(MachineSemantics::PureFixedTable) => None,
+            Some(MachineSemantics::Stateful) => {
+                Some("Lookups into stateful machines are deferred.".to_string())
+            }
+            None => unsupported_reason(id),
+        }
+    }
+
+    /// Returns why the identity cannot be processed in the current stage,
+    /// if it references a later-stage witness column or a challenge that is not available.
+    fn later_stage_reason(&self, id: &Identity<T>) -> Option<String> {
+        let stage = self.stage();
+        id.all_children().find_map(|e| match e {
+            Expression::Reference(r) if r.is_witness() && self.column_stage(r) > stage.into() => {
+                Some(format!(
+                    "References the stage-{} column {}, but the code is generated for stage {stage}.",
+                    self.column_stage(r),
+                    r.name
+                ))
+            }
+            Expression::Challenge(c) if c.stage >= stage.into() => Some(format!(
+                "References the stage-{} challenge {}, which is not available in stage {stage}.",
+                c.stage, c.id
+            )),
+            _ => None,
+        })
+    }
+
+    fn process_lookup(
+        &self,
+        lookup_id: u64,
+        left: &SelectedExpressions<T>,
+        right: &SelectedExpressions<T>,
+        offset: i32,
+    ) -> ProcessResult<T, V> {
+        // If the lookup can always be answered, i.e. it is registered as a pure
+        // function or the RHS is fully fixed columns...
+        let answerable = match self.machine_semantics(lookup_id) {
+            Some(MachineSemantics::PureFixedTable) => true,
+            Some(MachineSemantics::Stateful) => false,
+            None => is_fixed_table(right),
+        };
+        if answerable {
+            // and the selector is known to be 1...
+            if self
+                .evaluate(&left.selector, offset)
+                .and_then(|s| s.try_to_known().map(|k| k.is_known_one()))
+                == Some(true)
+            {
+                if let Some(lhs) = left
+                    .expressions
+                    .iter()
+                    .map(|e| self.evaluate(e, offset))
+                    .collect::<Option<Vec<_>>>()
+                {
+                    // and each unknown expression on the LHS has a single unknown variable.
+                    // There can be multiple unknown expressions with distinct variables
+                    // if the known values determine the row of the table, so that a
+                    // single call determines all of them.
+                    let unknown_variables = lhs
+                        .iter()
+                        .filter(|e| e.try_to_known().is_none())
+                        .map(|e| e.single_unknown_variable())
+                        .collect::<Option<Vec<_>>>();
+                    let known = lhs.iter().map(|e| e.try_to_known().is_some()).collect_vec();
+                    if unknown_variables.is_some_and(|vars| {
+                        vars.len() == 1
+                            || (vars.len() > 1
+                                && vars.iter().all_unique()
+                                && self.table_determines_unknowns(lookup_id, right, &known))
+                    }) {
+                        // The unknown values are taken from the table, so they satisfy
+                        // the range constraints of the corresponding columns.
+                        let range_constraints = lhs
+                            .iter()
+                            .zip(&right.expressions)
+                            .filter(|(l, _)| l.try_to_known().is_none())
+                            .filter_map(|(l, r)| {
+                                let var = l.try_to_single_variable()?;
+                                Some(Effect::RangeConstraint(
+                                    var.clone(),
+                                    self.table_range_constraint(r)?,
+                                ))
+                            })
+                            .collect_vec();
+                        if let Some(effects) = self.inline_fixed_lookup(lookup_id, &lhs, right) {
+                            return ProcessResult::complete(
+                                effects.into_iter().chain(range_constraints).collect(),
+                            );
+                        }
+                        let effects = std::iter::once(Effect::machine_call(
+                            lookup_id,
+                            lhs.into_iter()
+                                .map(|e| {
+                                    if let Some(val) = e.try_to_known() {
+                                        MachineCallArgument::Known(val.clone())
+                                    } else {
+                                        MachineCallArgument::Unknown(e)
+                                    }
+                                })
+                                .collect(),
+                        ))
+                        .chain(range_constraints)
+                        .collect();
+                        return ProcessResult::complete(effects);
+                    }
+                }
+            }
+        }
+        ProcessResult::empty()
+    }
+
+    /// Returns true if the RHS of the lookup is a fixed table where every row is
+    /// identified by the values of the columns marked in `known`, i.e. the answer
+    /// to a lookup with these known values is unique.
+    fn table_determines_unknowns(
+        &self,
+        lookup_id: u64,
+        right: &SelectedExpressions<T>,
+        known: &[bool],
+    ) -> bool {
+        let key = (lookup_id, known.to_vec());
+        if let Some(determined) = self.determined_lookups.borrow().get(&key) {
+            return *determined;
+        }
+        let determined = known.contains(&true)
+            && is_fixed_table(right)
+            && self
+                .fixed_table_rows(right, usize::MAX)
+                .is_some_and(|rows| {
+                    rows.iter()
+                        .map(|row| {
+                            row.iter()
+                                .zip(known)
+                                .filter(|(_, known)| **known)
+                                .map(|(value, _)| value)
+                                .collect_vec()
+                        })
+                        .all_unique()
+                });
+        self.determined_lookups.borrow_mut().insert(key, determined);
+        determined
+    }
+
+    /// Turns a lookup with one known and one unknown expression on the LHS into
+    /// code that selects the unknown value from the table, if the RHS is a fixed
+    /// table that is small enough, see `WitgenInferenceConfig::with_inlined_fixed_tables`.
+    /// Returns `None` if the lookup cannot be inlined, e.g. because the
+    /// known value does not determine the unknown value.
+    fn inline_fixed_lookup(
+        &self,
+        lookup_id: u64,
+        lhs: &[AffineSymbolicExpression<T, V>],
+        right: &SelectedExpressions<T>,
+    ) -> Option<Vec<Effect<T, V>>> {
+        let max_rows = self.config.max_inlined_table_rows?;
+        let [first, second] = lhs else {
+            return None;
+        };
+        let (key, unknown, key_index) = match (first.try_to_known(), second.try_to_known()) {
+            (Some(key), None) => (key, second, 0),
+            (None, Some(key)) => (key, first, 1),
+            _ => return None,
+        };
+        let rows = self.fixed_table_rows(right, max_rows)?;
+        let mut table = BTreeMap::new();
+        for row in rows {
+            let (k, v) = (row[key_index], row[1 - key_index]);
+            if *table.entry(k).or_insert(v) != v {
+                return None;
+            }
+        }
+        let value = SymbolicExpression::select(key.clone(), table.into_iter().collect())?;
+        let result = (unknown - &value.clone().into()).solve().ok()?;
+        if !result.complete {
+            return None;
+        }
+        // Pass the computed variable instead of selecting the value a second time.
+        let value = match unknown.try_to_single_variable() {
+            Some(var) => SymbolicExpression::from_symbol(var.clone(), value.range_constraint()),
+            None => value,
+        };
+        let mut arguments = vec![key.clone(), value];
+        if key_index == 1 {
+            arguments.reverse();
+        }
+        Some(
+            result
+                .effects
+                .into_iter()
+                .chain(std::iter::once(Effect::StateWrite(lookup_id, arguments)))
+                .collect(),
+        )
+    }
+
+    /// Returns the rows of a lookup table consisting of constants and fixed columns,
+    /// restricted to the rows where the selector is one, or `None` if the
+    /// table has more than `max_rows` rows or is not a fixed table.
+    fn fixed_table_rows(
+        &self,
+        right: &SelectedExpressions<T>,
+        max_rows: usize,
+    ) -> Option<Vec<Vec<T>>> {
+        let column = |e: &Expression<T>| match e {
+            Expression::Reference(r) if r.is_fixed() && !r.next => {
+                Some(self.fixed_data.fixed_cols[&r.poly_id].values_max_size())
+            }
+            _ => None,
+        };
+        let size = right
+            .expressions
+            .iter()
+            .chain(std::iter::once(&right.selector))
+            .filter_map(column)
+            .map(|values| values.len())
+            .max()?;
+        if size > max_rows {
+            return None;
+        }
+        let value = |e: &Expression<T>, row: usize| match e {
+            Expression::Number(n) => Some(*n),
+            _ => column(e)?.get(row).copied(),
+        };
+        (0..size)
+            .filter_map(|row| match value(&right.selector, row) {
+                Some(s) if s.is_zero() => None,
+                Some(s) if s.is_one() => Some(
+                    right
+                        .expressions
+                        .iter()
+                        .map(|e| value(e, row))
+                        .collect::<Option<Vec<_>>>(),
+                ),
+                _ => Some(None),
+            })
+            .collect()
+    }
+
+    /// Processes a lookup of a single known expression into a fixed column whose
+    /// values are exactly the values allowed by a bit mask, e.g. `[x] in [BYTE]`.
+    /// Such a lookup is a pure range check, so instead of a machine call, it results
+    /// in an assertion, a state write that records the multiplicity and, if the
+    /// expression is a single variable, a range constraint.
+    /// Returns `None` if the lookup is not a range check of a known expression.
+    fn process_range_check_lookup(
+        &self,
+        lookup_id: u64,
+        left: &SelectedExpressions<T>,
+        right: &SelectedExpressions<T>,
+        offset: i32,
+    ) -> Option<ProcessResult<T, V>> {
+        let selectors_are_one = [&left.selector, &right.selector].iter().all(|s| {
+            self.evaluate(s, offset)
+                .and_then(|s| s.try_to_known().map(|k| k.is_known_one()))
+                == Some(true)
+        });
+        let ([value], [Expression::Reference(table)]) =
+            (&left.expressions[..], &right.expressions[..])
+        else {
+            return None;
+        };
+        if !selectors_are_one || !table.is_fixed() {
+            return None;
+        }
+        let value = self.evaluate(value, offset)?.try_to_known()?.clone();
+        let rc = self.table_range_constraint(&right.expressions[0])?;
+        let (min, max) = rc.range();
+        let mask = *rc.mask();
+        if !min.is_zero() || max.to_integer() != mask {
+            return None;
+        }
+        let table_values = self.fixed_data.fixed_cols[&table.poly_id].values_max_size();
+        let allowed = allowed_values(&rc, table_values.len())?;
+        let table_values: HashSet<_> = table_values.iter().collect();
+        if allowed.len() != table_values.len() || !allowed.iter().all(|v| table_values.contains(v))
+        {
+            return None;
+        }
+        let range_constraint = match &value {
+            SymbolicExpression::Symbol(var, _) => {
+                Some(Effect::RangeConstraint(var.clone(), rc.clone()))
+            }
+            _ => None,
+        };
+        let effects = [
+            Assertion::assert_eq(value.clone(), value.clone() & T::from(mask).into()),
+            Effect::StateWrite(lookup_id, vec![value]),
+        ]
+        .into_iter()
+        .chain(range_constraint)
+        .collect();
+        Some(ProcessResult::complete(effects))
+    }
+
+    /// Processes a lookup of a single expression into a fixed column whose values
+    /// form a contiguous range of integers, without calling the fixed lookup machine.
+    /// As long as the value is an unknown cell, the range is used as a range constraint
+    /// for it. Once it is known, it is range-checked and the multiplicity is recorded
+    /// using a state write.
+    fn process_contiguous_range_lookup(
+        &self,
+        lookup_id: u64,
+        left: &SelectedExpressions<T>,
+        right: &SelectedExpressions<T>,
+        offset: i32,
+    ) -> Option<ProcessResult<T, V>> {
+        let ([value], [Expression::Reference(table)]) =
+            (&left.expressions[..], &right.expressions[..])
+        else {
+            return None;
+        };
+        if !table.is_fixed() || table.next {
+            return None;
+        }
+        let selectors_are_one = [&left.selector, &right.selector].iter().all(|s| {
+            self.evaluate(s, offset)
+                .and_then(|s| s.try_to_known().map(|k| k.is_known_one()))
+                == Some(true)
+        });
+        if !selectors_are_one {
+            return None;
+        }
+        let rc = self.contiguous_range(table)?;
+        let value = self.evaluate(value, offset)?;
+        match value.try_to_known() {
+            Some(SymbolicExpression::Symbol(var, known_rc)) => {
+                // The check can be skipped if the value is already known to be in range.
+                let implied = known_rc
+                    .as_ref()
+                    .is_some_and(|known_rc| known_rc.conjunction(&rc) == *known_rc);
+                let range_check = (!implied).then(|| Effect::RangeCheck(var.clone(), rc.clone()));
+                Some(ProcessResult::complete(
+                    range_check
+                        .into_iter()
+                        .chain([
+                            Effect::RangeConstraint(var.clone(), rc),
+                            Effect::StateWrite(lookup_id, vec![value.try_to_known()?.clone()]),
+                        ])
+                        .collect(),
+                ))
+            }
+            Some(SymbolicExpression::Concrete(v)) if rc.allows_value(*v) => Some(
+                ProcessResult::complete(vec![Effect::StateWrite(lookup_id, vec![(*v).into()])]),
+            ),
+            Some(_) => None,
+            None => Some(ProcessResult {
+                effects: vec![Effect::RangeConstraint(
+                    value.try_to_single_variable()?.clone(),
+                    rc,
+                )],
+                complete: false,
+            }),
+        }
+    }
+
+    /// Returns the range of values of the fixed column if they are exactly the
+    /// integers from the minimum to the maximum value, each appearing once.
+    /// The result is cached per column.
+    fn contiguous_range(&self, column: &AlgebraicReference) -> Option<RangeConstraint<T>> {
+        if let Some(range) = self.contiguous_ranges.borrow().get(&column.poly_id) {
+            return range.clone();
+        }
+        let values = self.fixed_data.fixed_cols[&column.poly_id]
+            .values_max_size()
+            .iter()
+            .sorted()
+            .collect_vec();
+        let range = match (values.first(), values.last()) {
+            (Some(&&min), Some(&&max))
+                if values.windows(2).all(|w| *w[1] == *w[0] + T::from(1)) =>
+            {
+                Some(RangeConstraint::from_range(min, max))
+            }
+            _ => None,
+        };
+        self.contiguous_ranges
+            .borrow_mut()
+            .insert(column.poly_id, range.clone());
+        range
+    }
+
+    /// Processes a bus interaction with multiplicity `1` (send) or `-1` (receive)
+    /// on the given row.
+    /// A send is complete once the payload is known, it cannot solve any cells
+    /// since the payload is produced by this machine. It results in a state write,
+    /// which commits the payload to the receiver (e.g. a memory store).
+    /// A receive solves the unknown payload cells, since the payload is provided
+    /// by the sender. It results in a machine call that reads the payload.
+    // TODO(bus_interaction) Sends should be able to solve cells as well once we
+    // have a concept of "can_be_answered" for the receivers.
+    fn process_bus_interaction(
+        &self,
+        bus_interaction: &PhantomBusInteractionIdentity<T>,
+        offset: i32,
+    ) -> ProcessResult<T, V> {
+        let direction = BusDirection::of(&bus_interaction.multiplicity);
+        let Some(multiplicity) = self
+            .evaluate(&bus_interaction.multiplicity, offset)
+            .and_then(|m| m.try_to_known().cloned())
+        else {
+            return ProcessResult::empty();
+        };
+        let active = match direction {
+            BusDirection::Send => multiplicity.is_known_one(),
+            BusDirection::Receive => multiplicity.is_known_minus_one(),
+        };
+        if !active {
+            return ProcessResult::empty();
+        }
+        let Some(payload) = bus_interaction
+            .tuple
+            .0
+            .iter()
+            .map(|e| self.evaluate(e, offset))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return ProcessResult::empty();
+        };
+        let unknown = payload
+            .iter()
+            .filter(|e| e.try_to_known().is_none())
+            .map(|e| e.single_unknown_variable())
+            .collect::<Option<Vec<_>>>();
+        let solvable = match (direction, unknown) {
+            (BusDirection::Send, Some(unknown)) => unknown.is_empty(),
+            (BusDirection::Receive, Some(unknown)) => unknown.iter().all_unique(),
+            (_, None) => false,
+        };
+        if !solvable {
+            return ProcessResult::empty();
+        }
+        if direction == BusDirection::Send {
+            return ProcessResult::complete(vec![Effect::StateWrite(
+                bus_interaction.id,
+                payload
+                    .into_iter()
+                    .map(|e| e.try_to_known().unwrap().clone())
+                    .collect(),
+            )]);
+        }
+        ProcessResult::complete(vec![Effect::machine_call(
+            bus_interaction.id,
+            payload
+                .into_iter()
+                .map(|e| {
+                    if let Some(val) = e.try_to_known() {
+                        MachineCallArgument::Known(val.clone())
+                    } else {
+                        MachineCallArgument::Unknown(e)
+                    }
+                })
+                .collect(),
+        )])
+    }
+
+    fn ingest_effects(&mut self, identity_id: u64, row_offset: i32, effects: Vec<Effect<T, V>>) {
+        for e in effects {
+            tracing::debug!(identity_id, row = row_offset, effect = %e);
+            if let Some(logger) = &mut self.effect_logger {
+                logger(identity_id, row_offset, &e);
+            }
+            match &e {
+                Effect::Assignment(cell, assignment) if self.known_cells.contains(cell) => {
+                    // The cell was already determined, so instead of writing it a second
+                    // time, we check that the new value agrees with the existing one.
+                    let rc = self.range_constraint(cell.clone());
+                    match (
+                        assignment.try_to_number(),
+                        rc.as_ref().and_then(|rc| rc.try_to_single_value()),
+                    ) {
+                        (Some(value), Some(existing)) => {
+                            if value != existing {
+                                log::debug!(
+                                    "{cell} is already known to be {existing}, not {value}"
+                                );
+                                self.conflicts.push(RangeConflict {
+                                    cell: cell.clone(),
+                                    value,
+                                    range_constraint: rc.unwrap(),
+                                });
+                            }
+                        }
+                        _ => self.push_code(Assertion::assert_eq(
+                            SymbolicExpression::from_symbol(cell.clone(), rc),
+                            assignment.clone(),
+                        )),
+                    }
+                }
+                Effect::Assignment(cell, assignment) => {
+                    self.add_known_cell(cell.clone());
+                    if let Some(rc) = assignment.range_constraint() {
+                        // If the cell was determined to be a constant, we add this
+                        // as a range constraint, so we can use it in future evaluations.
+                        self.add_range_constraint(cell.clone(), rc);
+                    }
+                    self.push_code(e);
+                }
+                Effect::RangeConstraint(cell, rc) => {
+                    let before = self.derived_range_constraints.get(cell).cloned();
+                    self.add_range_constraint(cell.clone(), rc.clone());
+                    if self.config.input_range_checks && self.inputs.contains(cell) {
+                        let after = self.derived_range_constraints.get(cell).cloned();
+                        if let Some(rc) = after.filter(|after| Some(after) != before.as_ref()) {
+                            self.push_code(Effect::RangeCheck(cell.clone(), rc));
+                        }
+                    }
+                }
+                Effect::MachineCall(_, _, outputs) => {
+                    for cell in outputs {
+                        self.add_known_cell(cell.clone());
+                    }
+                    self.push_code(e);
+                }
+                Effect::Assertion(_)
+                | Effect::RangeCheck(..)
+                | Effect::StateWrite(..)
+                | Effect::Infeasible(_) => self.push_code(e),
+            }
+        }
+    }
+
+    fn push_code(&mut self, effect: Effect<T, V>) {
+        match &effect {
+            Effect::MachineCall(id, ..) => {
+                *self.machine_call_counts.entry(*id).or_default() += 1;
+                self.called_machines.insert(*id);
+            }
+            Effect::StateWrite(id, _) => *self.machine_call_counts.entry(*id).or_default() += 1,
+            _ => {}
+        }
+        self.code.push(effect);
+        self.code_rounds.push(self.round);
+    }
+
+    fn add_known_cell(&mut self, cell: V) {
+        if self.known_cells.insert(cell) && self.round > 0 {
+            self.progress.cells_solved_per_round[self.round - 1] += 1;
+        }
+    }
+
+    fn add_range_constraint(&mut self, cell: V, rc: RangeConstraint<T>) {
+        let _span = debug_span!("range_constraint_conjunction", cell = %cell).entered();
+        let rc = match self.range_constraint(cell.clone()) {
+            None => rc,
+            Some(existing_rc) => {
+                // If one of the constraints fixes the value, it has to be
+                // allowed by the other one.
+                let conflict = [(&rc, &existing_rc), (&existing_rc, &rc)]
+                    .into_iter()
+                    .find_map(|(value_rc, other)| {
+                        let value = value_rc.try_to_single_value()?;
+                        (!other.allows_value(value)).then(|| (value, other.clone()))
+                    });
+                if let Some((value, range_constraint)) = conflict {
+                    log::debug!(
+                        "Value {value} of {cell} violates range constraint {range_constraint}"
+                    );
+                    self.conflicts.push(RangeConflict {
+                        cell,
+                        value,
+                        range_constraint,
+                    });
+                    return;
+                }
+                existing_rc.conjunction(&rc)
+            }
+        };
+        if !self.known_cells.contains(&cell) {
+            if let Some(v) = rc.try_to_single_value() {
+                // Special case: Cell is fixed to a constant by range constraints only.
+                self.add_known_cell(cell.clone());
+                self.constant_cells.insert(cell.clone(), v);
+                self.push_code(Effect::Assignment(cell.clone(), v.into()));
+            }
+        }
+        self.derived_range_constraints.insert(cell.clone(), rc);
+    }
+
+    fn evaluate(
+        &self,
+        expr: &Expression<T>,
+        offset: i32,
+    ) -> Option<AffineSymbolicExpression<T, V>> {
+        self.evaluate_with_unknown(expr, offset, None)
+    }
+
+    /// Records that `evaluate` failed because of an expression of the given kind
+    /// and returns `None`, see `unsupported_report`.
+    fn unsupported<R>(&self, kind: UnsupportedKind) -> Option<R> {
+        *self
+            .unsupported_counts
+            .borrow_mut()
+            .entry(kind)
+            .or_default() += 1;
+        None
+    }
+
+    /// Evaluates the fixed column reference on the given row using the fixed evaluator,
+    /// or returns the cached value if the cell has been evaluated before.
+    fn evaluate_fixed(&self, r: &AlgebraicReference, offset: i32) -> Option<T> {
+        let key = (r.poly_id, offset + r.next as i32);
+        if let Some(value) = self.fixed_values.borrow().get(&key) {
+            return *value;
+        }
+        let value = self.fixed_evaluator.evaluate(r, offset);
+        self.fixed_values.borrow_mut().insert(key, value);
+        value
+    }
+
+    /// Evaluates the expression like `evaluate`, but treats the cell `unknown`
+    /// as unknown even if it is known.
+    fn evaluate_with_unknown(
+        &self,
+        expr: &Expression<T>,
+        offset: i32,
+        unknown: Option<&V>,
+    ) -> Option<AffineSymbolicExpression<T, V>> {
+        Some(match expr {
+            Expression::Reference(r) => {
+                if r.is_fixed() {
+                    match self.evaluate_fixed(r, offset) {
+                        Some(value) => value.into(),
+                        None => match self.config.unresolved_fixed {
+                            UnresolvedFixed::Skip => {
+                                return self.unsupported(UnsupportedKind::UnresolvedFixedColumn)
+                            }
+                            UnresolvedFixed::Symbolic => {
+                                AffineSymbolicExpression::from_known_symbol(
+                                    self.resolve(r, offset),
+                                    None,
+                                )
+                            }
+                            UnresolvedFixed::Error => {
+                                log::debug!("Could not evaluate fixed column {r} on row {offset}.");
+                                self.unresolved_fixed.borrow_mut().get_or_insert_with(|| {
+                                    (r.name.clone(), offset + r.next as i32)
+                                });
+                                return None;
+                            }
+                        },
+                    }
+                } else if !r.is_witness() {
+                    // TODO we need to inline intermediate columns.
+                    return self.unsupported(UnsupportedKind::IntermediateColumn);
+                } else if self.column_stage(r) > self.stage().into() {
+                    // The cell can only be solved in a later stage.
+                    return self.unsupported(UnsupportedKind::LaterStageColumn);
+                } else {
+                    let cell = self.resolve(r, offset);
+                    // If a cell is known and has a compile-time constant value,
+                    // that value is stored in the range constraints.
+                    let rc = self.range_constraint(cell.clone());
+                    if let Some(val) = rc.as_ref().and_then(|rc| rc.try_to_single_value()) {
+                        val.into()
+                    } else if self.known_cells.contains(&cell) && Some(&cell) != unknown {
+                        AffineSymbolicExpression::from_known_symbol(cell, rc)
+                    } else {
+                        AffineSymbolicExpression::from_unknown_variable(cell, rc)
+                    }
+                }
+            }
+            Expression::PublicReference(name) => {
+                let Some(variable) = self.publics.get(name) else {
+                    return self.unsupported(UnsupportedKind::PublicReference);
+                };
+                AffineSymbolicExpression::from_known_symbol(
+                    variable.clone(),
+                    self.range_constraint(variable.clone()),
+                )
+            }
+            Expression::Challenge(c) => match self.fixed_data.challenges.get(&c.id) {
+                Some(value) if c.stage < self.stage().into() => (*value).into(),
+                _ => return self.unsupported(UnsupportedKind::Challenge),
+            },
+            Expression::Number(n) => (*n).into(),
+            Expression::BinaryOperation(op) => {
+                self.evaluate_binary_operation(op, offset, unknown)?
+            }
+            Expression::UnaryOperation(op) => self.evaluate_unary_operation(op, offset, unknown)?,
+        })
+    }
+
+    fn evaluate_binary_operation(
+        &self,
+        op: &AlgebraicBinaryOperation<T>,
+        offset: i32,
+        unknown: Option<&V>,
+    ) -> Option<AffineSymbolicExpression<T, V>> {
+        if matches!(
+            op.op,
+            AlgebraicBinaryOperator::Add | AlgebraicBinaryOperator::Sub
+        ) {
+            return self.evaluate_sum(op, offset, unknown);
+        }
+        let left = self.evaluate_with_unknown(&op.left, offset, unknown);
+        if op.op == AlgebraicBinaryOperator::Mul && left.as_ref().is_some_and(is_known_zero) {
+            // The product is zero, no matter if the right side can be evaluated.
+            return left;
+        }
+        let right = self.evaluate_with_unknown(&op.right, offset, unknown);
+        if op.op == AlgebraicBinaryOperator::Mul && right.as_ref().is_some_and(is_known_zero) {
+            return right;
+        }
+        let (left, right) = (left?, right?);
+        match op.op {
+            AlgebraicBinaryOperator::Add | AlgebraicBinaryOperator::Sub => unreachable!(),
+            AlgebraicBinaryOperator::Mul => left.try_mul(&right),
+            AlgebraicBinaryOperator::Pow => {
+                let (Some(base), Some(exponent)) = (
+                    left.try_to_known().and_then(|l| l.try_to_number()),
+                    right.try_to_known().and_then(|r| r.try_to_number()),
+                ) else {
+                    return self.unsupported(UnsupportedKind::NonConstantPower);
+                };
+                if base.is_zero()
+                    && exponent.is_zero()
+                    && self.config.pow_zero_zero == PowZeroZero::Error
+                {
+                    log::debug!("Cannot evaluate 0**0 on row {offset}.");
+                    return None;
+                }
+                Some(AffineSymbolicExpression::from(
+                    base.pow(exponent.to_integer()),
+                ))
+            }
+        }
+    }
+
+    /// Evaluates a chain of additions and subtractions. The chain is flattened
+    /// first and the summands are accumulated into a single expression, with
+    /// all numbers summed up separately, instead of creating a new expression
+    /// for every operation.
+    /// Subtracted sums are evaluated as a whole, so that their negation is
+    /// kept in the generated code.
+    fn evaluate_sum(
+        &self,
+        op: &AlgebraicBinaryOperation<T>,
+        offset: i32,
+        unknown: Option<&V>,
+    ) -> Option<AffineSymbolicExpression<T, V>> {
+        let mut result = AffineSymbolicExpression::from(T::zero());
+        let mut constant = T::zero();
+        // The summands still to process, together with a flag if they are subtracted.
+        let mut summands = vec![
+            (op.right.as_ref(), op.op == AlgebraicBinaryOperator::Sub),
+            (op.left.as_ref(), false),
+        ];
+        while let Some((expr, negated)) = summands.pop() {
+            match expr {
+                Expression::BinaryOperation(AlgebraicBinaryOperation {
+                    left,
+                    op: op @ (AlgebraicBinaryOperator::Add | AlgebraicBinaryOperator::Sub),
+                    right,
+                }) if !negated => {
+                    summands.push((right.as_ref(), *op == AlgebraicBinaryOperator::Sub));
+                    summands.push((left.as_ref(), false));
+                }
+                _ => {
+                    let value = self.evaluate_with_unknown(expr, offset, unknown)?;
+                    match value.try_to_known().and_then(|k| k.try_to_number()) {
+                        Some(n) if negated => constant -= n,
+                        Some(n) => constant += n,
+                        None if negated => result -= &value,
+                        None => result += &value,
+                    }
+                }
+            }
+        }
+        result += &AffineSymbolicExpression::from(constant);
+        Some(result)
+    }
+
+    fn evaluate_unary_operation(
+        &self,
+        op: &AlgebraicUnaryOperation<T>,
+        offset: i32,
+        unknown: Option<&V>,
+    ) -> Option<AffineSymbolicExpression<T, V>> {
+        let expr = self.evaluate_with_unknown(&op.expr, offset, unknown)?;
+        match op.op {
+            AlgebraicUnaryOperator::Minus => Some(-&expr),
+        }
+    }
+
+    /// Returns the range constraint on the values of a column of a fixed lookup table.
+    fn table_range_constraint(&self, expr: &Expression<T>) -> Option<RangeConstraint<T>> {
+        match expr {
+            Expression::Reference(r) => {
+                self.fixed_data
+                    .global_range_constraints
+                    .range_constraint(&AlgebraicReference {
+                        next: false,
+                        ..r.clone()
+                    })
+            }
+            Expression::Number(n) => Some(RangeConstraint::from_value(*n)),
+            _ => None,
+        }
+    }
+
+    /// Returns the current best-known range constraint on the given cell
+    /// combining global range constraints and newly derived local range constraints.
+    fn range_constraint(&self, cell: V) -> Option<RangeConstraint<T>> {
+        self.global_range_constraints
+            .get(&cell)
+            .into_iter()
+            .chain(self.derived_range_constraints.get(&cell))
+            .cloned()
+            .reduce(|gc, rc| gc.conjunction(&rc))
+    }
+}
+
+/// The estimated cost of a machine call, in addition to the cost of its arguments.
+const MACHINE_CALL_COST: usize = 10;
+
+/// A problem with the cells written by a program, see `verify_outputs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputProblem<V = Cell> {
+    /// A required output is not assigned.
+    Missing(V),
+    /// The cell is assigned more than once.
+    AssignedTwice(V),
+    /// The cell is assigned, but it is neither a required output nor read by the
+    /// program. This is informational, e.g. for intermediate cells of a block.
+    Unused(V),
+}
+
+impl<V> OutputProblem<V> {
+    /// Returns true if the program cannot be used because of this problem.
+    pub fn is_error(&self) -> bool {
+        match self {
+            OutputProblem::Missing(_) | OutputProblem::AssignedTwice(_) => true,
+            OutputProblem::Unused(_) => false,
+        }
+    }
+}
+
+impl<V: Display> Display for OutputProblem<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputProblem::Missing(v) => write!(f, "The output {v} is not assigned."),
+            OutputProblem::AssignedTwice(v) => write!(f, "{v} is assigned more than once."),
+            OutputProblem::Unused(v) => write!(f, "{v} is assigned, but never used."),
+        }
+    }
+}
+
+/// Checks that every cell written by `code` (by an assignment or as the output of
+/// a machine call) is written exactly once and that all `required_outputs` are
+/// written. Cells that are written but neither required nor read by the code
+/// are reported as informational problems, see `OutputProblem::is_error`.
+/// Returns all problems (missing outputs first, in the order of
+/// `required_outputs`, then the others in the order of the code), if there are any.
+pub fn verify_outputs<T: FieldElement, V: Clone + Ord + Hash + Display>(
+    code: &[Effect<T, V>],
+    required_outputs: &[V],
+) -> Result<(), Vec<OutputProblem<V>>> {
+    let mut written = vec![];
+    let mut read = HashSet::new();
+    for effect in code {
+        let (reads, writes) = reads_and_writes(effect);
+        read.extend(reads);
+        written.extend(writes);
+    }
+    let required: HashSet<_> = required_outputs.iter().collect();
+    let write_counts = written.iter().counts();
+    let problems = required_outputs
+        .iter()
+        .filter(|v| !write_counts.contains_key(v))
+        .map(|v| OutputProblem::Missing(v.clone()))
+        .chain(written.iter().unique().filter_map(|v| {
+            if write_counts[v] > 1 {
+                Some(OutputProblem::AssignedTwice((*v).clone()))
+            } else if !required.contains(v) && !read.contains(v) {
+                Some(OutputProblem::Unused((*v).clone()))
+            } else {
+                None
+            }
+        }))
+        .collect_vec();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+/// Returns an estimate of the cost of running the code, which is the number
+/// of operations in its expressions plus a fixed cost for every machine call.
+/// Range constraints are free since no code is generated for them.
+pub fn code_cost<T: FieldElement, V>(code: &[Effect<T, V>]) -> usize {
+    code.iter()
+        .map(|effect| match effect {
+            Effect::Assignment(_, value) => 1 + expression_cost(value),
+            Effect::RangeConstraint(..) => 0,
+            Effect::RangeCheck(..) => 1,
+            Effect::Assertion(Assertion { lhs, rhs, .. }) => {
+                1 + expression_cost(lhs) + expression_cost(rhs)
+            }
+            Effect::MachineCall(_, arguments, _) => {
+                MACHINE_CALL_COST
+                    + arguments
+                        .iter()
+                        .map(|argument| match argument {
+                            MachineCallArgument::Known(k) => expression_cost(k),
+                            MachineCallArgument::Unknown(_) => 1,
+                        })
+                        .sum::<usize>()
+            }
+            Effect::StateWrite(_, arguments) => {
+                MACHINE_CALL_COST + arguments.iter().map(expression_cost).sum::<usize>()
+            }
+            Effect::Infeasible(_) => 1,
+        })
+        .sum()
+}
+
+/// Depth-first search for a cycle in `dependencies` reachable from `var`, see
+/// `WitgenInference::validate_acyclic`. The variables on the current path are in `path`.
+fn find_cycle<'b, V: Ord + Hash + Eq + Clone>(
+    var: &'b V,
+    dependencies: &BTreeMap<&'b V, Vec<&'b V>>,
+    finished: &mut HashSet<&'b V>,
+    path: &mut Vec<&'b V>,
+) -> Result<(), Vec<V>> {
+    if let Some(start) = path.iter().position(|v| *v == var) {
+        return Err(path[start..].iter().map(|v| (*v).clone()).collect());
+    }
+    if finished.contains(var) {
+        return Ok(());
+    }
+    path.push(var);
+    for &dependency in dependencies.get(var).into_iter().flatten() {
+        find_cycle(dependency, dependencies, finished, path)?;
+    }
+    path.pop();
+    finished.insert(var);
+    Ok(())
+}
+
+/// Returns the variables read and the variables written by the effect at runtime.
+fn reads_and_writes<T: FieldElement, V: Clone + Ord + Display>(
+    effect: &Effect<T, V>,
+) -> (Vec<&V>, Vec<&V>) {
+    match effect {
+        Effect::Assignment(var, expr) => (expr.referenced_symbols().collect(), vec![var]),
+        Effect::RangeConstraint(..) | Effect::Infeasible(_) => (vec![], vec![]),
+        Effect::RangeCheck(var, _) => (vec![var], vec![]),
+        Effect::Assertion(Assertion { lhs, rhs, .. }) => (
+            lhs.referenced_symbols()
+                .chain(rhs.referenced_symbols())
+                .collect(),
+            vec![],
+        ),
+        Effect::MachineCall(_, arguments, outputs) => (
+            arguments
+                .iter()
+                .flat_map(|argument| match argument {
+                    MachineCallArgument::Known(expr) => expr.referenced_symbols().collect(),
+                    MachineCallArgument::Unknown(_) => vec![],
+                })
+                .collect(),
+            outputs.iter().collect(),
+        ),
+        Effect::StateWrite(_, arguments) => (
+            arguments
+                .iter()
+                .flat_map(|arg| arg.referenced_symbols())
+                .collect(),
+            vec![],
+        ),
+    }
+}
+
+/// The number of operations in the expression, where symbols and constants are free.
+fn expression_cost<T: FieldElement, V>(e: &SymbolicExpression<T, V>) -> usize {
+    match e {
+        SymbolicExpression::Concrete(_) | SymbolicExpression::Symbol(..) => 0,
+        SymbolicExpression::BinaryOperation(left, _, right, _) => {
+            1 + expression_cost(left) + expression_cost(right)
+        }
+        SymbolicExpression::UnaryOperation(_, inner, _) => 1 + expression_cost(inner),
+        // One comparison per entry of the table.
+        SymbolicExpression::Select(key, table, _) => table.len() + expression_cost(key),
+    }
+}
+
+/// Returns the values allowed by the range constraint, unless there are more than `limit`.
+fn allowed_values<T: FieldElement>(rc: &RangeConstraint<T>, limit: usize) -> Option<Vec<T>> {
+    let width = rc.range_width().try_into_u64()?;
+    if width > limit as u64 {
+        return None;
+    }
+    let (min, _) = rc.range();
+    Some(
+        (0..width)
+            .map(|i| min + T::from(i))
+            .filter(|v| rc.allows_value(*v))
+            .collect(),
+    )
+}
+
+/// Returns true if the expression is known to be zero at compile time.
+fn is_known_zero<T: FieldElement, V: Ord + Clone + Display>(
+    e: &AffineSymbolicExpression<T, V>,
+) -> bool {
+    e.try_to_known().is_some_and(|k| k.is_known_zero())
+}
+
+/// Returns the reason why `process_identity` can never complete the identity, if any.
+fn unsupported_reason<T>(id: &Identity<T>) -> Option<String> {
+    match id {
+        Identity::Polynomial(_) => None,
+        Identity::Lookup(LookupIdentity { right, .. })
+        | Identity::Permutation(PermutationIdentity { right, .. })
+        | Identity::PhantomPermutation(PhantomPermutationIdentity { right, .. })
+        | Identity::PhantomLookup(PhantomLookupIdentity { right, .. }) => {
+            if is_fixed_table(right) {
+                None
+            } else if right
+                .expressions
+                .iter()
+                .any(|e| matches!(e, Expression::Reference(r) if r.is_fixed() && r.next))
+            {
+                Some("Lookups into shifted fixed columns are not supported.".to_string())
+            } else {
+                Some("Only lookups into fixed columns are supported.".to_string())
+            }
+        }
+        Identity::PhantomBusInteraction(_) => None,
+        Identity::Connect(_) => Some(format!("{:?} identities are not supported.", id.kind())),
+    }
+}
+
+/// Returns true if the right-hand side of a lookup only consists of constants and
+/// unshifted fixed columns, i.e. it can be answered by the fixed lookup machine.
+/// Transition tables have to be given as separate columns like `[x, x'] in [FROM, TO]`,
+/// since the tuples of `[T, T']` span two rows of the table.
+fn is_fixed_table<T>(right: &SelectedExpressions<T>) -> bool {
+    right.expressions.iter().all(|e| match e {
+        Expression::Reference(r) => r.is_fixed() && !r.next,
+        Expression::Number(_) => true,
+        _ => false,
+    })
+}
+
+/// Returns true if the identity is a polynomial identity or a lookup that only
+/// references fixed columns and constants, i.e. a consistency check on the fixed
+/// columns that is irrelevant for witness generation.
+fn constrains_only_fixed_columns<T>(id: &Identity<T>) -> bool {
+    match id {
+        Identity::Polynomial(_)
+        | Identity::Lookup(_)
+        | Identity::Permutation(_)
+        | Identity::PhantomPermutation(_)
+        | Identity::PhantomLookup(_) => id.all_children().all(|e| match e {
+            Expression::Reference(r) => r.is_fixed(),
+            Expression::PublicReference(_) | Expression::Challenge(_) => false,
+            _ => true,
+        }),
+        Identity::PhantomBusInteraction(_) | Identity::Connect(_) => false,
+    }
+}
+
+/// Returns true if `process_identity` can process identities of this kind.
+fn is_supported<T>(id: &Identity<T>) -> bool {
+    match id {
+        Identity::Polynomial(_)
+        | Identity::Lookup(_)
+        | Identity::Permutation(_)
+        | Identity::PhantomPermutation(_)
+        | Identity::PhantomLookup(_)
+        | Identity::PhantomBusInteraction(_) => true,
+        Identity::Connect(_) => false,
+    }
+}
+
+/// Returns true if the expression is a negative number or a product with an odd number of
+/// negative numbers or negations, see `BusDirection::of`.
+fn is_negative<T: FieldElement>(e: &Expression<T>) -> bool {
+    match e {
+        Expression::Number(n) => !n.is_in_lower_half(),
+        Expression::UnaryOperation(AlgebraicUnaryOperation {
+            op: AlgebraicUnaryOperator::Minus,
+            expr,
+        }) => !is_negative(expr),
+        Expression::BinaryOperation(AlgebraicBinaryOperation {
+            left,
+            op: AlgebraicBinaryOperator::Mul,
+            right,
+        }) => is_negative(left) != is_negative(right),
+        _ => false,
+    }
+}
+
+pub trait FixedEvaluator<T: FieldElement> {
+    /// Evaluates the fixed column reference in an identity processed on `row_offset`.
+    /// Implementations have to take `var.next` into account themselves, i.e. the
+    /// value is taken from row `row_offset + 1` for next references, in the same
+    /// way as `Cell::from_reference` does for witness columns.
+    fn evaluate(&self, _var: &AlgebraicReference, _row_offset: i32) -> Option<T> {
+        None
+    }
+
+    /// The size of the machine instance the fixed values are taken from, if known.
+    fn degree(&self) -> Option<DegreeType> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::BTreeMap;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    use powdr_ast::analyzed::Analyzed;
+    use powdr_number::{BabyBearField, GoldilocksField};
+    use powdr_parser_util::SourceRef;
+
+    use crate::{
+        constant_evaluator,
+        witgen::{
+            global_constraints,
+            jit::{
+                cell::CellNaming,
+                code_parser::{parse_streamed_code, ParseError},
+                fixed_evaluator::{Boundary, FixedEvaluatorForFixedData},
+                interpreter::{EffectInterpreter, InterpreterError},
+                symbolic_expression::NumberFormat,
+                test_util::{
+                    algebraic_expression, assert_snapshot, column_cell, count_allocations,
+                    expression_fixture, find_cell, format_code, format_code_with_naming,
+                    format_code_with_number_format, instance_degree, solve_block_on,
+                    solve_in_field_on_rows_and_then, solve_on_rows, solve_on_rows_and_then,
+                    solve_xor, witgen_for, FixedDataBuilder, MockCallHandler, SpanCollector,
+                    XOR_INPUT,
+                },
+            },
+            FixedData,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn simple_polynomial_solving() {
+        let input = "let X; let Y; let Z; X = 1; Y = X + 1; Z * Y = X + 10;";
+        let code = solve_on_rows(input, &[0], vec![], None);
+        assert_eq!(code, "X[0] = 1;\nY[0] = 2;\nZ[0] = -9223372034707292155;");
+    }
+
+    #[test]
+    fn process_identity_where() {
+        let input = "
+        namespace N(8);
+            col fixed FIRST = [1] + [0]*;
+            let X;
+            FIRST * (X - 5) = 0;
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 8, vec![]);
+        let identity = &fixed_data.analyzed.identities[0];
+        let Identity::Polynomial(PolynomialIdentity { expression, .. }) = identity else {
+            panic!()
+        };
+        let Expression::BinaryOperation(AlgebraicBinaryOperation { left: gate, .. }) = expression
+        else {
+            panic!()
+        };
+        let rows = (0..8).collect_vec();
+        assert_eq!(
+            witgen.process_identity_where(identity, &rows, gate),
+            vec![(0, ProcessStatus::Complete)]
+        );
+        assert_eq!(format_code(&witgen.code()), "N::X[0] = 5;");
+    }
+
+    #[test]
+    fn process_with_writer() {
+        let input = "
+        namespace N(8);
+            let X;
+            let Y;
+            let Z;
+            Y = X * 2;
+            Z = Y + X;
+        ";
+        let rows = (0..4).collect_vec();
+        let new_inference =
+            || witgen_for(input, 8, rows.iter().map(|row| ("N::X", *row)).collect());
+
+        let (mut streaming, fixed_data) = new_inference();
+        let ids = &fixed_data.analyzed.identities;
+        let mut stream = vec![];
+        for id in ids {
+            let statuses = streaming
+                .process_with_writer(id, &rows, &mut stream)
+                .unwrap();
+            assert!(statuses.iter().all(|(_, status)| status.is_complete()));
+        }
+        assert!(streaming.code_so_far().is_empty());
+
+        let (mut in_memory, _) = new_inference();
+        for id in ids {
+            for row in &rows {
+                in_memory.process_identity(id, *row);
+            }
+        }
+        let code = in_memory.code();
+        assert_eq!(code.len(), 8);
+        let streamed = parse_streamed_code::<GoldilocksField>(&stream).unwrap();
+        assert_eq!(streamed, code);
+
+        let Err(error) = parse_streamed_code::<GoldilocksField>(&stream[..stream.len() - 1]) else {
+            panic!("Expected the truncated stream to be rejected.");
+        };
+        assert_eq!(
+            error,
+            ParseError {
+                line: 8,
+                message: "Truncated record".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn validate_acyclic() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let fib = solve_on_rows_and_then(
+            input,
+            &[0, 1, 2],
+            vec![("X", 0), ("Y", 0)],
+            None,
+            |witgen| {
+                witgen.validate_acyclic().unwrap();
+                format_code(&witgen.code())
+            },
+        );
+        assert_eq!(fib.lines().count(), 6);
+
+        let input = "let X; let Y; let Z; Y = X + 1;";
+        let cycle = solve_on_rows_and_then(input, &[0], vec![("X", 0)], None, |mut witgen| {
+            let [x, y, z] = ["X", "Y", "Z"].map(|name| {
+                Cell::new(
+                    name,
+                    witgen.fixed_data.try_column_by_name(name).unwrap().id,
+                    0,
+                )
+            });
+            let symbol = |cell: &Cell| SymbolicExpression::from_symbol(cell.clone(), None);
+            witgen.code = vec![
+                Effect::Assignment(z.clone(), symbol(&x) + GoldilocksField::from(1).into()),
+                Effect::Assignment(y.clone(), symbol(&z) * symbol(&z)),
+                Effect::Assignment(x.clone(), symbol(&y)),
+            ];
+            witgen.validate_acyclic().unwrap_err()
+        });
+        assert_eq!(
+            cycle.iter().map(|cell| cell.to_string()).collect_vec(),
+            ["Z[0]", "X[0]", "Y[0]"]
+        );
+    }
+
+    #[test]
+    fn verify_outputs() {
+        let input = "let X; let Y; let Z; Y = X + 1; Z = Y * 2;";
+        let code =
+            solve_on_rows_and_then(input, &[0, 1], vec![("X", 0), ("X", 1)], None, |witgen| {
+                witgen.code()
+            });
+        let [z0, y1, z1] = ["Z[0]", "Y[1]", "Z[1]"].map(|name| {
+            code.iter()
+                .find_map(|e| match e {
+                    Effect::Assignment(cell, _) if cell.to_string() == name => Some(cell.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        });
+        let outputs = [z0.clone(), z1.clone()];
+        assert_eq!(super::verify_outputs(&code, &outputs), Ok(()));
+
+        // The output on the latch row is missing, so `Y[1]` is not used anymore.
+        let without_latch_output = code
+            .iter()
+            .filter(|e| !matches!(e, Effect::Assignment(cell, _) if *cell == z1))
+            .cloned()
+            .collect_vec();
+        assert_eq!(
+            super::verify_outputs(&without_latch_output, &outputs),
+            Err(vec![OutputProblem::Missing(z1), OutputProblem::Unused(y1)])
+        );
+
+        let mut with_duplicate = code.clone();
+        let duplicate = with_duplicate
+            .iter()
+            .find(|e| matches!(e, Effect::Assignment(cell, _) if *cell == z0))
+            .unwrap()
+            .clone();
+        with_duplicate.push(duplicate);
+        let problems = super::verify_outputs(&with_duplicate, &outputs).unwrap_err();
+        assert_eq!(problems, vec![OutputProblem::AssignedTwice(z0)]);
+        assert_eq!(problems[0].to_string(), "Z[0] is assigned more than once.");
+        assert!(problems[0].is_error());
+    }
+
+    #[test]
+    fn number_format() {
+        let input = "let X; let Y; let Z; X = 1; Y = X + 1; Z * Y = X + 10;";
+        let code = solve_on_rows_and_then(input, &[0], vec![], None, |witgen| witgen.code());
+        assert_eq!(
+            format_code_with_number_format(&code, NumberFormat::Balanced),
+            "X[0] = 1;\nY[0] = 2;\nZ[0] = -9223372034707292155;"
+        );
+        assert_eq!(
+            format_code_with_number_format(&code, NumberFormat::Unsigned),
+            "X[0] = 1;\nY[0] = 2;\nZ[0] = 9223372034707292166;"
+        );
+    }
+
+    #[test]
+    fn multi_term_symbolic_assignment() {
+        let input = "let X; let A; let B; X = 2 * A + 3 * B;";
+        let code = solve_on_rows(input, &[0], vec![("A", 0), ("B", 0)], None);
+        assert_eq!(code, "X[0] = ((2 * A[0]) + (3 * B[0]));");
+        // The unknown has a coefficient and is on the same side as a known symbol.
+        let input = "let X; let A; let B; 2 * X + A = 3 * B;";
+        let code = solve_on_rows(input, &[0], vec![("A", 0), ("B", 0)], None);
+        assert_eq!(code, "X[0] = ((A[0] + -(3 * B[0])) / -2);");
+    }
+
+    #[test]
+    fn fib() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let code = solve_on_rows(input, &[0, 1], vec![("X", 0), ("Y", 0)], None);
+        assert_snapshot("fib", &code);
+    }
+
+    #[test]
+    fn fib_critical_path_length() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let length = solve_on_rows_and_then(
+            input,
+            &[0, 1, 2, 3],
+            vec![("X", 0), ("Y", 0)],
+            None,
+            |witgen| witgen.critical_path_length(),
+        );
+        // `Y[k + 1] = X[k] + Y[k]` depends on `Y[k]`, while `X[k + 1] = Y[k]` can run
+        // in parallel to the computation of `Y[k + 1]`.
+        assert_eq!(length, 4);
+    }
+
+    #[test]
+    fn next_reference_to_known_cell() {
+        // `X'` on row 3 is the known cell `X[4]`, in both identities and lookups.
+        let input = "let X; let Y; Y = X' + 1;";
+        let code = solve_on_rows(input, &[3], vec![("X", 4)], None);
+        assert_eq!(code, "Y[3] = (X[4] + 1);");
+        let input = "let X; let Y; Y' = X + 1;";
+        let code = solve_on_rows(input, &[2], vec![("X", 2)], None);
+        assert_eq!(code, "Y[3] = (X[2] + 1);");
+        let input = "namespace N(8); col fixed F = [0, 1]*; col fixed G = [1, 2]*; let X; let Y; [ X', Y ] in [ F, G ];";
+        let code = solve_on_rows(input, &[3], vec![("N::X", 4)], None);
+        assert_eq!(code, "lookup(0, [Known(N::X[4]), Unknown(N::Y[3])]);");
+    }
+
+    #[test]
+    fn budgeted_fib() {
+        let input = "namespace Fib(8); let X; let Y; X' = Y; Y' = X + Y;";
+        let run = |budgets: &[Budget]| {
+            let (mut witgen, fixed_data) = witgen_for(input, 8, vec![("Fib::X", 0), ("Fib::Y", 0)]);
+            let ids = &fixed_data.analyzed.identities;
+            let progress = budgets
+                .iter()
+                .map(|budget| witgen.run_budgeted(ids, &[0, 1, 2], *budget))
+                .collect_vec();
+            (progress, format_code(&witgen.code()))
+        };
+        let (progress, code) = run(&[Budget::Unlimited]);
+        assert_eq!(progress, vec![SolveProgress::Done]);
+        assert_eq!(code.lines().count(), 6);
+        assert_eq!(
+            run(&[Budget::Effects(3), Budget::Unlimited]),
+            (
+                vec![SolveProgress::Paused, SolveProgress::Done],
+                code.clone()
+            )
+        );
+        assert_eq!(
+            run(&[Budget::Attempts(1), Budget::Attempts(1), Budget::Unlimited]),
+            (
+                vec![
+                    SolveProgress::Paused,
+                    SolveProgress::Paused,
+                    SolveProgress::Done
+                ],
+                code
+            )
+        );
+    }
+
+    #[test]
+    fn known_zero_factor() {
+        // `X * Y` cannot be evaluated, but the selector is zero.
+        let input = "namespace N(4); col fixed S = [0]*; let X; let Y; S * (X * Y - 1) = 0;";
+        let code = solve_on_rows(input, &[0], vec![], Some(1));
+        assert_eq!(code, "");
+        let input = "namespace N(4); let S; let X; let Y; S = 0; (X * Y - 1) * S = 0;";
+        let code = solve_on_rows(input, &[0], vec![], Some(2));
+        assert_eq!(code, "N::S[0] = 0;");
+    }
+
+    #[test]
+    fn implied_by_global_constraints() {
+        let input = "
+        namespace N(8);
+            col fixed BITS3 = [0, 1, 2, 3, 4, 5, 6, 7];
+            col fixed F = [0, 1, 2, 3, 4, 5, 6, 8];
+            let X;
+            let Y;
+            let Z;
+            [ X ] in [ BITS3 ];
+            Y * (Y - 1) = 0;
+            [ Z ] in [ F ];
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let (fixed_data, _) =
+            global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        let ids = &analyzed.identities;
+        assert_eq!(witgen.process_identity(&ids[0], 0), ProcessStatus::Complete);
+        assert_eq!(witgen.process_identity(&ids[1], 0), ProcessStatus::Complete);
+        assert!(witgen.code_so_far().is_empty());
+        // The global range constraint of `Z` is a mask, which allows values
+        // that are not in `F`, so the lookup still has to be performed.
+        witgen.process_identity(&ids[2], 0);
+        assert_eq!(
+            format_code(witgen.code_so_far()),
+            "lookup(2, [Unknown(N::Z[0])]);"
+        );
+    }
+
+    #[test]
+    fn range_check_lookup() {
+        let input = "
+        namespace N(256);
+            col fixed BYTE(i) { i & 0xff };
+            col fixed F(i) { i % 200 };
+            let X;
+            let Y;
+            [ X ] in [ BYTE ];
+            [ X + Y ] in [ BYTE ];
+            [ X ] in [ F ];
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        // Only derive the constraints of the fixed columns, so that the range
+        // constraint on `X` has to come from the lookup.
+        let (fixed_data, _) = global_constraints::set_global_constraints(fixed_data, &[]);
+        let [x, y] = ["N::X", "N::Y"]
+            .map(|name| Cell::new(name, fixed_data.try_column_by_name(name).unwrap().id, 0));
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 256);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, [x.clone(), y]);
+        let ids = &analyzed.identities;
+        for id in &ids[..2] {
+            assert_eq!(witgen.process_identity(id, 0), ProcessStatus::Complete);
+        }
+        assert_eq!(
+            witgen.range_constraint(x),
+            Some(RangeConstraint::from_mask(0xffu64))
+        );
+        // `F` does not contain all values allowed by its mask, so this is not a range check.
+        assert_eq!(
+            witgen.process_identity(&ids[2], 0),
+            ProcessStatus::NoProgress
+        );
+        assert_eq!(
+            format_code(&witgen.code()),
+            "assert N::X[0] == (N::X[0] & 255);\n\
+             state_write(0, [N::X[0]]);\n\
+             assert (N::X[0] + N::Y[0]) == ((N::X[0] + N::Y[0]) & 255);\n\
+             state_write(1, [(N::X[0] + N::Y[0])]);"
+        );
+    }
+
+    #[test]
+    fn phantom_range_check_lookup() {
+        let input = "
+        namespace N(256);
+            col fixed BYTE(i) { i & 0xff };
+            let X;
+            let m;
+            Constr::PhantomLookup((Option::None, Option::None), [(X, BYTE)], m);
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let (fixed_data, _) = global_constraints::set_global_constraints(fixed_data, &[]);
+        let x = Cell::new("N::X", fixed_data.try_column_by_name("N::X").unwrap().id, 0);
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 256);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, [x]);
+        let id = &analyzed.identities[0];
+        assert!(matches!(id, Identity::PhantomLookup(_)));
+        assert_eq!(witgen.process_identity(id, 0), ProcessStatus::Complete);
+        // The multiplicity column of the phantom lookup is filled from the state write.
+        assert_eq!(
+            format_code(&witgen.code()),
+            "assert N::X[0] == (N::X[0] & 255);\n\
+             state_write(0, [N::X[0]]);"
+        );
+    }
+
+    #[test]
+    fn transition_table_lookup() {
+        let input = "
+        namespace N(4);
+            col fixed FROM = [0, 1, 2, 3];
+            col fixed TO = [1, 2, 3, 0];
+            let x;
+            [ x, x' ] in [ FROM, TO ];
+        ";
+        let code = solve_on_rows(input, &[0, 1], vec![("N::x", 0)], None);
+        assert_eq!(
+            code,
+            "lookup(0, [Known(N::x[0]), Unknown(N::x[1])]);\n\
+             lookup(0, [Known(N::x[1]), Unknown(N::x[2])]);"
+        );
+    }
+
+    #[test]
+    fn shifted_table_lookup() {
+        let input = "
+        namespace N(4);
+            col fixed T = [0, 1, 2, 3];
+            let x;
+            [ x, x' ] in [ T, T' ];
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 4, vec![("N::x", 0)]);
+        // The fixed lookup machine cannot answer lookups into `T'`.
+        assert_eq!(
+            witgen.process_identity(&fixed_data.analyzed.identities[0], 0),
+            ProcessStatus::Unsupported(
+                "Lookups into shifted fixed columns are not supported.".to_string()
+            )
+        );
+        assert!(witgen.code().is_empty());
+    }
+
+    #[test]
+    fn inlined_fixed_table() {
+        let input = "
+        namespace N(16);
+            col fixed OPCODE(i) { i };
+            col fixed FLAGS = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 7, 9, 3];
+            let op;
+            let flags;
+            let out;
+            [ op, flags ] in [ OPCODE, FLAGS ];
+            out = flags * 2;
+        ";
+        let solve = |config: WitgenInferenceConfig| {
+            let (witgen, fixed_data) = witgen_for(input, 16, vec![("N::op", 0)]);
+            let mut witgen = witgen.with_config(config);
+            for id in &fixed_data.analyzed.identities {
+                assert_eq!(witgen.process_identity(id, 0), ProcessStatus::Complete);
+            }
+            witgen.code()
+        };
+        let flags = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 7, 9, 3].map(GoldilocksField::from);
+
+        let called = solve(Default::default());
+        assert!(matches!(called[0], Effect::MachineCall(..)));
+        // The table has 16 rows, so it is not inlined with a limit of 15.
+        let too_large = solve(WitgenInferenceConfig::default().with_inlined_fixed_tables(15));
+        assert_eq!(format_code(&too_large), format_code(&called));
+        let inlined = solve(WitgenInferenceConfig::default().with_inlined_fixed_tables(16));
+        assert!(!inlined.iter().any(|e| matches!(e, Effect::MachineCall(..))));
+        assert_eq!(
+            format_code(&inlined),
+            "N::flags[0] = select(N::op[0], [0 => 3, 1 => 1, 2 => 4, 3 => 1, 4 => 5, 5 => 9, \
+             6 => 2, 7 => 6, 8 => 5, 9 => 3, 10 => 5, 11 => 8, 12 => 9, 13 => 7, 14 => 9, \
+             15 => 3]);\n\
+             state_write(0, [N::op[0], N::flags[0]]);\n\
+             N::out[0] = (N::flags[0] * 2);"
+        );
+
+        let op_cell = find_cell(&inlined, "N::op[0]");
+        let run = |code: &[Effect<GoldilocksField, Cell>], op: GoldilocksField| {
+            let mut calls = MockCallHandler::from_fn(|_, inputs: &[GoldilocksField]| {
+                let flag = flags[inputs[0].to_degree() as usize];
+                match inputs {
+                    // Machine call with the opcode as input.
+                    [_] => Some(vec![flag]),
+                    // State write of the full tuple.
+                    [_, f] => (*f == flag).then(Vec::new),
+                    _ => None,
+                }
+            })
+            .strict();
+            let values = EffectInterpreter::new(code)
+                .run([(op_cell.clone(), op)], &mut calls)
+                .unwrap()
+                .values;
+            (values, calls.calls().len())
+        };
+        for op in 0..16 {
+            let op = GoldilocksField::from(op);
+            assert_eq!(run(&inlined, op), run(&called, op));
+        }
+        // An opcode that is not in the table is rejected instead of selecting zero.
+        let mut calls = MockCallHandler::from_fn(|_, _| None).strict();
+        assert!(matches!(
+            EffectInterpreter::new(&inlined).run([(op_cell, 16.into())], &mut calls),
+            Err(InterpreterError::MissingSelectKey(key)) if key == 16.into()
+        ));
+    }
+
+    #[test]
+    fn contiguous_range_lookup() {
+        let input = "
+        namespace N(65536);
+            col fixed RANGE(i) { i };
+            let x;
+            let lo;
+            let hi;
+            let y;
+            x = lo + hi * 65536;
+            [ lo ] in [ RANGE ];
+            [ hi ] in [ RANGE ];
+            [ y ] in [ RANGE ];
+        ";
+        // No global constraints, so the range of lo and hi is only known from the lookups.
+        let (mut witgen, fixed_data) = witgen_for(input, 65536, vec![("N::x", 0), ("N::y", 0)]);
+        let plan = witgen
+            .solve_block(&fixed_data.analyzed.identities, 1)
+            .unwrap();
+        // The range constraints from the lookups allow the decomposition of x,
+        // and the results are known to be in range. The input y has to be checked.
+        assert_eq!(
+            format_code(&plan.code),
+            "assert N::y[0] in [0, 65535] & 0xffff;
+state_write(3, [N::y[0]]);
+N::lo[0] = (N::x[0] & 65535);
+N::hi[0] = ((N::x[0] & 4294901760) // 65536);
+assert N::x[0] == (N::x[0] & 4294967295);
+state_write(1, [N::lo[0]]);
+state_write(2, [N::hi[0]]);"
+        );
+        assert!(!plan
+            .code
+            .iter()
+            .any(|e| matches!(e, Effect::MachineCall(..))));
+    }
+
+    #[test]
+    fn lookup_with_multiple_outputs() {
+        let input = "
+        namespace N(4);
+            col fixed KEY = [0, 1, 2, 3];
+            col fixed HI = [7, 7, 8, 8];
+            col fixed LO = [5, 6, 5, 6];
+            let a;
+            let b;
+            let c;
+            let x;
+            let y;
+            let z;
+            [ a, b, c ] in [ KEY, HI, LO ];
+            [ x, y, z ] in [ HI, KEY, LO ];
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 4, vec![("N::a", 0), ("N::x", 0)]);
+        let ids = &fixed_data.analyzed.identities;
+        // KEY determines the row, so both outputs are solved by a single call.
+        assert_eq!(witgen.process_identity(&ids[0], 0), ProcessStatus::Complete);
+        // HI does not determine the row, so the call would not have a unique answer.
+        assert_eq!(
+            witgen.process_identity(&ids[1], 0),
+            ProcessStatus::NoProgress
+        );
+        assert_eq!(
+            format_code(&witgen.code()),
+            "lookup(0, [Known(N::a[0]), Unknown(N::b[0]), Unknown(N::c[0])]);"
+        );
+    }
+
+    #[test]
+    fn classify_identities() {
+        let input = "
+        namespace N(4);
+            col fixed BYTE(i) { i & 0xff };
+            let X;
+            let Y;
+            let Z;
+            X = Y + 1;
+            X * Y = Z;
+            [ X ] in [ BYTE ];
+            [ X ] in [ Y ];
+            [ X ] connect [ Y ];
+        ";
+        let (witgen, fixed_data) = witgen_for(input, 4, vec![]);
+        assert_eq!(
+            fixed_data
+                .analyzed
+                .identities
+                .iter()
+                .map(|id| witgen.classify(id))
+                .collect_vec(),
+            vec![
+                IdentitySupport::Supported,
+                // Quadratic, but solvable once X or Y is known.
+                IdentitySupport::Supported,
+                IdentitySupport::Supported,
+                // Can only be completed if the selector is zero.
+                IdentitySupport::ConditionallySupported,
+                IdentitySupport::Unsupported("Connect identities are not supported.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_identities_are_excluded() {
+        let input = "let X; let Y; let Z; X' = X + 1; [ Y ] connect [ Z ];";
+        let report = solve_on_rows_and_then(input, &[0, 1], vec![("X", 0)], None, |witgen| {
+            witgen.progress_report().clone()
+        });
+        // The connect identity is not part of the expected complete count.
+        assert_eq!(report.identities_completed_per_round, vec![2]);
+        assert_eq!(
+            report.unsupported_identities,
+            BTreeMap::from([(1, "Connect identities are not supported.".to_string())])
+        );
+    }
+
+    #[test]
+    fn runtime_scale_factor() {
+        let input = "
+        namespace N(8);
+            let X;
+            let S;
+            let Y;
+            X * S = Y;
+        ";
+        let code = solve_on_rows(input, &[0], vec![("N::S", 0), ("N::Y", 0)], None);
+        assert_eq!(
+            code,
+            "assert N::S[0] != 0;\nN::X[0] = (-N::Y[0] / -N::S[0]);"
+        );
+        let (mut witgen, fixed_data) = witgen_for(input, 8, vec![("N::S", 0), ("N::Y", 0)]);
+        let plan = witgen
+            .solve_block(&fixed_data.analyzed.identities, 1)
+            .unwrap();
+        assert_eq!(format_code(&plan.code), code);
+
+        // If the scale factor is range constrained to exclude zero, no assertion is needed.
+        let input = "
+        namespace N(8);
+            col fixed BYTE(i) { i & 0xff };
+            let X;
+            let S;
+            let T;
+            let Y;
+            [ T ] in [ BYTE ];
+            S = T + 1;
+            X * S = Y;
+        ";
+        let code = solve_on_rows(input, &[0], vec![("N::T", 0), ("N::Y", 0)], None);
+        assert_eq!(
+            code,
+            "N::S[0] = (N::T[0] + 1);\nN::X[0] = (-N::Y[0] / -N::S[0]);"
+        );
+
+        // A selector that might be zero is only divided by if nothing else determines X.
+        let input = "
+        namespace N(8);
+            let SEL;
+            let A;
+            let B;
+            let X;
+            SEL * (X - A) = 0;
+            X = B + 1;
+            B = A - 1;
+        ";
+        let code = solve_on_rows(input, &[0], vec![("N::SEL", 0), ("N::A", 0)], None);
+        assert_eq!(
+            code,
+            "N::B[0] = (N::A[0] + -1);\n\
+             N::X[0] = (N::B[0] + 1);\n\
+             assert (N::SEL[0] * (N::X[0] + -N::A[0])) == 0;"
+        );
+    }
+
+    #[test]
+    fn unsupported_challenge_is_reported() {
+        let input = "
+        namespace N(4);
+            col witness x;
+            col witness y;
+            y = x * challenge(0, 1);
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 4, vec![("N::x", 0)]);
+        assert!(witgen.unsupported_report().is_empty());
+        // The challenge of stage 0 is not available when generating code for stage 0.
+        let status = witgen.process_identity(&fixed_data.analyzed.identities[0], 0);
+        assert!(!status.is_complete());
+        assert_eq!(
+            witgen.unsupported_report(),
+            HashMap::from([(UnsupportedKind::Challenge, 1)])
+        );
+    }
+
+    #[test]
+    fn later_stage_accumulator() {
+        let input = "
+        namespace N(4);
+            col witness x;
+            col witness stage(1) acc;
+            acc = x * challenge(0, 1) + 1;
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let challenges = BTreeMap::from([(1, GoldilocksField::from(7))]);
+        for (stage, expected) in [
+            (
+                0,
+                Err(ProcessStatus::Unsupported(
+                    "References the stage-1 column N::acc, but the code is generated for stage 0."
+                        .to_string(),
+                )),
+            ),
+            (1, Ok("N::acc[0] = ((N::x[0] * 7) + 1);")),
+        ] {
+            let fixed_data =
+                FixedData::new(&analyzed, &fixed_col_vals, &[], challenges.clone(), stage);
+            let x = Cell::new("N::x", fixed_data.try_column_by_name("N::x").unwrap().id, 0);
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+            let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![x]);
+            assert_eq!(witgen.stage(), stage);
+            let status = witgen.process_identity(&analyzed.identities[0], 0);
+            match expected {
+                Ok(code) => {
+                    assert_eq!(status, ProcessStatus::Complete);
+                    assert_eq!(format_code(&witgen.code()), code);
+                }
+                Err(expected_status) => {
+                    assert_eq!(status, expected_status);
+                    assert!(witgen.code().is_empty());
+                }
+            }
+        }
+
+        // The challenge is only available after stage 0, even if its value is known.
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], challenges, 1);
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![])
+            .with_config(WitgenInferenceConfig::default().with_stage(0));
+        assert_eq!(witgen.stage(), 0);
+        assert!(matches!(
+            witgen.process_identity(&analyzed.identities[0], 0),
+            ProcessStatus::Unsupported(_)
+        ));
+    }
+
+    #[test]
+    fn order_by_cost() {
+        let input = "
+        namespace N(8);
+            col fixed A = [0, 1, 2, 3, 4, 5, 6, 7];
+            col fixed B = [1, 2, 3, 4, 5, 6, 7, 8];
+            let X;
+            let Y;
+            let Z;
+            [ Y, X ] in [ A, B ];
+            X = Y + 1;
+            Z = 2 * Y;
+        ";
+        let (witgen, fixed_data) = witgen_for(input, 8, vec![("N::Y", 0)]);
+        let cell = |name: &str| column_cell(fixed_data, name, 0);
+        let ids = &fixed_data.analyzed.identities;
+        let candidates = [(&ids[0], 0), (&ids[1], 0), (&ids[2], 0)];
+        // Both the lookup and the polynomial identity determine `X`, but the
+        // polynomial identity is cheaper. The last identity does not determine `X`.
+        let ordered = witgen.order_by_cost(&cell("N::X"), &candidates);
+        assert_eq!(
+            ordered.iter().map(|(id, _)| id.id()).collect_vec(),
+            [ids[1].id(), ids[0].id()]
+        );
+        // Nothing was derived on `witgen` itself.
+        assert!(witgen.code_so_far().is_empty());
+        assert_eq!(
+            code_cost(&[Effect::Assignment(
+                cell("N::X"),
+                SymbolicExpression::from_symbol(cell("N::Y"), None)
+                    + GoldilocksField::from(1).into()
+            )]),
+            2
+        );
+    }
+
+    #[test]
+    fn fixed_with_and_without_next() {
+        // `LATCH` and `FACTOR` are not periodic in any block size, so every row
+        // has different coefficients, and both are referenced with and without `'`.
+        let input = "
+        namespace N(8);
+            col fixed LATCH = [1, 0, 0, 1, 0, 0, 0, 0];
+            col fixed FACTOR = [1, 2, 4, 8, 16, 32, 64, 128];
+            let A;
+            let B;
+            A' = A * (1 - LATCH') + B * FACTOR + FACTOR' * LATCH;
+        ";
+        let known_cells = vec![
+            ("N::A", 0),
+            ("N::A", 7),
+            ("N::B", 0),
+            ("N::B", 1),
+            ("N::B", 2),
+            ("N::B", 7),
+        ];
+        let code = solve_on_rows(input, &[0, 1, 2, 7], known_cells, None);
+        // `LATCH'` is one on rows 2 and 7, where it wraps around to row 0,
+        // and `FACTOR' * LATCH` is only non-zero on row 0.
+        assert_eq!(
+            code,
+            "N::A[1] = ((N::A[0] + N::B[0]) + 2);
+N::A[2] = (N::A[1] + (N::B[1] * 2));
+N::A[3] = (N::B[2] * 4);
+N::A[8] = (N::B[7] * 128);"
+        );
+    }
+
+    #[test]
+    fn difference_range_constraint() {
+        let input = "
+        namespace N(256);
+            let BYTE: col = |i| i;
+            let NIBBLE: col = |i| i % 16;
+            let X;
+            let Y;
+            let Z;
+            [ X ] in [ BYTE ];
+            [ Y ] in [ NIBBLE ];
+            Z = X - Y;
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let (fixed_data, _) =
+            global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 256);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        let ids = &analyzed.identities;
+        assert_eq!(witgen.process_identity(&ids[2], 0), ProcessStatus::Progress);
+        let expected = RangeConstraint::from_range(-GoldilocksField::from(15), 255.into());
+        let Identity::Polynomial(PolynomialIdentity { expression, .. }) = &ids[2] else {
+            panic!()
+        };
+        let Expression::BinaryOperation(AlgebraicBinaryOperation { right, .. }) = expression else {
+            panic!()
+        };
+        // Both operands of `X - Y` are unknown, but the range of the difference is known.
+        let difference = witgen.evaluate(right, 0).unwrap();
+        assert_eq!(difference.range_constraint(), Some(expected.clone()));
+        // The range is transferred to `Z`.
+        let z = Cell::new("N::Z", fixed_data.try_column_by_name("N::Z").unwrap().id, 0);
+        assert_eq!(witgen.range_constraint(z), Some(expected));
+    }
+
+    #[test]
+    fn registered_machine_semantics() {
+        let input = "
+        namespace Table(8);
+            let A;
+            let B;
+        namespace N(8);
+            col fixed F = [0, 1, 2, 3, 4, 5, 6, 7];
+            col fixed G = [1, 2, 3, 4, 5, 6, 7, 8];
+            let X;
+            let Y;
+            let Z;
+            [ X, Y ] in [ Table::A, Table::B ];
+            [ X, Z ] in [ F, G ];
+        ";
+        let witgen = || witgen_for(input, 8, vec![("N::X", 0)]);
+
+        // By default, only the lookup into fixed columns is answered.
+        let (mut unregistered, fixed_data) = witgen();
+        let ids = &fixed_data.analyzed.identities;
+        assert!(matches!(
+            unregistered.process_identity(&ids[0], 0),
+            ProcessStatus::Unsupported(_)
+        ));
+        assert_eq!(
+            unregistered.process_identity(&ids[1], 0),
+            ProcessStatus::Complete
+        );
+
+        let (mut registered, _) = witgen();
+        registered.register_machine(ids[0].id(), MachineSemantics::PureFixedTable);
+        registered.register_machine(ids[1].id(), MachineSemantics::Stateful);
+        assert_eq!(
+            registered.machine_semantics(ids[1].id()),
+            Some(MachineSemantics::Stateful)
+        );
+        assert_eq!(
+            registered.process_identity(&ids[0], 0),
+            ProcessStatus::Complete
+        );
+        assert_eq!(
+            registered.process_identity(&ids[1], 0),
+            ProcessStatus::Unsupported("Lookups into stateful machines are deferred.".to_string())
+        );
+        assert_eq!(
+            format_code(&registered.code()),
+            "lookup(0, [Known(N::X[0]), Unknown(N::Y[0])]);"
+        );
+    }
+
+    #[test]
+    fn equality_check() {
+        let input = "namespace N(8); let X; let Y; let Z; X = Y; Z = 2 * X;";
+        let solve = |config: WitgenInferenceConfig| {
+            let (witgen, fixed_data) = witgen_for(input, 8, vec![("N::X", 0), ("N::Y", 0)]);
+            let mut witgen = witgen.with_config(config);
+            for id in &fixed_data.analyzed.identities {
+                assert_eq!(witgen.process_identity(id, 0), ProcessStatus::Complete);
+            }
+            format_code(&witgen.code())
+        };
+        // Both sides of `X = Y` are inputs, so the code has to check that they are equal.
+        assert_eq!(
+            solve(WitgenInferenceConfig::default()),
+            "assert N::X[0] == N::Y[0];\nN::Z[0] = (2 * N::X[0]);"
+        );
+        assert_eq!(
+            solve(WitgenInferenceConfig::default().with_equality_checks(false)),
+            "N::Z[0] = (2 * N::X[0]);"
+        );
+    }
+
+    #[test]
+    fn input_range_checks() {
+        let input = "
+namespace N(8);
+    let X;
+    let A;
+    let B;
+    A * (A - 1) = 0;
+    B * (B - 1) = 0;
+    X = A + 2 * B;
+";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let (fixed_data, retained_identities) =
+            global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
+        let x = Cell::new("N::X", fixed_data.try_column_by_name("N::X").unwrap().id, 0);
+        let solve = |config: WitgenInferenceConfig| {
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+            let mut witgen =
+                WitgenInference::new(&fixed_data, ref_eval, [x.clone()]).with_config(config);
+            for id in &retained_identities {
+                assert_eq!(witgen.process_identity(id, 0), ProcessStatus::Complete);
+            }
+            format_code(&witgen.code())
+        };
+        let code = "N::A[0] = (N::X[0] & 1);
+N::B[0] = ((N::X[0] & 2) // 2);
+assert N::X[0] == (N::X[0] & 3);";
+        assert_eq!(solve(WitgenInferenceConfig::default()), code);
+        // The decomposition implies that `X` fits into two bits,
+        // which is checked explicitly since `X` is an input.
+        assert_eq!(
+            solve(WitgenInferenceConfig::default().with_input_range_checks()),
+            format!("{code}\nassert N::X[0] in [0, 3] & 0x3;")
+        );
+    }
+
+    #[test]
+    fn reassignment_of_known_cell() {
+        let input = "namespace N(8); let X; let Y; let Z;";
+        let witgen = || witgen_for(input, 8, vec![("N::X", 0), ("N::Y", 0)]);
+
+        // A second constant value for `Z` is a conflict.
+        let (mut constant, fixed_data) = witgen();
+        let [x, y, z] = ["N::X", "N::Y", "N::Z"].map(|name| column_cell(fixed_data, name, 0));
+        let assign =
+            |value: SymbolicExpression<GoldilocksField, Cell>| Effect::Assignment(z.clone(), value);
+        let one = SymbolicExpression::from(GoldilocksField::from(1));
+        constant.ingest_effects(0, 0, vec![assign(one.clone()), assign(one)]);
+        assert!(constant.conflicts().is_empty());
+        constant.ingest_effects(1, 0, vec![assign(GoldilocksField::from(2).into())]);
+        assert_eq!(format_code(constant.code_so_far()), "N::Z[0] = 1;");
+        assert_eq!(
+            constant.conflicts(),
+            [RangeConflict {
+                cell: z.clone(),
+                value: 2.into(),
+                range_constraint: RangeConstraint::from_value(1.into()),
+            }]
+        );
+
+        // A second value computed at run-time has to be checked instead of assigned.
+        let (mut runtime, _) = witgen();
+        runtime.ingest_effects(
+            0,
+            0,
+            vec![
+                assign(SymbolicExpression::from_symbol(x.clone(), None)),
+                assign(SymbolicExpression::from_symbol(y.clone(), None)),
+            ],
+        );
+        assert_eq!(
+            format_code(runtime.code_so_far()),
+            "N::Z[0] = N::X[0];\nassert N::Z[0] == N::Y[0];"
+        );
+        assert!(runtime.conflicts().is_empty());
+    }
+
+    #[test]
+    fn array_elements() {
+        // Array elements are separate columns with consecutive ids,
+        // so each of them results in its own cell.
+        let input = "let x[2]; let y[2]; x[0] = y[0] + 1; x[1] = 2 * y[1];";
+        let code = solve_on_rows(input, &[0], vec![("y[0]", 0), ("y[1]", 0)], None);
+        assert_eq!(code, "x[0][0] = (y[0][0] + 1);\nx[1][0] = (2 * y[1][0]);");
+    }
+
+    #[test]
+    fn fib_with_naming() {
+        let input = "namespace Fib(8); let X; let Y; X' = Y; Y' = X + Y;";
+        let code = solve_on_rows_and_then(
+            input,
+            &[0, 1],
+            vec![("Fib::X", 0), ("Fib::Y", 0)],
+            None,
+            |witgen| witgen.code(),
+        );
+        assert_eq!(
+            format_code_with_naming(&code, CellNaming::At),
+            "Fib::X@1 = Fib::Y@0;
+Fib::Y@1 = (Fib::X@0 + Fib::Y@0);
+Fib::X@2 = Fib::Y@1;
+Fib::Y@2 = (Fib::X@1 + Fib::Y@1);"
+        );
+        assert_eq!(
+            format_code_with_naming(&code, CellNaming::Short),
+            "X[1] = Y[0];
+Y[1] = (X[0] + Y[0]);
+X[2] = Y[1];
+Y[2] = (X[1] + Y[1]);"
+        );
+    }
+
+    #[test]
+    fn fib_with_fixed() {
+        let input = "
+        namespace Fib(8);
+            col fixed FIRST = [1] + [0]*;
+            let x;
+            let y;
+            FIRST * (y - 1) = 0;
+            FIRST * (x - 1) = 0;
+            // This works in this test because we do not implement wrapping properly in this test.
+            x' - y = 0;
+            y' - (x + y) = 0;
+        ";
+        let code = solve_on_rows(input, &[0, 1, 2, 3], vec![], None);
+        assert_snapshot("fib_with_fixed", &code);
+    }
+
+    #[test]
+    fn known_one_fixed_selector() {
+        for identity in ["FIXED * (X - 3) = 0;", "(X - 3) * FIXED = 0;"] {
+            let input = format!(
+                "
+        namespace N(4);
+            col fixed FIXED = [1, 0, 1, 0];
+            let X;
+            {identity}
+        "
+            );
+            // The selector is divided out on the rows where it is one, and the
+            // identity holds trivially on the rows where it is zero.
+            let code = solve_on_rows(&input, &[0, 1, 2], vec![], None);
+            assert_eq!(code, "N::X[0] = 3;\nN::X[2] = 3;", "{identity}");
+        }
+    }
+
+    #[test]
+    fn fib_with_fixed_reads() {
+        let input = "
+        namespace Fib(8);
+            col fixed FIRST = [1] + [0]*;
+            let x;
+            let y;
+            FIRST * (y - 1) = 0;
+            FIRST * (x - 1) = 0;
+            x' - y = 0;
+            y' - (x + y) = 0;
+        ";
+        let reads = solve_on_rows_and_then(input, &[0, 1, 2, 3], vec![], None, |witgen| {
+            witgen.fixed_reads()
+        });
+        let first = PolyID {
+            id: 0,
+            ptype: PolynomialType::Constant,
+        };
+        assert_eq!(reads, (0..4).map(|row| (first, row)).collect());
+    }
+
+    #[test]
+    fn fib_with_fixed_dump_state() {
+        let input = "
+        namespace Fib(8);
+            col fixed FIRST = [1] + [0]*;
+            let x;
+            let y;
+            let z;
+            FIRST * (y - 1) = 0;
+            FIRST * (x - 1) = 0;
+            x' - y = 0;
+            y' - (x + y) = 0;
+        ";
+        let dump = solve_on_rows_and_then(input, &[0, 1, 2], vec![("Fib::z", 1)], None, |witgen| {
+            witgen.dump_state(0..5, None)
+        });
+        assert_eq!(
+            dump,
+            "row | Fib::x | Fib::y | Fib::z
+  0 |      1 |      1 |      ?
+  1 |      1 |      2 |      K
+  2 |      2 |      3 |      ?
+  3 |      3 |      5 |      ?
+  4 |      ? |      ? |      ?"
+        );
+    }
+
+    #[test]
+    fn previous_row_wraps() {
+        let input = "
+        namespace N(4);
+            col fixed LAST = [0, 0, 0, 7];
+            let x;
+            x' = LAST;
+        ";
+        // Processing the identity on row -1 determines x on row 0
+        // from the value of LAST on the last row.
+        let code = solve_on_rows(input, &[-1], vec![], None);
+        assert_eq!(code, "N::x[0] = 7;");
+
+        // Without wrapping, the value of LAST on row -1 is not known.
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let ref_eval =
+            FixedEvaluatorForFixedData::new(&fixed_data, 4).with_boundary(Boundary::Error);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]).with_config(
+            WitgenInferenceConfig::default().with_unresolved_fixed(UnresolvedFixed::Skip),
+        );
+        assert_eq!(
+            witgen.process_identity(&analyzed.identities[0], -1),
+            ProcessStatus::NoProgress
+        );
+        assert!(witgen
+            .process_identity(&analyzed.identities[0], 0)
+            .is_complete());
+        assert_eq!(format_code(&witgen.code()), "N::x[1] = 0;");
+    }
+
+    #[test]
+    fn assignment_violates_range_constraint() {
+        let input = "
+        namespace N(256);
+            let BYTE: col = |i| i & 0xff;
+            let X;
+            let Y;
+            [ X ] in [ BYTE ];
+            Y = 300;
+            X = Y;
+        ";
+        let conflicts = solve_on_rows_and_then(input, &[0], vec![], None, |witgen| {
+            witgen.conflicts().to_vec()
+        });
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].cell.to_string(), "N::X[0]");
+        assert_eq!(conflicts[0].value, GoldilocksField::from(300));
+        assert_eq!(
+            conflicts[0].range_constraint,
+            RangeConstraint::from_mask(0xffu64)
+        );
+    }
+
+    #[test]
+    fn xor() {
+        let code = solve_xor(|witgen| format_code(&witgen.code()));
+        assert_snapshot("xor", &code);
+    }
+
+    #[test]
+    fn xor_call_outputs() {
+        let outputs = solve_xor(|witgen| {
+            witgen
+                .code()
+                .iter()
+                .filter_map(|e| match e {
+                    Effect::MachineCall(_, _, outputs) => {
+                        Some(outputs.iter().map(|c| c.to_string()).join(", "))
+                    }
+                    _ => None,
+                })
+                .collect_vec()
+        });
+        assert_eq!(
+            outputs,
+            (3..=6)
+                .rev()
+                .map(|row| format!("Xor::B_byte[{row}]"))
+                .collect_vec()
+        );
+    }
+
+    #[test]
+    fn xor_inputs() {
+        let cell = |name: &str, id, row_offset| Cell {
+            column_name: name.to_string(),
+            id,
+            row_offset,
+        };
+        let (inputs, provenances) = solve_xor(|witgen| {
+            let provenances = [
+                cell("Xor::A", 3, 7),
+                cell("Xor::C", 5, 7),
+                cell("Xor::B", 4, 7),
+                cell("Xor::A", 3, 0),
+            ]
+            .map(|cell| witgen.provenance(&cell));
+            (witgen.inputs(), provenances)
+        });
+        assert_eq!(inputs, vec![cell("Xor::A", 3, 7), cell("Xor::C", 5, 7)]);
+        assert_eq!(
+            provenances,
+            [
+                Some(Provenance::Input),
+                Some(Provenance::Input),
+                Some(Provenance::Derived),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn xor_16_bit_baby_bear() {
+        // A 32-bit word does not fit into BabyBear, so we use 16-bit words.
+        let input = "
+namespace Xor(256 * 256);
+let latch: col = |i| { if (i % 2) == 1 { 1 } else { 0 } };
+let FACTOR: col = |i| { 1 << (((i + 1) % 2) * 8) };
+
+let a: int -> int = |i| i % 256;
+let b: int -> int = |i| (i / 256) % 256;
+let P_A: col = a;
+let P_B: col = b;
+let P_C: col = |i| a(i) ^ b(i);
+
+let A_byte;
+let B_byte;
+let C_byte;
+
+[ A_byte, B_byte, C_byte ] in [ P_A, P_B, P_C ];
+
+let A;
+let B;
+let C;
+
+A' = A * (1 - latch) + A_byte * FACTOR;
+B' = B * (1 - latch) + B_byte * FACTOR;
+C' = C * (1 - latch) + C_byte * FACTOR;
+";
+        let code = solve_in_field_on_rows_and_then::<BabyBearField, _>(
+            input,
+            &[1, 2, 3],
+            vec![("Xor::A", 3), ("Xor::C", 3)],
+            Some(8),
+            |witgen| format_code(&witgen.code()),
+        );
+        assert_eq!(
+            code,
+            "Xor::A_byte[2] = ((Xor::A[3] & 65280) // 256);
+Xor::A[2] = (Xor::A[3] & 255);
+assert Xor::A[3] == (Xor::A[3] & 65535);
+Xor::C_byte[2] = ((Xor::C[3] & 65280) // 256);
+Xor::C[2] = (Xor::C[3] & 255);
+assert Xor::C[3] == (Xor::C[3] & 65535);
+Xor::A_byte[1] = Xor::A[2];
+Xor::C_byte[1] = Xor::C[2];
+lookup(0, [Known(Xor::A_byte[2]), Unknown(Xor::B_byte[2]), Known(Xor::C_byte[2])]);
+lookup(0, [Known(Xor::A_byte[1]), Unknown(Xor::B_byte[1]), Known(Xor::C_byte[1])]);
+Xor::B[2] = Xor::B_byte[1];
+Xor::B[3] = (Xor::B[2] + (Xor::B_byte[2] * 256));"
+        );
+    }
+
+    #[test]
+    fn xor_tracing_spans() {
+        let collector = SpanCollector::default();
+        let spans = collector.spans.clone();
+        tracing::subscriber::with_default(collector, || solve_xor(|_| ()));
+        let spans = spans.lock().unwrap();
+        for phase in ["round", "process_identity", "range_constraint_conjunction"] {
+            assert!(spans.iter().any(|s| s == phase), "No span for {phase}.");
+        }
+    }
+
+    #[test]
+    fn xor_machine_call_summary() {
+        let summary = solve_xor(|witgen| witgen.machine_call_summary());
+        assert_eq!(summary, [(0, 4)].into_iter().collect());
+    }
+
+    #[test]
+    fn interned_range_constraints() {
+        let mut range_constraints = InternedRangeConstraints::default();
+        for i in 0..1000 {
+            let rc = RangeConstraint::<GoldilocksField>::from_mask(0xffu64);
+            range_constraints.insert(i, rc);
+        }
+        range_constraints.insert(0, RangeConstraint::from_mask(0xfu64));
+        assert_eq!(range_constraints.len(), 1000);
+        assert_eq!(
+            range_constraints.get(&1),
+            Some(&RangeConstraint::from_mask(0xffu64))
+        );
+        // The byte constraint is still interned, even though cell 0 does not use it anymore.
+        assert_eq!(range_constraints.interned.len(), 2);
+        assert_eq!(Rc::strong_count(&range_constraints.constraints[&1]), 1000);
+        assert!(
+            std::mem::size_of::<Rc<RangeConstraint<GoldilocksField>>>()
+                < std::mem::size_of::<RangeConstraint<GoldilocksField>>()
+        );
+    }
+
+    #[test]
+    fn chained_lookups() {
+        let input = "
+        namespace M(256);
+            let IN: col = |i| i;
+            let DOUBLE: col = |i| (2 * i) % 256;
+            let x;
+            let y;
+            let z;
+            let w;
+            [x, y] in [IN, DOUBLE];
+            z - y = 0;
+            [z, w] in [IN, DOUBLE];
+        ";
+        // The output of the first lookup determines the input of the second one.
+        let code = solve_on_rows(input, &[0], vec![("M::x", 0)], None);
+        assert_eq!(
+            code,
+            "lookup(0, [Known(M::x[0]), Unknown(M::y[0])]);
+M::z[0] = M::y[0];
+lookup(2, [Known(M::z[0]), Unknown(M::w[0])]);"
+        );
+    }
+
+    #[test]
+    fn xor_lookup_output_range_constraints() {
+        let range_constraints = solve_xor(|witgen| {
+            witgen
+                .derived_range_constraints
+                .iter()
+                .filter(|(cell, _)| cell.column_name == "Xor::B_byte")
+                .map(|(cell, rc)| (cell.to_string(), rc.clone()))
+                .collect::<BTreeMap<_, _>>()
+        });
+        let byte = RangeConstraint::from_mask(0xffu64);
+        assert_eq!(
+            range_constraints,
+            (3..=6)
+                .map(|row| (format!("Xor::B_byte[{row}]"), byte.clone()))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn fib_progress() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let (report, rounds) =
+            solve_on_rows_and_then(input, &[0, 1], vec![("X", 0), ("Y", 0)], None, |witgen| {
+                (
+                    witgen.progress_report().clone(),
+                    witgen.code_rounds().to_vec(),
+                )
+            });
+        assert_eq!(
+            report,
+            ProgressReport {
+                cells_solved_per_round: vec![4],
+                identities_completed_per_round: vec![4],
+                unsupported_identities: Default::default(),
+            }
+        );
+        assert_eq!(rounds, vec![1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn chained_progress() {
+        // Processing the rows in reverse order means that we
+        // can only make progress on one row per round.
+        let input = "let X; X' = X + 1;";
+        let (report, rounds) =
+            solve_on_rows_and_then(input, &[3, 2, 1, 0], vec![("X", 0)], None, |witgen| {
+                (
+                    witgen.progress_report().clone(),
+                    witgen.code_rounds().to_vec(),
+                )
+            });
+        assert_eq!(
+            report,
+            ProgressReport {
+                cells_solved_per_round: vec![1, 1, 1, 1],
+                identities_completed_per_round: vec![1, 1, 1, 1],
+                unsupported_identities: Default::default(),
+            }
+        );
+        assert_eq!(rounds, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn fib_effect_logger() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let (witgen, fixed_data) = witgen_for(input, 8, vec![("X", 0), ("Y", 0)]);
+        let mut events = vec![];
+        let mut witgen = witgen
+            .with_effect_logger(|id, row, effect| events.push(format!("{id} {row}: {effect}")));
+        let collector = SpanCollector::default();
+        let tracing_events = collector.events.clone();
+        tracing::subscriber::with_default(collector, || {
+            for row in [0, 1] {
+                for id in &fixed_data.analyzed.identities {
+                    assert!(witgen.process_identity(id, row).is_complete());
+                }
+            }
+        });
+        drop(witgen);
+        assert_eq!(
+            events,
+            [
+                "0 0: X[1] = Y[0];",
+                "1 0: Y[1] = (X[0] + Y[0]);",
+                "0 1: X[2] = Y[1];",
+                "1 1: Y[2] = (X[1] + Y[1]);",
+            ]
+        );
+        // Every effect is also reported as a tracing event.
+        assert_eq!(
+            *tracing_events.lock().unwrap(),
+            [
+                "identity_id=0 row=0 effect=X[1] = Y[0];",
+                "identity_id=1 row=0 effect=Y[1] = (X[0] + Y[0]);",
+                "identity_id=0 row=1 effect=X[2] = Y[1];",
+                "identity_id=1 row=1 effect=Y[2] = (X[1] + Y[1]);",
+            ]
+        );
+    }
+
+    #[test]
+    fn fib_block() {
+        // Only the identities are taken from the PIL source, the fixed data is
+        // built directly and has to result in the same column ids.
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").witness("Y").build();
+        let fixed_data = fixture.fixed_data();
+        let known_cells = ["X", "Y"].map(|name| Cell {
+            column_name: name.to_string(),
+            id: fixed_data.try_column_by_name(name).unwrap().id,
+            row_offset: 0,
+        });
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, known_cells);
+        let plan = witgen.solve_block(&analyzed.identities, 2).unwrap();
+        assert_eq!(plan.block_start(3), 6);
+        // The last row of the block determines the first row of the next block.
+        assert_snapshot("fib_block", &format_code(&plan.code));
+    }
+
+    #[test]
+    fn fib_minimal_inputs() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").witness("Y").build();
+        let fixed_data = fixture.fixed_data();
+        let y2 = Cell {
+            column_name: "Y".to_string(),
+            id: fixed_data.try_column_by_name("Y").unwrap().id,
+            row_offset: 2,
+        };
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        let inputs = witgen.minimal_inputs_for(&[y2], &analyzed.identities, &[0, 1]);
+        assert_eq!(
+            inputs
+                .iter()
+                .map(|cell| cell.to_string())
+                .sorted()
+                .collect_vec(),
+            ["X[0]", "Y[0]"]
+        );
+    }
+
+    #[test]
+    fn fib_trace_replay() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").witness("Y").build();
+        let fixed_data = fixture.fixed_data();
+        let new_witgen = || {
+            let known_cells = ["X", "Y"].map(|name| Cell {
+                column_name: name.to_string(),
+                id: fixed_data.try_column_by_name(name).unwrap().id,
+                row_offset: 0,
+            });
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+            WitgenInference::new(&fixed_data, ref_eval, known_cells).with_trace_recording()
+        };
+
+        let mut witgen = new_witgen();
+        let plan = witgen.solve_block(&analyzed.identities, 2).unwrap();
+        let path = std::env::temp_dir().join(format!("fib_trace_{}.txt", std::process::id()));
+        witgen.trace().unwrap().write_to_file(&path).unwrap();
+        let trace = InferenceTrace::read_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&trace, witgen.trace().unwrap());
+
+        let mut replayed = new_witgen();
+        replayed.replay(&analyzed.identities, &trace).unwrap();
+        assert_eq!(replayed.trace(), Some(&trace));
+        assert_eq!(format_code(&replayed.code()), format_code(&plan.code));
+    }
+
+    #[test]
+    fn identity_report() {
+        let input = "let X; let Y; let Z; X' = X + 1; Y = Z + 1; [ X ] connect [ Y ];";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .witness("Z")
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let cell = |name: &str, row_offset| {
+            Cell::new(
+                name,
+                fixed_data.try_column_by_name(name).unwrap().id,
+                row_offset,
+            )
+        };
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![cell("X", 0)]);
+        let rows = [0, 1, 2, 3];
+        witgen.run_budgeted(&analyzed.identities, &rows, Budget::Unlimited);
+        let report = witgen.identity_report(&analyzed.identities, &rows);
+        assert_eq!(
+            report.statuses[&(1, 2)],
+            IdentityStatus::NoProgress {
+                waiting_on: vec![cell("Y", 2), cell("Z", 2)]
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "Identity 0:
+    rows 0..=3: completed in round 1
+Identity 1:
+    rows 0..=3: waiting on Y, Z
+Identity 2:
+    rows 0..=3: unsupported: Connect identities are not supported.
+"
+        );
+    }
+
+    #[test]
+    fn caller_provided_column() {
+        let input = "let ROM; let ACC; ACC' = ACC + 2 * ROM;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("ROM")
+            .witness("ACC")
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let rom = fixed_data.try_column_by_name("ROM").unwrap();
+        let cell = |name: &str, row_offset| {
+            Cell::new(
+                name,
+                fixed_data.try_column_by_name(name).unwrap().id,
+                row_offset,
+            )
+        };
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![cell("ACC", 0)]);
+        witgen.mark_column_known(rom);
+        let plan = witgen.solve_block(&analyzed.identities, 3).unwrap();
+        assert_eq!(
+            format_code(&plan.code),
+            "ACC[1] = (ACC[0] + (2 * ROM[0]));
+ACC[2] = (ACC[1] + (2 * ROM[1]));
+ACC[3] = (ACC[2] + (2 * ROM[2]));"
+        );
+        assert_eq!(
+            plan.inputs,
+            vec![
+                cell("ROM", 0),
+                cell("ROM", 1),
+                cell("ROM", 2),
+                cell("ACC", 0)
+            ]
+        );
+        assert_eq!(witgen.provenance(&cell("ROM", 1)), Some(Provenance::Input));
+        // Cells on rows that are not referenced are not part of the interface.
+        assert_eq!(witgen.provenance(&cell("ROM", 5)), None);
+    }
+
+    #[test]
+    fn fib_round_trace() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").witness("Y").build();
+        let fixed_data = fixture.fixed_data();
+        let known_cells = ["X", "Y"].map(|name| Cell {
+            column_name: name.to_string(),
+            id: fixed_data.try_column_by_name(name).unwrap().id,
+            row_offset: 0,
+        });
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen =
+            WitgenInference::new(&fixed_data, ref_eval, known_cells).with_trace_recording();
+        // Processing the rows in reverse order completes one row per round.
+        let progress = witgen.run_budgeted(&analyzed.identities, &[2, 1, 0], Budget::Unlimited);
+        assert!(matches!(progress, SolveProgress::Done));
+        let rounds = witgen.trace().unwrap().rounds();
+        assert_eq!(
+            rounds.iter().map(|r| r.completed.clone()).collect_vec(),
+            vec![
+                vec![(0, 0), (1, 0)],
+                vec![(0, 1), (1, 1)],
+                vec![(0, 2), (1, 2)],
+            ]
+        );
+        // The last round, which detects the fixpoint, does not process any
+        // identities, and all other rounds make progress.
+        assert_eq!(rounds.iter().map(|r| r.round).collect_vec(), vec![1, 2, 3]);
+        assert!(rounds.iter().all(|r| r.made_progress()));
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    enum Variable {
+        Cell(Cell),
+        Param(usize),
+        Public(String),
+    }
+
+    impl Display for Variable {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Variable::Cell(cell) => write!(f, "{cell}"),
+                Variable::Param(i) => write!(f, "params[{i}]"),
+                Variable::Public(name) => write!(f, ":{name}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parameterized_constraint() {
+        let input = "let X; let Y; let P; X = 2 * P + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .witness("P")
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let param_id = fixed_data.try_column_by_name("P").unwrap().id;
+        let y = Cell {
+            column_name: "Y".to_string(),
+            id: fixed_data.try_column_by_name("Y").unwrap().id,
+            row_offset: 0,
+        };
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new_with_resolver(
+            &fixed_data,
+            ref_eval,
+            [Variable::Param(0), Variable::Cell(y)],
+            |r: &AlgebraicReference, row_offset| {
+                if r.poly_id.id == param_id {
+                    Variable::Param(0)
+                } else {
+                    Variable::Cell(Cell::from_reference(r, row_offset))
+                }
+            },
+        );
+        let plan = witgen.solve_block(&analyzed.identities, 1).unwrap();
+        assert_eq!(
+            plan.code.iter().map(|e| e.to_string()).join("\n"),
+            "X[0] = ((2 * params[0]) + Y[0]);"
+        );
+    }
+
+    #[test]
+    fn public_reference() {
+        let input = "
+        namespace N(8);
+            col fixed FIRST = [1] + [0]*;
+            let X;
+            let Y;
+            public start = X(0);
+            FIRST * (X - :start) = 0;
+            Y = X + 1;
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let solve = |register: bool| {
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+            let mut witgen = WitgenInference::new_with_resolver(
+                &fixed_data,
+                ref_eval,
+                [],
+                |r: &AlgebraicReference, row_offset| {
+                    Variable::Cell(Cell::from_reference(r, row_offset))
+                },
+            );
+            if register {
+                witgen.register_public("N::start", Variable::Public("N::start".to_string()));
+            }
+            let statuses = analyzed
+                .identities
+                .iter()
+                .map(|id| witgen.process_identity(id, 0))
+                .collect_vec();
+            let code = witgen.code().iter().map(|e| e.to_string()).join("\n");
+            (statuses, code)
+        };
+        // Without registration, the value of the public is not available.
+        assert_eq!(
+            solve(false),
+            (
+                vec![ProcessStatus::NoProgress, ProcessStatus::NoProgress],
+                String::new()
+            )
+        );
+        // The public is read at run-time and determines the cell it is anchored at.
+        assert_eq!(
+            solve(true),
+            (
+                vec![ProcessStatus::Complete, ProcessStatus::Complete],
+                "N::X[0] = :N::start;\nN::Y[0] = (N::X[0] + 1);".to_string()
+            )
+        );
+    }
+
+    struct NoFixedValues;
+
+    impl FixedEvaluator<GoldilocksField> for NoFixedValues {}
+
+    fn with_unresolved_fixed<R>(
+        unresolved_fixed: UnresolvedFixed,
+        f: impl FnOnce(
+            WitgenInference<'_, GoldilocksField, NoFixedValues, Variable>,
+            &[Identity<GoldilocksField>],
+        ) -> R,
+    ) -> R {
+        let input = "let X; let Y; col fixed F = [0]*; X = F + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .fixed("F", vec![0.into(); 8])
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let y = Cell {
+            column_name: "Y".to_string(),
+            id: fixed_data.try_column_by_name("Y").unwrap().id,
+            row_offset: 0,
+        };
+        let mut witgen = WitgenInference::new_with_resolver(
+            &fixed_data,
+            NoFixedValues,
+            [Variable::Cell(y)],
+            |r: &AlgebraicReference, row_offset| {
+                if r.is_fixed() {
+                    Variable::Param(0)
+                } else {
+                    Variable::Cell(Cell::from_reference(r, row_offset))
+                }
+            },
+        )
+        .with_config(WitgenInferenceConfig::default().with_unresolved_fixed(unresolved_fixed));
+        f(witgen, &analyzed.identities)
+    }
+
+    fn unresolved_fixed_code(unresolved_fixed: UnresolvedFixed) -> (ProcessStatus, String) {
+        with_unresolved_fixed(unresolved_fixed, |mut witgen, ids| {
+            let status = witgen.process_identity(&ids[0], 0);
+            let code = witgen.code().iter().map(|e| e.to_string()).join("\n");
+            (status, code)
+        })
+    }
+
+    #[test]
+    fn unresolved_fixed_skip() {
+        assert_eq!(
+            unresolved_fixed_code(UnresolvedFixed::Skip),
+            (ProcessStatus::NoProgress, String::new())
+        );
+    }
+
+    #[test]
+    fn unresolved_fixed_symbolic() {
+        assert_eq!(
+            unresolved_fixed_code(UnresolvedFixed::Symbolic),
+            (
+                ProcessStatus::Complete,
+                "X[0] = (params[0] + Y[0]);".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn unresolved_fixed_error() {
+        let (status, code) = unresolved_fixed_code(UnresolvedFixed::Error);
+        assert_eq!(
+            status,
+            ProcessStatus::UnresolvedFixed {
+                column: "F".to_string(),
+                row: 0
+            }
+        );
+        assert!(code.is_empty());
+        let error = with_unresolved_fixed(UnresolvedFixed::Error, |mut witgen, ids| {
+            witgen.solve_block(ids, 1).err()
+        })
+        .unwrap();
+        assert_eq!(
+            error,
+            WitgenJitError::UnresolvedFixed {
+                identity_id: 0,
+                column: "F".to_string(),
+                row: 0
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Identity 0 references F on row 0, which the fixed evaluator cannot provide."
+        );
+    }
+
+    /// Counts how often each fixed cell is evaluated.
+    #[derive(Default)]
+    struct CountingEvaluator {
+        evaluations: std::cell::RefCell<BTreeMap<(String, i32), usize>>,
+    }
+
+    impl FixedEvaluator<GoldilocksField> for &CountingEvaluator {
+        fn evaluate(&self, var: &AlgebraicReference, row_offset: i32) -> Option<GoldilocksField> {
+            let row = row_offset + var.next as i32;
+            *self
+                .evaluations
+                .borrow_mut()
+                .entry((var.name.clone(), row))
+                .or_default() += 1;
+            Some((row as u64 + 1).into())
+        }
+    }
+
+    #[test]
+    fn fixed_values_are_cached() {
+        let input = "
+        namespace N(8);
+            col fixed F = [1, 2, 3, 4, 5, 6, 7, 8];
+            let X;
+            let Y;
+            let Z;
+            X = F + Y;
+            Y = F * Z + F';
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let x = Cell {
+            column_name: "N::X".to_string(),
+            id: fixed_data.try_column_by_name("N::X").unwrap().id,
+            row_offset: 0,
+        };
+        let evaluator = CountingEvaluator::default();
+        let mut witgen = WitgenInference::new(&fixed_data, &evaluator, vec![x]);
+        let ids = &analyzed.identities;
+        assert_eq!(
+            witgen.process_identity(&ids[1], 0),
+            ProcessStatus::NoProgress
+        );
+        assert_eq!(
+            witgen.process_identity(&ids[1], 0),
+            ProcessStatus::NoProgress
+        );
+        assert_eq!(witgen.process_identity(&ids[0], 0), ProcessStatus::Complete);
+        assert_eq!(witgen.process_identity(&ids[1], 0), ProcessStatus::Complete);
+        assert_eq!(
+            format_code(&witgen.code()),
+            "N::Y[0] = (N::X[0] + -1);\nN::Z[0] = (N::Y[0] + -2);"
+        );
+        // F[0] is referenced by four evaluations of identities, but only
+        // evaluated once.
+        assert_eq!(
+            evaluator.evaluations.into_inner().into_iter().collect_vec(),
+            vec![(("N::F".to_string(), 0), 1), (("N::F".to_string(), 1), 1)]
+        );
+    }
+
+    #[test]
+    fn process_status() {
+        let input = "
+        namespace N(8);
+            col fixed F = [1, 2, 3, 4, 5, 6, 7, 8];
+            let X;
+            let Y;
+            let Z;
+            [ X ] in [ Y ];
+            [ X ] connect [ Y ];
+            X = Y + Z;
+            (Z - 1) * (Z - 2) = 0;
+            [ X, Y ] in [ F, F ];
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 8, vec![("N::X", 0)]);
+        let ids = &fixed_data.analyzed.identities;
+        assert_eq!(
+            witgen.process_identity(&ids[0], 0),
+            ProcessStatus::Unsupported(
+                "Only lookups into fixed columns are supported.".to_string()
+            )
+        );
+        assert_eq!(
+            witgen.process_identity(&ids[1], 0),
+            ProcessStatus::Unsupported("Connect identities are not supported.".to_string())
+        );
+        assert_eq!(
+            witgen.process_identity(&ids[2], 0),
+            ProcessStatus::NoProgress
+        );
+        assert_eq!(witgen.process_identity(&ids[3], 0), ProcessStatus::Progress);
+        assert_eq!(witgen.process_identity(&ids[4], 0), ProcessStatus::Complete);
+        assert_eq!(witgen.process_identity(&ids[2], 0), ProcessStatus::Complete);
+    }
+
+    #[test]
+    fn only_fixed_columns() {
+        let input = "
+        namespace N(8);
+            col fixed F = [1, 2]*;
+            col fixed G = [1, 3]*;
+            let X;
+            F = G;
+            [ F ] in [ G ];
+            X = F;
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 8, vec![]);
+        let ids = &fixed_data.analyzed.identities;
+        // `F = G` does not hold on row 1, but the identities are not evaluated at all.
+        assert_eq!(witgen.process_identity(&ids[0], 1), ProcessStatus::Complete);
+        assert_eq!(witgen.process_identity(&ids[1], 1), ProcessStatus::Complete);
+        assert!(witgen.code_so_far().is_empty());
+        assert!(witgen.fixed_reads().is_empty());
+        assert_eq!(witgen.process_identity(&ids[2], 1), ProcessStatus::Complete);
+        assert_eq!(format_code(&witgen.code()), "N::X[1] = 2;");
+    }
+
+    fn fib_on_last_row(policy: LastRowPolicy) -> (bool, String) {
+        let input = "namespace Fib(8); let X; let Y; X' = Y; Y' = X + Y;";
+        let (witgen, fixed_data) = witgen_for(input, 8, vec![("Fib::X", 7), ("Fib::Y", 7)]);
+        let mut witgen =
+            witgen.with_config(WitgenInferenceConfig::default().with_last_row_policy(policy));
+        let complete = fixed_data
+            .analyzed
+            .identities
+            .iter()
+            .all(|id| witgen.process_identity(id, 7).is_complete());
+        (complete, format_code(&witgen.code()))
     }
 
     #[test]
-    fn simple_polynomial_solving() {
-        let input = "let X; let Y; let Z; X = 1; Y = X + 1; Z * Y = X + 10;";
-        let code = solve_on_rows(input, &[0], vec![], None);
-        assert_eq!(code, "X[0] = 1;\nY[0] = 2;\nZ[0] = -9223372034707292155;");
+    fn last_row_unbounded() {
+        assert_eq!(
+            fib_on_last_row(LastRowPolicy::Unbounded),
+            (
+                true,
+                "Fib::X[8] = Fib::Y[7];\nFib::Y[8] = (Fib::X[7] + Fib::Y[7]);".to_string()
+            )
+        );
     }
 
     #[test]
-    fn fib() {
-        let input = "let X; let Y; X' = Y; Y' = X + Y;";
-        let code = solve_on_rows(input, &[0, 1], vec![("X", 0), ("Y", 0)], None);
+    fn last_row_skip() {
+        assert_eq!(fib_on_last_row(LastRowPolicy::Skip), (true, String::new()));
+    }
+
+    #[test]
+    fn last_row_wrap() {
+        assert_eq!(
+            fib_on_last_row(LastRowPolicy::Wrap),
+            (
+                true,
+                "Fib::X[0] = Fib::Y[7];\nFib::Y[0] = (Fib::X[7] + Fib::Y[7]);".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn bus_interaction_directions() {
+        let input = "
+        namespace N(8);
+            col fixed LATCH = [1]*;
+            let X;
+            let Y;
+            let Z;
+            Constr::PhantomBusInteraction(LATCH, [7, X, Y]);
+            Constr::PhantomBusInteraction(-1 * LATCH, [7, X, Z + 1]);
+            Constr::PhantomBusInteraction(LATCH, [7, X, Z]);
+        ";
+        let (mut witgen, fixed_data) = witgen_for(input, 8, vec![("N::X", 0), ("N::Y", 0)]);
+        let ids = &fixed_data.analyzed.identities;
+        let directions = ids
+            .iter()
+            .map(|id| {
+                let Identity::PhantomBusInteraction(i) = id else {
+                    panic!()
+                };
+                BusDirection::of(&i.multiplicity)
+            })
+            .collect_vec();
+        assert_eq!(
+            directions,
+            vec![
+                BusDirection::Send,
+                BusDirection::Receive,
+                BusDirection::Send
+            ]
+        );
+        // The payload of the second send is not known and cannot be solved by the send.
+        assert_eq!(
+            witgen.process_identity(&ids[2], 0),
+            ProcessStatus::NoProgress
+        );
+        // The payload of the first send is known, nothing new is solved.
+        assert_eq!(witgen.process_identity(&ids[0], 0), ProcessStatus::Complete);
+        assert_eq!(witgen.known_cells.len(), 2);
+        // The receive takes Z from the bus.
+        assert_eq!(witgen.process_identity(&ids[1], 0), ProcessStatus::Complete);
+        assert_eq!(witgen.process_identity(&ids[2], 0), ProcessStatus::Complete);
+        assert_eq!(
+            format_code(&witgen.code()),
+            "state_write(0, [7, N::X[0], N::Y[0]]);
+lookup(1, [Known(7), Known(N::X[0]), Unknown(N::Z[0] + 1)]);
+state_write(2, [7, N::X[0], N::Z[0]]);"
+        );
+    }
+
+    #[test]
+    fn memory_store() {
+        let input = "
+        namespace Main(8);
+            col fixed STEP(i) { i };
+            col fixed IS_STORE = [0, 1]*;
+            let ADDR;
+            let VALUE;
+            ADDR' = ADDR + 4;
+            VALUE' = VALUE * 2;
+            Constr::PhantomBusInteraction(IS_STORE, [42, ADDR, STEP, VALUE]);
+        ";
+        let code = solve_on_rows(
+            input,
+            &[0, 1, 2],
+            vec![("Main::ADDR", 0), ("Main::VALUE", 0)],
+            Some(7),
+        );
         assert_eq!(
             code,
-            "X[1] = Y[0];\nY[1] = (X[0] + Y[0]);\nX[2] = Y[1];\nY[2] = (X[1] + Y[1]);"
+            "Main::ADDR[1] = (Main::ADDR[0] + 4);
+Main::VALUE[1] = (Main::VALUE[0] * 2);
+Main::ADDR[2] = (Main::ADDR[1] + 4);
+Main::VALUE[2] = (Main::VALUE[1] * 2);
+state_write(2, [42, Main::ADDR[1], 1, Main::VALUE[1]]);
+Main::ADDR[3] = (Main::ADDR[2] + 4);
+Main::VALUE[3] = (Main::VALUE[2] * 2);"
         );
     }
 
     #[test]
-    fn fib_with_fixed() {
+    fn solve_error_unsupported() {
+        let input = "namespace N(8); let X; let Y; [ X ] connect [ Y ];";
+        assert_eq!(
+            solve_block_on(input, 1, Default::default()),
+            Err(WitgenJitError::UnsupportedIdentityKind {
+                identity_id: 0,
+                kind: IdentityKind::Connect
+            })
+        );
+    }
+
+    #[test]
+    fn solve_error_stuck() {
+        let input = "namespace N(8); let X; let Y; X = Y;";
+        let Err(WitgenJitError::Stuck(unknown)) = solve_block_on(input, 1, Default::default())
+        else {
+            panic!("Expected to be stuck.");
+        };
+        assert_eq!(
+            unknown.iter().map(|c| c.to_string()).collect_vec(),
+            ["N::X[0]", "N::Y[0]"]
+        );
+    }
+
+    #[test]
+    fn solve_error_incomplete() {
         let input = "
-        namespace Fib(8);
+        namespace N(256);
+            col fixed BYTE(i) { i & 0xff };
+            let sel;
+            let a;
+            sel $ [ a ] in [ BYTE ];
+        ";
+        // All cells are known, but the lookup cannot be resolved, since the
+        // selector is only known at runtime.
+        let (mut witgen, fixed_data) = witgen_for(input, 256, vec![("N::sel", 0), ("N::a", 0)]);
+        let Err(error) = witgen.solve_block(&fixed_data.analyzed.identities, 1) else {
+            panic!("Expected the lookup to be incomplete.");
+        };
+        assert_eq!(error, WitgenJitError::Incomplete(vec![(0, 0)]));
+        assert_eq!(error.to_string(), "Could not complete identity 0 on row 0");
+    }
+
+    #[test]
+    fn solve_error_conflict() {
+        let input = "namespace N(8); let X; X = 1; X = 2;";
+        assert_eq!(
+            solve_block_on(input, 1, Default::default()),
+            Err(WitgenJitError::Conflict(ConflictInfo {
+                identity_id: 1,
+                row_offset: 0,
+                message: "Linear constraint is not satisfiable: -1 != 0".to_string()
+            }))
+        );
+    }
+
+    #[test]
+    fn speculative_conflict() {
+        let input = "namespace N(8); let X; X = 1; X = 2;";
+        let config =
+            WitgenInferenceConfig::default().with_conflict_policy(ConflictPolicy::Speculative);
+        assert_eq!(
+            solve_block_on(input, 1, config),
+            Ok("N::X[0] = 1;
+infeasible(\"Linear constraint is not satisfiable: -1 != 0\");"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn pow_zero_zero() {
+        let input = "namespace N(8); col fixed ZERO = [0]*; let X; X = ZERO ** 0;";
+        assert_eq!(
+            solve_block_on(input, 1, Default::default()),
+            Ok("N::X[0] = 1;".to_string())
+        );
+        let config = WitgenInferenceConfig::default().with_pow_zero_zero(PowZeroZero::Error);
+        let Err(WitgenJitError::Stuck(unknown)) = solve_block_on(input, 1, config) else {
+            panic!("Expected to be stuck.");
+        };
+        assert_eq!(
+            unknown.iter().map(|c| c.to_string()).collect_vec(),
+            ["N::X[0]"]
+        );
+    }
+
+    #[test]
+    fn solve_error_row_out_of_bounds() {
+        let input = "namespace N(4); let X; X = 1;";
+        assert_eq!(
+            solve_block_on(input, 8, Default::default()),
+            Err(WitgenJitError::RowOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn solve_error_round_limit() {
+        // The value is propagated backwards from the last row, one row per round.
+        let input = "
+        namespace N(4);
+            col fixed LAST = [0, 0, 0, 1];
+            let X;
+            LAST * (X - 1) = 0;
+            (1 - LAST) * (X - X' - 1) = 0;
+        ";
+        assert_eq!(
+            solve_block_on(
+                input,
+                4,
+                WitgenInferenceConfig::default().with_round_limit(4)
+            )
+            .unwrap(),
+            "N::X[3] = 1;\nN::X[2] = 2;\nN::X[1] = 3;\nN::X[0] = 4;"
+        );
+        assert_eq!(
+            solve_block_on(
+                input,
+                4,
+                WitgenInferenceConfig::default().with_round_limit(2)
+            ),
+            Err(WitgenJitError::BudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn fixed_column_with_multiple_sizes() {
+        let input = "namespace N(8); col fixed F = [0]*; let X; X = F;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("N::X")
+            .fixed_with_sizes(
+                "N::F",
+                vec![
+                    [1, 2, 3, 4].map(GoldilocksField::from).to_vec(),
+                    [5, 6, 7, 8, 9, 10, 11, 12]
+                        .map(GoldilocksField::from)
+                        .to_vec(),
+                ],
+            )
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let solve = |degree, block_size| {
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, degree);
+            WitgenInference::new(&fixed_data, ref_eval, vec![])
+                .solve_block(&analyzed.identities, block_size)
+                .map(|plan| format_code(&plan.code))
+        };
+        assert_eq!(solve(4, 2).unwrap(), "N::X[0] = 1;\nN::X[1] = 2;");
+        assert_eq!(solve(8, 2).unwrap(), "N::X[0] = 5;\nN::X[1] = 6;");
+        // The columns are declared with degree 8, but the instance only has 4 rows.
+        assert_eq!(solve(4, 8), Err(WitgenJitError::RowOutOfBounds));
+    }
+
+    #[test]
+    fn pinned_solve_direction() {
+        let input = "let X; X' = X + 1;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").build();
+        let fixed_data = fixture.fixed_data();
+        let known_cell = Cell {
+            column_name: "X".to_string(),
+            id: fixed_data.try_column_by_name("X").unwrap().id,
+            row_offset: 1,
+        };
+        let solve = |direction: Option<SolveDirection>| {
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+            let mut witgen = WitgenInference::new(&fixed_data, ref_eval, [known_cell.clone()]);
+            if let Some(direction) = direction {
+                witgen = witgen.with_solve_direction(analyzed.identities[0].id(), direction);
+            }
+            witgen.solve_rows(&analyzed.identities, &[0, 1]).unwrap();
+            format_code(&witgen.code())
+        };
+        assert_eq!(solve(None), "X[0] = (X[1] + -1);\nX[2] = (X[1] + 1);");
+        assert_eq!(solve(Some(SolveDirection::Forward)), "X[2] = (X[1] + 1);");
+        assert_eq!(solve(Some(SolveDirection::Reverse)), "X[0] = (X[1] + -1);");
+    }
+
+    #[test]
+    fn max_decomposition_limbs() {
+        let input = "
+        namespace N(256);
+            col fixed BYTE(i) { i & 0xff };
+            col fixed V = [0x1234]*;
+            let X;
+            let a;
+            let b;
+            [a] in [BYTE];
+            [b] in [BYTE];
+            X = V;
+            X = a + 256 * b;
+        ";
+        assert_eq!(
+            solve_block_on(input, 1, Default::default()).unwrap(),
+            "N::X[0] = 4660;\nN::a[0] = 52;\nN::b[0] = (4608 // 256);"
+        );
+        let config = WitgenInferenceConfig::default().with_max_decomposition_limbs(1);
+        let Err(WitgenJitError::Stuck(unknown)) = solve_block_on(input, 1, config) else {
+            panic!("Expected to be stuck.");
+        };
+        assert_eq!(
+            unknown.iter().map(|c| c.to_string()).collect_vec(),
+            ["N::a[0]", "N::b[0]"]
+        );
+    }
+
+    #[test]
+    fn long_sum_allocations() {
+        let sum = (0..100).map(|_| "F * Y").join(" + ");
+        let input = format!("namespace N(4); col fixed F = [1, 2, 3, 4]; let X; let Y; X = {sum};");
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(&input).unwrap();
+        let fixture = FixedDataBuilder::new(4)
+            .witness("N::X")
+            .witness("N::Y")
+            .fixed("N::F", [1, 2, 3, 4].map(GoldilocksField::from).to_vec())
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+        let witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        let Identity::Polynomial(PolynomialIdentity { expression, .. }) = &analyzed.identities[0]
+        else {
+            panic!("Expected a polynomial identity.");
+        };
+        let Expression::BinaryOperation(AlgebraicBinaryOperation { right: sum, .. }) = expression
+        else {
+            panic!("Expected a subtraction.");
+        };
+        let (flattened, flattened_allocations) =
+            count_allocations(|| witgen.evaluate(sum, 1).unwrap());
+        // Evaluate the summands separately and add them up pairwise for comparison.
+        let summands = sum
+            .all_children()
+            .filter(|e| matches!(e, Expression::BinaryOperation(op) if op.op == AlgebraicBinaryOperator::Mul))
+            .collect_vec();
+        assert_eq!(summands.len(), 100);
+        let (pairwise, pairwise_allocations) = count_allocations(|| {
+            summands
+                .iter()
+                .map(|e| witgen.evaluate(e, 1).unwrap())
+                .reduce(|acc, summand| &acc + &summand)
+                .unwrap()
+        });
+        assert_eq!(flattened.to_string(), "200 * N::Y[1]");
+        assert_eq!(pairwise.to_string(), flattened.to_string());
+        assert!(
+            flattened_allocations < pairwise_allocations,
+            "{flattened_allocations} allocations, but {pairwise_allocations} when adding pairwise."
+        );
+    }
+
+    #[test]
+    fn column_priorities() {
+        let input = "let X; let Y; let Z; X = 1; Y = 2; Z = X + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .witness("Z")
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let priorities = [("Y", 5), ("Z", 10)]
+            .map(|(name, priority)| (fixed_data.try_column_by_name(name).unwrap(), priority))
+            .into_iter()
+            .collect();
+        let config = WitgenInferenceConfig::default().with_column_priorities(priorities);
+        let ids = analyzed.identities.iter().map(|id| (id, 0)).collect_vec();
+        let order = |known_cells: &[&str]| {
+            let known_cells = known_cells.iter().map(|name| Cell {
+                column_name: name.to_string(),
+                id: fixed_data.try_column_by_name(name).unwrap().id,
+                row_offset: 0,
+            });
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+            WitgenInference::new(&fixed_data, ref_eval, known_cells)
+                .with_config(config.clone())
+                .order_by_priority(&ids)
+                .into_iter()
+                .map(|(id, _)| id.id())
+                .collect_vec()
+        };
+        assert_eq!(order(&[]), [2, 1, 0]);
+        // Once Z is known, the identity determining it only has low-priority unknowns left.
+        assert_eq!(order(&["Z"]), [1, 2, 0]);
+    }
+
+    #[test]
+    fn fork_and_merge() {
+        let input = "let X; let Y; let Z; X = 1; Y = X + 1; Z = X + 2;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let [x_id, y_id, z_id] = &analyzed.identities[..] else {
+            panic!("Expected three identities.");
+        };
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .witness("Z")
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        assert!(witgen.process_identity(x_id, 0).is_complete());
+
+        let mut first = witgen.fork();
+        assert!(first.process_identity(y_id, 0).is_complete());
+        let mut second = witgen.fork();
+        assert!(second.process_identity(z_id, 0).is_complete());
+        assert_eq!(format_code(&second.code), "Z[0] = 3;");
+
+        witgen.merge_fork(first);
+        assert_eq!(format_code(&witgen.code), "X[0] = 1;\nY[0] = 2;");
+        // Z was only derived in the discarded fork.
+        assert!(!witgen
+            .known_cells
+            .iter()
+            .any(|cell| cell.column_name == "Z"));
+        assert!(witgen.process_identity(z_id, 0).is_complete());
+        assert_eq!(
+            format_code(&witgen.code()),
+            "X[0] = 1;\nY[0] = 2;\nZ[0] = 3;"
+        );
+    }
+
+    #[test]
+    fn merge_fork_with_public() {
+        let input = "
+        namespace N(8);
             col fixed FIRST = [1] + [0]*;
-            let x;
-            let y;
-            FIRST * (y - 1) = 0;
-            FIRST * (x - 1) = 0;
-            // This works in this test because we do not implement wrapping properly in this test.
-            x' - y = 0;
-            y' - (x + y) = 0;
+            let X;
+            public start = X(0);
+            FIRST * (X - :start) = 0;
         ";
-        let code = solve_on_rows(input, &[0, 1, 2, 3], vec![], None);
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new_with_resolver(
+            &fixed_data,
+            ref_eval,
+            [],
+            |r: &AlgebraicReference, row_offset| {
+                Variable::Cell(Cell::from_reference(r, row_offset))
+            },
+        );
+        let start = Variable::Public("N::start".to_string());
+        let mut fork = witgen.fork();
+        fork.register_public("N::start", start.clone());
+        witgen.merge_fork(fork);
+        assert_eq!(witgen.inputs(), [Variable::Public("N::start".to_string())]);
+        assert_eq!(witgen.provenance(&start), Some(Provenance::Input));
+        // The public registered in the fork can be used after merging.
         assert_eq!(
-            code,
-            "Fib::y[0] = 1;
-Fib::x[0] = 1;
-Fib::x[1] = 1;
-Fib::y[1] = 2;
-Fib::x[2] = 2;
-Fib::y[2] = 3;
-Fib::x[3] = 3;
-Fib::y[3] = 5;
-Fib::x[4] = 5;
-Fib::y[4] = 8;"
+            witgen.process_identity(&analyzed.identities[0], 0),
+            ProcessStatus::Complete
+        );
+        assert_eq!(
+            witgen.code().iter().map(|e| e.to_string()).join("\n"),
+            "N::X[0] = :N::start;"
         );
     }
 
     #[test]
-    fn xor() {
+    fn take_code_after_each_round() {
+        // Processing the rows in reverse order means that we
+        // can only make progress on one row per round.
+        let input = "let X; X' = X + 1;";
+        let rows = [3, 2, 1, 0];
+        let expected = solve_on_rows(input, &rows, vec![("X", 0)], None);
+
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").build();
+        let fixed_data = fixture.fixed_data();
+        let known_cells = [Cell {
+            column_name: "X".to_string(),
+            id: fixed_data.try_column_by_name("X").unwrap().id,
+            row_offset: 0,
+        }];
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        // The identity is processed again on the rows where it is already
+        // complete, which would result in equality checks.
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, known_cells)
+            .with_config(WitgenInferenceConfig::default().with_equality_checks(false));
+        let mut code = vec![];
+        for round in 1..=4 {
+            witgen.start_round();
+            // Process every other round in a fork, which has to be
+            // merged into a state whose code has already been taken.
+            let mut fork = (round % 2 == 0).then(|| witgen.fork());
+            let target = fork.as_mut().unwrap_or(&mut witgen);
+            for row in rows {
+                target.process_identity(&analyzed.identities[0], row);
+            }
+            if let Some(fork) = fork {
+                witgen.merge_fork(fork);
+            }
+            assert_eq!(witgen.code_so_far().len(), 1);
+            assert_eq!(witgen.code_rounds(), [round]);
+            code.extend(witgen.take_code());
+            assert!(witgen.code_so_far().is_empty());
+            assert!(witgen.code_rounds().is_empty());
+        }
+        assert_eq!(format_code(&code), expected);
+        assert!(witgen.code().is_empty());
+
+        // Machine calls in code that has been taken are still reported.
+        let (kept, drained) = solve_xor(|mut witgen| {
+            let kept = (witgen.cacheable_calls(), witgen.machine_call_summary());
+            witgen.take_code();
+            (
+                kept,
+                (witgen.cacheable_calls(), witgen.machine_call_summary()),
+            )
+        });
+        assert_eq!(kept.0, [0].into_iter().collect());
+        assert_eq!(drained, kept);
+    }
+
+    #[test]
+    fn machines_on_multiple_threads() {
         let input = "
-namespace Xor(256 * 256);
-    let latch: col = |i| { if (i % 4) == 3 { 1 } else { 0 } };
-    let FACTOR: col = |i| { 1 << (((i + 1) % 4) * 8) };
+        namespace Double(8);
+            col fixed IDX(i) { i };
+            let a;
+            a = 2 * IDX;
+        namespace Square(8);
+            col fixed IDX(i) { i };
+            let b;
+            b = IDX * IDX;
+        ";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let solve = |namespace: &str| {
+            let identities = analyzed
+                .identities
+                .iter()
+                .filter(|id| {
+                    id.all_children().any(
+                        |e| matches!(e, Expression::Reference(r) if r.name.starts_with(namespace)),
+                    )
+                })
+                .cloned()
+                .collect_vec();
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+            let plan = WitgenInference::new(&fixed_data, ref_eval, vec![])
+                .solve_block(&identities, 2)
+                .unwrap();
+            format_code(&plan.code)
+        };
+        let (double, square) = std::thread::scope(|s| {
+            let double = s.spawn(|| solve("Double::"));
+            let square = s.spawn(|| solve("Square::"));
+            (double.join().unwrap(), square.join().unwrap())
+        });
+        assert_eq!(double, "Double::a[0] = 0;\nDouble::a[1] = 2;");
+        assert_eq!(square, "Square::b[0] = 0;\nSquare::b[1] = 1;");
+    }
+
+    #[test]
+    fn constant_cells_from_range_constraints() {
+        let input = "let X; let Y; let Z; X = Y; Z = X + 1;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("Y")
+            .witness("Z")
+            .range_constraint("X", RangeConstraint::from_range(0.into(), 5.into()))
+            .range_constraint("Y", RangeConstraint::from_range(5.into(), 10.into()))
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        let plan = witgen.solve_block(&analyzed.identities, 1).unwrap();
+        assert_eq!(format_code(&plan.code), "Y[0] = 5;\nX[0] = 5;\nZ[0] = 6;");
+        // Only Y is fixed by range constraints, X and Z are determined by identities.
+        let constant_cells = witgen
+            .constant_cells()
+            .into_iter()
+            .map(|(cell, value)| (cell.to_string(), value))
+            .sorted()
+            .collect_vec();
+        assert_eq!(constant_cells, [("Y[0]".to_string(), 5.into())]);
+    }
+
+    #[test]
+    fn range_pinned_factor() {
+        let input = "let X; let C; let Y; X * C = Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8)
+            .witness("X")
+            .witness("C")
+            .witness("Y")
+            .range_constraint("C", RangeConstraint::from_range(5.into(), 5.into()))
+            .build();
+        let fixed_data = fixture.fixed_data();
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let y = Cell::new("Y", fixed_data.try_column_by_name("Y").unwrap().id, 0);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![y]);
+        // C is not known, but it can only be 5, so the identity is affine in X.
+        let plan = witgen.solve_block(&analyzed.identities, 1).unwrap();
+        assert_eq!(format_code(&plan.code), "X[0] = (-Y[0] / -5);");
+    }
 
-    let a: int -> int = |i| i % 256;
-    let b: int -> int = |i| (i / 256) % 256;
-    let P_A: col = a;
-    let P_B: col = b;
-    let P_C: col = |i| a(i) ^ b(i);
+    #[test]
+    fn product_of_linear_factors() {
+        let input = "let X; (X - 1) * (X - 2) * (X - 4) = 0;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").build();
+        let fixed_data = fixture.fixed_data();
+        let x = Cell {
+            column_name: "X".to_string(),
+            id: fixed_data.try_column_by_name("X").unwrap().id,
+            row_offset: 0,
+        };
+        let identity = &analyzed.identities[0];
 
-    let A_byte;
-    let B_byte;
-    let C_byte;
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![]);
+        assert_eq!(
+            witgen.process_identity(identity, 0),
+            ProcessStatus::Progress
+        );
+        assert_eq!(
+            witgen.range_constraint(x.clone()),
+            Some(RangeConstraint::from_range(1.into(), 4.into()))
+        );
 
-    [ A_byte, B_byte, C_byte ] in [ P_A, P_B, P_C ];
+        // Once X is known, the identity is checked.
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, vec![x]);
+        assert!(witgen.process_identity(identity, 0).is_complete());
+        assert_eq!(
+            format_code(&witgen.code()),
+            "assert (((X[0] + -1) * (X[0] + -2)) * (X[0] + -4)) == 0;"
+        );
+    }
 
-    let A;
-    let B;
-    let C;
+    #[test]
+    fn resume_from_snapshot() {
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(XOR_INPUT).unwrap();
+        let fixed_col_vals = constant_evaluator::generate(&analyzed);
+        let fixed_data = FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+        let (fixed_data, retained_identities) =
+            global_constraints::set_global_constraints(fixed_data, &analyzed.identities);
+        let ids = retained_identities.into_iter().cloned().collect_vec();
+        let rows = [3, 4, 5, 6, 7];
+        let known_cells = ["Xor::A", "Xor::C"].map(|name| Cell {
+            column_name: name.to_string(),
+            id: fixed_data.try_column_by_name(name).unwrap().id,
+            row_offset: 7,
+        });
+        let new_witgen = || {
+            let ref_eval =
+                FixedEvaluatorForFixedData::new(&fixed_data, instance_degree(&fixed_data));
+            WitgenInference::new(&fixed_data, ref_eval, known_cells.clone())
+        };
 
-    A' = A * (1 - latch) + A_byte * FACTOR;
-    B' = B * (1 - latch) + B_byte * FACTOR;
-    C' = C * (1 - latch) + C_byte * FACTOR;
-";
-        let code = solve_on_rows(
-            input,
-            // Use the second block to avoid wrap-around.
-            &[3, 4, 5, 6, 7],
-            vec![
-                ("Xor::A", 7),
-                ("Xor::C", 7), // We solve it in reverse, just for fun.
-            ],
-            Some(16),
+        let mut uninterrupted = new_witgen();
+        uninterrupted.solve_rows(&ids, &rows).unwrap();
+        let rounds = uninterrupted.round;
+        assert!(rounds > 2);
+
+        let mut first = new_witgen()
+            .with_config(WitgenInferenceConfig::default().with_round_limit(rounds / 2 - 1));
+        assert_eq!(
+            first.solve_rows(&ids, &rows),
+            Err(WitgenJitError::BudgetExceeded)
         );
+        assert_eq!(first.round, rounds / 2);
+        let snapshot = first.serialize_state().to_string();
+
+        let mut second = new_witgen();
+        second.restore_state(snapshot.parse().unwrap()).unwrap();
+        second.solve_rows(&ids, &rows).unwrap();
+        assert_eq!(second.round, rounds);
+        let resumed_code = first.code().into_iter().chain(second.code()).collect_vec();
         assert_eq!(
-            code,
-            "\
-Xor::A_byte[6] = ((Xor::A[7] & 4278190080) // 16777216);
-Xor::A[6] = (Xor::A[7] & 16777215);
-assert Xor::A[7] == (Xor::A[7] | 4294967295);
-Xor::C_byte[6] = ((Xor::C[7] & 4278190080) // 16777216);
-Xor::C[6] = (Xor::C[7] & 16777215);
-assert Xor::C[7] == (Xor::C[7] | 4294967295);
-Xor::A_byte[5] = ((Xor::A[6] & 16711680) // 65536);
-Xor::A[5] = (Xor::A[6] & 65535);
-assert Xor::A[6] == (Xor::A[6] | 16777215);
-Xor::C_byte[5] = ((Xor::C[6] & 16711680) // 65536);
-Xor::C[5] = (Xor::C[6] & 65535);
-assert Xor::C[6] == (Xor::C[6] | 16777215);
-lookup(0, [Known(Xor::A_byte[6]), Unknown(Xor::B_byte[6]), Known(Xor::C_byte[6])]);
-Xor::A_byte[4] = ((Xor::A[5] & 65280) // 256);
-Xor::A[4] = (Xor::A[5] & 255);
-assert Xor::A[5] == (Xor::A[5] | 65535);
-Xor::C_byte[4] = ((Xor::C[5] & 65280) // 256);
-Xor::C[4] = (Xor::C[5] & 255);
-assert Xor::C[5] == (Xor::C[5] | 65535);
-lookup(0, [Known(Xor::A_byte[5]), Unknown(Xor::B_byte[5]), Known(Xor::C_byte[5])]);
-Xor::A_byte[3] = Xor::A[4];
-Xor::C_byte[3] = Xor::C[4];
-lookup(0, [Known(Xor::A_byte[4]), Unknown(Xor::B_byte[4]), Known(Xor::C_byte[4])]);
-lookup(0, [Known(Xor::A_byte[3]), Unknown(Xor::B_byte[3]), Known(Xor::C_byte[3])]);
-Xor::B[4] = Xor::B_byte[3];
-Xor::B[5] = (Xor::B[4] + (Xor::B_byte[4] * 256));
-Xor::B[6] = (Xor::B[5] + (Xor::B_byte[5] * 65536));
-Xor::B[7] = (Xor::B[6] + (Xor::B_byte[6] * 16777216));"
+            format_code(&resumed_code),
+            format_code(&uninterrupted.code())
+        );
+
+        // Snapshots cannot be restored for a different PIL.
+        let fixture = expression_fixture();
+        let other_fixed_data = fixture.fixed_data();
+        let ref_eval = FixedEvaluatorForFixedData::new(&other_fixed_data, 4);
+        let mut other = WitgenInference::new(&other_fixed_data, ref_eval, vec![]);
+        assert_eq!(
+            other.restore_state(snapshot.parse().unwrap()),
+            Err(WitgenJitError::Serialization(
+                "The snapshot was taken for a different PIL.".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn fib_redundant_checks() {
+        let input = "let X; let Y; X' = Y; Y' = X + Y;";
+        let analyzed: Analyzed<GoldilocksField> =
+            powdr_pil_analyzer::analyze_string(input).unwrap();
+        let fixture = FixedDataBuilder::new(8).witness("X").witness("Y").build();
+        let fixed_data = fixture.fixed_data();
+        let known_cells = [("X", 0), ("Y", 0), ("X", 1)].map(|(name, row_offset)| Cell {
+            column_name: name.to_string(),
+            id: fixed_data.try_column_by_name(name).unwrap().id,
+            row_offset,
+        });
+        let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 8);
+        let mut witgen = WitgenInference::new(&fixed_data, ref_eval, known_cells)
+            .with_config(WitgenInferenceConfig::default().with_redundant_checks());
+        let plan = witgen.solve_block(&analyzed.identities, 1).unwrap();
+        assert_eq!(
+            format_code(&plan.code),
+            "assert X[1] == Y[0];
+Y[1] = (X[0] + Y[0]);"
         );
     }
+
+    /// Identities where `Z` is determined both as an expression and as a constant.
+    /// `Y = Z * A + B` can only be solved if `Z` is known to be a constant.
+    const ORDER_INPUT: &str = "
+namespace N(8);
+    let X;
+    let Y;
+    let Z;
+    let A;
+    let B;
+    A * (A - 1) = 0;
+    B * (B - 1) = 0;
+    Z = X + 1;
+    Z = 2;
+    Y = Z * A + B;
+";
+
+    /// Solves the identities in the given order and returns the values of all
+    /// cells when running the code on inputs that satisfy the identities.
+    fn solve_in_order(
+        analyzed: &Analyzed<GoldilocksField>,
+        fixed_data: &FixedData<GoldilocksField>,
+        order: &[usize],
+    ) -> Result<BTreeMap<String, GoldilocksField>, String> {
+        let identities = order
+            .iter()
+            .map(|i| analyzed.identities[*i].clone())
+            .collect_vec();
+        let inputs = [("N::X", 1), ("N::Y", 3)].map(|(name, value)| {
+            let id = fixed_data.try_column_by_name(name).unwrap().id;
+            (Cell::new(name, id, 0), GoldilocksField::from(value))
+        });
+        let ref_eval = FixedEvaluatorForFixedData::new(fixed_data, 8);
+        let mut witgen =
+            WitgenInference::new(fixed_data, ref_eval, inputs.iter().map(|(c, _)| c.clone()));
+        let plan = witgen
+            .solve_block(&identities, 1)
+            .map_err(|e| format!("{e:?}"))?;
+        let mut machine_calls = MockCallHandler::from_fn(|_, _| None).strict();
+        let result = EffectInterpreter::new(&plan.code)
+            .run(inputs, &mut machine_calls)
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(result
+            .values
+            .into_iter()
+            .map(|(cell, value)| (cell.to_string(), value))
+            .collect())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(50))]
+
+        /// The known cells and their values do not depend on the order of the identities,
+        /// in contrast to the code itself.
+        #[test]
+        fn order_independent_knowledge(order in Just((0..5).collect_vec()).prop_shuffle()) {
+            let analyzed: Analyzed<GoldilocksField> =
+                powdr_pil_analyzer::analyze_string(ORDER_INPUT).unwrap();
+            let fixed_col_vals = constant_evaluator::generate(&analyzed);
+            let fixed_data =
+                FixedData::new(&analyzed, &fixed_col_vals, &[], Default::default(), 0);
+            let expected = solve_in_order(&analyzed, &fixed_data, &[0, 1, 2, 3, 4]);
+            prop_assert!(expected.is_ok(), "{expected:?}");
+            prop_assert_eq!(solve_in_order(&analyzed, &fixed_data, &order), expected);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1000))]
+
+        #[test]
+        fn process_identity_does_not_panic(
+            expressions in prop::collection::vec(algebraic_expression(), 1..=3),
+            known in prop::collection::vec(any::<bool>(), 2),
+        ) {
+            let fixture = expression_fixture();
+            let fixed_data = fixture.fixed_data();
+            let identities = expressions
+                .into_iter()
+                .enumerate()
+                .map(|(id, expression)| {
+                    Identity::Polynomial(PolynomialIdentity {
+                        id: id as u64,
+                        source: SourceRef::unknown(),
+                        expression,
+                    })
+                })
+                .collect_vec();
+            let known_cells = ["X", "Y"]
+                .into_iter()
+                .zip(known)
+                .filter(|(_, known)| *known)
+                .map(|(name, _)| Cell {
+                    column_name: name.to_string(),
+                    id: fixed_data.try_column_by_name(name).unwrap().id,
+                    row_offset: 0,
+                })
+                .collect_vec();
+            let ref_eval = FixedEvaluatorForFixedData::new(&fixed_data, 4);
+            let mut witgen = WitgenInference::new(&fixed_data, ref_eval, known_cells.clone());
+            // Not all identities can be solved, we only check the generated code.
+            let _ = witgen.solve_block(&identities, 2);
+
+            // Every cell is assigned at most once and never overwrites an input.
+            let mut assigned: HashSet<_> = known_cells.into_iter().collect();
+            for effect in &witgen.code() {
+                match effect {
+                    Effect::Assignment(cell, _) => {
+                        prop_assert!(assigned.insert(cell.clone()), "{cell} assigned twice.");
+                    }
+                    Effect::Assertion(_) => {}
+                    Effect::RangeConstraint(..)
+                    | Effect::RangeCheck(..)
+                    | Effect::MachineCall(..)
+                    | Effect::StateWrite(..)
+                    | Effect::Infeasible(_) => {
+                        prop_assert!(false, "Unexpected effect: {effect}");
+                    }
+                }
+            }
+        }
+    }
 }
@@ -12,7 +12,7 @@ use crate::witgen::block_processor::BlockProcessor;
 use crate::witgen::data_structures::finalizable_data::FinalizableData;
 use crate::witgen::data_structures::multiplicity_counter::MultiplicityCounter;
 use crate::witgen::data_structures::mutable_state::MutableState;
-use crate::witgen::jit::jit_processor::JitProcessor;
+use crate::witgen::jit::jit_processor::{jit_witgen_enabled, JitProcessor};
 use crate::witgen::processor::{OuterQuery, Processor, SolverState};
 use crate::witgen::rows::{Row, RowIndex, RowPair};
 use crate::witgen::sequence_iterator::{
@@ -118,6 +118,10 @@ impl<'a, T: FieldElement> BlockMachine<'a, T> {
             &parts.witnesses,
             (0..block_size).map(|i| Row::fresh(fixed_data, start_index + i)),
         );
+        let mut jit_processor = JitProcessor::new(fixed_data, parts.clone(), block_size, latch_row);
+        if jit_witgen_enabled() {
+            jit_processor.enable();
+        }
         Some(BlockMachine {
             name,
             degree_range,
@@ -136,7 +140,7 @@ impl<'a, T: FieldElement> BlockMachine<'a, T> {
                 latch_row,
                 parts.identities.len(),
             ),
-            jit_processor: JitProcessor::new(fixed_data, parts.clone(), block_size, latch_row),
+            jit_processor,
         })
     }
 }
@@ -464,6 +468,10 @@ impl<'a, T: FieldElement> BlockMachine<'a, T> {
                 .process_lookup_direct(mutable_state, identity_id, values, data)?;
         assert!(success);
 
+        let global_latch_row_index = self.data.len() - 1 - self.block_size + self.latch_row;
+        self.multiplicity_counter
+            .increment_at_row(identity_id, global_latch_row_index);
+
         Ok(outer_query
             .direct_lookup_to_eval_result(input_output_data)?
             .report_side_effect())
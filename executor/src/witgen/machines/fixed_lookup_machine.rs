@@ -7,6 +7,7 @@ use std::mem;
 use itertools::{Either, Itertools};
 use powdr_ast::analyzed::{AlgebraicReference, PolynomialType};
 use powdr_number::{DegreeType, FieldElement};
+use tracing::debug_span;
 
 use crate::witgen::affine_expression::{AffineExpression, AlgebraicVariable};
 use crate::witgen::data_structures::caller_data::CallerData;
@@ -104,6 +105,13 @@ fn create_index<T: FieldElement>(
         .exactly_one()
         .expect("all columns in a given lookup are expected to have the same degree");
 
+    let _span = debug_span!(
+        "create_index",
+        identity_id = application.identity_id,
+        rows = degree
+    )
+    .entered();
+
     let index: HashMap<Vec<T>, IndexValue<T>> = (0..degree)
         .fold(
             (
@@ -45,6 +45,7 @@ mod util;
 mod vm_processor;
 
 pub use affine_expression::{AffineExpression, AffineResult, AlgebraicVariable};
+pub use jit::jit_processor::jit_answered_lookups;
 pub use evaluators::partial_expression_evaluator::{PartialExpressionEvaluator, SymbolicVariables};
 
 static OUTER_CODE_NAME: &str = "witgen (outer code)";
@@ -4,6 +4,7 @@ use std::{cmp, ops};
 use num_traits::Zero;
 
 use powdr_number::{log2_exact, FieldElement, LargeInt};
+use serde::{Deserialize, Serialize};
 
 /// Constraint on the values of a variable X.
 /// It does not have to be an interval.
@@ -12,7 +13,11 @@ use powdr_number::{log2_exact, FieldElement, LargeInt};
 /// and bit masks. The actual constraint is the conjunction of the two.
 ///
 /// Note that the same constraint can have multiple representations.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(
+    into = "SerializedRangeConstraint<T>",
+    from = "SerializedRangeConstraint<T>"
+)]
 pub struct RangeConstraint<T: FieldElement> {
     /// Bit-mask.
     mask: T::Integer,
@@ -188,6 +193,48 @@ fn range_width<T: FieldElement>(min: T, max: T) -> T::Integer {
     }
 }
 
+/// The serialized form of a range constraint. The mask is stored as little-endian
+/// bytes, since `T::Integer` does not implement `Serialize` for all fields.
+#[derive(Serialize, Deserialize)]
+struct SerializedRangeConstraint<T> {
+    mask: Vec<u8>,
+    min: T,
+    max: T,
+}
+
+impl<T: FieldElement> From<RangeConstraint<T>> for SerializedRangeConstraint<T> {
+    fn from(rc: RangeConstraint<T>) -> Self {
+        let mask = (0..T::Integer::NUM_BITS / 8)
+            .map(|i| {
+                let byte = (rc.mask >> (8 * i)) & T::Integer::from(0xff);
+                byte.try_into_u64().unwrap() as u8
+            })
+            .collect();
+        Self {
+            mask,
+            min: rc.min,
+            max: rc.max,
+        }
+    }
+}
+
+impl<T: FieldElement> From<SerializedRangeConstraint<T>> for RangeConstraint<T> {
+    fn from(rc: SerializedRangeConstraint<T>) -> Self {
+        let mask = rc
+            .mask
+            .iter()
+            .enumerate()
+            .fold(T::Integer::zero(), |mask, (i, byte)| {
+                mask | (T::Integer::from(*byte as u64) << (8 * i))
+            });
+        Self {
+            mask,
+            min: rc.min,
+            max: rc.max,
+        }
+    }
+}
+
 #[inline]
 fn mask_from_bits<T: FieldElement>(bits: usize) -> T::Integer {
     if bits == 0 {
@@ -608,4 +655,17 @@ mod test {
             },
         );
     }
+
+    #[test]
+    fn serialize() {
+        for rc in [
+            RCg::from_mask(0xff00u32),
+            RCg::from_range(7.into(), 3.into()),
+            RCg::from_value((-1).into()),
+            RCg::from_range(0.into(), (-1).into()),
+        ] {
+            let bytes = serde_cbor::to_vec(&rc).unwrap();
+            assert_eq!(serde_cbor::from_slice::<RCg>(&bytes).unwrap(), rc);
+        }
+    }
 }
@@ -0,0 +1,83 @@
+//! Compares the witness generated with `POWDR_JIT_WITGEN=1` against the regular witness.
+//! These tests live in their own binary, because they modify a process-wide
+//! environment variable.
+
+use std::sync::{Arc, Mutex};
+
+use powdr_executor::witgen::jit_answered_lookups;
+use powdr_linker::{LinkerMode, LinkerParams};
+use powdr_number::GoldilocksField;
+use powdr_pipeline::{
+    pipeline::Columns,
+    test_util::{resolve_test_file, test_mock_backend},
+    Pipeline,
+};
+use test_log::test;
+
+/// Serializes the tests in this binary, since they all set `POWDR_JIT_WITGEN`.
+static JIT_ENV: Mutex<()> = Mutex::new(());
+
+/// Runs `f` with `POWDR_JIT_WITGEN` set to `1` if `jit` is true and unset otherwise.
+fn with_jit<R>(jit: bool, f: impl FnOnce() -> R) -> R {
+    let _guard = JIT_ENV.lock().unwrap_or_else(|e| e.into_inner());
+    if jit {
+        std::env::set_var("POWDR_JIT_WITGEN", "1");
+    } else {
+        std::env::remove_var("POWDR_JIT_WITGEN");
+    }
+    let result = f();
+    std::env::remove_var("POWDR_JIT_WITGEN");
+    result
+}
+
+fn make_pipeline(file_name: &str) -> Pipeline<GoldilocksField> {
+    Pipeline::<GoldilocksField>::default()
+        .from_file(resolve_test_file(file_name))
+        .with_linker_params(LinkerParams {
+            // The bus linker connects machines via phantom lookups with
+            // multiplicity columns, which also have to be filled by the JIT path.
+            mode: LinkerMode::Bus,
+            ..Default::default()
+        })
+}
+
+fn compute_witness(file_name: &str) -> Arc<Columns<GoldilocksField>> {
+    make_pipeline(file_name).compute_witness().unwrap()
+}
+
+#[test]
+fn vm_to_block_witness_matches() {
+    let f = "asm/vm_to_block_unique_interface.asm";
+    let expected = with_jit(false, || compute_witness(f));
+    let multiplicities = expected
+        .iter()
+        .filter(|(name, _)| name.contains("multiplicities"))
+        .collect::<Vec<_>>();
+    assert!(!multiplicities.is_empty());
+    assert!(multiplicities
+        .iter()
+        .any(|(_, values)| values.iter().any(|v| *v != GoldilocksField::from(0u32))));
+
+    let (actual, answered) = with_jit(true, || {
+        let before = jit_answered_lookups();
+        let witness = compute_witness(f);
+        (witness, jit_answered_lookups() - before)
+    });
+    assert!(answered > 0, "No lookup was answered by generated code.");
+    assert_eq!(actual.len(), expected.len());
+    for ((name, actual), (expected_name, expected)) in actual.iter().zip(expected.iter()) {
+        assert_eq!(name, expected_name);
+        assert_eq!(actual, expected, "Column {name} differs");
+    }
+}
+
+#[test]
+fn vm_to_block_proof() {
+    let f = "asm/vm_to_block_unique_interface.asm";
+    let answered = with_jit(true, || {
+        let before = jit_answered_lookups();
+        test_mock_backend(make_pipeline(f));
+        jit_answered_lookups() - before
+    });
+    assert!(answered > 0, "No lookup was answered by generated code.");
+}